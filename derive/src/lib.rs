@@ -0,0 +1,374 @@
+//! Derives for the packet traits in `dam-networks`, for packets which are just a struct with one field holding the
+//! destination. Use them through the re-exports in `dam_networks::switches::routing`.
+//!
+//! This only needs the structure of the item, so it reads the tokens itself instead of pulling in a full parser.
+
+use proc_macro::{Delimiter, Group, Span, TokenStream, TokenTree};
+
+/// Implements `Packet<FieldType>` for a struct, with one of its fields marked `#[packet(destination)]` as its
+/// destination, which is cloned out.
+#[proc_macro_derive(Packet, attributes(packet))]
+pub fn derive_packet(item: TokenStream) -> TokenStream {
+    let item = match Item::parse(item) {
+        Ok(item) => item,
+        Err(message) => return compile_error(&message),
+    };
+
+    let mut destinations = item.fields.iter().filter(|field| field.destination);
+    let field = match (destinations.next(), destinations.next()) {
+        (Some(field), None) => field,
+        (None, _) => {
+            return compile_error(&format!(
+                "Packet can't be derived for {} without a field marked #[packet(destination)]",
+                item.name
+            ))
+        }
+        (Some(_), Some(_)) => {
+            return compile_error(&format!(
+                "Only one field of {} can be marked #[packet(destination)]",
+                item.name
+            ))
+        }
+    };
+
+    let location = &field.ty;
+    let code = format!(
+        "impl<{generics}> ::dam_networks::switches::routing::Packet<{location}> for {name}<{params}> \
+         where {location}: ::core::clone::Clone, {bounds} {{ \
+             fn destination(&self) -> {location} {{ \
+                 ::core::clone::Clone::clone(&self.{member}) \
+             }} \
+         }}",
+        generics = item.generics,
+        name = item.name,
+        params = item.params,
+        bounds = item.bounds,
+        member = field.member,
+    );
+    code.parse().unwrap()
+}
+
+/// Implements `DAMType` for a struct as the sum of the sizes of its fields, as is done for `SimplePacket`.
+#[proc_macro_derive(DAMType)]
+pub fn derive_dam_type(item: TokenStream) -> TokenStream {
+    let item = match Item::parse(item) {
+        Ok(item) => item,
+        Err(message) => return compile_error(&message),
+    };
+
+    let field_bounds: String = item
+        .fields
+        .iter()
+        .map(|field| format!("{}: ::dam::types::DAMType, ", field.ty))
+        .collect();
+    let sizes: Vec<_> = item
+        .fields
+        .iter()
+        .map(|field| format!("::dam::types::DAMType::dam_size(&self.{})", field.member))
+        .collect();
+    let size = match sizes.is_empty() {
+        true => "0".to_string(),
+        false => sizes.join(" + "),
+    };
+    let code = format!(
+        "impl<{generics}> ::dam::types::DAMType for {name}<{params}> \
+         where {field_bounds} {bounds} {{ \
+             fn dam_size(&self) -> usize {{ \
+                 {size} \
+             }} \
+         }}",
+        generics = item.generics,
+        name = item.name,
+        params = item.params,
+        bounds = item.bounds,
+    );
+    code.parse().unwrap()
+}
+
+fn compile_error(message: &str) -> TokenStream {
+    format!("::core::compile_error!({:?});", message)
+        .parse::<TokenStream>()
+        .unwrap()
+        .into_iter()
+        .map(|mut token| {
+            token.set_span(Span::call_site());
+            token
+        })
+        .collect()
+}
+
+struct Field {
+    /// How the field is reached from `self`: its name, or its index in a tuple struct.
+    member: String,
+    ty: String,
+    destination: bool,
+}
+
+/// The parts of a struct which go into an impl for it, as source text.
+struct Item {
+    name: String,
+    /// The generic parameters with their bounds, for the `impl<...>`.
+    generics: String,
+    /// Just the names of the generic parameters, for the type.
+    params: String,
+    /// The predicates of the struct's where clause, with a trailing comma if there are any.
+    bounds: String,
+    fields: Vec<Field>,
+}
+
+impl Item {
+    fn parse(item: TokenStream) -> Result<Self, String> {
+        let tokens: Vec<_> = item.into_iter().collect();
+        let mut rest = &tokens[..];
+
+        // Attributes and visibility, up to the keyword.
+        loop {
+            match rest {
+                [TokenTree::Ident(ident), ..] if ident.to_string() == "struct" => break,
+                [TokenTree::Ident(ident), ..]
+                    if matches!(ident.to_string().as_str(), "enum" | "union") =>
+                {
+                    return Err("Packets can only be derived for structs".to_string())
+                }
+                [_, tail @ ..] => rest = tail,
+                [] => return Err("Expected a struct".to_string()),
+            }
+        }
+        let name = match &rest[1] {
+            TokenTree::Ident(name) => name.to_string(),
+            _ => return Err("Expected the name of the struct".to_string()),
+        };
+        rest = &rest[2..];
+
+        let mut generics = vec![];
+        if matches!(rest.first(), Some(TokenTree::Punct(punct)) if punct.as_char() == '<') {
+            let close = closing_angle(rest).ok_or("Unclosed generic parameters")?;
+            generics = split_commas(&rest[1..close]);
+            rest = &rest[close + 1..];
+        }
+
+        let mut bounds = vec![];
+        let mut body = None;
+        for (index, token) in rest.iter().enumerate() {
+            match token {
+                TokenTree::Ident(ident) if ident.to_string() == "where" => {
+                    // The predicates run to the body or, for tuple structs, the final semicolon.
+                    let end = rest[index + 1..]
+                        .iter()
+                        .position(|token| match token {
+                            TokenTree::Group(group) => group.delimiter() == Delimiter::Brace,
+                            TokenTree::Punct(punct) => punct.as_char() == ';',
+                            _ => false,
+                        })
+                        .map_or(rest.len(), |end| index + 1 + end);
+                    bounds = split_commas(&rest[index + 1..end]);
+                    if body.is_none() {
+                        body = rest.get(end).cloned();
+                    }
+                    break;
+                }
+                TokenTree::Group(group)
+                    if body.is_none()
+                        && matches!(
+                            group.delimiter(),
+                            Delimiter::Brace | Delimiter::Parenthesis
+                        ) =>
+                {
+                    body = Some(token.clone());
+                }
+                _ => {}
+            }
+        }
+        let fields = match body {
+            Some(TokenTree::Group(group)) => parse_fields(&group)?,
+            // Unit structs.
+            _ => vec![],
+        };
+
+        let params = generics
+            .iter()
+            .map(|param| param_name(param))
+            .collect::<Vec<_>>();
+        let generics = generics
+            .iter()
+            .map(|param| text(without_default(param)))
+            .collect::<Vec<_>>();
+        Ok(Self {
+            name,
+            generics: generics.join(", "),
+            params: params.join(", "),
+            bounds: bounds.iter().map(|bound| text(bound) + ", ").collect(),
+            fields,
+        })
+    }
+}
+
+fn parse_fields(body: &Group) -> Result<Vec<Field>, String> {
+    let tokens: Vec<_> = body.stream().into_iter().collect();
+    let named = body.delimiter() == Delimiter::Brace;
+    let mut fields = vec![];
+    for (index, mut field) in split_commas(&tokens).into_iter().enumerate() {
+        let mut destination = false;
+        while let [TokenTree::Punct(punct), TokenTree::Group(attribute), tail @ ..] = field {
+            if punct.as_char() != '#' {
+                break;
+            }
+            if is_destination(attribute)? {
+                if destination {
+                    return Err(
+                        "A field can only be marked #[packet(destination)] once".to_string()
+                    );
+                }
+                destination = true;
+            }
+            field = tail;
+        }
+        field = without_visibility(field);
+
+        let (member, ty) = match (named, field) {
+            (true, [TokenTree::Ident(name), TokenTree::Punct(colon), ty @ ..])
+                if colon.as_char() == ':' =>
+            {
+                (name.to_string(), ty)
+            }
+            (true, _) => return Err("Expected a named field".to_string()),
+            (false, ty) => (index.to_string(), ty),
+        };
+        fields.push(Field {
+            member,
+            ty: text(ty),
+            destination,
+        });
+    }
+    Ok(fields)
+}
+
+/// Whether the contents of an attribute are `packet(destination)`. Other attributes are skipped, but anything else
+/// under `packet` is a mistake.
+fn is_destination(attribute: &Group) -> Result<bool, String> {
+    let tokens: Vec<_> = attribute.stream().into_iter().collect();
+    match &tokens[..] {
+        [TokenTree::Ident(ident), rest @ ..] if ident.to_string() == "packet" => match rest {
+            [TokenTree::Group(args)]
+                if args.delimiter() == Delimiter::Parenthesis
+                    && args.stream().to_string() == "destination" =>
+            {
+                Ok(true)
+            }
+            _ => Err(format!(
+                "Unknown packet attribute #[{}], expected #[packet(destination)]",
+                attribute.stream()
+            )),
+        },
+        _ => Ok(false),
+    }
+}
+
+fn without_visibility(field: &[TokenTree]) -> &[TokenTree] {
+    match field {
+        [TokenTree::Ident(ident), rest @ ..] if ident.to_string() == "pub" => match rest {
+            // `pub(crate)` and the like, as opposed to a tuple struct field of a tuple type.
+            [TokenTree::Group(group), tail @ ..]
+                if group.delimiter() == Delimiter::Parenthesis
+                    && matches!(
+                        group.stream().into_iter().next(),
+                        Some(TokenTree::Ident(scope)) if matches!(scope.to_string().as_str(), "crate" | "self" | "super" | "in")
+                    ) =>
+            {
+                tail
+            }
+            _ => rest,
+        },
+        _ => field,
+    }
+}
+
+/// The index of the `>` which closes the `<` at the start of `tokens`.
+fn closing_angle(tokens: &[TokenTree]) -> Option<usize> {
+    let mut depth = 0;
+    let mut after_dash = false;
+    for (index, token) in tokens.iter().enumerate() {
+        let mut dash = false;
+        if let TokenTree::Punct(punct) = token {
+            match punct.as_char() {
+                '<' => depth += 1,
+                // The arrow in `Fn() -> T` doesn't close anything.
+                '>' if !after_dash => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Some(index);
+                    }
+                }
+                '-' => dash = true,
+                _ => {}
+            }
+        }
+        after_dash = dash;
+    }
+    None
+}
+
+/// Splits a list at the commas which aren't nested inside angle brackets, dropping a trailing comma.
+fn split_commas(tokens: &[TokenTree]) -> Vec<&[TokenTree]> {
+    let mut parts = vec![];
+    let mut depth = 0;
+    let mut after_dash = false;
+    let mut start = 0;
+    for (index, token) in tokens.iter().enumerate() {
+        let mut dash = false;
+        if let TokenTree::Punct(punct) = token {
+            match punct.as_char() {
+                '<' => depth += 1,
+                '>' if !after_dash => depth -= 1,
+                '-' => dash = true,
+                ',' if depth == 0 => {
+                    parts.push(&tokens[start..index]);
+                    start = index + 1;
+                }
+                _ => {}
+            }
+        }
+        after_dash = dash;
+    }
+    if start < tokens.len() {
+        parts.push(&tokens[start..]);
+    }
+    parts
+}
+
+/// A generic parameter without its default, which can't be repeated on an impl.
+fn without_default(param: &[TokenTree]) -> &[TokenTree] {
+    let mut depth = 0;
+    for (index, token) in param.iter().enumerate() {
+        if let TokenTree::Punct(punct) = token {
+            match punct.as_char() {
+                '<' => depth += 1,
+                '>' => depth -= 1,
+                '=' if depth == 0 => return &param[..index],
+                _ => {}
+            }
+        }
+    }
+    param
+}
+
+/// The name by which a generic parameter is passed to the type: `'a` for lifetimes, `N` for `const N: usize`, and
+/// `T` for types.
+fn param_name(param: &[TokenTree]) -> String {
+    match param {
+        [TokenTree::Punct(quote), TokenTree::Ident(lifetime), ..] if quote.as_char() == '\'' => {
+            format!("'{}", lifetime)
+        }
+        [TokenTree::Ident(keyword), TokenTree::Ident(name), ..]
+            if keyword.to_string() == "const" =>
+        {
+            name.to_string()
+        }
+        [name, ..] => name.to_string(),
+        [] => String::new(),
+    }
+}
+
+fn text(tokens: &[TokenTree]) -> String {
+    tokens.iter().cloned().collect::<TokenStream>().to_string()
+}
@@ -0,0 +1,99 @@
+//! Builds a small network around a single [`SimpleSwitch`] from outside of the crate.
+//!
+//! A generator sends every packet to port 1, where a compute context bumps the payload and sends it back
+//! into the switch addressed to port 2, which is drained by a consumer.
+
+use dam::{
+    context_tools::ChannelElement,
+    simulation::{DotConvertible, ProgramBuilder},
+    utility_contexts::*,
+};
+use dam_networks::{
+    switches::routing::{Port, SimplePacket, Switch},
+    SimpleSwitch,
+};
+use fxhash::FxHashSet;
+
+const NUM_PACKETS: u16 = 2048;
+
+fn main() {
+    let mut ctx = ProgramBuilder::default();
+
+    let (g2switch_snd, g2switch_rcv) = ctx.unbounded();
+    ctx.add_child(GeneratorContext::new(
+        || {
+            (0..NUM_PACKETS).map(|i| SimplePacket {
+                location: 1u8,
+                payload: i,
+            })
+        },
+        g2switch_snd,
+    ));
+
+    // Maps 1 -> {1}, 2 -> {2}
+    let policy = fxhash::FxHashMap::from_iter([
+        (1u8, FxHashSet::from_iter([1usize])),
+        (2, FxHashSet::from_iter([2usize])),
+    ]);
+    let mut switch = SimpleSwitch::new(policy, 1);
+    switch.add_port(Port {
+        id: 0,
+        input: Some(g2switch_rcv),
+        output: None,
+    });
+
+    let (switch2comp_snd, switch2comp_rcv) = ctx.unbounded();
+    let (comp2switch_snd, comp2switch_rcv) = ctx.unbounded();
+    switch.add_port(Port {
+        id: 1,
+        input: Some(comp2switch_rcv),
+        output: Some(switch2comp_snd),
+    });
+
+    let mut comp = FunctionContext::new();
+    comp2switch_snd.attach_sender(&comp);
+    switch2comp_rcv.attach_receiver(&comp);
+    comp.set_run(move |time| {
+        for i in 0..NUM_PACKETS {
+            let ChannelElement {
+                time: _,
+                data:
+                    SimplePacket {
+                        location: _,
+                        payload,
+                    },
+            } = switch2comp_rcv.dequeue(time).unwrap();
+            comp2switch_snd
+                .enqueue(
+                    time,
+                    ChannelElement {
+                        time: time.tick() + 1,
+                        data: SimplePacket {
+                            location: 2,
+                            payload: payload + i + 100,
+                        },
+                    },
+                )
+                .unwrap();
+            time.incr_cycles(1);
+        }
+    });
+    ctx.add_child(comp);
+
+    let (switch2check_snd, switch2check_rcv) = ctx.unbounded();
+    ctx.add_child(ConsumerContext::new(switch2check_rcv));
+    switch.add_port(Port {
+        id: 2,
+        input: None,
+        output: Some(switch2check_snd),
+    });
+    ctx.add_child(switch);
+
+    let initialized = ctx.initialize(Default::default()).unwrap();
+    println!("{}", initialized.to_dot_string());
+    let executed = initialized.run(Default::default());
+    println!(
+        "Finished in {} cycles",
+        executed.elapsed_cycles().unwrap().time()
+    );
+}
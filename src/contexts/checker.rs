@@ -0,0 +1,256 @@
+use std::sync::{Arc, Mutex};
+
+use dam::context_tools::*;
+
+/// Whether a [`CheckerSink`] cares about the order its elements arrive in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CheckOrder {
+    #[default]
+    AnyOrder,
+    /// Elements must arrive in the order they were expected in.
+    ExactOrder,
+}
+
+/// Which elements a [`CheckerSink`] expects.
+enum Expected<T> {
+    /// Each of these, as many times as it is listed, along with whether it has arrived yet.
+    Elements(Vec<(T, bool)>),
+    /// This many elements which pass the check.
+    Matching {
+        check: Box<dyn Fn(&T) -> bool + Send + Sync>,
+        count: u64,
+    },
+}
+
+/// What a [`CheckerSink`] made of the elements it received.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CheckReport<T> {
+    pub received: u64,
+    /// How many elements the sink expected, and how many of those arrived.
+    pub expected: u64,
+    pub matched: u64,
+    /// Expected elements which never arrived. Only filled in once the input has closed, and only for sinks given a list
+    /// of elements, which the rest of `expected` are missing from otherwise.
+    pub missing: Vec<T>,
+    /// Elements which weren't expected at all.
+    pub unexpected: Vec<T>,
+    /// Elements which were expected, but arrived more times than they were listed.
+    pub duplicates: Vec<T>,
+    /// Expected elements which arrived before one listed ahead of them, when checking the order.
+    pub out_of_order: Vec<T>,
+    /// Whether the input has closed, so that nothing else will arrive.
+    pub closed: bool,
+}
+
+impl<T> Default for CheckReport<T> {
+    fn default() -> Self {
+        Self {
+            received: 0,
+            expected: 0,
+            matched: 0,
+            missing: vec![],
+            unexpected: vec![],
+            duplicates: vec![],
+            out_of_order: vec![],
+            closed: false,
+        }
+    }
+}
+
+impl<T> CheckReport<T> {
+    /// Whether everything expected arrived, and nothing else, in order if it had to be.
+    pub fn passed(&self) -> bool {
+        self.closed
+            && self.matched == self.expected
+            && self.unexpected.is_empty()
+            && self.duplicates.is_empty()
+            && self.out_of_order.is_empty()
+    }
+}
+
+/// A sink which checks what it receives against what it expects, and writes down whatever doesn't match instead of
+/// panicking, where nobody would see the message. Read the report once the run is over. Like a
+/// [`ConsumerContext`](dam::utility_contexts::ConsumerContext), it takes one element a cycle.
+#[context_macro]
+pub struct CheckerSink<T: DAMType> {
+    input: Receiver<T>,
+    expected: Expected<T>,
+    order: CheckOrder,
+    report: Arc<Mutex<CheckReport<T>>>,
+}
+
+impl<T: DAMType + PartialEq> Context for CheckerSink<T> {
+    fn run(&mut self) {
+        // The first expected element which hasn't arrived yet, when there is a list of them.
+        let mut next = 0;
+        while let Ok(ChannelElement { time: _, data }) = self.input.dequeue(&self.time) {
+            let mut report = self.report.lock().unwrap();
+            report.received += 1;
+            match &mut self.expected {
+                Expected::Elements(elements) => {
+                    match elements
+                        .iter()
+                        .position(|(element, arrived)| !arrived && *element == data)
+                    {
+                        Some(index) => {
+                            elements[index].1 = true;
+                            report.matched += 1;
+                            if self.order == CheckOrder::ExactOrder && index != next {
+                                report.out_of_order.push(data);
+                            }
+                            while next < elements.len() && elements[next].1 {
+                                next += 1;
+                            }
+                        }
+                        None if elements.iter().any(|(element, _)| *element == data) => {
+                            report.duplicates.push(data)
+                        }
+                        None => report.unexpected.push(data),
+                    }
+                }
+                Expected::Matching { check, count } => {
+                    if check(&data) && report.matched < *count {
+                        report.matched += 1;
+                    } else {
+                        report.unexpected.push(data);
+                    }
+                }
+            }
+            drop(report);
+            self.time.incr_cycles(1);
+        }
+
+        let mut report = self.report.lock().unwrap();
+        if let Expected::Elements(elements) = &self.expected {
+            report.missing = elements
+                .iter()
+                .filter(|(_, arrived)| !arrived)
+                .map(|(element, _)| element.clone())
+                .collect();
+        }
+        report.closed = true;
+    }
+}
+
+impl<T: DAMType + PartialEq> CheckerSink<T> {
+    /// Expects each of `elements`, as many times as they are listed, in any order.
+    pub fn new(input: Receiver<T>, elements: Vec<T>) -> Self {
+        let count = elements.len() as u64;
+        let expected = Expected::Elements(elements.into_iter().map(|x| (x, false)).collect());
+        Self::with_expected(input, expected, count)
+    }
+
+    /// Expects `count` elements which pass `check`, and nothing else. The order they arrive in doesn't matter.
+    pub fn matching(
+        input: Receiver<T>,
+        check: impl Fn(&T) -> bool + Send + Sync + 'static,
+        count: u64,
+    ) -> Self {
+        let expected = Expected::Matching {
+            check: Box::new(check),
+            count,
+        };
+        Self::with_expected(input, expected, count)
+    }
+
+    fn with_expected(input: Receiver<T>, expected: Expected<T>, count: u64) -> Self {
+        let sink = Self {
+            input,
+            expected,
+            order: CheckOrder::AnyOrder,
+            report: Arc::new(Mutex::new(CheckReport {
+                expected: count,
+                ..Default::default()
+            })),
+            context_info: Default::default(),
+        };
+        sink.input.attach_receiver(&sink);
+        sink
+    }
+
+    /// Only holds for sinks given a list of elements.
+    pub fn with_order(mut self, order: CheckOrder) -> Self {
+        self.order = order;
+        self
+    }
+
+    /// The handle is shared with the sink, so it can be read once the run is over.
+    pub fn report(&self) -> Arc<Mutex<CheckReport<T>>> {
+        self.report.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dam::{simulation::ProgramBuilder, utility_contexts::*};
+
+    use super::{CheckOrder, CheckReport, CheckerSink};
+
+    /// Sends `sent` to a checker expecting `expected`, and returns its report.
+    fn check(sent: Vec<u32>, expected: Vec<u32>, order: CheckOrder) -> CheckReport<u32> {
+        let mut ctx = ProgramBuilder::default();
+        let (snd, rcv) = ctx.unbounded();
+        ctx.add_child(GeneratorContext::new(move || sent.into_iter(), snd));
+        let checker = CheckerSink::new(rcv, expected).with_order(order);
+        let report = checker.report();
+        ctx.add_child(checker);
+        ctx.initialize(Default::default())
+            .unwrap()
+            .run(Default::default());
+        let report = report.lock().unwrap().clone();
+        report
+    }
+
+    #[test]
+    fn any_order_test() {
+        let report = check(vec![3, 1, 2, 1], vec![1, 1, 2, 3], CheckOrder::AnyOrder);
+        assert!(report.passed(), "{:?}", report);
+        assert_eq!(report.received, 4);
+        // The same elements have to come in as they were listed, though.
+        let report = check(vec![3, 1, 2, 1], vec![1, 1, 2, 3], CheckOrder::ExactOrder);
+        assert!(!report.passed());
+        assert_eq!(report.out_of_order, vec![3, 2]);
+        assert!(report.missing.is_empty());
+    }
+
+    #[test]
+    fn mismatch_test() {
+        let report = check(
+            vec![1, 2, 2, 5, 3],
+            vec![1, 2, 3, 4],
+            CheckOrder::ExactOrder,
+        );
+        let expected = CheckReport {
+            received: 5,
+            expected: 4,
+            matched: 3,
+            missing: vec![4],
+            unexpected: vec![5],
+            duplicates: vec![2],
+            out_of_order: vec![],
+            closed: true,
+        };
+        assert_eq!(report, expected);
+        assert!(!report.passed());
+    }
+
+    #[test]
+    fn matching_test() {
+        let mut ctx = ProgramBuilder::default();
+        let (snd, rcv) = ctx.unbounded();
+        ctx.add_child(GeneratorContext::new(|| (0..10u32).map(|x| x * 2), snd));
+        let checker = CheckerSink::matching(rcv, |x| x % 2 == 0, 12);
+        let report = checker.report();
+        ctx.add_child(checker);
+        ctx.initialize(Default::default())
+            .unwrap()
+            .run(Default::default());
+
+        let report = report.lock().unwrap();
+        // Every element passed, but two fewer came than were expected.
+        assert_eq!((report.matched, report.expected), (10, 12));
+        assert!(report.unexpected.is_empty() && report.closed);
+        assert!(!report.passed());
+    }
+}
@@ -0,0 +1,157 @@
+use std::collections::VecDeque;
+
+use dam::{
+    channel::utils::{EventTime, Peekable},
+    context_tools::*,
+    simulation::ProgramBuilder,
+};
+
+use super::wait::wait_for_work;
+
+/// A pipelined repeater, like the registers along a long wire. Every element comes out `delay` cycles after it went
+/// in, and at most `depth` of them can be on their way at once. Once it is full, the buffer stops taking elements,
+/// which backs up whoever is sending them.
+#[context_macro]
+pub struct ElasticBuffer<T: DAMType> {
+    input: Receiver<T>,
+    output: Sender<T>,
+    delay: u64,
+    depth: usize,
+    // Elements on their way, stamped with the cycle they get handed to the output on. That's the one before they
+    // come out, since whatever we send is time stamped for the next cycle at the earliest.
+    in_flight: VecDeque<ChannelElement<T>>,
+}
+
+impl<T: DAMType> Context for ElasticBuffer<T> {
+    fn run(&mut self) {
+        while self.wait_for_work() {
+            // Send first, so that a full buffer which gets rid of an element can take another on the same cycle.
+            if let Some(front) = self.in_flight.front() {
+                if front.time <= self.time.tick()
+                    && !matches!(
+                        self.output.try_enqueue(
+                            &self.time,
+                            ChannelElement {
+                                time: front.time + 1,
+                                data: front.data.clone(),
+                            }
+                        ),
+                        Err(dam::channel::EnqueueError::Full)
+                    )
+                {
+                    self.in_flight.pop_front();
+                }
+            }
+
+            if self.in_flight.len() < self.depth
+                && matches!(self.input.next_event(), EventTime::Ready(t) if t <= self.time.tick())
+            {
+                let ChannelElement { time: _, data } = self.input.dequeue(&self.time).unwrap();
+                self.in_flight.push_back(ChannelElement {
+                    time: self.time.tick() + (self.delay - 1),
+                    data,
+                });
+            }
+            self.time.incr_cycles(1);
+        }
+    }
+}
+
+impl<T: DAMType> ElasticBuffer<T> {
+    /// Creates a buffer which holds up to `depth` elements, each of which goes out `delay` cycles after it came in.
+    pub fn new(input: Receiver<T>, output: Sender<T>, delay: u64, depth: usize) -> Self {
+        assert!(depth > 0, "Buffers must hold at least one element!");
+        assert!(
+            delay > 0,
+            "Buffers must delay elements by at least a cycle!"
+        );
+        let buffer = Self {
+            input,
+            output,
+            delay,
+            depth,
+            in_flight: Default::default(),
+            context_info: Default::default(),
+        };
+        buffer.input.attach_receiver(&buffer);
+        buffer.output.attach_sender(&buffer);
+        buffer
+    }
+
+    /// Waits until there is something to do, returning false once the input has closed and everything in flight has
+    /// gone out. Full buffers don't take anything, so there's no point in waiting on the input.
+    fn wait_for_work(&mut self) -> bool {
+        let listening = self.in_flight.len() < self.depth;
+        let next_send = self.in_flight.front().map(|front| front.time);
+        wait_for_work(
+            &mut self.context_info.time,
+            &self.input,
+            listening,
+            next_send,
+        )
+    }
+}
+
+/// Builds `n` elastic buffers in a row after `input`, and returns the far end of the last one. The buffers are joined
+/// by channels with room for two elements, which is enough to keep one moving every cycle.
+pub fn chain<'a, T: DAMType + 'a>(
+    ctx: &mut ProgramBuilder<'a>,
+    input: Receiver<T>,
+    n: usize,
+    delay: u64,
+    depth: usize,
+) -> Receiver<T> {
+    (0..n).fold(input, |input, _| {
+        let (snd, rcv) = ctx.bounded(2);
+        ctx.add_child(ElasticBuffer::new(input, snd, delay, depth));
+        rcv
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use dam::{context_tools::ChannelElement, simulation::ProgramBuilder, utility_contexts::*};
+
+    use super::chain;
+
+    const NUM_ELEMENTS: u32 = 64;
+    const STAGES: usize = 4;
+    const DELAY: u64 = 3;
+
+    #[test]
+    fn chain_test() {
+        let mut ctx = ProgramBuilder::default();
+
+        let (snd, rcv) = ctx.unbounded();
+        ctx.add_child(GeneratorContext::new(|| 0..NUM_ELEMENTS, snd));
+        let rcv = chain(&mut ctx, rcv, STAGES, DELAY, DELAY as usize);
+
+        let received = Arc::new(Mutex::new(vec![]));
+        let mut sink = FunctionContext::new();
+        rcv.attach_receiver(&sink);
+        let sink_log = received.clone();
+        sink.set_run(move |time| {
+            while let Ok(ChannelElement { time, data }) = rcv.dequeue(time) {
+                sink_log.lock().unwrap().push((data, time.time()));
+            }
+        });
+        ctx.add_child(sink);
+
+        ctx.initialize(Default::default())
+            .unwrap()
+            .run(Default::default());
+
+        let received = received.lock().unwrap().clone();
+        assert_eq!(
+            Vec::from_iter(received.iter().map(|(data, _)| *data)),
+            Vec::from_iter(0..NUM_ELEMENTS)
+        );
+        // The generator sends its first element on cycle 1.
+        let (_, first) = received[0];
+        assert_eq!(first, 1 + STAGES as u64 * DELAY);
+        // After that, the pipeline keeps up with the generator.
+        assert!(received.windows(2).all(|pair| pair[1].1 == pair[0].1 + 1));
+    }
+}
@@ -0,0 +1,251 @@
+use dam::context_tools::*;
+
+use crate::switches::routing::{SimplePacket, TaggedFlit, Words};
+
+/// Breaks each packet into [`TaggedFlit`]s: a head, body flits of up to `words_per_flit` words of the payload each,
+/// and a tail. Sends one flit per cycle. Packets are numbered in the order they come in, so unlike the
+/// [`crate::switches::Packetizer`] for wormhole switches, the flits of different packets can safely be interleaved.
+#[context_macro]
+pub struct Packetize<LT: DAMType, PT: DAMType> {
+    input: Receiver<SimplePacket<LT, PT>>,
+    output: Sender<TaggedFlit<LT>>,
+    words_per_flit: usize,
+    next_id: u64,
+}
+
+impl<LT: DAMType, PT: DAMType + Words> Context for Packetize<LT, PT> {
+    fn run(&mut self) {
+        while let Ok(ChannelElement { time: _, data }) = self.input.dequeue(&self.time) {
+            let words = data.payload.to_words();
+            let destination = data.location;
+            let packet_id = self.next_id;
+            self.next_id += 1;
+
+            let head = TaggedFlit::Head {
+                destination: destination.clone(),
+                packet_id,
+                length: words.len(),
+            };
+            let body = words
+                .chunks(self.words_per_flit)
+                .map(|words| TaggedFlit::Body {
+                    destination: destination.clone(),
+                    packet_id,
+                    words: words.to_vec(),
+                });
+            let tail = TaggedFlit::Tail {
+                destination: destination.clone(),
+                packet_id,
+            };
+            for flit in std::iter::once(head)
+                .chain(body)
+                .chain(std::iter::once(tail))
+            {
+                self.output
+                    .enqueue(
+                        &self.time,
+                        ChannelElement {
+                            time: self.time.tick() + 1,
+                            data: flit,
+                        },
+                    )
+                    .unwrap();
+                self.time.incr_cycles(1);
+            }
+        }
+    }
+}
+
+impl<LT: DAMType, PT: DAMType> Packetize<LT, PT>
+where
+    Self: Context,
+{
+    pub fn new(
+        input: Receiver<SimplePacket<LT, PT>>,
+        output: Sender<TaggedFlit<LT>>,
+        words_per_flit: usize,
+    ) -> Self {
+        assert!(words_per_flit > 0, "Flits must carry at least one word!");
+        let packetize = Self {
+            input,
+            output,
+            words_per_flit,
+            next_id: 0,
+            context_info: Default::default(),
+        };
+        packetize.input.attach_receiver(&packetize);
+        packetize.output.attach_sender(&packetize);
+        packetize
+    }
+
+    /// Numbers packets from `first_id` up, instead of from 0, so that several packetizers feeding the same network
+    /// can be given IDs which don't collide.
+    pub fn with_first_id(mut self, first_id: u64) -> Self {
+        self.next_id = first_id;
+        self
+    }
+}
+
+/// Puts packets back together from the flits made by a [`Packetize`], sending each one on once its tail arrives.
+/// Flits of different packets may come in any interleaving, but each packet's own flits must stay in order.
+#[context_macro]
+pub struct Depacketize<LT: DAMType, PT: DAMType> {
+    input: Receiver<TaggedFlit<LT>>,
+    output: Sender<SimplePacket<LT, PT>>,
+}
+
+impl<LT: DAMType, PT: DAMType + Words> Context for Depacketize<LT, PT> {
+    fn run(&mut self) {
+        // The destination, length and words so far of every packet whose head has come in but whose tail hasn't.
+        let mut partial = fxhash::FxHashMap::<u64, (LT, usize, Vec<u64>)>::default();
+        while let Ok(ChannelElement { time: _, data }) = self.input.dequeue(&self.time) {
+            match data {
+                TaggedFlit::Head {
+                    destination,
+                    packet_id,
+                    length,
+                } => {
+                    let previous = partial.insert(packet_id, (destination, length, vec![]));
+                    assert!(
+                        previous.is_none(),
+                        "Got a second head for packet {}!",
+                        packet_id
+                    );
+                }
+                TaggedFlit::Body {
+                    packet_id, words, ..
+                } => match partial.get_mut(&packet_id) {
+                    Some((_, _, received)) => received.extend(words),
+                    None => panic!("Got a body flit for packet {} without a head!", packet_id),
+                },
+                TaggedFlit::Tail { packet_id, .. } => {
+                    let (location, length, words) =
+                        partial.remove(&packet_id).unwrap_or_else(|| {
+                            panic!("Got a tail flit for packet {} without a head!", packet_id)
+                        });
+                    assert_eq!(
+                        words.len(),
+                        length,
+                        "Packet {} is missing words!",
+                        packet_id
+                    );
+                    self.output
+                        .enqueue(
+                            &self.time,
+                            ChannelElement {
+                                time: self.time.tick() + 1,
+                                data: SimplePacket {
+                                    location,
+                                    payload: PT::from_words(&words),
+                                },
+                            },
+                        )
+                        .unwrap();
+                }
+            }
+        }
+    }
+}
+
+impl<LT: DAMType, PT: DAMType> Depacketize<LT, PT>
+where
+    Self: Context,
+{
+    pub fn new(input: Receiver<TaggedFlit<LT>>, output: Sender<SimplePacket<LT, PT>>) -> Self {
+        let depacketize = Self {
+            input,
+            output,
+            context_info: Default::default(),
+        };
+        depacketize.input.attach_receiver(&depacketize);
+        depacketize.output.attach_sender(&depacketize);
+        depacketize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use dam::{context_tools::*, simulation::ProgramBuilder, utility_contexts::*};
+
+    use crate::{
+        contexts::Merge,
+        random::SplitMix64,
+        switches::routing::{BytesPacket, Payload, SimplePacket},
+    };
+
+    use super::{Depacketize, Packetize};
+
+    const NUM_PACKETS: usize = 100;
+    const MAX_BYTES: u64 = 40;
+    const WORDS_PER_FLIT: usize = 2;
+
+    /// Packets for random destinations, with random bytes of random lengths, some of them empty.
+    fn random_packets(seed: u64) -> Vec<BytesPacket<u8>> {
+        let mut rng = SplitMix64::new(seed);
+        (0..NUM_PACKETS)
+            .map(|_| {
+                let length = rng.below(MAX_BYTES + 1) as usize;
+                SimplePacket {
+                    location: rng.below(16) as u8,
+                    payload: Payload((0..length).map(|_| rng.below(256) as u8).collect()),
+                }
+            })
+            .collect()
+    }
+
+    /// Packetizes each of `sources` on its own, merges the flits onto one channel, and depacketizes them again.
+    fn round_trip(sources: Vec<Vec<BytesPacket<u8>>>) -> Vec<BytesPacket<u8>> {
+        let mut ctx = ProgramBuilder::default();
+        let mut flits = vec![];
+        for (source, packets) in sources.into_iter().enumerate() {
+            let (snd, rcv) = ctx.unbounded();
+            ctx.add_child(GeneratorContext::new(move || packets.into_iter(), snd));
+            let (flit_snd, flit_rcv) = ctx.unbounded();
+            let first_id = (source * NUM_PACKETS) as u64;
+            ctx.add_child(Packetize::new(rcv, flit_snd, WORDS_PER_FLIT).with_first_id(first_id));
+            flits.push(flit_rcv);
+        }
+        let (merged_snd, merged_rcv) = ctx.unbounded();
+        ctx.add_child(Merge::new(flits, merged_snd, 1));
+        let (snd, rcv) = ctx.unbounded();
+        ctx.add_child(Depacketize::new(merged_rcv, snd));
+
+        let received = Arc::new(Mutex::new(vec![]));
+        let mut sink = FunctionContext::new();
+        rcv.attach_receiver(&sink);
+        let sink_log = received.clone();
+        sink.set_run(move |time| {
+            while let Ok(ChannelElement { time: _, data }) = rcv.dequeue(time) {
+                sink_log.lock().unwrap().push(data);
+            }
+        });
+        ctx.add_child(sink);
+        ctx.initialize(Default::default())
+            .unwrap()
+            .run(Default::default());
+
+        let received = received.lock().unwrap().clone();
+        received
+    }
+
+    #[test]
+    fn flit_round_trip_test() {
+        let packets = random_packets(0);
+        assert_eq!(round_trip(vec![packets.clone()]), packets);
+    }
+
+    #[test]
+    fn interleaved_reassembly_test() {
+        // The two streams' flits take turns on the merged channel, so their packets arrive interleaved.
+        let sources = vec![random_packets(1), random_packets(2)];
+        let mut received = round_trip(sources.clone());
+        let mut sent: Vec<_> = sources.into_iter().flatten().collect();
+
+        let key = |packet: &BytesPacket<u8>| (packet.location, packet.payload.0.clone());
+        received.sort_by_key(key);
+        sent.sort_by_key(key);
+        assert_eq!(received, sent);
+    }
+}
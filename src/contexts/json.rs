@@ -0,0 +1,118 @@
+use std::io::{BufRead, Write};
+
+use dam::context_tools::*;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+/// One line of a [`JsonSink`]'s output: an element, and the cycle it arrived on.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Recorded<T> {
+    pub tick: u64,
+    pub element: T,
+}
+
+/// A sink which writes every element it receives to `writer` as a line of JSON, so that a run can be replayed or
+/// looked over afterwards. Read the lines back with [`read_json_lines`].
+#[context_macro]
+pub struct JsonSink<T: DAMType> {
+    input: Receiver<T>,
+    writer: Box<dyn Write + Send + Sync>,
+}
+
+impl<T: DAMType + Serialize> Context for JsonSink<T> {
+    fn run(&mut self) {
+        while let Ok(ChannelElement { time: _, data }) = self.input.dequeue(&self.time) {
+            let line = Recorded {
+                tick: self.time.tick().time(),
+                element: data,
+            };
+            serde_json::to_writer(&mut self.writer, &line).expect("Couldn't serialize element!");
+            writeln!(self.writer).expect("Couldn't write element!");
+        }
+        self.writer.flush().expect("Couldn't flush JSON output!");
+    }
+}
+
+impl<T: DAMType + Serialize> JsonSink<T> {
+    pub fn new(input: Receiver<T>, writer: impl Write + Send + Sync + 'static) -> Self {
+        let sink = Self {
+            input,
+            writer: Box::new(writer),
+            context_info: Default::default(),
+        };
+        sink.input.attach_receiver(&sink);
+        sink
+    }
+}
+
+/// Loads what a [`JsonSink`] wrote, in the order it was written.
+pub fn read_json_lines<T: DeserializeOwned>(
+    reader: impl BufRead,
+) -> Result<Vec<Recorded<T>>, serde_json::Error> {
+    reader
+        .lines()
+        .filter(|line| !matches!(line, Ok(line) if line.is_empty()))
+        .map(|line| serde_json::from_str(&line.map_err(serde_json::Error::io)?))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Write,
+        sync::{Arc, Mutex},
+    };
+
+    use dam::{simulation::ProgramBuilder, utility_contexts::*};
+
+    use crate::switches::routing::{Payload, SimplePacket, TimedPacket};
+
+    use super::{read_json_lines, JsonSink, Recorded};
+
+    const NUM_PACKETS: usize = 50;
+
+    type TestPacket = TimedPacket<SimplePacket<u8, Payload>>;
+
+    /// Somewhere to write the JSON which can still be read once the sink is done with it.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn json_round_trip_test() {
+        let packets: Vec<TestPacket> = (0..NUM_PACKETS)
+            .map(|i| TimedPacket {
+                packet: SimplePacket {
+                    location: i as u8 % 4,
+                    payload: Payload((0..i as u8 % 8).collect()),
+                },
+                injected_at: i as u64,
+            })
+            .collect();
+
+        let mut ctx = ProgramBuilder::default();
+        let (snd, rcv) = ctx.unbounded();
+        let sent = packets.clone();
+        ctx.add_child(GeneratorContext::new(move || sent.into_iter(), snd));
+        let buffer = SharedBuffer::default();
+        ctx.add_child(JsonSink::new(rcv, buffer.clone()));
+        ctx.initialize(Default::default())
+            .unwrap()
+            .run(Default::default());
+
+        let written = buffer.0.lock().unwrap();
+        let recorded: Vec<Recorded<TestPacket>> = read_json_lines(written.as_slice()).unwrap();
+        assert_eq!(recorded.len(), NUM_PACKETS);
+        assert!(recorded.windows(2).all(|pair| pair[0].tick < pair[1].tick));
+        let received: Vec<_> = recorded.into_iter().map(|line| line.element).collect();
+        assert_eq!(received, packets);
+    }
+}
@@ -0,0 +1,269 @@
+use std::{
+    collections::BTreeMap,
+    hash::Hash,
+    sync::{Arc, Mutex},
+};
+
+use dam::{context_tools::*, structures::SyncSendMarker};
+
+use crate::switches::routing::{Packet, Timestamped};
+
+/// Feeds packets into the network one per cycle, like [`dam::utility_contexts::GeneratorContext`], stamping each with
+/// the cycle it enters on.
+#[context_macro]
+pub struct TimedGenerator<T: DAMType, IType, FType> {
+    iterator: Option<FType>,
+    output: Sender<T>,
+    _marker: SyncSendMarker<IType>,
+}
+
+impl<T: DAMType + Timestamped, IType, FType> Context for TimedGenerator<T, IType, FType>
+where
+    IType: Iterator<Item = T>,
+    FType: FnOnce() -> IType + Send + Sync,
+{
+    fn run(&mut self) {
+        if let Some(iterator) = self.iterator.take() {
+            for packet in iterator() {
+                // Whatever we send is time stamped for the next cycle at the earliest, which is when it goes in.
+                let injected_at = self.time.tick() + 1;
+                self.output
+                    .enqueue(
+                        &self.time,
+                        ChannelElement {
+                            time: injected_at,
+                            data: packet.stamp(injected_at.time()),
+                        },
+                    )
+                    .unwrap();
+                self.time.incr_cycles(1);
+            }
+        }
+    }
+}
+
+impl<T: DAMType + Timestamped, IType, FType> TimedGenerator<T, IType, FType>
+where
+    Self: Context,
+{
+    pub fn new(iterator: FType, output: Sender<T>) -> Self {
+        let generator = Self {
+            iterator: Some(iterator),
+            output,
+            _marker: Default::default(),
+            context_info: Default::default(),
+        };
+        generator.output.attach_sender(&generator);
+        generator
+    }
+}
+
+/// How long the packets for one destination took to get there, in cycles.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LatencySummary {
+    pub count: u64,
+    pub min: u64,
+    pub max: u64,
+    pub total: u64,
+    /// How many packets took each number of cycles.
+    pub histogram: BTreeMap<u64, u64>,
+}
+
+impl LatencySummary {
+    fn record(&mut self, latency: u64) {
+        self.min = match self.count {
+            0 => latency,
+            _ => self.min.min(latency),
+        };
+        self.max = self.max.max(latency);
+        self.count += 1;
+        self.total += latency;
+        *self.histogram.entry(latency).or_default() += 1;
+    }
+
+    pub fn mean(&self) -> f64 {
+        self.total as f64 / self.count as f64
+    }
+}
+
+/// Latencies collected by a [`LatencySink`], by destination.
+#[derive(Clone, Debug)]
+pub struct LatencyStats<LocationType> {
+    pub destinations: fxhash::FxHashMap<LocationType, LatencySummary>,
+}
+
+impl<LocationType> Default for LatencyStats<LocationType> {
+    fn default() -> Self {
+        Self {
+            destinations: Default::default(),
+        }
+    }
+}
+
+impl<LocationType> LatencyStats<LocationType> {
+    /// The latencies of every packet, whichever destination it was for.
+    pub fn overall(&self) -> LatencySummary {
+        let mut overall = LatencySummary::default();
+        for (latency, count) in self
+            .destinations
+            .values()
+            .flat_map(|summary| summary.histogram.iter())
+        {
+            for _ in 0..*count {
+                overall.record(*latency);
+            }
+        }
+        overall
+    }
+}
+
+/// A sink which measures how long each packet took to arrive since it was injected, without holding anything up.
+#[context_macro]
+pub struct LatencySink<T: DAMType, LT> {
+    input: Receiver<T>,
+    stats: Arc<Mutex<LatencyStats<LT>>>,
+}
+
+impl<T: DAMType, LT> Context for LatencySink<T, LT>
+where
+    T: Packet<LT> + Timestamped,
+    LT: Eq + Hash + Send + Sync,
+{
+    fn run(&mut self) {
+        while let Ok(ChannelElement { time: _, data }) = self.input.dequeue(&self.time) {
+            let latency = self.time.tick().time() - data.injected_at();
+            self.stats
+                .lock()
+                .unwrap()
+                .destinations
+                .entry(data.destination())
+                .or_default()
+                .record(latency);
+        }
+    }
+}
+
+impl<T: DAMType, LT> LatencySink<T, LT>
+where
+    Self: Context,
+{
+    pub fn new(input: Receiver<T>) -> Self {
+        let sink = Self {
+            input,
+            stats: Default::default(),
+            context_info: Default::default(),
+        };
+        sink.input.attach_receiver(&sink);
+        sink
+    }
+
+    /// The handle is shared with the sink, so it can be read once the run is over.
+    pub fn stats(&self) -> Arc<Mutex<LatencyStats<LT>>> {
+        self.stats.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dam::simulation::ProgramBuilder;
+    use fxhash::FxHashSet;
+
+    use crate::switches::{
+        routing::{Port, SimplePacket, Switch, TimedPacket},
+        SimpleSwitch,
+    };
+
+    use super::{LatencySink, LatencyStats, TimedGenerator};
+
+    const FIRST_HOP: u64 = 2;
+    const SECOND_HOP: u64 = 3;
+
+    type TestPacket = TimedPacket<SimplePacket<u8, u16>>;
+
+    fn packet(location: u8, payload: u16) -> TestPacket {
+        TimedPacket {
+            packet: SimplePacket { location, payload },
+            injected_at: 0,
+        }
+    }
+
+    /// Sends each of `sources` into its own input of the first of two switches in a row, and everything out of output
+    /// 1 of each, to the next switch and then the sink.
+    fn run_chain(sources: Vec<Vec<TestPacket>>) -> LatencyStats<u8> {
+        let mut ctx = ProgramBuilder::default();
+        let mut switches = [FIRST_HOP, SECOND_HOP]
+            .map(|latency| SimpleSwitch::new(|_: &u8| FxHashSet::from_iter([1]), latency));
+        for (id, packets) in sources.into_iter().enumerate() {
+            let (snd, rcv) = ctx.unbounded();
+            ctx.add_child(TimedGenerator::new(move || packets.into_iter(), snd));
+            // Port 1 is taken by the output.
+            let id = if id == 0 { 0 } else { id + 1 };
+            switches[0].add_port(Port {
+                id,
+                input: Some(rcv),
+                output: None,
+            });
+        }
+        let (snd, rcv) = ctx.unbounded();
+        switches[0].add_port(Port {
+            id: 1,
+            input: None,
+            output: Some(snd),
+        });
+        switches[1].add_port(Port {
+            id: 0,
+            input: Some(rcv),
+            output: None,
+        });
+        let (snd, rcv) = ctx.unbounded();
+        switches[1].add_port(Port {
+            id: 1,
+            input: None,
+            output: Some(snd),
+        });
+        let sink = LatencySink::new(rcv);
+        let stats = sink.stats();
+        ctx.add_child(sink);
+        switches
+            .into_iter()
+            .for_each(|switch| ctx.add_child(switch));
+        ctx.initialize(Default::default())
+            .unwrap()
+            .run(Default::default());
+
+        let stats = stats.lock().unwrap().clone();
+        stats
+    }
+
+    #[test]
+    fn uncontended_latency_test() {
+        let packets = (0..8).map(|i| packet(i as u8 % 2, i)).collect();
+        let stats = run_chain(vec![packets]);
+
+        // Nothing ever waits, so every packet takes exactly as long as the two hops.
+        assert_eq!(stats.destinations.len(), 2);
+        for summary in stats.destinations.values() {
+            assert_eq!(summary.count, 4);
+            assert_eq!(
+                (summary.min, summary.max),
+                (FIRST_HOP + SECOND_HOP, FIRST_HOP + SECOND_HOP)
+            );
+            assert_eq!(summary.mean(), (FIRST_HOP + SECOND_HOP) as f64);
+        }
+    }
+
+    #[test]
+    fn queueing_latency_test() {
+        // Both packets want the same output on the same cycle, so one of them has to wait a cycle for it.
+        let stats = run_chain(vec![vec![packet(0, 0)], vec![packet(0, 1)]]);
+
+        let summary = stats.overall();
+        let hops = FIRST_HOP + SECOND_HOP;
+        assert_eq!(
+            summary.histogram,
+            [(hops, 1), (hops + 1, 1)].into_iter().collect()
+        );
+        assert_eq!(summary.mean(), hops as f64 + 0.5);
+    }
+}
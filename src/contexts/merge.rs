@@ -0,0 +1,163 @@
+use dam::{
+    channel::utils::{EventTime, Peekable},
+    context_tools::*,
+};
+
+/// Merges several channels into one, moving one element per cycle. Inputs with something ready take turns, round
+/// robin. Elements go out `latency` cycles after they were picked, and the merge finishes once every input has
+/// closed.
+#[context_macro]
+pub struct Merge<T: DAMType> {
+    // Inputs are retired as they close, so each keeps its original index alongside it.
+    inputs: Vec<(usize, Receiver<T>)>,
+    output: Sender<T>,
+    latency: u64,
+    last_granted: Option<usize>,
+}
+
+impl<T: DAMType> Context for Merge<T> {
+    fn run(&mut self) {
+        while let Some(ready) = self.advance_to_next_event() {
+            // Start from the first input after the one which went most recently.
+            let start = match self.last_granted {
+                Some(last) => ready.partition_point(|(index, _)| *index <= last) % ready.len(),
+                None => 0,
+            };
+            let (index, position) = ready[start];
+            self.last_granted = Some(index);
+
+            let ChannelElement { time: _, data } =
+                self.inputs[position].1.dequeue(&self.time).unwrap();
+            self.output
+                .enqueue(
+                    &self.time,
+                    ChannelElement {
+                        time: self.time.tick() + self.latency,
+                        data,
+                    },
+                )
+                .unwrap();
+            self.time.incr_cycles(1);
+        }
+    }
+}
+
+impl<T: DAMType> Merge<T> {
+    /// Ties the inputs to the output, which go by their position in `inputs` when it comes to taking turns.
+    pub fn new(inputs: Vec<Receiver<T>>, output: Sender<T>, latency: u64) -> Self {
+        let merge = Self {
+            inputs: inputs.into_iter().enumerate().collect(),
+            output,
+            latency,
+            last_granted: None,
+            context_info: Default::default(),
+        };
+        merge
+            .inputs
+            .iter()
+            .for_each(|(_, rcv)| rcv.attach_receiver(&merge));
+        merge.output.attach_sender(&merge);
+        merge
+    }
+
+    /// Waits until at least one input has something, and returns the (original index, position) of every ready
+    /// input. Returns None once every input has closed.
+    fn advance_to_next_event(&mut self) -> Option<Vec<(usize, usize)>> {
+        loop {
+            self.inputs
+                .retain(|(_, chan)| !matches!(chan.peek(), dam::channel::PeekResult::Closed));
+            if self.inputs.is_empty() {
+                return None;
+            }
+
+            match self
+                .inputs
+                .iter()
+                .map(|(_, chan)| chan.next_event())
+                .min()
+                .unwrap()
+            {
+                EventTime::Ready(t) => {
+                    self.time.advance(t);
+                    let t = self.time.tick();
+                    return Some(
+                        self.inputs
+                            .iter()
+                            .enumerate()
+                            .filter(|(_, (_, chan))| match chan.peek() {
+                                dam::channel::PeekResult::Something(x) => x.time <= t,
+                                _ => false,
+                            })
+                            .map(|(position, (index, _))| (*index, position))
+                            .collect(),
+                    );
+                }
+                EventTime::Nothing(t) => self.time.advance(t + 1),
+                // Something closed since we last checked, which the next pass takes care of.
+                EventTime::Closed => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use dam::{context_tools::ChannelElement, simulation::ProgramBuilder, utility_contexts::*};
+
+    use super::Merge;
+
+    const NUM_PACKETS: u32 = 32;
+    const NUM_INPUTS: u32 = 4;
+
+    #[test]
+    fn merge_test() {
+        let mut ctx = ProgramBuilder::default();
+
+        let mut inputs = vec![];
+        for input in 0..NUM_INPUTS {
+            let (snd, rcv) = ctx.unbounded();
+            ctx.add_child(GeneratorContext::new(
+                move || (0..NUM_PACKETS).map(move |i| input * 1000 + i),
+                snd,
+            ));
+            inputs.push(rcv);
+        }
+        let (snd, rcv) = ctx.unbounded::<u32>();
+        ctx.add_child(Merge::new(inputs, snd, 2));
+
+        let received = Arc::new(Mutex::new(vec![]));
+        let mut sink = FunctionContext::new();
+        rcv.attach_receiver(&sink);
+        let sink_log = received.clone();
+        sink.set_run(move |time| {
+            while let Ok(ChannelElement { time, data }) = rcv.dequeue(time) {
+                sink_log.lock().unwrap().push((data, time.time()));
+            }
+        });
+        ctx.add_child(sink);
+
+        ctx.initialize(Default::default())
+            .unwrap()
+            .run(Default::default());
+
+        let received = received.lock().unwrap().clone();
+        assert_eq!(received.len() as u32, NUM_INPUTS * NUM_PACKETS);
+        // One element per cycle.
+        assert!(received.windows(2).all(|pair| pair[1].1 == pair[0].1 + 1));
+        // With everyone always ready, the inputs strictly take turns.
+        let order: Vec<_> = received.iter().map(|(data, _)| data / 1000).collect();
+        assert!(order
+            .chunks(NUM_INPUTS as usize)
+            .all(|turn| turn == Vec::from_iter(0..NUM_INPUTS)));
+        for input in 0..NUM_INPUTS {
+            let from_input: Vec<_> = received
+                .iter()
+                .filter(|(data, _)| data / 1000 == input)
+                .map(|(data, _)| data % 1000)
+                .collect();
+            assert_eq!(from_input, Vec::from_iter(0..NUM_PACKETS));
+        }
+    }
+}
@@ -0,0 +1,45 @@
+pub mod checker;
+pub mod elastic;
+pub mod flits;
+#[cfg(feature = "serde")]
+pub mod json;
+pub mod latency;
+pub mod merge;
+pub mod nic;
+pub mod phase_flip;
+pub mod rate_limit;
+pub mod reorder;
+pub mod sequence;
+pub mod split;
+pub mod trace;
+pub mod tracking;
+pub mod traffic;
+pub mod transactions;
+pub mod translate;
+pub mod tunnel;
+mod wait;
+
+pub use checker::{CheckOrder, CheckReport, CheckerSink};
+pub use elastic::{chain, ElasticBuffer};
+pub use flits::{Depacketize, Packetize};
+#[cfg(feature = "serde")]
+pub use json::{read_json_lines, JsonSink, Recorded};
+pub use latency::{LatencySink, LatencyStats, LatencySummary, TimedGenerator};
+pub use merge::Merge;
+pub use nic::{Nic, NicRx};
+pub use phase_flip::PhaseFlip;
+pub use rate_limit::{RateLimiter, RateLimiterStats};
+pub use reorder::{ReorderBuffer, ReorderStall, ReorderStats};
+pub use sequence::{SequenceChecker, SequenceReport};
+pub use split::{Split, SplitStats};
+pub use trace::{Trace, TraceError, TraceProblem, TraceRecord, TraceReplayGen};
+pub use tracking::{
+    Checkpoint, IdAllocator, PacketRegistry, Sighting, TrackingGenerator, TrackingSink,
+};
+pub use traffic::{
+    BurstStats, BurstyTrafficGen, Destinations, OnOffModel, Permutation, PermutationTraffic,
+    RandomTrafficGen, TrafficBudget,
+};
+pub use transactions::{RequesterContext, Responder, TransactionStats};
+pub use translate::AddressTranslator;
+pub use tunnel::{Decapsulate, Encapsulate};
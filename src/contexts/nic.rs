@@ -0,0 +1,241 @@
+use std::sync::{Arc, Mutex};
+
+use dam::context_tools::*;
+
+use crate::switches::routing::SimplePacket;
+
+/// Connects something which deals in bare values to a network, wrapping each value in a packet addressed to wherever
+/// `destination` picks for it. Packets go out `latency` cycles after their values arrive, one per cycle.
+#[context_macro]
+pub struct Nic<V: DAMType, LT: DAMType> {
+    input: Receiver<V>,
+    output: Sender<SimplePacket<LT, V>>,
+    destination: Box<dyn FnMut(&V) -> LT + Send + Sync>,
+    latency: u64,
+}
+
+impl<V: DAMType, LT: DAMType> Context for Nic<V, LT> {
+    fn run(&mut self) {
+        while let Ok(ChannelElement { time: _, data }) = self.input.dequeue(&self.time) {
+            let location = (self.destination)(&data);
+            self.output
+                .enqueue(
+                    &self.time,
+                    ChannelElement {
+                        time: self.time.tick() + self.latency,
+                        data: SimplePacket {
+                            location,
+                            payload: data,
+                        },
+                    },
+                )
+                .unwrap();
+            self.time.incr_cycles(1);
+        }
+    }
+}
+
+impl<V: DAMType, LT: DAMType> Nic<V, LT> {
+    pub fn new(
+        input: Receiver<V>,
+        output: Sender<SimplePacket<LT, V>>,
+        destination: impl FnMut(&V) -> LT + Send + Sync + 'static,
+        latency: u64,
+    ) -> Self {
+        let nic = Self {
+            input,
+            output,
+            destination: Box::new(destination),
+            latency,
+            context_info: Default::default(),
+        };
+        nic.input.attach_receiver(&nic);
+        nic.output.attach_sender(&nic);
+        nic
+    }
+
+    /// Sends every value to `destination`.
+    pub fn fixed(
+        input: Receiver<V>,
+        output: Sender<SimplePacket<LT, V>>,
+        destination: LT,
+        latency: u64,
+    ) -> Self
+    where
+        LT: 'static,
+    {
+        Self::new(input, output, move |_| destination.clone(), latency)
+    }
+}
+
+/// The other side of a [`Nic`], which takes packets as they leave the network and sends on just their values. Values
+/// go out `latency` cycles after their packets arrive, one per cycle.
+#[context_macro]
+pub struct NicRx<LT: DAMType, V: DAMType> {
+    input: Receiver<SimplePacket<LT, V>>,
+    output: Sender<V>,
+    latency: u64,
+    expected: Option<LT>,
+    misdelivered: Arc<Mutex<u64>>,
+}
+
+impl<LT: DAMType + PartialEq, V: DAMType> Context for NicRx<LT, V> {
+    fn run(&mut self) {
+        while let Ok(ChannelElement { time: _, data }) = self.input.dequeue(&self.time) {
+            if matches!(&self.expected, Some(location) if *location != data.location) {
+                *self.misdelivered.lock().unwrap() += 1;
+            } else {
+                self.output
+                    .enqueue(
+                        &self.time,
+                        ChannelElement {
+                            time: self.time.tick() + self.latency,
+                            data: data.payload,
+                        },
+                    )
+                    .unwrap();
+            }
+            self.time.incr_cycles(1);
+        }
+    }
+}
+
+impl<LT: DAMType + PartialEq, V: DAMType> NicRx<LT, V> {
+    pub fn new(input: Receiver<SimplePacket<LT, V>>, output: Sender<V>, latency: u64) -> Self {
+        let rx = Self {
+            input,
+            output,
+            latency,
+            expected: None,
+            misdelivered: Default::default(),
+            context_info: Default::default(),
+        };
+        rx.input.attach_receiver(&rx);
+        rx.output.attach_sender(&rx);
+        rx
+    }
+
+    /// Drops any packet which isn't addressed to `location`, rather than passing its value on.
+    pub fn expecting(mut self, location: LT) -> Self {
+        self.expected = Some(location);
+        self
+    }
+
+    /// How many packets were dropped for being addressed somewhere else. The handle is shared with the NIC, so it can
+    /// be read once the run is over.
+    pub fn misdelivered(&self) -> Arc<Mutex<u64>> {
+        self.misdelivered.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use dam::{context_tools::*, simulation::ProgramBuilder, utility_contexts::*};
+
+    use crate::switches::{
+        routing::{Port, Switch},
+        SimpleSwitch,
+    };
+
+    use super::{Nic, NicRx};
+
+    const NUM_VALUES: u32 = 64;
+    // The switch only closes once both NICs sending into it have, so each side counts what it is waiting for rather
+    // than waiting for the switch to close.
+    const REPLIES: u32 = NUM_VALUES - NUM_VALUES / 8;
+    // Replies to location 3 go out of the same port as those to 0, but aren't for the NIC behind it.
+    const STRAY: u8 = 3;
+
+    #[test]
+    fn exchange_test() {
+        let mut ctx = ProgramBuilder::default();
+        let policy = |location: &u8| match *location {
+            0 | STRAY => fxhash::FxHashSet::from_iter([0]),
+            _ => fxhash::FxHashSet::from_iter([1]),
+        };
+        let mut switch = SimpleSwitch::new(policy, 1);
+        // Each side has a NIC taking its values into the switch, and another taking values out of it.
+        let mut ejected = vec![];
+        let mut injected = vec![];
+        for port in 0..2 {
+            let (inject_snd, inject_rcv) = ctx.unbounded();
+            let (eject_snd, eject_rcv) = ctx.unbounded();
+            switch.add_port(Port {
+                id: port,
+                input: Some(inject_rcv),
+                output: Some(eject_snd),
+            });
+            injected.push(inject_snd);
+            ejected.push(eject_rcv);
+        }
+        ctx.add_child(switch);
+        let [b_eject, a_eject] = [ejected.pop().unwrap(), ejected.pop().unwrap()];
+        let [b_inject, a_inject] = [injected.pop().unwrap(), injected.pop().unwrap()];
+
+        // A sends everything to B, which sends each value back doubled, with every eighth going astray.
+        let (a_snd, a_values) = ctx.unbounded();
+        ctx.add_child(Nic::fixed(a_values, a_inject, 1u8, 1));
+        let (b_snd, b_values) = ctx.unbounded();
+        ctx.add_child(Nic::new(
+            b_values,
+            b_inject,
+            |value: &u32| match value % 16 {
+                0 => STRAY,
+                _ => 0,
+            },
+            2,
+        ));
+        let (a_results_snd, a_results) = ctx.unbounded();
+        let a_rx = NicRx::new(a_eject, a_results_snd, 1).expecting(0);
+        let misdelivered = a_rx.misdelivered();
+        ctx.add_child(a_rx);
+        let (b_results_snd, b_results) = ctx.unbounded();
+        ctx.add_child(NicRx::new(b_eject, b_results_snd, 2).expecting(1));
+
+        let replies = Arc::new(Mutex::new(vec![]));
+        let mut a = FunctionContext::new();
+        a_snd.attach_sender(&a);
+        a_results.attach_receiver(&a);
+        let log = replies.clone();
+        a.set_run(move |time| {
+            for value in 0..NUM_VALUES {
+                a_snd
+                    .enqueue(time, ChannelElement::new(time.tick() + 1, value))
+                    .unwrap();
+                time.incr_cycles(1);
+            }
+            for _ in 0..REPLIES {
+                let ChannelElement { time: _, data } = a_results.dequeue(time).unwrap();
+                log.lock().unwrap().push(data);
+            }
+        });
+        ctx.add_child(a);
+
+        let mut b = FunctionContext::new();
+        b_results.attach_receiver(&b);
+        b_snd.attach_sender(&b);
+        b.set_run(move |time| {
+            for _ in 0..NUM_VALUES {
+                let ChannelElement { time: _, data } = b_results.dequeue(time).unwrap();
+                b_snd
+                    .enqueue(time, ChannelElement::new(time.tick() + 1, data * 2))
+                    .unwrap();
+                time.incr_cycles(1);
+            }
+        });
+        ctx.add_child(b);
+
+        ctx.initialize(Default::default())
+            .unwrap()
+            .run(Default::default());
+
+        let expected: Vec<_> = (0..NUM_VALUES)
+            .map(|value| value * 2)
+            .filter(|value| value % 16 != 0)
+            .collect();
+        assert_eq!(*replies.lock().unwrap(), expected);
+        assert_eq!(*misdelivered.lock().unwrap(), NUM_VALUES as u64 / 8);
+    }
+}
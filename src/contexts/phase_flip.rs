@@ -0,0 +1,175 @@
+use dam::context_tools::*;
+
+use crate::switches::routing::TwoPhasePacket;
+
+/// Sits in front of one of the inputs of the switch at `node`, in a network doing two phase routing. Packets whose
+/// intermediate is this node have finished their first phase, so the flip clears it on their way in, and the switch
+/// sends them on towards their final destination instead of handing them to the node. Everything else passes through
+/// untouched. One packet goes through per cycle, without any latency of its own.
+///
+/// Every input of the node needs a flip in front of it, including the one packets are injected on, since a packet's
+/// intermediate can be where it started.
+#[context_macro]
+pub struct PhaseFlip<LT: DAMType, PT: DAMType> {
+    node: LT,
+    input: Receiver<TwoPhasePacket<LT, PT>>,
+    output: Sender<TwoPhasePacket<LT, PT>>,
+}
+
+impl<LT: DAMType + PartialEq, PT: DAMType> Context for PhaseFlip<LT, PT> {
+    fn run(&mut self) {
+        while let Ok(ChannelElement { time: _, data }) = self.input.dequeue(&self.time) {
+            let data = match &data.intermediate {
+                Some(intermediate) if *intermediate == self.node => data.flipped(),
+                _ => data,
+            };
+            // Receivers which have gone away just miss out on their packet.
+            let _ = self.output.enqueue(
+                &self.time,
+                ChannelElement {
+                    time: self.time.tick(),
+                    data,
+                },
+            );
+            self.time.incr_cycles(1);
+        }
+    }
+}
+
+impl<LT: DAMType, PT: DAMType> PhaseFlip<LT, PT>
+where
+    Self: Context,
+{
+    /// Passes packets from `input` on to `output`, which should lead into the switch at `node`.
+    pub fn new(
+        node: LT,
+        input: Receiver<TwoPhasePacket<LT, PT>>,
+        output: Sender<TwoPhasePacket<LT, PT>>,
+    ) -> Self {
+        let flip = Self {
+            node,
+            input,
+            output,
+            context_info: Default::default(),
+        };
+        flip.input.attach_receiver(&flip);
+        flip.output.attach_sender(&flip);
+        flip
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dam::simulation::ProgramBuilder;
+
+    use crate::{
+        random::SplitMix64,
+        switches::{
+            mesh::{MeshPorts, XyPolicy},
+            routing::{Coord2D, Port, Switch, TwoPhasePacket},
+            testing::{run_mesh, run_mesh_with_ports},
+            SimpleSwitch,
+        },
+    };
+
+    use super::PhaseFlip;
+
+    const SIZE: u16 = 4;
+    const PACKETS_PER_NODE: u16 = 16;
+    const TOTAL: usize = (SIZE * 2 * PACKETS_PER_NODE) as usize;
+    const LATENCY: u64 = 1;
+
+    const PORTS: MeshPorts = MeshPorts {
+        local: 0,
+        minus_y: 1,
+        plus_x: 2,
+        plus_y: 3,
+        minus_x: 4,
+    };
+
+    type Packet = TwoPhasePacket<Coord2D, u16>;
+
+    /// Traffic which XY routing handles about as badly as it can: every node in the bottom half of the mesh sends all
+    /// of its packets, one per cycle, to one of two nodes at the top of the left edge. XY routing takes every one of
+    /// them up the same link of that edge. The payload records when each packet was sent, and with `valiant`, each
+    /// packet goes by way of a random node first.
+    fn adversarial(node: Coord2D, valiant: bool) -> Vec<(u64, Packet)> {
+        let target = Coord2D {
+            x: 0,
+            y: SIZE / 2 + node.x % 2,
+        };
+        let mut rng = SplitMix64::new((node.y * SIZE + node.x) as u64);
+        (1..=PACKETS_PER_NODE)
+            .filter(|_| node.y < SIZE / 2)
+            .map(|sent| {
+                let intermediate = valiant.then(|| Coord2D {
+                    x: rng.below(SIZE as u64) as u16,
+                    y: rng.below(SIZE as u64) as u16,
+                });
+                let packet = TwoPhasePacket {
+                    intermediate,
+                    final_dest: target,
+                    payload: sent,
+                };
+                (sent as u64, packet)
+            })
+            .collect()
+    }
+
+    /// The longest any packet took to get to its destination.
+    fn worst_latency(arrivals: Vec<(Coord2D, u64, Packet)>) -> u64 {
+        assert_eq!(arrivals.len(), TOTAL);
+        arrivals
+            .into_iter()
+            .map(|(node, arrived, packet)| {
+                assert_eq!(node, packet.final_dest);
+                assert_eq!(packet.intermediate, None);
+                arrived - packet.payload as u64
+            })
+            .max()
+            .unwrap()
+    }
+
+    #[test]
+    fn valiant_adversarial_test() {
+        let switch = |node| SimpleSwitch::new(XyPolicy::new(node, SIZE, SIZE, PORTS), LATENCY);
+
+        let xy = worst_latency(run_mesh(
+            SIZE,
+            SIZE,
+            PORTS,
+            |node| adversarial(node, false),
+            switch,
+            TOTAL,
+        ));
+
+        // Every input gets a flip in front of it.
+        let add_port = |ctx: &mut ProgramBuilder,
+                        node,
+                        switch: &mut SimpleSwitch<Packet, Coord2D, XyPolicy>,
+                        port: Port<Packet>| {
+            let input = port.input.map(|input| {
+                let (snd, rcv) = ctx.unbounded();
+                ctx.add_child(PhaseFlip::new(node, input, snd));
+                rcv
+            });
+            switch.add_port(Port { input, ..port });
+        };
+        let valiant = worst_latency(run_mesh_with_ports(
+            SIZE,
+            SIZE,
+            PORTS,
+            |node| adversarial(node, true),
+            switch,
+            add_port,
+            TOTAL,
+        ));
+
+        assert!(
+            valiant < xy,
+            "Worst case latency: {} cycles with XY, {} cycles with Valiant",
+            xy,
+            valiant
+        );
+    }
+}
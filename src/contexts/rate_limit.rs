@@ -0,0 +1,216 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use dam::{
+    channel::utils::{EventTime, Peekable},
+    context_tools::*,
+    structures::Time,
+};
+
+use super::wait::wait_for_work;
+
+// Slack for the rounding in adding up fractional tokens, so that four quarters still make a whole one.
+const TOKEN_EPSILON: f64 = 1e-9;
+
+/// What a [`RateLimiter`] did to the elements it let through.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RateLimiterStats {
+    /// How many elements have been let through.
+    pub forwarded: u64,
+    /// Cycles the elements spent waiting for tokens, all added up.
+    pub total_delay: u64,
+    /// The most elements that were ever waiting at once.
+    pub max_queue: usize,
+}
+
+/// A token bucket, which passes elements on in order, at most one per cycle, and only while it has a token to spend
+/// on each. It gains `tokens_per_cycle` tokens every cycle, which may be a fraction of one, and holds up to `depth` of
+/// them, which is how many elements can go through back to back after it has been idle. It starts out full.
+///
+/// Elements which come in while the bucket is empty wait in a queue inside the limiter, however many there are, so
+/// that it never pushes back on whoever sends to it. Those that don't have to wait go out on the cycle they came in
+/// on, as if the limiter weren't there.
+#[context_macro]
+pub struct RateLimiter<T: DAMType> {
+    input: Receiver<T>,
+    output: Sender<T>,
+    tokens_per_cycle: f64,
+    depth: f64,
+    tokens: f64,
+    refilled_at: u64,
+    // Elements waiting for a token, along with the cycle they came in on.
+    queue: VecDeque<(u64, T)>,
+    stats: Arc<Mutex<RateLimiterStats>>,
+}
+
+impl<T: DAMType> Context for RateLimiter<T> {
+    fn run(&mut self) {
+        let mut next_send = None;
+        while self.wait_for_work(next_send) {
+            let tick = self.time.tick();
+            while matches!(self.input.next_event(), EventTime::Ready(t) if t <= tick) {
+                let ChannelElement { time, data } = self.input.dequeue(&self.time).unwrap();
+                self.queue.push_back((time.time(), data));
+            }
+            let mut stats = self.stats.lock().unwrap();
+            stats.max_queue = stats.max_queue.max(self.queue.len());
+
+            self.tokens = self
+                .depth
+                .min(self.tokens + self.tokens_per_cycle * (tick.time() - self.refilled_at) as f64);
+            self.refilled_at = tick.time();
+            if self.tokens + TOKEN_EPSILON >= 1.0 {
+                if let Some((arrived, data)) = self.queue.pop_front() {
+                    self.tokens -= 1.0;
+                    self.output
+                        .enqueue(
+                            &self.time,
+                            ChannelElement {
+                                time: tick + 1,
+                                data,
+                            },
+                        )
+                        .unwrap();
+                    stats.forwarded += 1;
+                    stats.total_delay += tick.time() - arrived;
+                }
+            }
+            drop(stats);
+
+            next_send = match self.queue.is_empty() {
+                true => None,
+                false => {
+                    let wanted = (1.0 - self.tokens - TOKEN_EPSILON).max(0.0);
+                    Some(tick + ((wanted / self.tokens_per_cycle).ceil() as u64).max(1))
+                }
+            };
+            self.time.incr_cycles(1);
+        }
+    }
+}
+
+impl<T: DAMType> RateLimiter<T> {
+    pub fn new(input: Receiver<T>, output: Sender<T>, tokens_per_cycle: f64, depth: u64) -> Self {
+        assert!(
+            tokens_per_cycle > 0.0,
+            "Limiters need to gain tokens to let anything through!"
+        );
+        assert!(depth > 0, "Buckets must hold at least one token!");
+        let limiter = Self {
+            input,
+            output,
+            tokens_per_cycle,
+            depth: depth as f64,
+            tokens: depth as f64,
+            refilled_at: 0,
+            queue: Default::default(),
+            stats: Default::default(),
+            context_info: Default::default(),
+        };
+        limiter.input.attach_receiver(&limiter);
+        limiter.output.attach_sender(&limiter);
+        limiter
+    }
+
+    /// The handle is shared with the limiter, so it can be read once the run is over.
+    pub fn stats(&self) -> Arc<Mutex<RateLimiterStats>> {
+        self.stats.clone()
+    }
+
+    /// Waits until something comes in or the element at the head of the queue can go at `next_send`, returning false
+    /// once the input has closed and the queue has emptied.
+    fn wait_for_work(&mut self, next_send: Option<Time>) -> bool {
+        wait_for_work(&mut self.context_info.time, &self.input, true, next_send)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use dam::{
+        context_tools::ChannelElement, simulation::ProgramBuilder, structures::Time,
+        utility_contexts::*,
+    };
+
+    use super::{RateLimiter, RateLimiterStats};
+
+    /// Sends an element through a limiter on each cycle of `sent_at`, and returns when each came out, with the stats.
+    fn limit(
+        sent_at: Vec<u64>,
+        tokens_per_cycle: f64,
+        depth: u64,
+    ) -> (Vec<(u64, u32)>, RateLimiterStats) {
+        let mut ctx = ProgramBuilder::default();
+        let (snd, rcv) = ctx.unbounded();
+        let mut source = FunctionContext::new();
+        snd.attach_sender(&source);
+        source.set_run(move |time| {
+            for (index, cycle) in sent_at.iter().enumerate() {
+                time.advance(Time::new(*cycle - 1));
+                snd.enqueue(time, ChannelElement::new(time.tick() + 1, index as u32))
+                    .unwrap();
+            }
+        });
+        ctx.add_child(source);
+        let (limited_snd, limited_rcv) = ctx.unbounded();
+        let limiter = RateLimiter::new(rcv, limited_snd, tokens_per_cycle, depth);
+        let stats = limiter.stats();
+        ctx.add_child(limiter);
+
+        let arrivals = Arc::new(Mutex::new(vec![]));
+        let mut sink = FunctionContext::new();
+        limited_rcv.attach_receiver(&sink);
+        let log = arrivals.clone();
+        sink.set_run(move |time| {
+            while let Ok(element) = limited_rcv.dequeue(time) {
+                log.lock()
+                    .unwrap()
+                    .push((element.time.time(), element.data));
+            }
+        });
+        ctx.add_child(sink);
+        ctx.initialize(Default::default())
+            .unwrap()
+            .run(Default::default());
+        let arrivals = arrivals.lock().unwrap().clone();
+        let stats = stats.lock().unwrap().clone();
+        (arrivals, stats)
+    }
+
+    #[test]
+    fn burst_test() {
+        const ELEMENTS: u64 = 100;
+        // Back to back, from cycle 1 on.
+        let (arrivals, stats) = limit((1..=ELEMENTS).collect(), 0.5, 1);
+        assert_eq!(arrivals.len() as u64, ELEMENTS);
+        // In order, at one every other cycle, starting with the one that came in on cycle 1 and went straight out.
+        for (index, (arrived_at, element)) in arrivals.iter().enumerate() {
+            assert_eq!(*element, index as u32);
+            assert_eq!(*arrived_at, 2 + 2 * index as u64);
+        }
+        assert_eq!(arrivals.last().unwrap().0, 2 * ELEMENTS);
+        // The element sent on cycle i + 1 goes out on cycle 2i + 1.
+        assert_eq!(stats.total_delay, (0..ELEMENTS).sum::<u64>());
+        assert_eq!(stats.max_queue, ELEMENTS as usize / 2);
+        assert_eq!(stats.forwarded, ELEMENTS);
+    }
+
+    #[test]
+    fn under_rate_test() {
+        // A quarter of a token a cycle, and one element every four cycles, so none of them ever waits.
+        let sent_at: Vec<_> = (0..25).map(|index| 1 + 4 * index).collect();
+        let (arrivals, stats) = limit(sent_at.clone(), 0.25, 1);
+        let expected: Vec<_> = sent_at
+            .iter()
+            .enumerate()
+            .map(|(index, cycle)| (cycle + 1, index as u32))
+            .collect();
+        assert_eq!(arrivals, expected);
+        assert_eq!(stats.total_delay, 0);
+        assert_eq!(stats.max_queue, 1);
+    }
+}
@@ -0,0 +1,296 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+use dam::context_tools::*;
+
+use crate::switches::routing::Sequenced;
+
+/// Where a [`ReorderBuffer`] was stuck when its input closed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ReorderStall {
+    /// The sequence number which never came.
+    pub waiting_for: u64,
+    /// The sequence numbers of the elements held back behind it, which were never passed on.
+    pub held: Vec<u64>,
+    /// The sequence numbers of whatever came in once the buffer was stuck for good, which it had no room for.
+    pub queued: Vec<u64>,
+}
+
+/// What a [`ReorderBuffer`] did to the elements it put back in order.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ReorderStats {
+    /// How many elements have been passed on, in order.
+    pub forwarded: u64,
+    /// The most elements that were ever held back at once.
+    pub max_occupancy: usize,
+    /// Cycles spent full, taking nothing in until the next number came.
+    pub stalled_cycles: u64,
+    /// Number of elements whose sequence number had already come through, which are thrown away.
+    pub duplicates: u64,
+    /// Only filled in once the input has closed, if anything was still held back.
+    pub stall: Option<ReorderStall>,
+}
+
+/// Puts elements back in the order they were sent, such as after a [`SprayPolicy`](crate::switches::SprayPolicy) has
+/// sent them down paths of different lengths. Elements are numbered from 0, and each goes on once every one numbered
+/// before it has, at most one a cycle. Up to `capacity` elements can be held back waiting for an earlier one, and
+/// once that many are, the buffer takes nothing else in until the next number comes, pushing back on its input rather
+/// than dropping anything.
+///
+/// Since the input is a single queue, a full buffer can't go on if the number it is waiting for is stuck behind
+/// another element, without dropping something to make room. It stops passing anything on instead, and takes in the
+/// rest of its input only so that it sees it close, reporting what it held and what came after in
+/// [`ReorderStats::stall`]. `capacity` needs to cover the furthest anything can fall behind to avoid that.
+#[context_macro]
+pub struct ReorderBuffer<T: DAMType> {
+    input: Receiver<T>,
+    output: Sender<T>,
+    capacity: usize,
+    next: u64,
+    held: BTreeMap<u64, T>,
+    stats: Arc<Mutex<ReorderStats>>,
+}
+
+impl<T: DAMType + Sequenced> Context for ReorderBuffer<T> {
+    fn run(&mut self) {
+        loop {
+            if let Some(data) = self.held.remove(&self.next) {
+                self.forward(data);
+                continue;
+            }
+
+            if self.held.len() >= self.capacity {
+                let stalled_at = self.time.tick();
+                let head = self.input.peek_next(&self.time);
+                self.stats.lock().unwrap().stalled_cycles +=
+                    self.time.tick().time() - stalled_at.time();
+                // Anything but the next number or a duplicate, which gets thrown away anyway, needs room.
+                if let Ok(ChannelElement { time: _, data }) = head {
+                    if data.seq() > self.next && !self.held.contains_key(&data.seq()) {
+                        return self.stuck();
+                    }
+                }
+            }
+
+            let Ok(ChannelElement { time: _, data }) = self.input.dequeue(&self.time) else {
+                break;
+            };
+            let seq = data.seq();
+            if seq == self.next {
+                self.forward(data);
+            } else if seq < self.next || self.held.contains_key(&seq) {
+                self.stats.lock().unwrap().duplicates += 1;
+            } else {
+                self.held.insert(seq, data);
+                let mut stats = self.stats.lock().unwrap();
+                stats.max_occupancy = stats.max_occupancy.max(self.held.len());
+            }
+        }
+
+        if !self.held.is_empty() {
+            self.record_stall(vec![]);
+        }
+    }
+}
+
+impl<T: DAMType + Sequenced> ReorderBuffer<T> {
+    pub fn new(input: Receiver<T>, output: Sender<T>, capacity: usize) -> Self {
+        assert!(
+            capacity > 0,
+            "Reorder buffers must hold at least one element!"
+        );
+        let buffer = Self {
+            input,
+            output,
+            capacity,
+            next: 0,
+            held: Default::default(),
+            stats: Default::default(),
+            context_info: Default::default(),
+        };
+        buffer.input.attach_receiver(&buffer);
+        buffer.output.attach_sender(&buffer);
+        buffer
+    }
+
+    /// The handle is shared with the buffer, so it can be read once the run is over.
+    pub fn stats(&self) -> Arc<Mutex<ReorderStats>> {
+        self.stats.clone()
+    }
+
+    /// Waits out the rest of the input once the buffer can't go on, and reports where it got to.
+    fn stuck(&mut self) {
+        let mut queued = vec![];
+        while let Ok(ChannelElement { time: _, data }) = self.input.dequeue(&self.time) {
+            queued.push(data.seq());
+        }
+        self.record_stall(queued);
+    }
+
+    fn record_stall(&mut self, queued: Vec<u64>) {
+        self.stats.lock().unwrap().stall = Some(ReorderStall {
+            waiting_for: self.next,
+            held: self.held.keys().copied().collect(),
+            queued,
+        });
+    }
+
+    fn forward(&mut self, data: T) {
+        self.output
+            .enqueue(
+                &self.time,
+                ChannelElement {
+                    time: self.time.tick() + 1,
+                    data,
+                },
+            )
+            .unwrap();
+        self.next += 1;
+        self.stats.lock().unwrap().forwarded += 1;
+        self.time.incr_cycles(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dam::{
+        context_tools::ChannelElement, simulation::ProgramBuilder, structures::Time,
+        utility_contexts::*,
+    };
+
+    use crate::{
+        contexts::{CheckOrder, CheckerSink},
+        switches::routing::SequencedPacket,
+    };
+
+    use super::{ReorderBuffer, ReorderStall};
+
+    type TestPacket = SequencedPacket<u8, u16>;
+
+    fn packet(seq: u64) -> TestPacket {
+        SequencedPacket {
+            source: 0,
+            location: 0,
+            seq,
+            payload: seq as u16,
+        }
+    }
+
+    #[test]
+    fn shuffled_test() {
+        const NUM_PACKETS: u64 = 32;
+        const BLOCK: u64 = 4;
+        const LATE: u64 = 10;
+        let mut ctx = ProgramBuilder::default();
+        let (snd, rcv) = ctx.bounded(2);
+        // Each block of four comes in backwards, one a cycle, except that packet 0 and everything after it are another
+        // 10 cycles late.
+        let mut source = FunctionContext::new();
+        snd.attach_sender(&source);
+        source.set_run(move |time| {
+            let shuffled =
+                (0..NUM_PACKETS).map(|index| index - index % BLOCK + BLOCK - 1 - index % BLOCK);
+            for (index, seq) in shuffled.enumerate() {
+                let cycle = index as u64 + 1 + if index as u64 >= BLOCK - 1 { LATE } else { 0 };
+                time.advance(Time::new(cycle - 1));
+                snd.enqueue(time, ChannelElement::new(time.tick() + 1, packet(seq)))
+                    .unwrap();
+            }
+        });
+        ctx.add_child(source);
+
+        let (ordered_snd, ordered_rcv) = ctx.bounded(2);
+        let buffer = ReorderBuffer::new(rcv, ordered_snd, BLOCK as usize - 1);
+        let stats = buffer.stats();
+        ctx.add_child(buffer);
+        let checker = CheckerSink::new(ordered_rcv, (0..NUM_PACKETS).map(packet).collect())
+            .with_order(CheckOrder::ExactOrder);
+        let report = checker.report();
+        ctx.add_child(checker);
+        ctx.initialize(Default::default())
+            .unwrap()
+            .run(Default::default());
+
+        let report = report.lock().unwrap();
+        assert!(report.passed(), "{:?}", report);
+        let stats = stats.lock().unwrap();
+        assert_eq!(stats.forwarded, NUM_PACKETS);
+        assert_eq!(stats.max_occupancy, BLOCK as usize - 1);
+        // Full from when packet 1 came in until packet 0 did, on the cycle after it would have without the delay, and
+        // then for the cycle before the first packet of each block after that came in.
+        assert_eq!(stats.stalled_cycles, LATE + 1 + NUM_PACKETS / BLOCK - 1);
+        assert_eq!(stats.stall, None);
+    }
+
+    #[test]
+    fn missing_test() {
+        let mut ctx = ProgramBuilder::default();
+        let (snd, rcv) = ctx.unbounded();
+        ctx.add_child(GeneratorContext::new(
+            || (0..10).filter(|seq| *seq != 4).map(packet),
+            snd,
+        ));
+        let (ordered_snd, ordered_rcv) = ctx.unbounded();
+        let buffer = ReorderBuffer::new(rcv, ordered_snd, 8);
+        let stats = buffer.stats();
+        ctx.add_child(buffer);
+        let checker = CheckerSink::new(ordered_rcv, (0..4).map(packet).collect())
+            .with_order(CheckOrder::ExactOrder);
+        let report = checker.report();
+        ctx.add_child(checker);
+        ctx.initialize(Default::default())
+            .unwrap()
+            .run(Default::default());
+
+        // Everything before the gap goes through, and everything after it is held back for good.
+        assert!(report.lock().unwrap().passed());
+        let stats = stats.lock().unwrap();
+        assert_eq!(stats.forwarded, 4);
+        assert_eq!(stats.max_occupancy, 5);
+        assert_eq!(stats.stalled_cycles, 0);
+        let expected = ReorderStall {
+            waiting_for: 4,
+            held: (5..10).collect(),
+            queued: vec![],
+        };
+        assert_eq!(stats.stall, Some(expected));
+    }
+
+    #[test]
+    fn stuck_test() {
+        const CAPACITY: usize = 4;
+        let mut ctx = ProgramBuilder::default();
+        let (snd, rcv) = ctx.bounded(2);
+        ctx.add_child(GeneratorContext::new(
+            || (0..20).filter(|seq| *seq != 4).map(packet),
+            snd,
+        ));
+        let (ordered_snd, ordered_rcv) = ctx.bounded(2);
+        let buffer = ReorderBuffer::new(rcv, ordered_snd, CAPACITY);
+        let stats = buffer.stats();
+        ctx.add_child(buffer);
+        let checker = CheckerSink::new(ordered_rcv, (0..4).map(packet).collect())
+            .with_order(CheckOrder::ExactOrder);
+        let report = checker.report();
+        ctx.add_child(checker);
+        ctx.initialize(Default::default())
+            .unwrap()
+            .run(Default::default());
+
+        // The buffer fills up with 5 to 8, and 9 is stuck at the head of its input with nowhere to go.
+        assert!(report.lock().unwrap().passed());
+        let stats = stats.lock().unwrap();
+        assert_eq!(stats.forwarded, 4);
+        assert_eq!(stats.max_occupancy, CAPACITY);
+        let expected = ReorderStall {
+            waiting_for: 4,
+            held: (5..9).collect(),
+            queued: (9..20).collect(),
+        };
+        assert_eq!(stats.stall, Some(expected));
+    }
+}
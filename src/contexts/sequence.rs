@@ -0,0 +1,223 @@
+use std::{
+    collections::BTreeSet,
+    sync::{Arc, Mutex},
+};
+
+use dam::context_tools::*;
+
+use crate::switches::routing::Sequenced;
+
+/// What a [`SequenceChecker`] made of the packets it received.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SequenceReport {
+    pub received: u64,
+    /// Number of packets which arrived after one from the same stream that was sent later.
+    pub out_of_order: u64,
+    /// The furthest any packet fell behind, as how far ahead of it the latest packet of its stream was.
+    pub max_displacement: u64,
+    /// Number of packets whose sequence number had already come through.
+    pub duplicates: u64,
+    /// The (stream, sequence number) of every packet which never arrived, though a later one from its stream did.
+    /// Only filled in once the input has closed.
+    pub gaps: Vec<(u64, u64)>,
+}
+
+/// Where a stream has got to: the next sequence number in order, and the ones below it still outstanding.
+#[derive(Default)]
+struct StreamState {
+    next: u64,
+    missing: BTreeSet<u64>,
+}
+
+/// A sink which checks that packets arrive in the order they were sent. Every stream is numbered from 0, and by
+/// default all of the packets belong to the one stream.
+#[context_macro]
+pub struct SequenceChecker<T: DAMType> {
+    input: Receiver<T>,
+    stream: fn(&T) -> u64,
+    report: Arc<Mutex<SequenceReport>>,
+}
+
+impl<T: DAMType + Sequenced> Context for SequenceChecker<T> {
+    fn run(&mut self) {
+        let mut streams = fxhash::FxHashMap::<u64, StreamState>::default();
+        while let Ok(ChannelElement { time: _, data }) = self.input.dequeue(&self.time) {
+            let state = streams.entry((self.stream)(&data)).or_default();
+            let seq = data.seq();
+            let mut report = self.report.lock().unwrap();
+            report.received += 1;
+            if seq >= state.next {
+                state.missing.extend(state.next..seq);
+                state.next = seq + 1;
+            } else if state.missing.remove(&seq) {
+                report.out_of_order += 1;
+                report.max_displacement = report.max_displacement.max(state.next - 1 - seq);
+            } else {
+                report.duplicates += 1;
+            }
+        }
+
+        let mut gaps: Vec<_> = streams
+            .into_iter()
+            .flat_map(|(stream, state)| state.missing.into_iter().map(move |seq| (stream, seq)))
+            .collect();
+        gaps.sort_unstable();
+        self.report.lock().unwrap().gaps = gaps;
+    }
+}
+
+impl<T: DAMType + Sequenced> SequenceChecker<T> {
+    pub fn new(input: Receiver<T>) -> Self {
+        let checker = Self {
+            input,
+            stream: |_| 0,
+            report: Default::default(),
+            context_info: Default::default(),
+        };
+        checker.input.attach_receiver(&checker);
+        checker
+    }
+
+    /// Numbers each stream on its own, such as each source's packets, with `stream` telling which one a packet is in.
+    pub fn with_streams(mut self, stream: fn(&T) -> u64) -> Self {
+        self.stream = stream;
+        self
+    }
+
+    /// The handle is shared with the checker, so it can be read once the run is over.
+    pub fn report(&self) -> Arc<Mutex<SequenceReport>> {
+        self.report.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dam::{simulation::ProgramBuilder, utility_contexts::*};
+
+    use crate::{
+        contexts::Merge,
+        switches::{
+            routing::{Port, SequencedPacket, Switch},
+            SimpleSwitch, SprayPolicy,
+        },
+    };
+
+    use super::{SequenceChecker, SequenceReport};
+
+    const NUM_PACKETS: u64 = 16;
+    const NUM_SOURCES: u8 = 2;
+
+    type TestPacket = SequencedPacket<u8, u16>;
+
+    fn packets(source: u8) -> impl Iterator<Item = TestPacket> {
+        (0..NUM_PACKETS).map(move |seq| SequencedPacket {
+            source,
+            location: 0,
+            seq,
+            payload: seq as u16,
+        })
+    }
+
+    #[test]
+    fn in_order_test() {
+        let mut ctx = ProgramBuilder::default();
+        let (snd, rcv) = ctx.unbounded();
+        // The sources' packets interleave, but each source's are in order.
+        ctx.add_child(GeneratorContext::new(
+            || (0..NUM_SOURCES).flat_map(packets),
+            snd,
+        ));
+        let checker = SequenceChecker::new(rcv).with_streams(|packet| packet.source as u64);
+        let report = checker.report();
+        ctx.add_child(checker);
+        ctx.initialize(Default::default())
+            .unwrap()
+            .run(Default::default());
+
+        let expected = SequenceReport {
+            received: NUM_PACKETS * NUM_SOURCES as u64,
+            ..Default::default()
+        };
+        assert_eq!(*report.lock().unwrap(), expected);
+    }
+
+    #[test]
+    fn sprayed_reordering_test() {
+        const SLOW_LATENCY: u64 = 4;
+        let mut ctx = ProgramBuilder::default();
+        let (snd, rcv) = ctx.unbounded();
+        ctx.add_child(GeneratorContext::new(|| packets(0), snd));
+
+        // Every other packet takes the slow path, and falls behind the ones after it.
+        let policy = SprayPolicy::new(fxhash::FxHashMap::from_iter([(0u8, vec![1, 2])]));
+        let mut switch = SimpleSwitch::new(policy, 1);
+        switch.set_output_latency(2, SLOW_LATENCY);
+        switch.add_port(Port {
+            id: 0,
+            input: Some(rcv),
+            output: None,
+        });
+        let mut paths = vec![];
+        for id in 1..=2 {
+            let (snd, rcv) = ctx.unbounded();
+            switch.add_port(Port {
+                id,
+                input: None,
+                output: Some(snd),
+            });
+            paths.push(rcv);
+        }
+        ctx.add_child(switch);
+        let (snd, rcv) = ctx.unbounded();
+        ctx.add_child(Merge::new(paths, snd, 1));
+        let checker = SequenceChecker::new(rcv);
+        let report = checker.report();
+        ctx.add_child(checker);
+        ctx.initialize(Default::default())
+            .unwrap()
+            .run(Default::default());
+
+        let report = report.lock().unwrap();
+        assert_eq!(report.received, NUM_PACKETS);
+        assert!(report.out_of_order > 0);
+        assert!(report.max_displacement > 0);
+        assert_eq!(report.duplicates, 0);
+        assert!(report.gaps.is_empty());
+    }
+
+    #[test]
+    fn gaps_and_duplicates_test() {
+        let mut ctx = ProgramBuilder::default();
+        let (snd, rcv) = ctx.unbounded();
+        ctx.add_child(GeneratorContext::new(
+            || {
+                [0, 3, 1, 3, 5]
+                    .map(|seq| SequencedPacket {
+                        source: 0u8,
+                        location: 0u8,
+                        seq,
+                        payload: 0u16,
+                    })
+                    .into_iter()
+            },
+            snd,
+        ));
+        let checker = SequenceChecker::new(rcv);
+        let report = checker.report();
+        ctx.add_child(checker);
+        ctx.initialize(Default::default())
+            .unwrap()
+            .run(Default::default());
+
+        // 1 came in two behind 3, and 2 and 4 never came at all.
+        let expected = SequenceReport {
+            received: 5,
+            out_of_order: 1,
+            max_displacement: 2,
+            duplicates: 1,
+            gaps: vec![(0, 2), (0, 4)],
+        };
+        assert_eq!(*report.lock().unwrap(), expected);
+    }
+}
@@ -0,0 +1,188 @@
+use std::sync::{Arc, Mutex};
+
+use dam::{context_tools::*, structures::SyncSendMarker};
+
+use crate::switches::{
+    policy::{Policy, RouteDecision, UnroutableAction, UnroutableError},
+    routing::Packet,
+};
+
+/// Counters collected by a [`Split`] while it runs.
+#[derive(Clone, Debug, Default)]
+pub struct SplitStats {
+    /// Number of packets dropped, whether by the policy or for lack of a route.
+    pub dropped: u64,
+    /// Why the split stopped early, under [`UnroutableAction::Error`]. Splits only have the one input, which is
+    /// reported as port 0.
+    pub error: Option<UnroutableError>,
+}
+
+/// Sends each packet from a single input to whichever outputs its policy picks, one packet per cycle. Copies go out
+/// `latency` cycles after the packet arrived.
+#[context_macro]
+pub struct Split<T: DAMType, LT, PolicyType> {
+    input: Receiver<T>,
+    outputs: fxhash::FxHashMap<usize, Sender<T>>,
+
+    policy: PolicyType,
+    unroutable: UnroutableAction,
+    latency: u64,
+    stats: Arc<Mutex<SplitStats>>,
+
+    _marker: SyncSendMarker<LT>,
+}
+
+impl<T: DAMType, LT, PolicyType> Context for Split<T, LT, PolicyType>
+where
+    T: Packet<LT>,
+    PolicyType: Policy<LT> + Sync + Send,
+{
+    fn run(&mut self) {
+        while let Ok(ChannelElement { time: _, data }) = self.input.dequeue(&self.time) {
+            let decision = self.policy.try_route(&data.destination());
+            let decision = self
+                .unroutable
+                .resolve(decision, |port| self.outputs.contains_key(&port));
+            let targets = match decision {
+                RouteDecision::Forward(targets) => targets,
+                RouteDecision::Drop => {
+                    self.stats.lock().unwrap().dropped += 1;
+                    fxhash::FxHashSet::default()
+                }
+                RouteDecision::Error => {
+                    self.stats.lock().unwrap().error = Some(UnroutableError {
+                        port: 0,
+                        cycle: self.time.tick().time(),
+                    });
+                    return;
+                }
+            };
+
+            for target in targets {
+                self.outputs
+                    .get(&target)
+                    .unwrap()
+                    .enqueue(
+                        &self.time,
+                        ChannelElement {
+                            time: self.time.tick() + self.latency,
+                            data: data.clone(),
+                        },
+                    )
+                    .unwrap();
+            }
+            self.time.incr_cycles(1);
+        }
+    }
+}
+
+impl<T: DAMType, LT, PolicyType> Split<T, LT, PolicyType>
+where
+    Self: Context,
+{
+    /// Ties the input to the outputs, which are keyed by the port IDs the policy picks from.
+    pub fn new(
+        input: Receiver<T>,
+        outputs: fxhash::FxHashMap<usize, Sender<T>>,
+        policy: PolicyType,
+        latency: u64,
+    ) -> Self {
+        let split = Self {
+            input,
+            outputs,
+            policy,
+            unroutable: Default::default(),
+            latency,
+            stats: Default::default(),
+            _marker: Default::default(),
+            context_info: Default::default(),
+        };
+        split.input.attach_receiver(&split);
+        split
+            .outputs
+            .values()
+            .for_each(|snd| snd.attach_sender(&split));
+        split
+    }
+
+    /// Sets what happens to packets whose destination the policy doesn't know. By default, the split panics.
+    pub fn with_unroutable(mut self, unroutable: UnroutableAction) -> Self {
+        self.unroutable = unroutable;
+        self
+    }
+
+    /// How many packets the split threw away, and the error it stopped on, if any. The handle is shared with the
+    /// split, so it can be read once the simulation is done.
+    pub fn stats(&self) -> Arc<Mutex<SplitStats>> {
+        self.stats.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use dam::{context_tools::ChannelElement, simulation::ProgramBuilder, utility_contexts::*};
+    use fxhash::FxHashSet;
+
+    use crate::switches::{policy::UnroutableAction, routing::SimplePacket};
+
+    use super::Split;
+
+    const NUM_PACKETS: u16 = 100;
+
+    #[test]
+    fn scatter_test() {
+        let mut ctx = ProgramBuilder::default();
+
+        // Location 4 is multicast to both 0 and 1, and location 5 isn't routable at all.
+        let policy = fxhash::FxHashMap::from_iter(
+            (0..4u8)
+                .map(|location| (location, FxHashSet::from_iter([location as usize])))
+                .chain([(4, FxHashSet::from_iter([0, 1]))]),
+        );
+
+        let (snd, rcv) = ctx.unbounded();
+        ctx.add_child(GeneratorContext::new(
+            || {
+                (0..NUM_PACKETS).map(|payload| SimplePacket {
+                    location: (payload % 6) as u8,
+                    payload,
+                })
+            },
+            snd,
+        ));
+
+        let counts = Arc::new(Mutex::new(fxhash::FxHashMap::default()));
+        let mut outputs = fxhash::FxHashMap::default();
+        for port in 0..4 {
+            let (snd, rcv) = ctx.unbounded::<SimplePacket<u8, u16>>();
+            let mut sink = FunctionContext::new();
+            rcv.attach_receiver(&sink);
+            let sink_log = counts.clone();
+            sink.set_run(move |time| {
+                while let Ok(ChannelElement { time: _, data: _ }) = rcv.dequeue(time) {
+                    *sink_log.lock().unwrap().entry(port).or_insert(0) += 1;
+                }
+            });
+            ctx.add_child(sink);
+            outputs.insert(port, snd);
+        }
+
+        let split = Split::new(rcv, outputs, policy, 1).with_unroutable(UnroutableAction::Drop);
+        let stats = split.stats();
+        ctx.add_child(split);
+
+        ctx.initialize(Default::default())
+            .unwrap()
+            .run(Default::default());
+
+        // 100 packets cycle through six locations, so locations 0 to 3 get 17 packets each, and 4 and 5 get 16.
+        let counts = counts.lock().unwrap().clone();
+        assert_eq!(
+            counts,
+            fxhash::FxHashMap::from_iter([(0, 17 + 16), (1, 17 + 16), (2, 17), (3, 17)])
+        );
+        assert_eq!(stats.lock().unwrap().dropped, 16);
+    }
+}
@@ -0,0 +1,311 @@
+use std::path::Path;
+
+use dam::{context_tools::*, structures::Time};
+
+use crate::switches::routing::SimplePacket;
+
+/// One packet of a [`Trace`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TraceRecord {
+    pub inject_cycle: u64,
+    pub source: usize,
+    pub destination: usize,
+    pub size: usize,
+}
+
+/// What was wrong with a line of a trace.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TraceProblem {
+    /// The line had this many fields, rather than four.
+    FieldCount(usize),
+    /// A field wasn't a whole number.
+    NotANumber { field: &'static str, text: String },
+    /// The line goes in before the line above it, which went in on `previous`.
+    OutOfOrder { previous: u64 },
+}
+
+/// A trace couldn't be loaded.
+#[derive(Debug)]
+pub enum TraceError {
+    Io(std::io::Error),
+    /// Line `line`, counting from 1, was malformed.
+    Malformed {
+        line: usize,
+        problem: TraceProblem,
+    },
+}
+
+impl std::fmt::Display for TraceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (line, problem) = match self {
+            Self::Io(error) => return write!(f, "Couldn't read the trace: {}", error),
+            Self::Malformed { line, problem } => (line, problem),
+        };
+        write!(f, "Line {}: ", line)?;
+        match problem {
+            TraceProblem::FieldCount(fields) => write!(
+                f,
+                "expected 4 fields (inject_cycle, src, dst, size), but found {}",
+                fields
+            ),
+            TraceProblem::NotANumber { field, text } => {
+                write!(f, "{} should be a whole number, not {:?}", field, text)
+            }
+            TraceProblem::OutOfOrder { previous } => write!(
+                f,
+                "injects before the line above it, which injects on cycle {}",
+                previous
+            ),
+        }
+    }
+}
+
+impl std::error::Error for TraceError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(error) => Some(error),
+            Self::Malformed { .. } => None,
+        }
+    }
+}
+
+/// The packets of a workload, as lines of `inject_cycle, src, dst, size`. Blank lines and lines starting with `#` are
+/// skipped. Lines must be in order of their inject cycles, since a trace out of order is more likely a mistake than
+/// something to sort out, so any line which injects before the one above it is rejected.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Trace {
+    records: Vec<TraceRecord>,
+}
+
+impl Trace {
+    pub fn parse(text: &str) -> Result<Self, TraceError> {
+        let mut records: Vec<TraceRecord> = vec![];
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let malformed = |problem| TraceError::Malformed {
+                line: index + 1,
+                problem,
+            };
+            let fields: Vec<_> = line.split(',').map(str::trim).collect();
+            let [inject_cycle, source, destination, size] = fields[..] else {
+                return Err(malformed(TraceProblem::FieldCount(fields.len())));
+            };
+            let number = |field, text: &str| {
+                text.parse::<u64>().map_err(|_| {
+                    malformed(TraceProblem::NotANumber {
+                        field,
+                        text: text.to_string(),
+                    })
+                })
+            };
+            let record = TraceRecord {
+                inject_cycle: number("inject_cycle", inject_cycle)?,
+                source: number("src", source)? as usize,
+                destination: number("dst", destination)? as usize,
+                size: number("size", size)? as usize,
+            };
+            if let Some(previous) = records.last() {
+                if record.inject_cycle < previous.inject_cycle {
+                    return Err(malformed(TraceProblem::OutOfOrder {
+                        previous: previous.inject_cycle,
+                    }));
+                }
+            }
+            records.push(record);
+        }
+        Ok(Self { records })
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, TraceError> {
+        Self::parse(&std::fs::read_to_string(path).map_err(TraceError::Io)?)
+    }
+
+    /// Every packet, in the order they go in.
+    pub fn records(&self) -> &[TraceRecord] {
+        &self.records
+    }
+}
+
+/// Replays the packets of a [`Trace`] from one of its sources, with the payload `payload` makes for each. Each packet
+/// is sent on its inject cycle, so that it goes in on the cycle after, as it would from any other generator. Packets
+/// which share a cycle are all sent on it, and go in one after another, while packets held up by a full channel are
+/// sent as soon as there is room, with the ones after them waiting their turn.
+#[context_macro]
+pub struct TraceReplayGen<PT: DAMType, F> {
+    output: Sender<SimplePacket<usize, PT>>,
+    records: Vec<TraceRecord>,
+    payload: F,
+}
+
+impl<PT: DAMType, F> Context for TraceReplayGen<PT, F>
+where
+    F: FnMut(&TraceRecord) -> PT + Send + Sync,
+{
+    fn run(&mut self) {
+        for record in std::mem::take(&mut self.records) {
+            if record.inject_cycle > self.time.tick().time() {
+                self.time.advance(Time::new(record.inject_cycle));
+            }
+            let packet = SimplePacket {
+                location: record.destination,
+                payload: (self.payload)(&record),
+            };
+            self.output
+                .enqueue(
+                    &self.time,
+                    ChannelElement {
+                        time: self.time.tick() + 1,
+                        data: packet,
+                    },
+                )
+                .unwrap();
+        }
+    }
+}
+
+impl<PT: DAMType, F> TraceReplayGen<PT, F>
+where
+    Self: Context,
+{
+    /// Replays the packets `trace` has from `source`.
+    pub fn new(
+        output: Sender<SimplePacket<usize, PT>>,
+        trace: &Trace,
+        source: usize,
+        payload: F,
+    ) -> Self {
+        let generator = Self {
+            output,
+            records: trace
+                .records()
+                .iter()
+                .filter(|record| record.source == source)
+                .copied()
+                .collect(),
+            payload,
+            context_info: Default::default(),
+        };
+        generator.output.attach_sender(&generator);
+        generator
+    }
+
+    /// Loads the trace at `path`, and replays the packets it has from `source`.
+    pub fn from_file(
+        output: Sender<SimplePacket<usize, PT>>,
+        path: impl AsRef<Path>,
+        source: usize,
+        payload: F,
+    ) -> Result<Self, TraceError> {
+        Ok(Self::new(output, &Trace::from_file(path)?, source, payload))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use dam::{simulation::ProgramBuilder, utility_contexts::*};
+
+    use crate::{
+        switches::routing::Payload,
+        topologies::{build_crossbar, MeshConfig},
+    };
+
+    use super::{Trace, TraceError, TraceProblem, TraceReplayGen};
+
+    const GOLDEN: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/traces/crossbar.csv");
+    const NODES: usize = 4;
+
+    fn problem(text: &str) -> (usize, TraceProblem) {
+        match Trace::parse(text) {
+            Err(TraceError::Malformed { line, problem }) => (line, problem),
+            result => panic!("{:?} should have been malformed!", result),
+        }
+    }
+
+    #[test]
+    fn malformed_trace_test() {
+        assert_eq!(
+            problem("0, 0, 1, 8\n\n1, 0, 1\n"),
+            (3, TraceProblem::FieldCount(3))
+        );
+        assert_eq!(
+            problem("# inject_cycle, src, dst, size\n0, zero, 1, 8\n"),
+            (
+                2,
+                TraceProblem::NotANumber {
+                    field: "src",
+                    text: "zero".to_string()
+                }
+            )
+        );
+        assert_eq!(
+            problem("4, 0, 1, 8\n2, 1, 0, 8\n"),
+            (2, TraceProblem::OutOfOrder { previous: 4 })
+        );
+        let error = Trace::parse("4, 0, 1, 8\n2, 1, 0, 8\n").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Line 2: injects before the line above it, which injects on cycle 4"
+        );
+        assert!(matches!(
+            Trace::from_file("no/such/trace.csv"),
+            Err(TraceError::Io(_))
+        ));
+    }
+
+    #[test]
+    fn golden_trace_test() {
+        let cfg = MeshConfig::default();
+        let trace = Trace::from_file(GOLDEN).unwrap();
+        let mut ctx = ProgramBuilder::default();
+        let handles = build_crossbar::<Payload>(&mut ctx, NODES, cfg);
+        let arrivals = Arc::new(Mutex::new(vec![]));
+        for (node, (inject, eject)) in handles.into_iter().enumerate() {
+            // Every byte of the payload is the source, so that the sink can tell who sent it.
+            ctx.add_child(TraceReplayGen::new(inject, &trace, node, |record| {
+                Payload(vec![record.source as u8; record.size])
+            }));
+
+            let mut sink = FunctionContext::new();
+            eject.attach_receiver(&sink);
+            let log = arrivals.clone();
+            sink.set_run(move |time| {
+                while let Ok(element) = eject.dequeue(time) {
+                    let payload = element.data.payload.0;
+                    log.lock().unwrap().push((
+                        element.time.time(),
+                        payload[0],
+                        node,
+                        payload.len(),
+                    ));
+                }
+            });
+            ctx.add_child(sink);
+        }
+        ctx.initialize(Default::default())
+            .unwrap()
+            .run(Default::default());
+
+        let mut arrivals = arrivals.lock().unwrap().clone();
+        arrivals.sort();
+        let latency = cfg.router_latency + 2 * cfg.link_latency;
+        // Each packet goes in the cycle after the one it was sent on, and then crosses the crossbar, unless something
+        // ahead of it from the same source or for the same destination holds it up by a cycle.
+        let expected = vec![
+            (1 + latency, 0, 1, 8),
+            // Sent alongside the one before it, from the same source.
+            (2 + latency, 0, 2, 8),
+            (4 + latency, 1, 0, 16),
+            (4 + latency, 2, 3, 4),
+            // Both for node 0 on the same cycle, from different sources, so one of them waits for the other.
+            (11 + latency, 2, 0, 32),
+            (12 + latency, 3, 0, 64),
+        ];
+        assert_eq!(arrivals.len(), trace.records().len());
+        assert_eq!(arrivals, expected);
+    }
+}
@@ -0,0 +1,335 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use dam::{context_tools::*, structures::SyncSendMarker};
+
+use crate::switches::routing::Identified;
+
+/// Hands out packet IDs. Clones share the same counter, so IDs are unique across everyone holding one.
+#[derive(Clone, Debug, Default)]
+pub struct IdAllocator {
+    next: Arc<AtomicU64>,
+}
+
+impl IdAllocator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn allocate(&self) -> u64 {
+        self.next.fetch_add(1, Ordering::Relaxed)
+    }
+}
+
+/// The last place a packet was seen, and the cycle it was seen on.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Sighting {
+    pub place: String,
+    pub cycle: u64,
+}
+
+#[derive(Debug, Default)]
+struct Registry {
+    in_flight: BTreeMap<u64, Sighting>,
+    delivered: u64,
+    unexpected: u64,
+}
+
+/// Keeps track of every packet which has been injected but not delivered yet, and where it was last seen. Clones share
+/// the same records, so the registry can be handed to every generator, checkpoint and sink, and read once the run is
+/// over.
+#[derive(Clone, Debug, Default)]
+pub struct PacketRegistry {
+    registry: Arc<Mutex<Registry>>,
+}
+
+impl PacketRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn inject(&self, id: u64, place: impl Into<String>, cycle: u64) {
+        let sighting = Sighting {
+            place: place.into(),
+            cycle,
+        };
+        let previous = self.registry.lock().unwrap().in_flight.insert(id, sighting);
+        assert!(previous.is_none(), "Packet {} was injected twice!", id);
+    }
+
+    /// Notes that the packet was seen at `place`. Packets which aren't in flight are ignored.
+    pub fn sight(&self, id: u64, place: impl Into<String>, cycle: u64) {
+        if let Some(sighting) = self.registry.lock().unwrap().in_flight.get_mut(&id) {
+            *sighting = Sighting {
+                place: place.into(),
+                cycle,
+            };
+        }
+    }
+
+    /// Checks the packet off. Returns false if it wasn't in flight, having never been injected or having already been
+    /// delivered.
+    pub fn deliver(&self, id: u64) -> bool {
+        let mut registry = self.registry.lock().unwrap();
+        match registry.in_flight.remove(&id) {
+            Some(_) => {
+                registry.delivered += 1;
+                true
+            }
+            None => {
+                registry.unexpected += 1;
+                false
+            }
+        }
+    }
+
+    /// Every packet which hasn't been delivered, by ID, with where it was last seen.
+    pub fn in_flight(&self) -> Vec<(u64, Sighting)> {
+        let registry = self.registry.lock().unwrap();
+        registry
+            .in_flight
+            .iter()
+            .map(|(id, sighting)| (*id, sighting.clone()))
+            .collect()
+    }
+
+    pub fn delivered(&self) -> u64 {
+        self.registry.lock().unwrap().delivered
+    }
+
+    /// How many deliveries were of packets which weren't in flight.
+    pub fn unexpected(&self) -> u64 {
+        self.registry.lock().unwrap().unexpected
+    }
+}
+
+/// Feeds packets into the network one per cycle, like [`dam::utility_contexts::GeneratorContext`], giving each a
+/// fresh ID and registering it as injected.
+#[context_macro]
+pub struct TrackingGenerator<T: DAMType, IType, FType> {
+    iterator: Option<FType>,
+    output: Sender<T>,
+    ids: IdAllocator,
+    registry: PacketRegistry,
+    _marker: SyncSendMarker<IType>,
+}
+
+impl<T: DAMType + Identified, IType, FType> Context for TrackingGenerator<T, IType, FType>
+where
+    IType: Iterator<Item = T>,
+    FType: FnOnce() -> IType + Send + Sync,
+{
+    fn run(&mut self) {
+        if let Some(iterator) = self.iterator.take() {
+            for packet in iterator() {
+                let id = self.ids.allocate();
+                let injected_at = self.time.tick() + 1;
+                self.registry.inject(id, "injected", injected_at.time());
+                self.output
+                    .enqueue(
+                        &self.time,
+                        ChannelElement {
+                            time: injected_at,
+                            data: packet.with_id(id),
+                        },
+                    )
+                    .unwrap();
+                self.time.incr_cycles(1);
+            }
+        }
+    }
+}
+
+impl<T: DAMType + Identified, IType, FType> TrackingGenerator<T, IType, FType>
+where
+    Self: Context,
+{
+    pub fn new(
+        iterator: FType,
+        output: Sender<T>,
+        ids: IdAllocator,
+        registry: PacketRegistry,
+    ) -> Self {
+        let generator = Self {
+            iterator: Some(iterator),
+            output,
+            ids,
+            registry,
+            _marker: Default::default(),
+            context_info: Default::default(),
+        };
+        generator.output.attach_sender(&generator);
+        generator
+    }
+}
+
+/// Passes packets along a channel, reporting each one to the registry as seen at `place`. Putting these on the
+/// channels into and out of a switch is how switches report where packets got to. Packets come out a cycle after
+/// they went in.
+#[context_macro]
+pub struct Checkpoint<T: DAMType> {
+    input: Receiver<T>,
+    output: Sender<T>,
+    place: String,
+    registry: PacketRegistry,
+}
+
+impl<T: DAMType + Identified> Context for Checkpoint<T> {
+    fn run(&mut self) {
+        while let Ok(ChannelElement { time: _, data }) = self.input.dequeue(&self.time) {
+            self.registry
+                .sight(data.id(), self.place.as_str(), self.time.tick().time());
+            self.output
+                .enqueue(
+                    &self.time,
+                    ChannelElement {
+                        time: self.time.tick() + 1,
+                        data,
+                    },
+                )
+                .unwrap();
+        }
+    }
+}
+
+impl<T: DAMType + Identified> Checkpoint<T> {
+    pub fn new(
+        input: Receiver<T>,
+        output: Sender<T>,
+        place: impl Into<String>,
+        registry: PacketRegistry,
+    ) -> Self {
+        let checkpoint = Self {
+            input,
+            output,
+            place: place.into(),
+            registry,
+            context_info: Default::default(),
+        };
+        checkpoint.input.attach_receiver(&checkpoint);
+        checkpoint.output.attach_sender(&checkpoint);
+        checkpoint
+    }
+}
+
+/// A sink which checks off every packet it receives as delivered.
+#[context_macro]
+pub struct TrackingSink<T: DAMType> {
+    input: Receiver<T>,
+    registry: PacketRegistry,
+}
+
+impl<T: DAMType + Identified> Context for TrackingSink<T> {
+    fn run(&mut self) {
+        while let Ok(ChannelElement { time: _, data }) = self.input.dequeue(&self.time) {
+            self.registry.deliver(data.id());
+        }
+    }
+}
+
+impl<T: DAMType + Identified> TrackingSink<T> {
+    pub fn new(input: Receiver<T>, registry: PacketRegistry) -> Self {
+        let sink = Self {
+            input,
+            registry,
+            context_info: Default::default(),
+        };
+        sink.input.attach_receiver(&sink);
+        sink
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dam::simulation::ProgramBuilder;
+
+    use crate::{
+        contexts::ElasticBuffer,
+        switches::{
+            routing::{IdentifiedPacket, Port, SimplePacket, Switch},
+            testing::direct_policy,
+            LossySwitch,
+        },
+    };
+
+    use super::{Checkpoint, IdAllocator, PacketRegistry, TrackingGenerator, TrackingSink};
+
+    const NUM_PACKETS: u16 = 32;
+
+    #[test]
+    fn id_allocator_test() {
+        let ids = IdAllocator::new();
+        let shared = ids.clone();
+        assert_eq!(
+            (ids.allocate(), shared.allocate(), ids.allocate()),
+            (0, 1, 2)
+        );
+    }
+
+    #[test]
+    fn dropped_ids_test() {
+        let mut ctx = ProgramBuilder::default();
+        let registry = PacketRegistry::new();
+
+        let packets = (0..NUM_PACKETS).map(|payload| IdentifiedPacket {
+            packet: SimplePacket {
+                location: 1u8,
+                payload,
+            },
+            id: 0,
+        });
+        let (snd, rcv) = ctx.unbounded();
+        ctx.add_child(TrackingGenerator::new(
+            move || packets,
+            snd,
+            IdAllocator::new(),
+            registry.clone(),
+        ));
+        let (checked_snd, checked_rcv) = ctx.unbounded();
+        ctx.add_child(Checkpoint::new(
+            rcv,
+            checked_snd,
+            "switch input",
+            registry.clone(),
+        ));
+
+        // The switch's output only has room for two packets, and the buffer after it only takes one every four
+        // cycles, so most of the packets get dropped.
+        let mut switch = LossySwitch::new(direct_policy([1]), 1);
+        let drops = switch.drops();
+        let (out_snd, out_rcv) = ctx.bounded(2);
+        switch.add_port(Port {
+            id: 0,
+            input: Some(checked_rcv),
+            output: None,
+        });
+        switch.add_port(Port {
+            id: 1,
+            input: None,
+            output: Some(out_snd),
+        });
+        ctx.add_child(switch);
+        let (slow_snd, slow_rcv) = ctx.unbounded();
+        ctx.add_child(ElasticBuffer::new(out_rcv, slow_snd, 4, 1));
+        ctx.add_child(TrackingSink::new(slow_rcv, registry.clone()));
+        ctx.initialize(Default::default())
+            .unwrap()
+            .run(Default::default());
+
+        let lost = registry.in_flight();
+        assert_eq!(lost.len() as u64, drops.lock().unwrap().drops[&(0, 1)]);
+        assert!(!lost.is_empty());
+        assert_eq!(registry.delivered() + lost.len() as u64, NUM_PACKETS as u64);
+        assert_eq!(registry.unexpected(), 0);
+        // Everything which went missing did so after making it into the switch.
+        assert!(lost
+            .iter()
+            .all(|(_, sighting)| sighting.place == "switch input"));
+    }
+}
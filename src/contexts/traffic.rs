@@ -0,0 +1,810 @@
+use std::{
+    collections::BTreeMap,
+    hash::Hash,
+    sync::{Arc, Mutex},
+};
+
+use dam::context_tools::*;
+use fxhash::FxHashMap;
+
+use crate::{random::SplitMix64, switches::routing::SimplePacket};
+
+/// When a traffic generator stops.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TrafficBudget {
+    /// Once it has sent this many packets.
+    Packets(u64),
+    /// Once its clock reaches this cycle, however many packets that came to.
+    Cycles(u64),
+}
+
+/// Where a [`RandomTrafficGen`] sends each of its packets. A list of destinations converts into uniform traffic
+/// between them.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Destinations<LT> {
+    /// Every destination is equally likely.
+    Uniform(Vec<LT>),
+    /// `fraction` of the packets go to one of `hotspots`, each equally likely, and the rest to any of `destinations`,
+    /// which may include the hotspots as well.
+    Hotspot {
+        hotspots: Vec<LT>,
+        fraction: f64,
+        destinations: Vec<LT>,
+    },
+}
+
+impl<LT> From<Vec<LT>> for Destinations<LT> {
+    fn from(destinations: Vec<LT>) -> Self {
+        Self::Uniform(destinations)
+    }
+}
+
+impl<LT> Destinations<LT> {
+    fn validate(&self) {
+        match self {
+            Self::Uniform(destinations) => {
+                assert!(!destinations.is_empty(), "Traffic needs somewhere to go!")
+            }
+            Self::Hotspot {
+                hotspots,
+                fraction,
+                destinations,
+            } => {
+                assert!(
+                    (0.0..=1.0).contains(fraction),
+                    "Hotspot fractions are probabilities, between 0 and 1!"
+                );
+                assert!(
+                    !hotspots.is_empty() || *fraction == 0.0,
+                    "Hotspot traffic needs a hotspot to go to!"
+                );
+                assert!(
+                    !destinations.is_empty() || *fraction == 1.0,
+                    "Traffic needs somewhere to go!"
+                );
+            }
+        }
+    }
+
+    fn pick(&self, rng: &mut SplitMix64) -> &LT {
+        let choices = match self {
+            Self::Uniform(destinations) => destinations,
+            Self::Hotspot {
+                hotspots,
+                fraction,
+                destinations,
+            } => match rng.next_f64() < *fraction {
+                true => hotspots,
+                false => destinations,
+            },
+        };
+        &choices[rng.below(choices.len() as u64) as usize]
+    }
+}
+
+/// Sends random traffic: on every cycle it flips a coin which comes up with `injection_rate`, and if it does, sends a
+/// packet to one of `destinations`, picked as they say, with the payload `payload` makes for the packet's number among
+/// the ones it has sent. Two generators with the same seed and settings send the same packets on the
+/// same cycles, as long as nothing holds them up.
+///
+/// A full channel holds the generator up instead of costing it packets, so a [`TrafficBudget::Cycles`] budget sends
+/// fewer of them once the network falls behind.
+#[context_macro]
+pub struct RandomTrafficGen<LT: DAMType, PT: DAMType, F> {
+    output: Sender<SimplePacket<LT, PT>>,
+    rng: SplitMix64,
+    destinations: Destinations<LT>,
+    injection_rate: f64,
+    payload: F,
+    budget: TrafficBudget,
+    sent: Arc<Mutex<FxHashMap<LT, u64>>>,
+}
+
+impl<LT: DAMType + Eq + Hash, PT: DAMType, F> Context for RandomTrafficGen<LT, PT, F>
+where
+    F: FnMut(u64) -> PT + Send + Sync,
+{
+    fn run(&mut self) {
+        let mut sent = 0;
+        loop {
+            match self.budget {
+                TrafficBudget::Packets(packets) if sent >= packets => break,
+                TrafficBudget::Cycles(cycles) if self.time.tick().time() >= cycles => break,
+                _ => {}
+            }
+            if self.rng.next_f64() < self.injection_rate {
+                let destination = self.destinations.pick(&mut self.rng).clone();
+                *self
+                    .sent
+                    .lock()
+                    .unwrap()
+                    .entry(destination.clone())
+                    .or_default() += 1;
+                let packet = SimplePacket {
+                    location: destination,
+                    payload: (self.payload)(sent),
+                };
+                self.output
+                    .enqueue(
+                        &self.time,
+                        ChannelElement {
+                            time: self.time.tick() + 1,
+                            data: packet,
+                        },
+                    )
+                    .unwrap();
+                sent += 1;
+            }
+            self.time.incr_cycles(1);
+        }
+    }
+}
+
+impl<LT: DAMType, PT: DAMType, F> RandomTrafficGen<LT, PT, F>
+where
+    Self: Context,
+{
+    pub fn new(
+        output: Sender<SimplePacket<LT, PT>>,
+        seed: u64,
+        destinations: impl Into<Destinations<LT>>,
+        injection_rate: f64,
+        payload: F,
+        budget: TrafficBudget,
+    ) -> Self {
+        let destinations = destinations.into();
+        destinations.validate();
+        assert!(
+            (0.0..=1.0).contains(&injection_rate),
+            "Injection rates are probabilities, between 0 and 1!"
+        );
+        assert!(
+            injection_rate > 0.0 || !matches!(budget, TrafficBudget::Packets(_)),
+            "Generators which never inject would never use up a packet budget!"
+        );
+        let generator = Self {
+            output,
+            rng: SplitMix64::new(seed),
+            destinations,
+            injection_rate,
+            payload,
+            budget,
+            sent: Default::default(),
+            context_info: Default::default(),
+        };
+        generator.output.attach_sender(&generator);
+        generator
+    }
+
+    /// How many packets the generator has sent to each destination. The handle is shared with the generator, so it can
+    /// be read once the run is over.
+    pub fn sent(&self) -> Arc<Mutex<FxHashMap<LT, u64>>> {
+        self.sent.clone()
+    }
+}
+
+/// The standard synthetic permutations, which send everything from each source to the one destination they pick for it
+/// out of `nodes`. All but [`Permutation::Neighbor`] work on the bits of the node indices, so they need a power of two
+/// nodes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Permutation {
+    /// Swaps the high and low halves of the bits, as transposing a square grid of nodes would. With an odd number of
+    /// bits, the bits are rotated right by half of them, rounded down.
+    Transpose,
+    /// Reverses the order of the bits.
+    BitReverse,
+    /// Rotates the bits left by one, as a perfect shuffle does.
+    Shuffle,
+    /// Flips every bit, which sends each node to the one furthest from it.
+    BitComplement,
+    /// Sends each node to the next one, wrapping around at the end.
+    Neighbor,
+}
+
+impl Permutation {
+    /// Where everything from `source` goes, out of `nodes` nodes.
+    pub fn destination(&self, source: usize, nodes: usize) -> usize {
+        assert!(source < nodes, "Sources must be one of the nodes!");
+        if *self == Self::Neighbor {
+            return (source + 1) % nodes;
+        }
+        assert!(
+            nodes.is_power_of_two(),
+            "Bit permutations need a power of two nodes!"
+        );
+        let bits = nodes.trailing_zeros();
+        if bits == 0 {
+            return 0;
+        }
+        let mask = nodes - 1;
+        let rotate_left = |by: u32| ((source << by) | (source >> (bits - by))) & mask;
+        match self {
+            Self::Transpose => rotate_left(bits - bits / 2),
+            Self::BitReverse => source.reverse_bits() >> (usize::BITS - bits),
+            Self::Shuffle => rotate_left(1),
+            Self::BitComplement => !source & mask,
+            Self::Neighbor => unreachable!(),
+        }
+    }
+}
+
+/// Sends `packets` packets from node `source` of a network of `nodes`, all to the destination `pattern` picks for it,
+/// with the payload `payload` makes for each packet's number among them. They go out evenly spaced at `rate` packets a
+/// cycle, the first one straight away, so that each node of the network can have a generator of its own.
+#[context_macro]
+pub struct PermutationTraffic<PT: DAMType, F> {
+    output: Sender<SimplePacket<usize, PT>>,
+    destination: usize,
+    packets: u64,
+    rate: f64,
+    payload: F,
+}
+
+impl<PT: DAMType, F> Context for PermutationTraffic<PT, F>
+where
+    F: FnMut(u64) -> PT + Send + Sync,
+{
+    fn run(&mut self) {
+        // How many packets the generator is owed by now, which it sends one of whenever it reaches a whole one.
+        let mut credit = 1.0;
+        let mut sent = 0;
+        while sent < self.packets {
+            if credit >= 1.0 {
+                credit -= 1.0;
+                let packet = SimplePacket {
+                    location: self.destination,
+                    payload: (self.payload)(sent),
+                };
+                self.output
+                    .enqueue(
+                        &self.time,
+                        ChannelElement {
+                            time: self.time.tick() + 1,
+                            data: packet,
+                        },
+                    )
+                    .unwrap();
+                sent += 1;
+            }
+            credit += self.rate;
+            self.time.incr_cycles(1);
+        }
+    }
+}
+
+impl<PT: DAMType, F> PermutationTraffic<PT, F>
+where
+    Self: Context,
+{
+    pub fn new(
+        output: Sender<SimplePacket<usize, PT>>,
+        pattern: Permutation,
+        source: usize,
+        nodes: usize,
+        packets: u64,
+        rate: f64,
+        payload: F,
+    ) -> Self {
+        assert!(
+            rate > 0.0 && rate <= 1.0,
+            "Generators send at most one packet a cycle, and need to send some!"
+        );
+        let generator = Self {
+            output,
+            destination: pattern.destination(source, nodes),
+            packets,
+            rate,
+            payload,
+            context_info: Default::default(),
+        };
+        generator.output.attach_sender(&generator);
+        generator
+    }
+}
+
+/// The two-state Markov model a [`BurstyTrafficGen`] follows. Each cycle it spends on, it injects with
+/// `injection_rate`, and at the end of every cycle it switches state with the probability for the state it is in, so
+/// that on and off periods last `1 / on_to_off` and `1 / off_to_on` cycles on average.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OnOffModel {
+    pub injection_rate: f64,
+    pub on_to_off: f64,
+    pub off_to_on: f64,
+}
+
+impl OnOffModel {
+    /// The share of cycles a packet should go out on in the long run.
+    pub fn offered_load(&self) -> f64 {
+        self.injection_rate * self.off_to_on / (self.on_to_off + self.off_to_on)
+    }
+}
+
+/// What a [`BurstyTrafficGen`] got up to.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BurstStats {
+    pub sent: u64,
+    /// How many cycles went by before the generator ran out of budget.
+    pub cycles: u64,
+    /// How many on periods lasted each number of cycles, leaving out one the run ended partway through.
+    pub on_periods: BTreeMap<u64, u64>,
+}
+
+impl BurstStats {
+    /// The share of cycles a packet actually went out on, which a full channel holding the generator up brings down.
+    pub fn injection_rate(&self) -> f64 {
+        self.sent as f64 / self.cycles as f64
+    }
+}
+
+/// Sends bursty traffic, switching between on and off as `model` says, and starting off. While it is on, it sends
+/// packets as a [`RandomTrafficGen`] would, to one of `destinations` with the payload `payload` makes for the packet's
+/// number among the ones it has sent, and while it is off, it sends nothing. Two generators with the same seed and
+/// settings send the same packets on the same cycles, as long as nothing holds them up.
+#[context_macro]
+pub struct BurstyTrafficGen<LT: DAMType, PT: DAMType, F> {
+    output: Sender<SimplePacket<LT, PT>>,
+    rng: SplitMix64,
+    model: OnOffModel,
+    destinations: Destinations<LT>,
+    payload: F,
+    budget: TrafficBudget,
+    stats: Arc<Mutex<BurstStats>>,
+}
+
+impl<LT: DAMType, PT: DAMType, F> Context for BurstyTrafficGen<LT, PT, F>
+where
+    F: FnMut(u64) -> PT + Send + Sync,
+{
+    fn run(&mut self) {
+        let mut sent = 0;
+        // How long the generator has been on for, if it is.
+        let mut on_for: Option<u64> = None;
+        loop {
+            match self.budget {
+                TrafficBudget::Packets(packets) if sent >= packets => break,
+                TrafficBudget::Cycles(cycles) if self.time.tick().time() >= cycles => break,
+                _ => {}
+            }
+            if on_for.is_some() && self.rng.next_f64() < self.model.injection_rate {
+                let packet = SimplePacket {
+                    location: self.destinations.pick(&mut self.rng).clone(),
+                    payload: (self.payload)(sent),
+                };
+                self.output
+                    .enqueue(
+                        &self.time,
+                        ChannelElement {
+                            time: self.time.tick() + 1,
+                            data: packet,
+                        },
+                    )
+                    .unwrap();
+                sent += 1;
+            }
+            on_for = match on_for {
+                Some(cycles) if self.rng.next_f64() < self.model.on_to_off => {
+                    let mut stats = self.stats.lock().unwrap();
+                    *stats.on_periods.entry(cycles + 1).or_default() += 1;
+                    None
+                }
+                Some(cycles) => Some(cycles + 1),
+                None if self.rng.next_f64() < self.model.off_to_on => Some(0),
+                None => None,
+            };
+            self.time.incr_cycles(1);
+        }
+        let mut stats = self.stats.lock().unwrap();
+        stats.sent = sent;
+        stats.cycles = self.time.tick().time();
+    }
+}
+
+impl<LT: DAMType, PT: DAMType, F> BurstyTrafficGen<LT, PT, F>
+where
+    Self: Context,
+{
+    pub fn new(
+        output: Sender<SimplePacket<LT, PT>>,
+        seed: u64,
+        model: OnOffModel,
+        destinations: impl Into<Destinations<LT>>,
+        payload: F,
+        budget: TrafficBudget,
+    ) -> Self {
+        let destinations = destinations.into();
+        destinations.validate();
+        for probability in [model.injection_rate, model.on_to_off, model.off_to_on] {
+            assert!(
+                (0.0..=1.0).contains(&probability),
+                "The model's rates are probabilities, between 0 and 1!"
+            );
+        }
+        assert!(
+            (model.injection_rate > 0.0 && model.off_to_on > 0.0)
+                || !matches!(budget, TrafficBudget::Packets(_)),
+            "Generators which never inject would never use up a packet budget!"
+        );
+        let generator = Self {
+            output,
+            rng: SplitMix64::new(seed),
+            model,
+            destinations,
+            payload,
+            budget,
+            stats: Default::default(),
+            context_info: Default::default(),
+        };
+        generator.output.attach_sender(&generator);
+        generator
+    }
+
+    /// The handle is shared with the generator, so it can be read once the run is over.
+    pub fn stats(&self) -> Arc<Mutex<BurstStats>> {
+        self.stats.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use dam::{simulation::ProgramBuilder, utility_contexts::*};
+    use fxhash::FxHashMap;
+
+    use crate::{
+        switches::routing::Coord2D,
+        topologies::{build_hypercube, build_mesh_2d, MeshConfig},
+    };
+
+    use super::{
+        BurstyTrafficGen, Destinations, OnOffModel, Permutation, PermutationTraffic,
+        RandomTrafficGen, TrafficBudget,
+    };
+
+    const SIZE: u16 = 4;
+    const CYCLES: u64 = 500;
+    const INJECTION_RATE: f64 = 0.1;
+
+    #[test]
+    fn uniform_mesh_traffic_test() {
+        let mut ctx = ProgramBuilder::default();
+        let handles = build_mesh_2d::<Coord2D, u64>(&mut ctx, SIZE, SIZE, MeshConfig::default());
+
+        let bounds = Coord2D::new(SIZE, SIZE);
+        let nodes: Vec<_> = (0..SIZE as usize * SIZE as usize)
+            .map(|index| Coord2D::from_index(index, bounds))
+            .collect();
+        let arrivals = Arc::new(Mutex::new(vec![]));
+        for (node, (inject, eject)) in handles {
+            let source = node.to_index(bounds) as u64;
+            let destinations: Vec<_> = nodes
+                .iter()
+                .copied()
+                .filter(|other| *other != node)
+                .collect();
+            ctx.add_child(RandomTrafficGen::new(
+                inject,
+                source,
+                destinations,
+                INJECTION_RATE,
+                move |sequence| source << 32 | sequence,
+                TrafficBudget::Cycles(CYCLES),
+            ));
+
+            let mut sink = FunctionContext::new();
+            eject.attach_receiver(&sink);
+            let log = arrivals.clone();
+            sink.set_run(move |time| {
+                while let Ok(element) = eject.dequeue(time) {
+                    log.lock().unwrap().push((node, element.data));
+                }
+            });
+            ctx.add_child(sink);
+        }
+        ctx.initialize(Default::default())
+            .unwrap()
+            .run(Default::default());
+
+        let arrivals = arrivals.lock().unwrap();
+        assert!(arrivals
+            .iter()
+            .all(|(node, packet)| packet.location == *node
+                && packet.payload >> 32 != node.to_index(bounds) as u64));
+        // Light enough that nothing should hold the generators up, so about one packet in ten cycles from each node.
+        let expected = (nodes.len() as u64 * CYCLES) as f64 * INJECTION_RATE;
+        let delivered = arrivals.len() as f64;
+        assert!(
+            (delivered - expected).abs() < expected * 0.1,
+            "Expected about {} packets, but {} arrived!",
+            expected,
+            delivered
+        );
+    }
+
+    #[test]
+    fn hotspot_mesh_traffic_test() {
+        const HOTSPOT_RATE: f64 = 0.3;
+        const HOTSPOT_CYCLES: u64 = 200;
+        // Deep enough to hold the backlog building up behind the hotspot for the whole run.
+        let cfg = MeshConfig {
+            channel_depth: 64,
+            ..Default::default()
+        };
+        let mut ctx = ProgramBuilder::default();
+        let handles = build_mesh_2d::<Coord2D, u64>(&mut ctx, SIZE, SIZE, cfg);
+
+        let bounds = Coord2D::new(SIZE, SIZE);
+        let hotspot = Coord2D::new(1, 2);
+        let nodes: Vec<_> = (0..SIZE as usize * SIZE as usize)
+            .map(|index| Coord2D::from_index(index, bounds))
+            .collect();
+        let arrivals = Arc::new(Mutex::new(vec![]));
+        let mut sent = vec![];
+        for (node, (inject, eject)) in handles {
+            let destinations: Vec<_> = nodes
+                .iter()
+                .copied()
+                .filter(|other| *other != node)
+                .collect();
+            let destinations = match node == hotspot {
+                true => Destinations::Uniform(destinations),
+                false => Destinations::Hotspot {
+                    hotspots: vec![hotspot],
+                    fraction: 0.3,
+                    destinations,
+                },
+            };
+            let generator = RandomTrafficGen::new(
+                inject,
+                node.to_index(bounds) as u64,
+                destinations,
+                HOTSPOT_RATE,
+                |sequence| sequence,
+                TrafficBudget::Cycles(HOTSPOT_CYCLES),
+            );
+            sent.push(generator.sent());
+            ctx.add_child(generator);
+
+            let mut sink = FunctionContext::new();
+            eject.attach_receiver(&sink);
+            let log = arrivals.clone();
+            sink.set_run(move |time| {
+                while let Ok(element) = eject.dequeue(time) {
+                    log.lock().unwrap().push((node, element.time.time()));
+                }
+            });
+            ctx.add_child(sink);
+        }
+        ctx.initialize(Default::default())
+            .unwrap()
+            .run(Default::default());
+
+        let mut expected = FxHashMap::default();
+        for counts in sent {
+            for (destination, count) in counts.lock().unwrap().iter() {
+                *expected.entry(*destination).or_default() += count;
+            }
+        }
+        let arrivals = arrivals.lock().unwrap();
+        let mut received: FxHashMap<_, u64> = FxHashMap::default();
+        for (node, _) in arrivals.iter() {
+            *received.entry(*node).or_default() += 1;
+        }
+        assert_eq!(received, expected);
+
+        // How many packets each node took in per cycle, from the first one to arrive until the last.
+        let ejection_rate = |node: Coord2D| {
+            let times: Vec<_> = arrivals
+                .iter()
+                .filter(|(arrived_at, _)| *arrived_at == node)
+                .map(|(_, time)| *time)
+                .collect();
+            let (first, last) = (times.iter().min().unwrap(), times.iter().max().unwrap());
+            times.len() as f64 / (last - first + 1) as f64
+        };
+        // Everyone else sends the hotspot far more than the one packet a cycle it can take in.
+        assert!(ejection_rate(hotspot) > 0.9, "{}", ejection_rate(hotspot));
+        for node in nodes.into_iter().filter(|node| *node != hotspot) {
+            assert!(
+                ejection_rate(node) < 0.5,
+                "{} {}",
+                node,
+                ejection_rate(node)
+            );
+        }
+    }
+
+    /// Runs a generator on its own with `budget`, and returns each packet it sent with the cycle it went out on.
+    fn generate(seed: u64, budget: TrafficBudget) -> Vec<(u64, u8, u32)> {
+        let mut ctx = ProgramBuilder::default();
+        let (snd, rcv) = ctx.unbounded();
+        ctx.add_child(RandomTrafficGen::new(
+            snd,
+            seed,
+            (0..8).collect::<Vec<_>>(),
+            0.25,
+            |sequence| sequence as u32,
+            budget,
+        ));
+        let sent = Arc::new(Mutex::new(vec![]));
+        let mut sink = FunctionContext::new();
+        rcv.attach_receiver(&sink);
+        let log = sent.clone();
+        sink.set_run(move |time| {
+            while let Ok(element) = rcv.dequeue(time) {
+                let packet = element.data;
+                log.lock()
+                    .unwrap()
+                    .push((element.time.time(), packet.location, packet.payload));
+            }
+        });
+        ctx.add_child(sink);
+        ctx.initialize(Default::default())
+            .unwrap()
+            .run(Default::default());
+        let sent = sent.lock().unwrap().clone();
+        sent
+    }
+
+    #[test]
+    fn reproducible_traffic_test() {
+        let first = generate(7, TrafficBudget::Packets(64));
+        assert_eq!(first.len(), 64);
+        assert_eq!(first, generate(7, TrafficBudget::Packets(64)));
+        assert_ne!(first, generate(8, TrafficBudget::Packets(64)));
+        // Numbered in order, with at least one cycle between each, and some cycles going by empty.
+        assert!(first
+            .iter()
+            .enumerate()
+            .all(|(index, (_, _, payload))| *payload == index as u32));
+        assert!(first.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        assert!(first.last().unwrap().0 > 64);
+    }
+
+    const NODES: usize = 8;
+    const PATTERNS: [Permutation; 5] = [
+        Permutation::Transpose,
+        Permutation::BitReverse,
+        Permutation::Shuffle,
+        Permutation::BitComplement,
+        Permutation::Neighbor,
+    ];
+
+    #[test]
+    fn permutation_destinations_test() {
+        let destinations = |pattern: Permutation| -> Vec<_> {
+            (0..NODES)
+                .map(|source| pattern.destination(source, NODES))
+                .collect()
+        };
+        assert_eq!(
+            destinations(Permutation::Transpose),
+            [0, 4, 1, 5, 2, 6, 3, 7]
+        );
+        assert_eq!(
+            destinations(Permutation::BitReverse),
+            [0, 4, 2, 6, 1, 5, 3, 7]
+        );
+        assert_eq!(destinations(Permutation::Shuffle), [0, 2, 4, 6, 1, 3, 5, 7]);
+        assert_eq!(
+            destinations(Permutation::BitComplement),
+            [7, 6, 5, 4, 3, 2, 1, 0]
+        );
+        assert_eq!(
+            destinations(Permutation::Neighbor),
+            [1, 2, 3, 4, 5, 6, 7, 0]
+        );
+        // Transposing a 4 by 4 grid.
+        assert_eq!(Permutation::Transpose.destination(0b0111, 16), 0b1101);
+    }
+
+    #[test]
+    fn permutation_traffic_test() {
+        const PACKETS: u64 = 8;
+        for pattern in PATTERNS {
+            let mut ctx = ProgramBuilder::default();
+            let handles = build_hypercube(&mut ctx, NODES.trailing_zeros(), MeshConfig::default());
+            let arrivals = Arc::new(Mutex::new(vec![]));
+            for (node, (inject, eject)) in handles.into_iter().enumerate() {
+                ctx.add_child(PermutationTraffic::new(
+                    inject,
+                    pattern,
+                    node,
+                    NODES,
+                    PACKETS,
+                    0.5,
+                    move |_| node as u32,
+                ));
+
+                let mut sink = FunctionContext::new();
+                eject.attach_receiver(&sink);
+                let log = arrivals.clone();
+                sink.set_run(move |time| {
+                    while let Ok(element) = eject.dequeue(time) {
+                        log.lock().unwrap().push((node, element.data.payload));
+                    }
+                });
+                ctx.add_child(sink);
+            }
+            ctx.initialize(Default::default())
+                .unwrap()
+                .run(Default::default());
+
+            let arrivals = arrivals.lock().unwrap();
+            assert_eq!(
+                arrivals.len() as u64,
+                NODES as u64 * PACKETS,
+                "{:?}",
+                pattern
+            );
+            for (node, source) in arrivals.iter() {
+                assert_eq!(
+                    pattern.destination(*source as usize, NODES),
+                    *node,
+                    "{:?} sent {} to {}",
+                    pattern,
+                    source,
+                    node
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn bursty_traffic_test() {
+        const PACKETS: u64 = 2000;
+        let model = OnOffModel {
+            injection_rate: 0.8,
+            on_to_off: 0.25,
+            off_to_on: 0.1,
+        };
+        let mut ctx = ProgramBuilder::default();
+        let (snd, rcv) = ctx.unbounded();
+        let generator = BurstyTrafficGen::new(
+            snd,
+            3,
+            model,
+            vec![0u8],
+            |sequence| sequence,
+            TrafficBudget::Packets(PACKETS),
+        );
+        let stats = generator.stats();
+        ctx.add_child(generator);
+        let received = Arc::new(Mutex::new(0));
+        let mut sink = FunctionContext::new();
+        rcv.attach_receiver(&sink);
+        let count = received.clone();
+        sink.set_run(move |time| {
+            while rcv.dequeue(time).is_ok() {
+                *count.lock().unwrap() += 1;
+            }
+        });
+        ctx.add_child(sink);
+        ctx.initialize(Default::default())
+            .unwrap()
+            .run(Default::default());
+
+        let stats = stats.lock().unwrap();
+        assert_eq!(stats.sent, PACKETS);
+        assert_eq!(*received.lock().unwrap(), PACKETS);
+        let close_to = |actual: f64, expected: f64| (actual - expected).abs() < expected * 0.1;
+        assert!(
+            close_to(stats.injection_rate(), model.offered_load()),
+            "{} {}",
+            stats.injection_rate(),
+            model.offered_load()
+        );
+        // On periods are geometric, so they last 1 / on_to_off cycles on average, and a quarter of them only last one.
+        let periods: u64 = stats.on_periods.values().sum();
+        let total: u64 = stats
+            .on_periods
+            .iter()
+            .map(|(length, count)| length * count)
+            .sum();
+        let mean = total as f64 / periods as f64;
+        assert!(close_to(mean, 1.0 / model.on_to_off), "{}", mean);
+        let single = stats.on_periods[&1] as f64 / periods as f64;
+        assert!((single - model.on_to_off).abs() < 0.05, "{}", single);
+    }
+}
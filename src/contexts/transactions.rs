@@ -0,0 +1,281 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+use dam::context_tools::*;
+
+use crate::switches::routing::{Request, Response};
+
+/// Answers requests, such as a memory bank would: works out the payload of each response with `service`, and sends it
+/// back to wherever the request came from `latency` cycles after the request arrived. Takes a new request every cycle.
+#[context_macro]
+pub struct Responder<LT: DAMType, PT: DAMType, RT: DAMType, ServiceType> {
+    input: Receiver<Request<LT, PT>>,
+    output: Sender<Response<LT, RT>>,
+    service: ServiceType,
+    latency: u64,
+}
+
+impl<LT: DAMType, PT: DAMType, RT: DAMType, ServiceType> Context
+    for Responder<LT, PT, RT, ServiceType>
+where
+    ServiceType: FnMut(&Request<LT, PT>) -> RT + Send + Sync,
+{
+    fn run(&mut self) {
+        while let Ok(ChannelElement { time: _, data }) = self.input.dequeue(&self.time) {
+            let payload = (self.service)(&data);
+            self.output
+                .enqueue(
+                    &self.time,
+                    ChannelElement {
+                        time: self.time.tick() + self.latency,
+                        data: data.respond(payload),
+                    },
+                )
+                .unwrap();
+            self.time.incr_cycles(1);
+        }
+    }
+}
+
+impl<LT: DAMType, PT: DAMType, RT: DAMType, ServiceType> Responder<LT, PT, RT, ServiceType>
+where
+    Self: Context,
+{
+    pub fn new(
+        input: Receiver<Request<LT, PT>>,
+        output: Sender<Response<LT, RT>>,
+        service: ServiceType,
+        latency: u64,
+    ) -> Self {
+        let responder = Self {
+            input,
+            output,
+            service,
+            latency,
+            context_info: Default::default(),
+        };
+        responder.input.attach_receiver(&responder);
+        responder.output.attach_sender(&responder);
+        responder
+    }
+}
+
+/// Collected by a [`RequesterContext`] while it runs.
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TransactionStats {
+    /// How many cycles each transaction took, by `txn_id`, from its request going out to its response arriving.
+    pub round_trips: BTreeMap<u64, u64>,
+    /// The most transactions which were ever outstanding at once.
+    pub max_outstanding: usize,
+}
+
+/// Issues `count` requests from `location`, one per cycle, with `next_request` giving the destination and payload of
+/// each by its number. At most `window` can be outstanding at once, after which the requester waits for a response
+/// before sending the next. Requests are numbered from 0 unless given another first `txn_id`.
+#[context_macro]
+pub struct RequesterContext<LT: DAMType, PT: DAMType, RT: DAMType, RequestType> {
+    output: Sender<Request<LT, PT>>,
+    input: Receiver<Response<LT, RT>>,
+    location: LT,
+    count: u64,
+    window: usize,
+    next_request: RequestType,
+    first_id: u64,
+    stats: Arc<Mutex<TransactionStats>>,
+}
+
+impl<LT: DAMType + PartialEq, PT: DAMType, RT: DAMType, RequestType> Context
+    for RequesterContext<LT, PT, RT, RequestType>
+where
+    RequestType: FnMut(u64) -> (LT, PT) + Send + Sync,
+{
+    fn run(&mut self) {
+        // When each outstanding request went out, and where to, by txn_id.
+        let mut outstanding = fxhash::FxHashMap::<u64, (u64, LT)>::default();
+        let mut issued = 0;
+        while issued < self.count || !outstanding.is_empty() {
+            if issued < self.count && outstanding.len() < self.window {
+                let txn_id = self.first_id + issued;
+                let (location, payload) = (self.next_request)(issued);
+                let sent_at = self.time.tick() + 1;
+                outstanding.insert(txn_id, (sent_at.time(), location.clone()));
+                {
+                    let mut stats = self.stats.lock().unwrap();
+                    stats.max_outstanding = stats.max_outstanding.max(outstanding.len());
+                }
+                self.output
+                    .enqueue(
+                        &self.time,
+                        ChannelElement {
+                            time: sent_at,
+                            data: Request {
+                                source: self.location.clone(),
+                                location,
+                                txn_id,
+                                payload,
+                            },
+                        },
+                    )
+                    .unwrap();
+                issued += 1;
+                self.time.incr_cycles(1);
+                continue;
+            }
+
+            // Responses are only taken in once the window fills up, but they're timed by when they arrived.
+            let ChannelElement { time, data } = self
+                .input
+                .dequeue(&self.time)
+                .expect("Responses stopped coming with transactions still outstanding!");
+            let (sent_at, destination) = outstanding.remove(&data.txn_id).unwrap_or_else(|| {
+                panic!(
+                    "Got a response for transaction {} which wasn't outstanding!",
+                    data.txn_id
+                )
+            });
+            assert_eq!(
+                data.source, destination,
+                "The response to transaction {} came from the wrong place!",
+                data.txn_id
+            );
+            self.stats
+                .lock()
+                .unwrap()
+                .round_trips
+                .insert(data.txn_id, time.time() - sent_at);
+        }
+    }
+}
+
+impl<LT: DAMType, PT: DAMType, RT: DAMType, RequestType> RequesterContext<LT, PT, RT, RequestType>
+where
+    Self: Context,
+{
+    pub fn new(
+        output: Sender<Request<LT, PT>>,
+        input: Receiver<Response<LT, RT>>,
+        location: LT,
+        count: u64,
+        window: usize,
+        next_request: RequestType,
+    ) -> Self {
+        assert!(window > 0, "Requesters need room for at least one request!");
+        let requester = Self {
+            output,
+            input,
+            location,
+            count,
+            window,
+            next_request,
+            first_id: 0,
+            stats: Default::default(),
+            context_info: Default::default(),
+        };
+        requester.output.attach_sender(&requester);
+        requester.input.attach_receiver(&requester);
+        requester
+    }
+
+    /// Numbers requests from `first_id` up, so that several requesters can be told apart by their txn_ids.
+    pub fn with_first_id(mut self, first_id: u64) -> Self {
+        self.first_id = first_id;
+        self
+    }
+
+    /// The handle is shared with the requester, so it can be read once the run is over.
+    pub fn stats(&self) -> Arc<Mutex<TransactionStats>> {
+        self.stats.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dam::simulation::ProgramBuilder;
+
+    use crate::switches::{
+        routing::{Port, Switch},
+        testing::direct_policy,
+        SimpleSwitch,
+    };
+
+    use super::{RequesterContext, Responder};
+
+    const CORES: [u8; 2] = [0, 1];
+    const BANKS: [u8; 2] = [2, 3];
+    const REQUESTS: u64 = 16;
+    const WINDOW: usize = 4;
+    const SERVICE_LATENCY: u64 = 5;
+
+    #[test]
+    fn memory_crossbar_test() {
+        let mut ctx = ProgramBuilder::default();
+        // Requests and responses get crossbars of their own, so that neither can hold up the other.
+        let mut requests = SimpleSwitch::new(direct_policy(BANKS.map(usize::from)), 1);
+        let mut responses = SimpleSwitch::new(direct_policy(CORES.map(usize::from)), 1);
+
+        let mut stats = vec![];
+        for core in CORES {
+            let (req_snd, req_rcv) = ctx.unbounded();
+            let (resp_snd, resp_rcv) = ctx.unbounded();
+            // Each core alternates between the banks, so both cores go after the same bank at the same time.
+            let requester = RequesterContext::new(req_snd, resp_rcv, core, REQUESTS, WINDOW, |n| {
+                (BANKS[n as usize % BANKS.len()], n as u32)
+            })
+            .with_first_id(core as u64 * 100);
+            stats.push(requester.stats());
+            ctx.add_child(requester);
+            requests.add_port(Port {
+                id: core as usize,
+                input: Some(req_rcv),
+                output: None,
+            });
+            responses.add_port(Port {
+                id: core as usize,
+                input: None,
+                output: Some(resp_snd),
+            });
+        }
+        for bank in BANKS {
+            let (req_snd, req_rcv) = ctx.unbounded();
+            let (resp_snd, resp_rcv) = ctx.unbounded();
+            requests.add_port(Port {
+                id: bank as usize,
+                input: None,
+                output: Some(req_snd),
+            });
+            responses.add_port(Port {
+                id: bank as usize,
+                input: Some(resp_rcv),
+                output: None,
+            });
+            ctx.add_child(Responder::new(
+                req_rcv,
+                resp_snd,
+                |request: &_| request.payload * 2,
+                SERVICE_LATENCY,
+            ));
+        }
+        ctx.add_child(requests);
+        ctx.add_child(responses);
+        ctx.initialize(Default::default())
+            .unwrap()
+            .run(Default::default());
+
+        // A hop through each crossbar and the service time, plus however long the request waited for its bank.
+        let uncontended = 2 + SERVICE_LATENCY;
+        for (core, stats) in CORES.into_iter().zip(stats) {
+            let stats = stats.lock().unwrap();
+            let first_id = core as u64 * 100;
+            assert!(stats
+                .round_trips
+                .keys()
+                .copied()
+                .eq(first_id..first_id + REQUESTS));
+            assert_eq!(stats.max_outstanding, WINDOW);
+            assert_eq!(stats.round_trips.values().min().copied(), Some(uncontended));
+        }
+    }
+}
@@ -0,0 +1,163 @@
+use dam::{context_tools::*, structures::SyncSendMarker};
+
+use crate::switches::routing::{MutablePacket, Packet};
+
+/// Sits on the boundary between two networks which address things differently, rewriting the destination of each
+/// packet that crosses from one to the other. The mapping can be any function from the incoming location type to the
+/// outgoing one, such as a lookup in a table. Packets go out `latency` cycles after they arrive, one per cycle.
+#[context_macro]
+pub struct AddressTranslator<TIn: DAMType, TOut: DAMType, LTIn, LTOut, MapType> {
+    input: Receiver<TIn>,
+    output: Sender<TOut>,
+
+    translate: MapType,
+    latency: u64,
+
+    _marker: SyncSendMarker<(LTIn, LTOut)>,
+}
+
+impl<TIn: DAMType, TOut: DAMType, LTIn, LTOut, MapType> Context
+    for AddressTranslator<TIn, TOut, LTIn, LTOut, MapType>
+where
+    TIn: Packet<LTIn> + MutablePacket<LTOut, Output = TOut>,
+    LTIn: Sync + Send,
+    LTOut: Sync + Send,
+    MapType: FnMut(&LTIn) -> LTOut + Sync + Send,
+{
+    fn run(&mut self) {
+        while let Ok(ChannelElement { time: _, data }) = self.input.dequeue(&self.time) {
+            let destination = (self.translate)(&data.destination());
+            self.output
+                .enqueue(
+                    &self.time,
+                    ChannelElement {
+                        time: self.time.tick() + self.latency,
+                        data: data.with_destination(destination),
+                    },
+                )
+                .unwrap();
+            self.time.incr_cycles(1);
+        }
+    }
+}
+
+impl<TIn: DAMType, TOut: DAMType, LTIn, LTOut, MapType>
+    AddressTranslator<TIn, TOut, LTIn, LTOut, MapType>
+where
+    Self: Context,
+{
+    /// Ties the input, whose packets are addressed in the first network's locations, to the output, which gets them
+    /// readdressed by `translate`.
+    pub fn new(
+        input: Receiver<TIn>,
+        output: Sender<TOut>,
+        translate: MapType,
+        latency: u64,
+    ) -> Self {
+        let translator = Self {
+            input,
+            output,
+            translate,
+            latency,
+            _marker: Default::default(),
+            context_info: Default::default(),
+        };
+        translator.input.attach_receiver(&translator);
+        translator.output.attach_sender(&translator);
+        translator
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use dam::{context_tools::ChannelElement, simulation::ProgramBuilder, utility_contexts::*};
+
+    use crate::switches::{
+        routing::{Port, SimplePacket, Switch},
+        SimpleSwitch,
+    };
+
+    use super::AddressTranslator;
+
+    const NUM_PACKETS: u16 = 64;
+    const NUM_BANKS: usize = 4;
+
+    #[test]
+    fn logical_to_physical_test() {
+        let mut ctx = ProgramBuilder::default();
+
+        // Cores are addressed by logical IDs in the hundreds, and each one is backed by a memory bank behind a
+        // physical port.
+        let core_to_bank = fxhash::FxHashMap::from_iter(
+            (0..NUM_BANKS).map(|bank| (100 + bank as u16 * 7, bank as u8)),
+        );
+        let cores: Vec<_> = core_to_bank.keys().copied().collect();
+
+        let (gen_snd, gen_rcv) = ctx.unbounded();
+        let generated_cores = cores.clone();
+        ctx.add_child(GeneratorContext::new(
+            move || {
+                let cores = generated_cores.clone();
+                (0..NUM_PACKETS).map(move |payload| SimplePacket {
+                    location: cores[payload as usize % NUM_BANKS],
+                    payload,
+                })
+            },
+            gen_snd,
+        ));
+
+        let (translated_snd, translated_rcv) = ctx.unbounded();
+        let table = core_to_bank.clone();
+        ctx.add_child(AddressTranslator::new(
+            gen_rcv,
+            translated_snd,
+            move |core: &u16| table[core],
+            1,
+        ));
+
+        let policy = fxhash::FxHashMap::from_iter(
+            (0..NUM_BANKS).map(|bank| (bank as u8, fxhash::FxHashSet::from_iter([bank]))),
+        );
+        let mut switch = SimpleSwitch::new(policy, 1);
+        switch.add_port(Port {
+            id: NUM_BANKS,
+            input: Some(translated_rcv),
+            output: None,
+        });
+
+        let received = Arc::new(Mutex::new(vec![]));
+        for bank in 0..NUM_BANKS {
+            let (snd, rcv) = ctx.unbounded::<SimplePacket<u8, u16>>();
+            switch.add_port(Port {
+                id: bank,
+                input: None,
+                output: Some(snd),
+            });
+            let mut sink = FunctionContext::new();
+            rcv.attach_receiver(&sink);
+            let sink_log = received.clone();
+            sink.set_run(move |time| {
+                while let Ok(ChannelElement { time: _, data }) = rcv.dequeue(time) {
+                    sink_log.lock().unwrap().push((bank, data));
+                }
+            });
+            ctx.add_child(sink);
+        }
+        ctx.add_child(switch);
+
+        ctx.initialize(Default::default())
+            .unwrap()
+            .run(Default::default());
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), NUM_PACKETS as usize);
+        for (bank, packet) in received.iter() {
+            // Each packet comes out of the bank behind the core it was sent to, addressed to that bank.
+            let core = cores[packet.payload as usize % NUM_BANKS];
+            assert_eq!(*bank, core_to_bank[&core] as usize);
+            assert_eq!(packet.location, *bank as u8);
+        }
+    }
+}
@@ -0,0 +1,215 @@
+use dam::{context_tools::*, structures::SyncSendMarker};
+
+use crate::switches::routing::{Encapsulated, Packet};
+
+/// The entrance to a tunnel through a transit network which addresses things differently. Each packet is wrapped,
+/// whole, in an outer packet addressed to the far end of the tunnel for its destination, as given by `exit`, such as a
+/// lookup in a table. Packets go out `latency` cycles after they arrive, one per cycle.
+#[context_macro]
+pub struct Encapsulate<T: DAMType, LT, OuterLT: DAMType, MapType> {
+    input: Receiver<T>,
+    output: Sender<Encapsulated<OuterLT, T>>,
+
+    exit: MapType,
+    latency: u64,
+
+    _marker: SyncSendMarker<LT>,
+}
+
+impl<T: DAMType, LT, OuterLT: DAMType, MapType> Context for Encapsulate<T, LT, OuterLT, MapType>
+where
+    T: Packet<LT>,
+    LT: Sync + Send,
+    MapType: FnMut(&LT) -> OuterLT + Sync + Send,
+{
+    fn run(&mut self) {
+        while let Ok(ChannelElement { time: _, data }) = self.input.dequeue(&self.time) {
+            let location = (self.exit)(&data.destination());
+            self.output
+                .enqueue(
+                    &self.time,
+                    ChannelElement {
+                        time: self.time.tick() + self.latency,
+                        data: Encapsulated {
+                            location,
+                            payload: data,
+                        },
+                    },
+                )
+                .unwrap();
+            self.time.incr_cycles(1);
+        }
+    }
+}
+
+impl<T: DAMType, LT, OuterLT: DAMType, MapType> Encapsulate<T, LT, OuterLT, MapType>
+where
+    Self: Context,
+{
+    pub fn new(
+        input: Receiver<T>,
+        output: Sender<Encapsulated<OuterLT, T>>,
+        exit: MapType,
+        latency: u64,
+    ) -> Self {
+        let encapsulate = Self {
+            input,
+            output,
+            exit,
+            latency,
+            _marker: Default::default(),
+            context_info: Default::default(),
+        };
+        encapsulate.input.attach_receiver(&encapsulate);
+        encapsulate.output.attach_sender(&encapsulate);
+        encapsulate
+    }
+}
+
+/// The exit of a tunnel, which unwraps the packets made by an [`Encapsulate`] and sends them on as they were.
+/// Packets go out `latency` cycles after they arrive, one per cycle.
+#[context_macro]
+pub struct Decapsulate<OuterLT: DAMType, T: DAMType> {
+    input: Receiver<Encapsulated<OuterLT, T>>,
+    output: Sender<T>,
+    latency: u64,
+}
+
+impl<OuterLT: DAMType, T: DAMType> Context for Decapsulate<OuterLT, T> {
+    fn run(&mut self) {
+        while let Ok(ChannelElement { time: _, data }) = self.input.dequeue(&self.time) {
+            self.output
+                .enqueue(
+                    &self.time,
+                    ChannelElement {
+                        time: self.time.tick() + self.latency,
+                        data: data.payload,
+                    },
+                )
+                .unwrap();
+            self.time.incr_cycles(1);
+        }
+    }
+}
+
+impl<OuterLT: DAMType, T: DAMType> Decapsulate<OuterLT, T> {
+    pub fn new(input: Receiver<Encapsulated<OuterLT, T>>, output: Sender<T>, latency: u64) -> Self {
+        let decapsulate = Self {
+            input,
+            output,
+            latency,
+            context_info: Default::default(),
+        };
+        decapsulate.input.attach_receiver(&decapsulate);
+        decapsulate.output.attach_sender(&decapsulate);
+        decapsulate
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use dam::{context_tools::*, simulation::ProgramBuilder, utility_contexts::*};
+
+    use crate::switches::{
+        routing::{Encapsulated, Port, SimplePacket, Switch},
+        SimpleSwitch,
+    };
+
+    use super::{Decapsulate, Encapsulate};
+
+    const NUM_PACKETS: u32 = 32;
+    // Logical endpoints 100 and 101 are behind transit switch 0, and 102 and 103 behind transit switch 1.
+    const ENDPOINTS: [u16; 4] = [100, 101, 102, 103];
+    const LOCAL: usize = 2;
+
+    type InnerPacket = SimplePacket<u16, u32>;
+
+    #[test]
+    fn tunnel_test() {
+        let mut ctx = ProgramBuilder::default();
+
+        let (gen_snd, gen_rcv) = ctx.unbounded();
+        ctx.add_child(GeneratorContext::new(
+            || {
+                (0..NUM_PACKETS).map(|payload| SimplePacket {
+                    location: ENDPOINTS[payload as usize % ENDPOINTS.len()],
+                    payload,
+                })
+            },
+            gen_snd,
+        ));
+        let (tunnel_snd, tunnel_rcv) = ctx.unbounded();
+        ctx.add_child(Encapsulate::new(
+            gen_rcv,
+            tunnel_snd,
+            |endpoint: &u16| ((endpoint - 100) / 2) as u8,
+            1,
+        ));
+
+        // The transit switches only know each other: switch 0 takes packets for switch 1 across the link on port 1,
+        // and both let out their own through their local port.
+        let received = Arc::new(Mutex::new(vec![]));
+        let mut switches: Vec<_> = (0..2u8)
+            .map(|switch| {
+                let policy = move |exit: &u8| match *exit == switch {
+                    true => fxhash::FxHashSet::from_iter([LOCAL]),
+                    false => fxhash::FxHashSet::from_iter([1]),
+                };
+                SimpleSwitch::new(policy, 1)
+            })
+            .collect();
+        let (link_snd, link_rcv) = ctx.unbounded::<Encapsulated<u8, InnerPacket>>();
+        switches[0].add_port(Port {
+            id: 0,
+            input: Some(tunnel_rcv),
+            output: None,
+        });
+        switches[0].add_port(Port {
+            id: 1,
+            input: None,
+            output: Some(link_snd),
+        });
+        switches[1].add_port(Port {
+            id: 0,
+            input: Some(link_rcv),
+            output: None,
+        });
+        for (exit, switch) in switches.iter_mut().enumerate() {
+            let (exit_snd, exit_rcv) = ctx.unbounded();
+            switch.add_port(Port {
+                id: LOCAL,
+                input: None,
+                output: Some(exit_snd),
+            });
+            let (snd, rcv) = ctx.unbounded::<InnerPacket>();
+            ctx.add_child(Decapsulate::new(exit_rcv, snd, 1));
+            let mut sink = FunctionContext::new();
+            rcv.attach_receiver(&sink);
+            let sink_log = received.clone();
+            sink.set_run(move |time| {
+                while let Ok(ChannelElement { time: _, data }) = rcv.dequeue(time) {
+                    sink_log.lock().unwrap().push((exit, data));
+                }
+            });
+            ctx.add_child(sink);
+        }
+        switches
+            .into_iter()
+            .for_each(|switch| ctx.add_child(switch));
+
+        ctx.initialize(Default::default())
+            .unwrap()
+            .run(Default::default());
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), NUM_PACKETS as usize);
+        for (exit, packet) in received.iter() {
+            // Each packet leaves the tunnel at the switch its endpoint is behind, as it went in.
+            let endpoint = ENDPOINTS[packet.payload as usize % ENDPOINTS.len()];
+            assert_eq!(packet.location, endpoint);
+            assert_eq!(*exit as u16, (endpoint - 100) / 2);
+        }
+    }
+}
@@ -0,0 +1,39 @@
+use dam::{
+    channel::utils::{EventTime, Peekable},
+    context_tools::*,
+    structures::Time,
+};
+
+/// Waits until there is something to do, for contexts with one input which hold on to what comes in until it is time
+/// to send it: whatever arrives on `input` by the current cycle, or the next thing to go out at `next_send`.
+/// Contexts which can't take anything else in at the moment leave the input alone by passing false for `listening`.
+/// Returns false once none of that will ever come, since the input has closed or isn't being listened to, and there's
+/// nothing left to send.
+pub(crate) fn wait_for_work<T: DAMType>(
+    time: &mut TimeManager,
+    input: &Receiver<T>,
+    listening: bool,
+    next_send: Option<Time>,
+) -> bool {
+    loop {
+        let tick = time.tick();
+        let next_arrival = match input.next_event() {
+            _ if !listening => None,
+            EventTime::Ready(t) => Some(t),
+            // If there's nothing ready, hop forward one tick after. The sender can't send anything for before then,
+            // but could still send something for this cycle if it hasn't caught up to it yet.
+            EventTime::Nothing(t) if t < tick => {
+                time.advance(t + 1);
+                continue;
+            }
+            EventTime::Nothing(t) => Some(t + 1),
+            EventTime::Closed => None,
+        };
+
+        match next_arrival.into_iter().chain(next_send).min() {
+            Some(t) if t <= tick => return true,
+            Some(t) => time.advance(t),
+            None => return false,
+        }
+    }
+}
@@ -1 +1,9 @@
+// The derives name things by their paths from outside the crate, which have to work from inside it as well.
+extern crate self as dam_networks;
+
+pub mod contexts;
+pub mod random;
 pub mod switches;
+pub mod topologies;
+
+pub use switches::SimpleSwitch;
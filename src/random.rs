@@ -0,0 +1,52 @@
+/// A small, seeded pseudo-random number generator (SplitMix64), for parts of a simulation which need to make random
+/// choices but still come out the same on every run with the same seed.
+#[derive(Clone, Debug)]
+pub struct SplitMix64 {
+    state: u64,
+}
+
+impl SplitMix64 {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// A number in `0..bound`, each equally likely.
+    pub fn below(&mut self, bound: u64) -> u64 {
+        assert!(bound > 0, "The bound must be positive!");
+        // Throws away the few values at the top which would make the low numbers come up more often.
+        let limit = u64::MAX - u64::MAX % bound;
+        loop {
+            let value = self.next_u64();
+            if value < limit {
+                return value % bound;
+            }
+        }
+    }
+
+    /// A number in `0.0..1.0`, out of the top 53 bits of the next output, so that every one an `f64` can tell apart
+    /// is equally likely.
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SplitMix64;
+
+    #[test]
+    fn known_sequence_test() {
+        // The first outputs for seed 0, as published with the reference implementation.
+        let mut rng = SplitMix64::new(0);
+        assert_eq!(rng.next_u64(), 0xE220_A839_7B1D_CDAF);
+        assert_eq!(rng.next_u64(), 0x6E78_9E6A_A1B9_65F4);
+    }
+}
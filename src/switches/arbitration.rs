@@ -0,0 +1,125 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Determines the order in which a switch considers its ready inputs each cycle.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ArbitrationPolicy {
+    /// Inputs are always considered in ascending port ID order, so lower ports take precedence.
+    #[default]
+    Unordered,
+    /// Inputs are considered in port ID order, starting from the port after the last one granted.
+    RoundRobin,
+    /// Inputs are considered by the timestamp of their head packet, oldest first, so that long-waiting packets win.
+    OldestFirst,
+    /// Like [`ArbitrationPolicy::RoundRobin`], but each input may win up to its weight (see
+    /// `set_port_weight`) times per frame before inputs with grants left in the frame take precedence over it.
+    WeightedRoundRobin,
+}
+
+/// Matches inputs to outputs with iSLIP: each unmatched output grants one of its requesters, starting from a rotating
+/// pointer, and each input accepts one of its grants the same way. Every iteration tries to match whatever was left
+/// over by the previous one.
+#[derive(Clone, Debug)]
+pub struct IslipArbiter {
+    iterations: usize,
+    grant_pointers: fxhash::FxHashMap<usize, usize>,
+    accept_pointers: fxhash::FxHashMap<usize, usize>,
+}
+
+impl IslipArbiter {
+    pub fn new(iterations: usize) -> Self {
+        assert!(iterations > 0, "iSLIP needs at least one iteration!");
+        Self {
+            iterations,
+            grant_pointers: Default::default(),
+            accept_pointers: Default::default(),
+        }
+    }
+
+    /// Takes the set of outputs each input would like to send to, and returns the output each matched input won.
+    pub fn schedule(
+        &mut self,
+        requests: &BTreeMap<usize, fxhash::FxHashSet<usize>>,
+    ) -> BTreeMap<usize, usize> {
+        let mut matches = BTreeMap::new();
+        let mut matched_outputs = fxhash::FxHashSet::default();
+        for iteration in 0..self.iterations {
+            let unmatched_outputs: BTreeSet<usize> = requests
+                .values()
+                .flatten()
+                .filter(|output| !matched_outputs.contains(*output))
+                .copied()
+                .collect();
+
+            let mut grants = BTreeMap::<usize, Vec<usize>>::new();
+            for output in unmatched_outputs {
+                let requesters = requests
+                    .iter()
+                    .filter(|(input, targets)| {
+                        !matches.contains_key(*input) && targets.contains(&output)
+                    })
+                    .map(|(input, _)| *input);
+                let pointer = self.grant_pointers.get(&output).copied().unwrap_or(0);
+                if let Some(input) = next_from(requesters, pointer) {
+                    grants.entry(input).or_default().push(output);
+                }
+            }
+            if grants.is_empty() {
+                break;
+            }
+
+            for (input, offers) in grants {
+                let pointer = self.accept_pointers.get(&input).copied().unwrap_or(0);
+                let output = next_from(offers.into_iter(), pointer).unwrap();
+                matches.insert(input, output);
+                matched_outputs.insert(output);
+                // Pointers only move past matches made on the first iteration, which is what keeps iSLIP from
+                // starving anyone.
+                if iteration == 0 {
+                    self.grant_pointers.insert(output, input + 1);
+                    self.accept_pointers.insert(input, output + 1);
+                }
+            }
+        }
+        matches
+    }
+}
+
+/// The first candidate at or after the pointer, wrapping around to the lowest one.
+fn next_from(candidates: impl Iterator<Item = usize>, pointer: usize) -> Option<usize> {
+    let candidates: Vec<_> = candidates.collect();
+    let after_pointer = candidates
+        .iter()
+        .filter(|candidate| **candidate >= pointer)
+        .min();
+    after_pointer.or(candidates.iter().min()).copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use fxhash::FxHashSet;
+
+    use super::IslipArbiter;
+
+    #[test]
+    fn islip_iterations_test() {
+        // Both inputs want both outputs, so on the first iteration both outputs grant input 0.
+        let requests = BTreeMap::from_iter([
+            (0, FxHashSet::from_iter([0, 1])),
+            (1, FxHashSet::from_iter([0, 1])),
+        ]);
+
+        // A second iteration lets output 1 match input 1 instead.
+        assert_eq!(IslipArbiter::new(1).schedule(&requests).len(), 1);
+        assert_eq!(IslipArbiter::new(2).schedule(&requests).len(), 2);
+
+        // Even with one iteration, the pointers desynchronize after the first round.
+        let mut arbiter = IslipArbiter::new(1);
+        assert_eq!(arbiter.schedule(&requests), BTreeMap::from_iter([(0, 0)]));
+        assert_eq!(
+            arbiter.schedule(&requests),
+            BTreeMap::from_iter([(0, 1), (1, 0)])
+        );
+    }
+}
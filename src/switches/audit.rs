@@ -0,0 +1,458 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Display,
+    hash::Hash,
+};
+
+use fxhash::{FxHashMap, FxHashSet};
+
+/// How much a [`Diagnostic`] matters. Errors lose or misroute packets, or keep the network from shutting down, while
+/// warnings point at parts of the network nothing uses.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+/// Something a [`NetworkAuditor`] found wrong with a network.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Diagnostic<LT> {
+    /// A packet for `destination` reaches `switch`, which has no route for it.
+    Unroutable { switch: usize, destination: LT },
+    /// The route for `destination` at `switch` names `port`, which isn't one of that switch's outputs.
+    UnknownPort {
+        switch: usize,
+        port: usize,
+        destination: LT,
+    },
+    /// The route for `destination` at `switch` leaves through `port`, which nothing is on the other end of.
+    DeadEnd {
+        switch: usize,
+        port: usize,
+        destination: LT,
+    },
+    /// The route for `destination` at `switch` leaves through `port`, straight to the endpoint for `delivered_to`.
+    Misdelivered {
+        switch: usize,
+        port: usize,
+        destination: LT,
+        delivered_to: LT,
+    },
+    /// Packets for `destination` go around `switches` forever, starting from the lowest switch ID.
+    RoutingLoop {
+        switches: Vec<usize>,
+        destination: LT,
+    },
+    /// No route at `switch` ever leaves through `port`.
+    UnusedOutput { switch: usize, port: usize },
+    /// Nothing ever sends to `port` on `switch`.
+    UnfedInput { switch: usize, port: usize },
+}
+
+impl<LT> Diagnostic<LT> {
+    pub fn severity(&self) -> Severity {
+        match self {
+            Self::UnusedOutput { .. } | Self::UnfedInput { .. } => Severity::Warning,
+            _ => Severity::Error,
+        }
+    }
+}
+
+impl<LT: Display> Display for Diagnostic<LT> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unroutable {
+                switch,
+                destination,
+            } => write!(f, "Switch {} has no route to {}", switch, destination),
+            Self::UnknownPort {
+                switch,
+                port,
+                destination,
+            } => write!(
+                f,
+                "Switch {} routes {} to port {}, which it has no output on",
+                switch, destination, port
+            ),
+            Self::DeadEnd {
+                switch,
+                port,
+                destination,
+            } => write!(
+                f,
+                "Switch {} routes {} to port {}, which leads nowhere",
+                switch, destination, port
+            ),
+            Self::Misdelivered {
+                switch,
+                port,
+                destination,
+                delivered_to,
+            } => write!(
+                f,
+                "Switch {} routes {} to port {}, which leads to {}",
+                switch, destination, port, delivered_to
+            ),
+            Self::RoutingLoop {
+                switches,
+                destination,
+            } => {
+                write!(f, "Packets for {} loop through switches", destination)?;
+                for switch in switches {
+                    write!(f, " {}", switch)?;
+                }
+                Ok(())
+            }
+            Self::UnusedOutput { switch, port } => {
+                write!(
+                    f,
+                    "Switch {} never routes anything to port {}",
+                    switch, port
+                )
+            }
+            Self::UnfedInput { switch, port } => {
+                write!(f, "Nothing sends to port {} of switch {}", port, switch)
+            }
+        }
+    }
+}
+
+/// Everything a [`NetworkAuditor`] found, errors first, in the same order every time.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AuditReport<LT> {
+    pub diagnostics: Vec<Diagnostic<LT>>,
+}
+
+impl<LT> AuditReport<LT> {
+    pub fn errors(&self) -> impl Iterator<Item = &Diagnostic<LT>> {
+        self.diagnostics
+            .iter()
+            .filter(|diagnostic| diagnostic.severity() == Severity::Error)
+    }
+
+    pub fn warnings(&self) -> impl Iterator<Item = &Diagnostic<LT>> {
+        self.diagnostics
+            .iter()
+            .filter(|diagnostic| diagnostic.severity() == Severity::Warning)
+    }
+
+    pub fn has_errors(&self) -> bool {
+        self.errors().next().is_some()
+    }
+
+    /// Whether nothing at all was found, warnings included.
+    pub fn is_clean(&self) -> bool {
+        self.diagnostics.is_empty()
+    }
+}
+
+impl<LT: Display> Display for AuditReport<LT> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for diagnostic in &self.diagnostics {
+            let severity = match diagnostic.severity() {
+                Severity::Warning => "warning",
+                Severity::Error => "error",
+            };
+            writeln!(f, "{}: {}", severity, diagnostic)?;
+        }
+        Ok(())
+    }
+}
+
+/// Where an output of a switch leads.
+#[derive(Clone, Debug)]
+enum Hop<LT> {
+    Switch(usize),
+    Endpoint(LT),
+}
+
+/// Checks a network for mistakes which would otherwise only show up partway through a run, as packets that never
+/// arrive or switches that never shut down. It is told about the network the same way the switches are, as the routing
+/// table each switch was built with and the ports each one was given, along with what is on the other end of them, and
+/// [`NetworkAuditor::audit`] is meant to run before the program is initialized.
+///
+/// Every switch must be able to route a packet to every endpoint, over ports which the switch has outputs on and which
+/// lead to the next switch or to the endpoint itself, without going around in circles. Outputs which no route leaves
+/// through and inputs which nothing sends to are reported as warnings.
+#[derive(Clone, Debug)]
+pub struct NetworkAuditor<LT> {
+    tables: BTreeMap<usize, FxHashMap<LT, FxHashSet<usize>>>,
+    inputs: BTreeMap<usize, BTreeSet<usize>>,
+    outputs: BTreeMap<usize, BTreeSet<usize>>,
+    // Which inputs have something sending to them, and where each output leads.
+    fed: BTreeSet<(usize, usize)>,
+    next_hops: BTreeMap<(usize, usize), Hop<LT>>,
+    endpoints: Vec<LT>,
+}
+
+impl<LT> Default for NetworkAuditor<LT> {
+    fn default() -> Self {
+        Self {
+            tables: Default::default(),
+            inputs: Default::default(),
+            outputs: Default::default(),
+            fed: Default::default(),
+            next_hops: Default::default(),
+            endpoints: vec![],
+        }
+    }
+}
+
+impl<LT: Clone + Ord + Hash> NetworkAuditor<LT> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Gives `switch` the routing table it was built with.
+    pub fn with_switch(mut self, switch: usize, table: FxHashMap<LT, FxHashSet<usize>>) -> Self {
+        assert!(
+            self.tables.insert(switch, table).is_none(),
+            "Every switch needs an ID of its own!"
+        );
+        self
+    }
+
+    /// Gives `switch` an input on `port`, with nothing sending to it yet.
+    pub fn with_input(mut self, switch: usize, port: usize) -> Self {
+        assert!(
+            self.inputs.entry(switch).or_default().insert(port),
+            "Input port was already occupied!"
+        );
+        self
+    }
+
+    /// Gives `switch` an output on `port`, leading nowhere yet.
+    pub fn with_output(mut self, switch: usize, port: usize) -> Self {
+        assert!(
+            self.outputs.entry(switch).or_default().insert(port),
+            "Output port was already occupied!"
+        );
+        self
+    }
+
+    /// Links output `from_port` of `from` to input `to_port` of `to`, giving both switches the ports, as
+    /// [`crate::topologies::Link`]s between switches do.
+    pub fn with_link(self, from: usize, from_port: usize, to: usize, to_port: usize) -> Self {
+        let mut auditor = self.with_output(from, from_port).with_input(to, to_port);
+        auditor.fed.insert((to, to_port));
+        auditor.next_hops.insert((from, from_port), Hop::Switch(to));
+        auditor
+    }
+
+    /// Puts the endpoint for `location` on `port` of `switch`, which it both sends to and receives from.
+    pub fn with_endpoint(self, location: LT, switch: usize, port: usize) -> Self {
+        assert!(
+            !self.endpoints.contains(&location),
+            "Every endpoint needs a location of its own!"
+        );
+        let mut auditor = self.with_input(switch, port).with_output(switch, port);
+        auditor.fed.insert((switch, port));
+        auditor
+            .next_hops
+            .insert((switch, port), Hop::Endpoint(location.clone()));
+        auditor.endpoints.push(location);
+        auditor
+    }
+
+    /// Every switch mentioned so far, whether or not it was given a table.
+    fn switches(&self) -> BTreeSet<usize> {
+        self.tables
+            .keys()
+            .chain(self.inputs.keys())
+            .chain(self.outputs.keys())
+            .copied()
+            .collect()
+    }
+
+    pub fn audit(&self) -> AuditReport<LT> {
+        let mut found = BTreeSet::new();
+        let mut used = BTreeSet::new();
+        for destination in &self.endpoints {
+            for switch in self.switches() {
+                self.follow(destination, &mut vec![switch], &mut found, &mut used);
+            }
+        }
+        for (switch, outputs) in &self.outputs {
+            for port in outputs {
+                if !used.contains(&(*switch, *port)) {
+                    found.insert(Diagnostic::UnusedOutput {
+                        switch: *switch,
+                        port: *port,
+                    });
+                }
+            }
+        }
+        for (switch, inputs) in &self.inputs {
+            for port in inputs {
+                if !self.fed.contains(&(*switch, *port)) {
+                    found.insert(Diagnostic::UnfedInput {
+                        switch: *switch,
+                        port: *port,
+                    });
+                }
+            }
+        }
+        AuditReport {
+            diagnostics: found.into_iter().collect(),
+        }
+    }
+
+    /// Follows every route for `destination` onwards from the last switch of `path`, noting each output taken in
+    /// `used`.
+    fn follow(
+        &self,
+        destination: &LT,
+        path: &mut Vec<usize>,
+        found: &mut BTreeSet<Diagnostic<LT>>,
+        used: &mut BTreeSet<(usize, usize)>,
+    ) {
+        let switch = *path.last().unwrap();
+        let Some(ports) = self
+            .tables
+            .get(&switch)
+            .and_then(|table| table.get(destination))
+            .filter(|ports| !ports.is_empty())
+        else {
+            found.insert(Diagnostic::Unroutable {
+                switch,
+                destination: destination.clone(),
+            });
+            return;
+        };
+        let ports: BTreeSet<_> = ports.iter().copied().collect();
+        for port in ports {
+            let has_output = self
+                .outputs
+                .get(&switch)
+                .is_some_and(|outputs| outputs.contains(&port));
+            if !has_output {
+                found.insert(Diagnostic::UnknownPort {
+                    switch,
+                    port,
+                    destination: destination.clone(),
+                });
+                continue;
+            }
+            used.insert((switch, port));
+            match self.next_hops.get(&(switch, port)) {
+                None => {
+                    found.insert(Diagnostic::DeadEnd {
+                        switch,
+                        port,
+                        destination: destination.clone(),
+                    });
+                }
+                Some(Hop::Endpoint(location)) => {
+                    if location != destination {
+                        found.insert(Diagnostic::Misdelivered {
+                            switch,
+                            port,
+                            destination: destination.clone(),
+                            delivered_to: location.clone(),
+                        });
+                    }
+                }
+                Some(Hop::Switch(next)) => {
+                    if let Some(start) = path.iter().position(|visited| visited == next) {
+                        let mut switches = path[start..].to_vec();
+                        let lowest = (0..switches.len())
+                            .min_by_key(|index| switches[*index])
+                            .unwrap();
+                        switches.rotate_left(lowest);
+                        found.insert(Diagnostic::RoutingLoop {
+                            switches,
+                            destination: destination.clone(),
+                        });
+                        continue;
+                    }
+                    path.push(*next);
+                    self.follow(destination, path, found, used);
+                    path.pop();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fxhash::{FxHashMap, FxHashSet};
+
+    use super::{Diagnostic, NetworkAuditor};
+
+    fn table<const N: usize>(routes: [(char, usize); N]) -> FxHashMap<char, FxHashSet<usize>> {
+        routes
+            .into_iter()
+            .map(|(destination, port)| (destination, FxHashSet::from_iter([port])))
+            .collect()
+    }
+
+    /// A line of switches 0-1-2, each with an endpoint named after it on port 0, and its neighbours on ports 1 and 2.
+    fn line() -> NetworkAuditor<char> {
+        NetworkAuditor::new()
+            .with_endpoint('a', 0, 0)
+            .with_endpoint('b', 1, 0)
+            .with_endpoint('c', 2, 0)
+            .with_link(0, 1, 1, 1)
+            .with_link(1, 1, 0, 1)
+            .with_link(1, 2, 2, 1)
+            .with_link(2, 1, 1, 2)
+    }
+
+    #[test]
+    fn clean_network_test() {
+        let report = line()
+            .with_switch(0, table([('a', 0), ('b', 1), ('c', 1)]))
+            .with_switch(1, table([('a', 1), ('b', 0), ('c', 2)]))
+            .with_switch(2, table([('a', 1), ('b', 1), ('c', 0)]))
+            .audit();
+        assert!(report.is_clean(), "{}", report);
+    }
+
+    #[test]
+    fn broken_network_test() {
+        let report = line()
+            // Port 2 is on switch 1, not switch 0.
+            .with_switch(0, table([('a', 0), ('b', 1), ('c', 2)]))
+            // Sends packets for a back to switch 2, and packets for c to b.
+            .with_switch(1, table([('a', 2), ('b', 0), ('c', 0)]))
+            // Forgets about b.
+            .with_switch(2, table([('a', 1), ('c', 0)]))
+            // A second input that nothing was ever hooked up to.
+            .with_input(2, 2)
+            .audit();
+        assert_eq!(
+            report.diagnostics,
+            vec![
+                Diagnostic::Unroutable {
+                    switch: 2,
+                    destination: 'b'
+                },
+                Diagnostic::UnknownPort {
+                    switch: 0,
+                    port: 2,
+                    destination: 'c'
+                },
+                Diagnostic::Misdelivered {
+                    switch: 1,
+                    port: 0,
+                    destination: 'c',
+                    delivered_to: 'b'
+                },
+                Diagnostic::RoutingLoop {
+                    switches: vec![1, 2],
+                    destination: 'a'
+                },
+                // Which nothing goes back to switch 0 through, now that packets for a loop instead.
+                Diagnostic::UnusedOutput { switch: 1, port: 1 },
+                Diagnostic::UnfedInput { switch: 2, port: 2 },
+            ]
+        );
+        assert!(report.has_errors());
+        assert_eq!(report.warnings().count(), 2);
+        assert_eq!(
+            report.to_string().lines().next(),
+            Some("error: Switch 2 has no route to b")
+        );
+    }
+}
@@ -0,0 +1,231 @@
+use std::hash::Hash;
+
+use dam::{context_tools::*, structures::SyncSendMarker};
+
+use super::{
+    policy::Policy,
+    routing::{Packet, Port, PortMap, Switch},
+};
+
+/// Which ports a [`Bus`] sends each packet to.
+#[derive(Clone, Copy, Debug)]
+pub enum Targets<P> {
+    /// Whichever ports the policy picks for the packet's destination.
+    Policy(P),
+    /// Every port other than the one the packet came from, like a true shared medium.
+    AllButSource,
+}
+
+// Buses which broadcast never hold a policy, so they're built around one which can't exist.
+impl<LocationType> Policy<LocationType> for std::convert::Infallible {
+    fn route(&mut self, _target: &LocationType) -> fxhash::FxHashSet<usize> {
+        match *self {}
+    }
+}
+
+/// A shared medium which all of its ports take turns on. Every cycle, at most one ready input is granted the bus,
+/// round robin, and its packet is sent to every port the policy picks at once.
+///
+/// Grants normally take a single cycle. Buses with a width hold onto the bus for `ceil(dam_size / width)` cycles
+/// instead, and their packets take an extra `ceil(dam_size / width)` cycles to arrive, the same as a
+/// [`super::simple::SimpleSwitch`] output with that width. A target which can't take its copy holds up the whole bus.
+#[context_macro]
+pub struct Bus<T, LT, PolicyType>
+where
+    T: DAMType,
+{
+    ports: PortMap<T>,
+
+    targets: Targets<PolicyType>,
+    latency: u64,
+    // Bits per cycle, for buses which model serialization.
+    width: Option<usize>,
+    // The first cycle on which the bus is free again.
+    busy_until: Time,
+    last_granted: Option<usize>,
+
+    _marker: SyncSendMarker<LT>,
+}
+
+impl<T: DAMType, LT, PolicyType> Context for Bus<T, LT, PolicyType>
+where
+    T: Packet<LT>,
+    LT: Eq + Hash,
+    PolicyType: Policy<LT> + Sync + Send,
+{
+    fn run(&mut self) {
+        while self.ports.wait_for_input(&mut self.context_info.time) {
+            let ready = self.ports.ready(self.time.tick());
+            let busy_until = self.busy_until;
+            if busy_until > self.time.tick() {
+                self.time.advance(busy_until);
+                continue;
+            }
+
+            // Round robin, starting from the first port after the last one to have the bus.
+            let start = match self.last_granted {
+                Some(last) => ready.partition_point(|id| *id <= last) % ready.len(),
+                None => 0,
+            };
+            let input = ready[start];
+            self.last_granted = Some(input);
+
+            let ChannelElement { time: _, data } = self
+                .ports
+                .inputs
+                .get(&input)
+                .unwrap()
+                .dequeue(&self.time)
+                .unwrap();
+            let targets: Vec<_> = match &mut self.targets {
+                Targets::Policy(policy) => policy.route(&data.destination()).into_iter().collect(),
+                Targets::AllButSource => self
+                    .ports
+                    .outputs
+                    .keys()
+                    .filter(|id| **id != input)
+                    .copied()
+                    .collect(),
+            };
+
+            let serialization = match self.width {
+                Some(width) => data.dam_size().div_ceil(width) as u64,
+                None => 0,
+            };
+            for target in targets {
+                // Receivers which have gone away just miss out on their copy.
+                let _ = self.ports.outputs.get(&target).unwrap().enqueue(
+                    &self.time,
+                    ChannelElement {
+                        time: self.time.tick() + self.latency + serialization,
+                        data: data.clone(),
+                    },
+                );
+            }
+            self.busy_until = self.time.tick() + serialization.max(1);
+            self.time.incr_cycles(1);
+        }
+    }
+}
+
+impl<T: DAMType, LT, PolicyType> Switch<T> for Bus<T, LT, PolicyType>
+where
+    Self: Context,
+{
+    fn add_port(&mut self, port: Port<T>) {
+        port.attach(self);
+        self.ports.insert(port);
+    }
+}
+
+impl<T: DAMType, LT, PolicyType> Bus<T, LT, PolicyType>
+where
+    Self: Context,
+{
+    /// Creates a bus which sends each packet to the ports its policy picks, `latency` cycles after it was granted.
+    pub fn new(policy: PolicyType, latency: u64) -> Self {
+        Self::with_targets(Targets::Policy(policy), latency)
+    }
+
+    /// Creates a bus which sends each packet to `targets`, `latency` cycles after it was granted.
+    pub fn with_targets(targets: Targets<PolicyType>, latency: u64) -> Self {
+        Self {
+            ports: Default::default(),
+            targets,
+            latency,
+            width: None,
+            busy_until: Default::default(),
+            last_granted: None,
+            _marker: Default::default(),
+            context_info: Default::default(),
+        }
+    }
+
+    /// Models the bus as moving `bits_per_cycle` bits each cycle, so that every grant holds onto it for
+    /// `ceil(dam_size / bits_per_cycle)` cycles, and its packet takes that many cycles longer to arrive.
+    pub fn with_width(mut self, bits_per_cycle: usize) -> Self {
+        assert!(bits_per_cycle > 0, "Bus widths must be positive!");
+        self.width = Some(bits_per_cycle);
+        self
+    }
+}
+
+impl<T: DAMType, LT> Bus<T, LT, std::convert::Infallible>
+where
+    Self: Context,
+{
+    /// Creates a bus which sends each packet to every port but the one it came from, `latency` cycles after it was
+    /// granted.
+    pub fn broadcast(latency: u64) -> Self {
+        Self::with_targets(Targets::AllButSource, latency)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use crate::switches::{
+        routing::SimplePacket,
+        testing::{connect, run_switch, stream},
+    };
+
+    use super::Bus;
+
+    const NUM_PACKETS: u16 = 32;
+    const NUM_TALKERS: u16 = 3;
+
+    /// Has every talker flood a broadcast bus, and returns (sender, receiver, arrival time) for every copy.
+    fn talker_arrivals(bus: Bus<SimplePacket<u8, u16>, u8, Infallible>) -> Vec<(u16, u16, u64)> {
+        let talkers = (0..NUM_TALKERS).map(|talker| {
+            let packets = (0..NUM_PACKETS).map(move |i| SimplePacket {
+                location: 0u8,
+                payload: talker * 1000 + i,
+            });
+            (talker as usize, stream(packets))
+        });
+        let arrivals = run_switch(talkers, 0..NUM_TALKERS as usize, connect(bus));
+        arrivals
+            .into_iter()
+            .map(|(port, time, packet)| (packet.payload / 1000, port as u16, time))
+            .collect()
+    }
+
+    /// Returns the distinct times at which copies showed up.
+    fn grant_times(arrivals: &[(u16, u16, u64)]) -> Vec<u64> {
+        let mut times: Vec<_> = arrivals.iter().map(|(_, _, time)| *time).collect();
+        times.sort();
+        times.dedup();
+        times
+    }
+
+    #[test]
+    fn one_grant_per_cycle_test() {
+        let arrivals = talker_arrivals(Bus::broadcast(1));
+        let total = (NUM_TALKERS * NUM_PACKETS) as u64;
+
+        // Nobody hears themselves, and everyone hears everyone else.
+        assert!(arrivals
+            .iter()
+            .all(|(sender, receiver, _)| sender != receiver));
+        assert_eq!(arrivals.len() as u64, total * (NUM_TALKERS as u64 - 1));
+
+        // Every packet gets a cycle of its own, no matter how many talkers are waiting.
+        let times = grant_times(&arrivals);
+        assert_eq!(times.len() as u64, total);
+        assert_eq!(times[times.len() - 1] - times[0], total - 1);
+    }
+
+    #[test]
+    fn bus_width_test() {
+        // Packets are 24 bits, so each one holds onto an 8 bit bus for three cycles.
+        let arrivals = talker_arrivals(Bus::broadcast(1).with_width(8));
+        let total = (NUM_TALKERS * NUM_PACKETS) as u64;
+
+        let times = grant_times(&arrivals);
+        assert_eq!(times.len() as u64, total);
+        assert!(times.windows(2).all(|pair| pair[1] - pair[0] == 3));
+        // The first grant goes out on cycle 1, and arrives after the latency plus the three cycles on the wire.
+        assert_eq!(times[0], 1 + 1 + 3);
+    }
+}
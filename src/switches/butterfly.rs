@@ -0,0 +1,246 @@
+use dam::{context_tools::*, simulation::ProgramBuilder, structures::SyncSendMarker};
+
+use super::routing::{Packet, Port, PortMap, Switch};
+
+/// The switching element of butterfly and omega networks, with two inputs and two outputs, both numbered 0 and 1.
+/// Each packet leaves through the output picked by a single bit of its destination, `latency` cycles after it was
+/// forwarded. When both inputs want the same output, one of them waits for the next cycle, and gets to go first the
+/// next time the two collide.
+#[context_macro]
+pub struct Butterfly2x2<T, LT>
+where
+    T: DAMType,
+{
+    ports: PortMap<T>,
+
+    bit: u32,
+    latency: u64,
+    // The input which goes first when both want the same output.
+    priority: usize,
+
+    _marker: SyncSendMarker<LT>,
+}
+
+impl<T: DAMType, LT> Context for Butterfly2x2<T, LT>
+where
+    T: Packet<LT>,
+    LT: Into<u64> + Sync + Send,
+{
+    fn run(&mut self) {
+        while self.ports.wait_for_input(&mut self.context_info.time) {
+            let mut ready = self.ports.ready(self.time.tick());
+            if ready.first() != Some(&self.priority) {
+                ready.reverse();
+            }
+
+            let mut used_outputs = [false; 2];
+            for input in ready {
+                let receiver = self.ports.inputs.get(&input).unwrap();
+                let dam::channel::PeekResult::Something(ChannelElement { time: _, data }) =
+                    receiver.peek()
+                else {
+                    continue;
+                };
+                let output = ((data.destination().into() >> self.bit) & 1) as usize;
+                if used_outputs[output] {
+                    self.priority = input;
+                    continue;
+                }
+                used_outputs[output] = true;
+                receiver.dequeue(&self.time).unwrap();
+                // Receivers which have gone away just miss out on their packet.
+                let _ = self.ports.outputs.get(&output).unwrap().enqueue(
+                    &self.time,
+                    ChannelElement {
+                        time: self.time.tick() + self.latency,
+                        data,
+                    },
+                );
+            }
+            self.time.incr_cycles(1);
+        }
+    }
+}
+
+impl<T: DAMType, LT> Switch<T> for Butterfly2x2<T, LT>
+where
+    Self: Context,
+{
+    fn add_port(&mut self, port: Port<T>) {
+        assert!(port.id < 2, "2x2 elements only have ports 0 and 1!");
+        port.attach(self);
+        self.ports.insert(port);
+    }
+}
+
+impl<T: DAMType, LT> Butterfly2x2<T, LT>
+where
+    Self: Context,
+{
+    /// Creates an element which sends packets to output 0 or 1 by bit `bit` of their destination.
+    pub fn new(bit: u32, latency: u64) -> Self {
+        Self {
+            ports: Default::default(),
+            bit,
+            latency,
+            priority: 0,
+            _marker: Default::default(),
+            context_info: Default::default(),
+        }
+    }
+}
+
+/// Builds an omega network connecting `n` endpoints through `log2(n)` stages of [`Butterfly2x2`] elements, each of
+/// which takes `latency` cycles. Returns the `n` senders packets go into the network through, and the `n` receivers
+/// they come out of, where the receiver at index `d` gets everything addressed to destination `d`.
+///
+/// The lines going into every stage are perfectly shuffled, so that the stages can fix one bit of the destination
+/// each, from the most significant one down. The elements shut down once every sender has closed.
+pub fn build_butterfly<'a, T, LT>(
+    ctx: &mut ProgramBuilder<'a>,
+    n: usize,
+    latency: u64,
+) -> (Vec<Sender<T>>, Vec<Receiver<T>>)
+where
+    T: DAMType + Packet<LT> + 'a,
+    LT: Into<u64> + Sync + Send + 'a,
+{
+    assert!(
+        n >= 2 && n.is_power_of_two(),
+        "Butterfly networks need a power of two endpoints!"
+    );
+    let stages = n.trailing_zeros();
+    let (inputs, mut lines): (Vec<_>, Vec<_>) = (0..n).map(|_| ctx.unbounded()).unzip();
+
+    for stage in 0..stages {
+        // Line p moves to position p rotated left by one bit.
+        let mut shuffled: Vec<_> = (0..n).map(|_| None).collect();
+        for (position, line) in lines.into_iter().enumerate() {
+            shuffled[((position << 1) | (position >> (stages - 1))) & (n - 1)] = Some(line);
+        }
+
+        let mut shuffled = shuffled.into_iter().map(Option::unwrap);
+        lines = vec![];
+        for _ in 0..n / 2 {
+            let mut element = Butterfly2x2::new(stages - 1 - stage, latency);
+            for id in 0..2 {
+                let (snd, rcv) = ctx.unbounded();
+                element.add_port(Port {
+                    id,
+                    input: shuffled.next(),
+                    output: Some(snd),
+                });
+                lines.push(rcv);
+            }
+            ctx.add_child(element);
+        }
+    }
+    (inputs, lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use dam::{
+        context_tools::ChannelElement, simulation::ProgramBuilder, structures::Time,
+        utility_contexts::*,
+    };
+
+    use crate::switches::{
+        routing::SimplePacket,
+        testing::{connect, run_switch, TestPacket},
+    };
+
+    use super::{build_butterfly, Butterfly2x2};
+
+    const NUM_ENDPOINTS: usize = 8;
+    const LATENCY: u64 = 2;
+
+    /// Sends one packet from every endpoint to `destination(source)` on cycle 1, and returns (source, exit, arrival
+    /// time) for each.
+    fn permutation_run(destination: impl Fn(usize) -> usize) -> Vec<(u16, usize, u64)> {
+        let mut ctx = ProgramBuilder::default();
+        let (inputs, outputs) = build_butterfly::<TestPacket, u8>(&mut ctx, NUM_ENDPOINTS, LATENCY);
+
+        for (source, snd) in inputs.into_iter().enumerate() {
+            let packet = SimplePacket {
+                location: destination(source) as u8,
+                payload: source as u16,
+            };
+            let mut context = FunctionContext::new();
+            snd.attach_sender(&context);
+            context.set_run(move |time| {
+                snd.enqueue(
+                    time,
+                    ChannelElement {
+                        time: Time::new(1),
+                        data: packet,
+                    },
+                )
+                .unwrap();
+            });
+            ctx.add_child(context);
+        }
+
+        let arrivals = Arc::new(Mutex::new(vec![]));
+        for (exit, rcv) in outputs.into_iter().enumerate() {
+            let mut sink = FunctionContext::new();
+            rcv.attach_receiver(&sink);
+            let sink_log = arrivals.clone();
+            sink.set_run(move |time| {
+                while let Ok(ChannelElement { time, data }) = rcv.dequeue(time) {
+                    sink_log
+                        .lock()
+                        .unwrap()
+                        .push((data.payload, exit, time.time()));
+                }
+            });
+            ctx.add_child(sink);
+        }
+
+        ctx.initialize(Default::default())
+            .unwrap()
+            .run(Default::default());
+
+        let mut arrivals = arrivals.lock().unwrap().clone();
+        arrivals.sort();
+        arrivals
+    }
+
+    #[test]
+    fn permutation_test() {
+        // A cyclic shift, which the network can carry without any two packets wanting the same line.
+        let destination = |source| (source + 3) % NUM_ENDPOINTS;
+        let arrivals = permutation_run(destination);
+
+        assert_eq!(arrivals.len(), NUM_ENDPOINTS);
+        for (source, exit, arrival) in arrivals {
+            assert_eq!(exit, destination(source as usize));
+            // One hop per stage, and there are three stages for eight endpoints.
+            assert_eq!(arrival, 1 + 3 * LATENCY);
+        }
+    }
+
+    #[test]
+    fn contention_test() {
+        // Both inputs want output 1, so one of them has to wait a cycle.
+        let packets = |payload| {
+            let packet = SimplePacket {
+                location: 0b10u8,
+                payload,
+            };
+            vec![(1, packet)]
+        };
+        let arrivals = run_switch(
+            [(0, packets(0)), (1, packets(1))],
+            [0, 1],
+            connect(Butterfly2x2::new(1, LATENCY)),
+        );
+        let times: Vec<_> = arrivals
+            .iter()
+            .map(|(port, time, packet)| (*port, *time, packet.payload))
+            .collect();
+        assert_eq!(times, vec![(1, 1 + LATENCY, 0), (1, 2 + LATENCY, 1)]);
+    }
+}
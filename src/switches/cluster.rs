@@ -0,0 +1,341 @@
+use std::{collections::BTreeMap, hash::Hash, marker::PhantomData};
+
+use dam::{context_tools::*, simulation::ProgramBuilder};
+
+use crate::contexts::{Merge, Split};
+
+use super::{
+    policy::Policy,
+    routing::{Packet, Port, Switch},
+    simple::SimpleSwitch,
+};
+
+// The port on every leaf which leads up to the spine.
+const UPLINK: usize = usize::MAX;
+
+/// Routes packets coming into a leaf: straight to their ports when those are all on this leaf, and up to the spine
+/// otherwise.
+struct LeafPolicy<P> {
+    inner: P,
+    leaf: usize,
+    leaf_radix: usize,
+}
+
+impl<LT, P: Policy<LT>> Policy<LT> for LeafPolicy<P> {
+    fn route(&mut self, target: &LT) -> fxhash::FxHashSet<usize> {
+        let targets = self.inner.route(target);
+        if targets
+            .iter()
+            .all(|port| port / self.leaf_radix == self.leaf)
+        {
+            targets
+        } else {
+            // The spine hands out every copy, including the ones for this leaf, so that none of them come out twice.
+            fxhash::FxHashSet::from_iter([UPLINK])
+        }
+    }
+}
+
+/// Routes packets on the spine, down to every leaf which has one of their ports.
+struct SpinePolicy<P> {
+    inner: P,
+    leaf_radix: usize,
+}
+
+impl<LT, P: Policy<LT>> Policy<LT> for SpinePolicy<P> {
+    fn route(&mut self, target: &LT) -> fxhash::FxHashSet<usize> {
+        self.inner
+            .route(target)
+            .into_iter()
+            .map(|port| port / self.leaf_radix)
+            .collect()
+    }
+}
+
+/// Picks out the ports on a single leaf, for packets coming down from the spine.
+struct LocalPolicy<P> {
+    inner: P,
+    leaf: usize,
+    leaf_radix: usize,
+}
+
+impl<LT, P: Policy<LT>> Policy<LT> for LocalPolicy<P> {
+    fn route(&mut self, target: &LT) -> fxhash::FxHashSet<usize> {
+        self.inner
+            .route(target)
+            .into_iter()
+            .filter(|port| port / self.leaf_radix == self.leaf)
+            .collect()
+    }
+}
+
+/// A two level switch built out of [`SimpleSwitch`]es: `spine_radix` leaves with `leaf_radix` external ports each,
+/// all tied together by a single spine. External port `p` sits on leaf `p / leaf_radix`.
+///
+/// Ports are registered through [`Switch::add_port`], the same as on a single switch, and every internal switch
+/// routes by the same policy. Packets whose ports are all on the leaf they came in on take a single hop. Everything
+/// else goes up to the spine, which sends one copy down to each leaf with a target, and that leaf hands it out to its
+/// own ports, for three hops in all. Each hop takes `latency` cycles.
+///
+/// Unlike the other switches, a cluster isn't a context of its own. Once its ports are in, [`ClusterSwitch::build`]
+/// adds it to the program in place of [`ProgramBuilder::add_child`].
+pub struct ClusterSwitch<T: Clone, LT, PolicyType> {
+    inputs: BTreeMap<usize, Receiver<T>>,
+    outputs: BTreeMap<usize, Sender<T>>,
+
+    policy: PolicyType,
+    leaf_radix: usize,
+    spine_radix: usize,
+    latency: u64,
+
+    _marker: PhantomData<LT>,
+}
+
+impl<T: DAMType, LT, PolicyType> Switch<T> for ClusterSwitch<T, LT, PolicyType> {
+    fn add_port(&mut self, port: Port<T>) {
+        let id = port.id;
+        assert!(
+            id < self.leaf_radix * self.spine_radix,
+            "Port {} is out of range!",
+            id
+        );
+        if let Some(rcv) = port.input {
+            assert!(
+                self.inputs.insert(id, rcv).is_none(),
+                "Input port was already occupied!"
+            );
+        }
+        if let Some(snd) = port.output {
+            assert!(
+                self.outputs.insert(id, snd).is_none(),
+                "Output port was already occupied!"
+            );
+        }
+    }
+}
+
+impl<T: DAMType, LT, PolicyType> ClusterSwitch<T, LT, PolicyType>
+where
+    T: Packet<LT>,
+    LT: Eq + Hash,
+    PolicyType: Policy<LT> + Clone + Sync + Send,
+{
+    /// Creates a cluster of `spine_radix` leaves with `leaf_radix` ports each, whose internal hops take `latency`
+    /// cycles.
+    pub fn new(policy: PolicyType, leaf_radix: usize, spine_radix: usize, latency: u64) -> Self {
+        assert!(leaf_radix > 0, "Leaves need at least one port!");
+        assert!(spine_radix > 0, "The spine needs at least one leaf!");
+        assert!(latency > 0, "Internal hops must take at least a cycle!");
+        Self {
+            inputs: Default::default(),
+            outputs: Default::default(),
+            policy,
+            leaf_radix,
+            spine_radix,
+            latency,
+            _marker: Default::default(),
+        }
+    }
+
+    /// Adds the internal switches, and the links between them, to the program. This is how a cluster gets added,
+    /// since it has no context of its own to hand to [`ProgramBuilder::add_child`].
+    ///
+    /// Packets only ever flow from the leaves up to the spine and back down to the outputs, never around in a
+    /// circle, so the whole cluster finishes once its external inputs have closed.
+    pub fn build<'a>(self, ctx: &mut ProgramBuilder<'a>)
+    where
+        T: 'a,
+        LT: 'a,
+        PolicyType: 'a,
+    {
+        let mut spine = SimpleSwitch::new(
+            SpinePolicy {
+                inner: self.policy.clone(),
+                leaf_radix: self.leaf_radix,
+            },
+            self.latency,
+        );
+        let mut inputs = self.inputs;
+        let mut outputs = self.outputs;
+
+        for leaf in 0..self.spine_radix {
+            let ports = leaf * self.leaf_radix..(leaf + 1) * self.leaf_radix;
+            let mut up = SimpleSwitch::new(
+                LeafPolicy {
+                    inner: self.policy.clone(),
+                    leaf,
+                    leaf_radix: self.leaf_radix,
+                },
+                self.latency,
+            );
+            let mut down = fxhash::FxHashMap::default();
+            for id in ports {
+                let input = inputs.remove(&id);
+                // Each output hears from both its own leaf and the spine, which are merged into it without any
+                // latency of their own.
+                let local = outputs.remove(&id).map(|output| {
+                    let (local_snd, local_rcv) = ctx.unbounded();
+                    let (remote_snd, remote_rcv) = ctx.unbounded();
+                    down.insert(id, remote_snd);
+                    ctx.add_child(Merge::new(vec![local_rcv, remote_rcv], output, 0));
+                    local_snd
+                });
+                if input.is_some() || local.is_some() {
+                    up.add_port(Port {
+                        id,
+                        input,
+                        output: local,
+                    });
+                }
+            }
+
+            let (up_snd, up_rcv) = ctx.unbounded();
+            let (down_snd, down_rcv) = ctx.unbounded();
+            up.add_port(Port {
+                id: UPLINK,
+                input: None,
+                output: Some(up_snd),
+            });
+            spine.add_port(Port {
+                id: leaf,
+                input: Some(up_rcv),
+                output: Some(down_snd),
+            });
+            ctx.add_child(up);
+            ctx.add_child(Split::new(
+                down_rcv,
+                down,
+                LocalPolicy {
+                    inner: self.policy.clone(),
+                    leaf,
+                    leaf_radix: self.leaf_radix,
+                },
+                self.latency,
+            ));
+        }
+        ctx.add_child(spine);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dam::simulation::ProgramBuilder;
+    use fxhash::FxHashSet;
+
+    use crate::switches::{
+        routing::{Port, SimplePacket, Switch},
+        testing::{direct_policy, run_switch, Arrival, TestPacket},
+        SimpleSwitch,
+    };
+
+    use super::ClusterSwitch;
+
+    const LEAF_RADIX: usize = 4;
+    const SPINE_RADIX: usize = 4;
+    const NUM_PORTS: usize = LEAF_RADIX * SPINE_RADIX;
+    const LATENCY: u64 = 2;
+    // Goes out to one port on each of leaves 0, 1 and 2.
+    const MULTICAST: u8 = 100;
+
+    fn policy() -> fxhash::FxHashMap<u8, FxHashSet<usize>> {
+        let mut policy = direct_policy(0..NUM_PORTS);
+        policy.insert(MULTICAST, FxHashSet::from_iter([1, 5, 9]));
+        policy
+    }
+
+    /// Has every port send `traffic(port)`, as (send time, location) pairs. Each packet's payload records its source
+    /// port and send time.
+    fn arrivals(
+        traffic: impl Fn(usize) -> Vec<(u64, u8)>,
+        add_switch: impl FnOnce(&mut ProgramBuilder, Vec<Port<TestPacket>>),
+    ) -> Vec<Arrival<TestPacket>> {
+        let sources = (0..NUM_PORTS).map(|port| {
+            let packets = traffic(port)
+                .into_iter()
+                .map(|(sent, location)| {
+                    let packet = SimplePacket {
+                        location,
+                        payload: port as u16 * 1000 + sent as u16,
+                    };
+                    (sent, packet)
+                })
+                .collect();
+            (port, packets)
+        });
+        run_switch(sources, 0..NUM_PORTS, add_switch)
+    }
+
+    fn flat(ctx: &mut ProgramBuilder, ports: Vec<Port<TestPacket>>) {
+        let mut switch = SimpleSwitch::new(policy(), LATENCY);
+        ports.into_iter().for_each(|port| switch.add_port(port));
+        ctx.add_child(switch);
+    }
+
+    fn cluster(ctx: &mut ProgramBuilder, ports: Vec<Port<TestPacket>>) {
+        let mut switch = ClusterSwitch::new(policy(), LEAF_RADIX, SPINE_RADIX, LATENCY);
+        ports.into_iter().for_each(|port| switch.add_port(port));
+        switch.build(ctx);
+    }
+
+    /// Returns (output port, payload) for every packet, ignoring when it showed up.
+    fn delivered(arrivals: Vec<Arrival<TestPacket>>) -> Vec<(usize, u16)> {
+        let mut delivered: Vec<_> = arrivals
+            .into_iter()
+            .map(|(port, _, packet)| (port, packet.payload))
+            .collect();
+        delivered.sort();
+        delivered
+    }
+
+    #[test]
+    fn matches_flat_switch_test() {
+        // Everyone sends to a spread of ports, some on their own leaf and some on others.
+        let traffic = |port: usize| {
+            (1..8)
+                .map(|i| (i as u64, ((port * 5 + i * 3) % NUM_PORTS) as u8))
+                .collect()
+        };
+        let flat_delivered = delivered(arrivals(traffic, flat));
+        assert_eq!(flat_delivered.len(), NUM_PORTS * 7);
+        assert_eq!(flat_delivered, delivered(arrivals(traffic, cluster)));
+    }
+
+    #[test]
+    fn internal_hop_latency_test() {
+        // Port 0 sends to its neighbor on the same leaf, then to the far end of the cluster.
+        let traffic = |port: usize| match port {
+            0 => vec![(1, 1), (2, NUM_PORTS as u8 - 1)],
+            _ => vec![],
+        };
+        let latencies = |arrivals: Vec<Arrival<TestPacket>>| {
+            arrivals
+                .into_iter()
+                .map(|(port, arrived, packet)| (port, arrived - (packet.payload % 1000) as u64))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            latencies(arrivals(traffic, flat)),
+            vec![(1, LATENCY), (NUM_PORTS - 1, LATENCY)]
+        );
+        assert_eq!(
+            latencies(arrivals(traffic, cluster)),
+            vec![(1, LATENCY), (NUM_PORTS - 1, 3 * LATENCY)]
+        );
+    }
+
+    #[test]
+    fn spanning_multicast_test() {
+        // Port 0 multicasts to its own leaf and two others, while port 4 talks to port 1 on port 0's leaf. The run
+        // only returns once every internal switch has seen its inputs close, so this also checks that the cluster
+        // shuts down.
+        let traffic = |port: usize| match port {
+            0 => (1..9).map(|sent| (sent, MULTICAST)).collect(),
+            4 => (1..9).map(|sent| (sent, 1)).collect(),
+            _ => vec![],
+        };
+        let flat_delivered = delivered(arrivals(traffic, flat));
+        // Every target gets exactly one copy of each multicast packet.
+        assert_eq!(flat_delivered.len(), 3 * 8 + 8);
+        assert_eq!(flat_delivered, delivered(arrivals(traffic, cluster)));
+    }
+}
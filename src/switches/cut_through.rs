@@ -0,0 +1,214 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    hash::Hash,
+};
+
+use dam::{
+    channel::utils::{EventTime, Peekable},
+    context_tools::*,
+    structures::SyncSendMarker,
+};
+
+use super::{
+    policy::Policy,
+    routing::{Packet, Port, PortMap, Switch},
+};
+
+// A packet making its way through the header pipeline, along with when it comes out and the targets which still need
+// a copy.
+struct InFlight<T> {
+    ready_at: Time,
+    targets: fxhash::FxHashSet<usize>,
+    data: T,
+}
+
+/// A crossbar switch which routes each packet as soon as it arrives, then spends `header_latency` cycles processing
+/// its header before it can be forwarded. The header pipeline is separate from the link, and takes in a new packet
+/// on each input every cycle, so back-to-back packets overlap their header processing.
+///
+/// An uncontended packet arrives `header_latency + link_latency` cycles after it reached the switch. Packets which
+/// come out of the pipeline to find their output taken wait at its end, and hold up the packets behind them.
+#[context_macro]
+pub struct CutThroughSwitch<T, LT, PolicyType>
+where
+    T: DAMType,
+{
+    ports: PortMap<T>,
+    pipelines: BTreeMap<usize, VecDeque<InFlight<T>>>,
+
+    policy: PolicyType,
+    header_latency: u64,
+    link_latency: u64,
+
+    _marker: SyncSendMarker<LT>,
+}
+
+impl<T: DAMType, LT, PolicyType> Context for CutThroughSwitch<T, LT, PolicyType>
+where
+    T: Packet<LT>,
+    LT: Eq + Hash,
+    PolicyType: Policy<LT> + Sync + Send,
+{
+    fn run(&mut self) {
+        while self.wait_for_work() {
+            // Forwarding first frees up the last stage of each pipeline for whoever is behind it.
+            self.forward();
+            self.accept();
+            self.time.incr_cycles(1);
+        }
+    }
+}
+
+impl<T: DAMType, LT, PolicyType> Switch<T> for CutThroughSwitch<T, LT, PolicyType>
+where
+    Self: Context,
+{
+    fn add_port(&mut self, port: Port<T>) {
+        port.attach(self);
+        self.ports.insert(port);
+    }
+}
+
+impl<T: DAMType, LT, PolicyType> CutThroughSwitch<T, LT, PolicyType>
+where
+    T: Packet<LT>,
+    LT: Eq + Hash,
+    PolicyType: Policy<LT> + Sync + Send,
+{
+    /// Routes one ready packet from each input, and starts it down that input's pipeline.
+    fn accept(&mut self) {
+        let tick = self.time.tick();
+        // Each pipeline stage holds one packet.
+        let depth = self.header_latency.max(1) as usize;
+        let arrivals: Vec<_> = self
+            .ports
+            .inputs
+            .iter()
+            .filter(|(id, chan)| {
+                self.pipelines.get(id).map_or(0, VecDeque::len) < depth
+                    && matches!(chan.next_event(), EventTime::Ready(t) if t <= tick)
+            })
+            .map(|(id, _)| *id)
+            .collect();
+
+        for id in arrivals {
+            let ChannelElement { time: _, data } = self
+                .ports
+                .inputs
+                .get(&id)
+                .unwrap()
+                .dequeue(&self.time)
+                .unwrap();
+            let targets = self.policy.route(&data.destination());
+            self.pipelines.entry(id).or_default().push_back(InFlight {
+                ready_at: tick + self.header_latency,
+                targets,
+                data,
+            });
+        }
+    }
+}
+
+impl<T: DAMType, LT, PolicyType> CutThroughSwitch<T, LT, PolicyType>
+where
+    Self: Context,
+{
+    /// Creates a switch which takes `header_latency` cycles to process each packet's header, after which the packet
+    /// takes another `link_latency` cycles to reach the next hop.
+    pub fn new(policy: PolicyType, header_latency: u64, link_latency: u64) -> Self {
+        Self {
+            ports: Default::default(),
+            pipelines: Default::default(),
+            policy,
+            header_latency,
+            link_latency,
+            _marker: Default::default(),
+            context_info: Default::default(),
+        }
+    }
+
+    /// Sends a copy of the packet at the end of each pipeline to every free target, in port order.
+    fn forward(&mut self) {
+        let tick = self.time.tick();
+        let mut used_outputs = fxhash::FxHashSet::default();
+        let mut pipelines = std::mem::take(&mut self.pipelines);
+        for pipeline in pipelines.values_mut() {
+            let Some(front) = pipeline.front_mut() else {
+                continue;
+            };
+            if front.ready_at > tick {
+                continue;
+            }
+            front.targets.retain(|target| {
+                if used_outputs.contains(target) {
+                    return true;
+                }
+                let result = self.ports.outputs.get(target).unwrap().try_enqueue(
+                    &self.time,
+                    ChannelElement {
+                        time: tick + self.link_latency,
+                        data: front.data.clone(),
+                    },
+                );
+                if let Err(dam::channel::EnqueueError::Full) = result {
+                    return true;
+                }
+                used_outputs.insert(*target);
+                false
+            });
+            if front.targets.is_empty() {
+                pipeline.pop_front();
+            }
+        }
+        self.pipelines = pipelines;
+    }
+
+    /// Waits until there is something to do, returning false once every input has closed and every pipeline has
+    /// drained.
+    fn wait_for_work(&mut self) -> bool {
+        self.pipelines.values().any(|pipeline| !pipeline.is_empty())
+            || self.ports.wait_for_input(&mut self.context_info.time)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::switches::{
+        routing::SimplePacket,
+        testing::{connect, direct_policy, run_switch, stream},
+    };
+
+    use super::CutThroughSwitch;
+
+    const NUM_PACKETS: u16 = 32;
+    const HEADER_LATENCY: u64 = 3;
+    const LINK_LATENCY: u64 = 1;
+
+    #[test]
+    fn pipelined_header_test() {
+        let packets = (0..NUM_PACKETS).map(|payload| SimplePacket {
+            location: 1u8,
+            payload,
+        });
+        let arrivals = run_switch(
+            [(0, stream(packets))],
+            [1],
+            connect(CutThroughSwitch::new(
+                direct_policy([1]),
+                HEADER_LATENCY,
+                LINK_LATENCY,
+            )),
+        );
+
+        assert_eq!(
+            Vec::from_iter(arrivals.iter().map(|(_, _, packet)| packet.payload)),
+            Vec::from_iter(0..NUM_PACKETS)
+        );
+        // Packets are sent on cycle 1, and one more every cycle after that.
+        let (_, first, _) = arrivals[0];
+        assert_eq!(first, 1 + HEADER_LATENCY + LINK_LATENCY);
+        // Once the pipeline has filled up, a packet comes out every cycle.
+        let (_, last, _) = arrivals[arrivals.len() - 1];
+        assert_eq!(last - first, NUM_PACKETS as u64 - 1);
+    }
+}
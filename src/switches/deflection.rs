@@ -0,0 +1,322 @@
+use std::{
+    cmp::Reverse,
+    collections::BTreeSet,
+    sync::{Arc, Mutex},
+};
+
+use dam::{context_tools::*, structures::SyncSendMarker};
+
+use super::{
+    policy::DeflectionPolicy,
+    routing::{HopCounted, Packet, Port, PortMap, Switch},
+};
+
+/// Counters collected by a [`DeflectionSwitch`] while it runs.
+#[derive(Clone, Debug, Default)]
+pub struct DeflectionStats {
+    /// How many packets went out of a port their policy didn't prefer.
+    pub deflected: u64,
+    /// How many packets went out of one of their preferred ports.
+    pub forwarded: u64,
+}
+
+/// A bufferless, hot-potato switch. Every packet which shows up on an input leaves on the very same cycle: it takes one
+/// of its preferred ports if it can, and is deflected out of any other acceptable port that's still free if not.
+/// Packets which have made the most hops go first, which keeps them from being deflected forever, and each packet's
+/// hop count goes up by one on its way through.
+///
+/// This only works out if there is an output for every packet, so the switch checks that it has at least as many
+/// outputs as inputs before it starts. Injection ports, set up with [`DeflectionSwitch::with_injection`], are the
+/// exception: new packets only get in on the outputs left over after the traffic already in the network, and
+/// otherwise wait at their input, so they don't count against the outputs.
+#[context_macro]
+pub struct DeflectionSwitch<T, LT, PolicyType>
+where
+    T: DAMType,
+{
+    ports: PortMap<T>,
+
+    policy: PolicyType,
+    latency: u64,
+    injection: BTreeSet<usize>,
+    stats: Arc<Mutex<DeflectionStats>>,
+
+    _marker: SyncSendMarker<LT>,
+}
+
+impl<T: DAMType, LT, PolicyType> Context for DeflectionSwitch<T, LT, PolicyType>
+where
+    T: Packet<LT> + HopCounted,
+    LT: Sync + Send,
+    PolicyType: DeflectionPolicy<LT> + Sync + Send,
+{
+    fn run(&mut self) {
+        let transit_inputs = self
+            .ports
+            .inputs
+            .keys()
+            .filter(|id| !self.injection.contains(id))
+            .count();
+        assert!(
+            self.ports.outputs.len() >= transit_inputs,
+            "Deflection switches need an output for every input which can't wait!"
+        );
+
+        while self.ports.wait_for_input(&mut self.context_info.time) {
+            let mut heads: Vec<_> = self
+                .ports
+                .ready(self.time.tick())
+                .into_iter()
+                .filter_map(|id| match self.ports.inputs[&id].peek() {
+                    dam::channel::PeekResult::Something(ChannelElement { time: _, data }) => {
+                        Some((id, data))
+                    }
+                    _ => None,
+                })
+                .collect();
+            // Traffic already in the network goes before new packets, and the packets which have come furthest go
+            // first. The sort is stable, so ties stay in port order.
+            heads.sort_by_key(|(id, data)| (self.injection.contains(id), Reverse(data.hops())));
+
+            let mut free: BTreeSet<_> = self.ports.outputs.keys().copied().collect();
+            let mut stats = self.stats.lock().unwrap();
+            for (input, data) in heads {
+                let target = data.destination();
+                let preferred = self.policy.preferred(&target);
+                let output = match free.iter().find(|port| preferred.contains(port)) {
+                    Some(port) => Some(*port),
+                    None => free
+                        .iter()
+                        .copied()
+                        .find(|port| self.policy.acceptable(&target, *port)),
+                };
+                let Some(output) = output else {
+                    assert!(
+                        self.injection.contains(&input),
+                        "The packet on port {} had nowhere to be deflected to!",
+                        input
+                    );
+                    continue;
+                };
+                free.remove(&output);
+                if preferred.contains(&output) {
+                    stats.forwarded += 1;
+                } else {
+                    stats.deflected += 1;
+                }
+
+                self.ports.inputs[&input].dequeue(&self.time).unwrap();
+                let hops = data.hops().saturating_add(1);
+                // Receivers which have gone away just miss out on their packet.
+                let _ = self.ports.outputs[&output].enqueue(
+                    &self.time,
+                    ChannelElement {
+                        time: self.time.tick() + self.latency,
+                        data: data.with_hops(hops),
+                    },
+                );
+            }
+            drop(stats);
+            self.time.incr_cycles(1);
+        }
+    }
+}
+
+impl<T: DAMType, LT, PolicyType> Switch<T> for DeflectionSwitch<T, LT, PolicyType>
+where
+    Self: Context,
+{
+    fn add_port(&mut self, port: Port<T>) {
+        port.attach(self);
+        self.ports.insert(port);
+    }
+}
+
+impl<T: DAMType, LT, PolicyType> DeflectionSwitch<T, LT, PolicyType>
+where
+    Self: Context,
+{
+    /// Creates a switch which sends each packet out `latency` cycles after it arrives.
+    pub fn new(policy: PolicyType, latency: u64) -> Self {
+        Self {
+            ports: Default::default(),
+            policy,
+            latency,
+            injection: Default::default(),
+            stats: Default::default(),
+            _marker: Default::default(),
+            context_info: Default::default(),
+        }
+    }
+
+    /// Marks the input of `port` as one which new packets enter the network through. Packets on it only go once
+    /// everything else has been given an output, and wait if there is nothing acceptable left.
+    pub fn with_injection(mut self, port: usize) -> Self {
+        self.injection.insert(port);
+        self
+    }
+
+    /// How many packets went out of a preferred port, and how many were deflected. The handle is shared with the
+    /// switch, so it can be read once the simulation is done.
+    pub fn stats(&self) -> Arc<Mutex<DeflectionStats>> {
+        self.stats.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::switches::{
+        mesh::MeshPorts,
+        policy::{DeflectAnywhere, DeflectionPolicy},
+        routing::{Coord2D, HoppedPacket, SimplePacket},
+        testing::{connect, direct_policy, run_mesh, run_switch, stream},
+    };
+
+    use super::DeflectionSwitch;
+
+    const LATENCY: u64 = 1;
+
+    #[test]
+    fn deflect_instead_of_wait_test() {
+        // Both inputs want output 1 on the same cycle, and the loser goes out of the first free port instead.
+        let packets = |payload| {
+            let packet = HoppedPacket {
+                packet: SimplePacket {
+                    location: 1u8,
+                    payload,
+                },
+                hops: 0,
+            };
+            vec![(1, packet)]
+        };
+        let switch = DeflectionSwitch::new(DeflectAnywhere(direct_policy(0..3)), LATENCY);
+        let stats = switch.stats();
+        let arrivals = run_switch([(0, packets(0)), (1, packets(1))], 0..3, connect(switch));
+
+        let arrivals: Vec<_> = arrivals
+            .into_iter()
+            .map(|(port, time, packet)| (port, time, packet.packet.payload, packet.hops))
+            .collect();
+        assert_eq!(
+            arrivals,
+            vec![(0, 1 + LATENCY, 1, 1), (1, 1 + LATENCY, 0, 1)]
+        );
+        let stats = stats.lock().unwrap();
+        assert_eq!((stats.forwarded, stats.deflected), (1, 1));
+    }
+
+    const MESH_SIZE: u16 = 3;
+    const NUM_NODES: usize = (MESH_SIZE * MESH_SIZE) as usize;
+    const HOTSPOT: u8 = 4;
+    const PACKETS_PER_NODE: u16 = 8;
+    // Well past anything a packet should need, so that packets which get this far are taken to be livelocked.
+    const LIVELOCK_HOPS: u8 = 64;
+
+    const PORTS: MeshPorts = MeshPorts {
+        local: 0,
+        minus_y: 1,
+        plus_x: 2,
+        plus_y: 3,
+        minus_x: 4,
+    };
+
+    fn coordinates(node: u8) -> Coord2D {
+        Coord2D {
+            x: node as u16 % MESH_SIZE,
+            y: node as u16 / MESH_SIZE,
+        }
+    }
+
+    /// Prefers every direction which gets a packet closer to its node, and never deflects packets out of the local
+    /// port, since they would leave the network at the wrong node.
+    struct MeshPolicy {
+        node: Coord2D,
+    }
+
+    impl DeflectionPolicy<u8> for MeshPolicy {
+        fn preferred(&mut self, target: &u8) -> fxhash::FxHashSet<usize> {
+            let target = coordinates(*target);
+            let directions = [
+                (target.x > self.node.x, PORTS.plus_x),
+                (target.x < self.node.x, PORTS.minus_x),
+                (target.y > self.node.y, PORTS.plus_y),
+                (target.y < self.node.y, PORTS.minus_y),
+            ];
+            let closer: fxhash::FxHashSet<_> = directions
+                .into_iter()
+                .filter(|(closer, _)| *closer)
+                .map(|(_, port)| port)
+                .collect();
+            if closer.is_empty() {
+                fxhash::FxHashSet::from_iter([PORTS.local])
+            } else {
+                closer
+            }
+        }
+
+        fn acceptable(&mut self, _target: &u8, port: usize) -> bool {
+            port != PORTS.local
+        }
+    }
+
+    #[test]
+    fn mesh_hotspot_test() {
+        const TOTAL: usize = (NUM_NODES - 1) * PACKETS_PER_NODE as usize;
+        let hotspot = coordinates(HOTSPOT);
+
+        // Everyone but the hotspot sends all of its packets to the hotspot, one per cycle.
+        let traffic = |node: Coord2D| {
+            let packets = (0..PACKETS_PER_NODE)
+                .filter(|_| node != hotspot)
+                .map(|i| HoppedPacket {
+                    packet: SimplePacket {
+                        location: HOTSPOT,
+                        payload: (node.y * MESH_SIZE + node.x) * 100 + i,
+                    },
+                    hops: 0,
+                });
+            stream(packets)
+        };
+        let mut stats = vec![];
+        let arrivals = run_mesh(
+            MESH_SIZE,
+            MESH_SIZE,
+            PORTS,
+            traffic,
+            |node| {
+                let switch =
+                    DeflectionSwitch::new(MeshPolicy { node }, LATENCY).with_injection(PORTS.local);
+                stats.push(switch.stats());
+                switch
+            },
+            TOTAL,
+        );
+
+        assert_eq!(arrivals.len(), TOTAL);
+        let mut fewest_hops = 0;
+        let mut hops = 0;
+        for (node, _, packet) in arrivals.iter() {
+            assert_eq!(*node, hotspot);
+            let source = coordinates((packet.packet.payload / 100) as u8);
+            // Every switch on a shortest path counts a hop, including the one at either end.
+            let shortest = source.manhattan_distance(&hotspot) + 1;
+            assert!(packet.hops as u32 >= shortest);
+            assert!(packet.hops < LIVELOCK_HOPS, "{:?} is livelocked", packet);
+            fewest_hops += shortest as u64;
+            hops += packet.hops as u64;
+        }
+
+        let deflected: u64 = stats
+            .iter()
+            .map(|stats| stats.lock().unwrap().deflected)
+            .sum();
+        // The hotspot can only eject one packet a cycle, so the rest have to go around.
+        assert!(deflected > 0);
+        assert!(
+            hops > fewest_hops,
+            "Average hop count: {:.2}, against {:.2} without deflections",
+            hops as f64 / TOTAL as f64,
+            fewest_hops as f64 / TOTAL as f64
+        );
+    }
+}
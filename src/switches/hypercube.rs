@@ -0,0 +1,137 @@
+use super::policy::{Policy, RouteDecision};
+
+/// E-cube routing for a hypercube of `2^d` nodes, as seen from the switch at node `position`, where `d` is the number
+/// of dimensions in `dimension_ports`. Packets correct the lowest dimension their node differs from the destination
+/// in first, leaving through the port registered for it, and go out of the local port once there's nothing left to
+/// correct. Since every packet fixes its dimensions in the same order, the routing is deterministic and deadlock
+/// free. Destinations outside of the hypercube aren't routable.
+#[derive(Clone, Debug)]
+pub struct EcubePolicy {
+    position: u64,
+    dimension_ports: Vec<usize>,
+    local: usize,
+}
+
+impl EcubePolicy {
+    pub fn new(position: u64, dimension_ports: Vec<usize>, local: usize) -> Self {
+        let dimensions = dimension_ports.len();
+        assert!(
+            dimensions >= 64 || position >> dimensions == 0,
+            "Node {} is outside of the {}-dimensional hypercube!",
+            position,
+            dimensions
+        );
+        Self {
+            position,
+            dimension_ports,
+            local,
+        }
+    }
+}
+
+impl<LT: Copy + Into<u64>> Policy<LT> for EcubePolicy {
+    fn route(&mut self, target: &LT) -> fxhash::FxHashSet<usize> {
+        self.try_route(target).into_ports_or_else(|| {
+            format!(
+                "{} is outside of the {}-dimensional hypercube!",
+                (*target).into(),
+                self.dimension_ports.len()
+            )
+        })
+    }
+
+    fn try_route(&mut self, target: &LT) -> RouteDecision {
+        let difference = self.position ^ (*target).into();
+        let port = match difference.trailing_zeros() as usize {
+            64 => self.local,
+            dimension => match self.dimension_ports.get(dimension) {
+                Some(port) => *port,
+                None => return RouteDecision::Error,
+            },
+        };
+        RouteDecision::Forward(fxhash::FxHashSet::from_iter([port]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::switches::{
+        policy::{Policy, RouteDecision},
+        routing::{HoppedPacket, SimplePacket},
+        testing::{run_network, stream},
+        SimpleSwitch,
+    };
+
+    use super::EcubePolicy;
+
+    const DIMENSIONS: usize = 3;
+    const NUM_NODES: usize = 1 << DIMENSIONS;
+    const LOCAL: usize = 0;
+    const ROUNDS: u16 = 2;
+
+    /// Dimension `i` leaves through port `i + 1`.
+    fn dimension_ports() -> Vec<usize> {
+        (1..=DIMENSIONS).collect()
+    }
+
+    #[test]
+    fn ecube_lowest_dimension_first_test() {
+        let mut policy = EcubePolicy::new(0b101, dimension_ports(), LOCAL);
+        // 0b101 ^ 0b110 = 0b011, so dimension 0 goes first.
+        assert_eq!(policy.route(&0b110u8), fxhash::FxHashSet::from_iter([1]));
+        assert_eq!(policy.route(&0b001u8), fxhash::FxHashSet::from_iter([3]));
+        assert_eq!(
+            policy.route(&0b101u8),
+            fxhash::FxHashSet::from_iter([LOCAL])
+        );
+        assert!(matches!(
+            Policy::<u8>::try_route(&mut policy, &0b1101),
+            RouteDecision::Error
+        ));
+    }
+
+    #[test]
+    fn ecube_all_to_all_test() {
+        let total = ROUNDS as usize * NUM_NODES * (NUM_NODES - 1);
+        let switches = (0..NUM_NODES as u64)
+            .map(|node| {
+                SimpleSwitch::new(EcubePolicy::new(node, dimension_ports(), LOCAL), 1)
+                    .with_on_forward(|packet: &mut HoppedPacket<SimplePacket<u8, u16>>, _| {
+                        packet.hops += 1
+                    })
+            })
+            .collect();
+        // Every node is linked to the one which differs from it in each dimension, by that dimension's port.
+        let links: Vec<_> = (0..NUM_NODES)
+            .flat_map(|node| {
+                (0..DIMENSIONS)
+                    .map(move |dimension| (node, node ^ (1 << dimension), dimension + 1))
+                    .filter(|(node, neighbor, _)| node < neighbor)
+                    .map(|(node, neighbor, port)| (node, port, neighbor, port))
+            })
+            .collect();
+        let endpoints: Vec<_> = (0..NUM_NODES).map(|node| (node, LOCAL)).collect();
+        let traffic = |source: usize| {
+            let packets = (0..ROUNDS * NUM_NODES as u16)
+                .map(|i| (i as usize % NUM_NODES) as u8)
+                .filter(|destination| *destination as usize != source)
+                .map(|location| HoppedPacket {
+                    packet: SimplePacket {
+                        location,
+                        payload: source as u16,
+                    },
+                    hops: 0,
+                });
+            stream(packets)
+        };
+        let arrivals = run_network(switches, &links, &endpoints, traffic, total);
+
+        assert_eq!(arrivals.len(), total);
+        for (node, _, packet) in arrivals {
+            assert_eq!(node, packet.packet.location as usize);
+            // One hop for each dimension the nodes differ in, and one more out of the local port.
+            let distance = (node ^ packet.packet.payload as usize).count_ones();
+            assert_eq!(packet.hops as u32, distance + 1);
+        }
+    }
+}
@@ -0,0 +1,417 @@
+use dam::context_tools::*;
+
+/// The directions out of a node in a two dimensional mesh or torus.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Dir2D {
+    PlusX,
+    MinusX,
+    PlusY,
+    MinusY,
+}
+
+impl Dir2D {
+    pub const ALL: [Dir2D; 4] = [Dir2D::PlusX, Dir2D::MinusX, Dir2D::PlusY, Dir2D::MinusY];
+
+    /// The direction back the way this one went, which is the port a link arrives on at the other end.
+    pub fn opposite(self) -> Self {
+        match self {
+            Dir2D::PlusX => Dir2D::MinusX,
+            Dir2D::MinusX => Dir2D::PlusX,
+            Dir2D::PlusY => Dir2D::MinusY,
+            Dir2D::MinusY => Dir2D::PlusY,
+        }
+    }
+}
+
+/// The directions out of a node in a three dimensional mesh or torus.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Dir3D {
+    PlusX,
+    MinusX,
+    PlusY,
+    MinusY,
+    PlusZ,
+    MinusZ,
+}
+
+impl Dir3D {
+    pub const ALL: [Dir3D; 6] = [
+        Dir3D::PlusX,
+        Dir3D::MinusX,
+        Dir3D::PlusY,
+        Dir3D::MinusY,
+        Dir3D::PlusZ,
+        Dir3D::MinusZ,
+    ];
+
+    pub fn opposite(self) -> Self {
+        match self {
+            Dir3D::PlusX => Dir3D::MinusX,
+            Dir3D::MinusX => Dir3D::PlusX,
+            Dir3D::PlusY => Dir3D::MinusY,
+            Dir3D::MinusY => Dir3D::PlusY,
+            Dir3D::PlusZ => Dir3D::MinusZ,
+            Dir3D::MinusZ => Dir3D::PlusZ,
+        }
+    }
+}
+
+/// Moves `position` one step along a dimension of `size` nodes, or returns `None` if that's off the edge.
+fn step(position: u16, forward: bool, size: u16) -> Option<u16> {
+    match forward {
+        true => position.checked_add(1).filter(|next| *next < size),
+        false => position.checked_sub(1),
+    }
+}
+
+/// Moves `position` one step along a dimension of `size` nodes, going around the far edge.
+fn wrapping_step(position: u16, forward: bool, size: u16) -> u16 {
+    match forward {
+        true => (position + 1) % size,
+        false => (position + size - 1) % size,
+    }
+}
+
+/// A node's position in a two dimensional mesh or torus.
+///
+/// The helpers which need to know the size of the mesh take it as `bounds`, the coordinate one past its far corner,
+/// so a `width` by `height` mesh is bounded by `Coord2D::new(width, height)`. Nodes are numbered row by row, and
+/// coordinates sort in the same order as their indices.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Coord2D {
+    pub x: u16,
+    pub y: u16,
+}
+
+impl Coord2D {
+    pub fn new(x: u16, y: u16) -> Self {
+        Self { x, y }
+    }
+
+    pub fn manhattan_distance(&self, other: &Self) -> u32 {
+        self.x.abs_diff(other.x) as u32 + self.y.abs_diff(other.y) as u32
+    }
+
+    pub fn within(&self, bounds: Self) -> bool {
+        self.x < bounds.x && self.y < bounds.y
+    }
+
+    /// The next node over in `direction`, if the mesh goes that far.
+    pub fn step(&self, direction: Dir2D, bounds: Self) -> Option<Self> {
+        let (x, y) = (self.x, self.y);
+        match direction {
+            Dir2D::PlusX => step(x, true, bounds.x).map(|x| Self { x, y }),
+            Dir2D::MinusX => step(x, false, bounds.x).map(|x| Self { x, y }),
+            Dir2D::PlusY => step(y, true, bounds.y).map(|y| Self { x, y }),
+            Dir2D::MinusY => step(y, false, bounds.y).map(|y| Self { x, y }),
+        }
+    }
+
+    /// The next node over in `direction` on a torus, where stepping off of one edge comes back in at the other.
+    pub fn wrapping_step(&self, direction: Dir2D, bounds: Self) -> Self {
+        let (x, y) = (self.x, self.y);
+        match direction {
+            Dir2D::PlusX => Self::new(wrapping_step(x, true, bounds.x), y),
+            Dir2D::MinusX => Self::new(wrapping_step(x, false, bounds.x), y),
+            Dir2D::PlusY => Self::new(x, wrapping_step(y, true, bounds.y)),
+            Dir2D::MinusY => Self::new(x, wrapping_step(y, false, bounds.y)),
+        }
+    }
+
+    /// Every node next to this one in the mesh, and which direction it's in. Nodes on an edge have fewer neighbors.
+    pub fn neighbors(&self, bounds: Self) -> Vec<(Dir2D, Self)> {
+        Dir2D::ALL
+            .into_iter()
+            .filter_map(|direction| Some((direction, self.step(direction, bounds)?)))
+            .collect()
+    }
+
+    pub fn to_index(&self, bounds: Self) -> usize {
+        assert!(
+            self.within(bounds),
+            "{} is outside of the {}x{} mesh!",
+            self,
+            bounds.x,
+            bounds.y
+        );
+        self.y as usize * bounds.x as usize + self.x as usize
+    }
+
+    pub fn from_index(index: usize, bounds: Self) -> Self {
+        let width = bounds.x as usize;
+        let position = Self::new((index % width) as u16, (index / width) as u16);
+        assert!(
+            position.within(bounds),
+            "Node {} is outside of the {}x{} mesh!",
+            index,
+            bounds.x,
+            bounds.y
+        );
+        position
+    }
+}
+
+impl Ord for Coord2D {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        (self.y, self.x).cmp(&(other.y, other.x))
+    }
+}
+
+impl PartialOrd for Coord2D {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl std::fmt::Display for Coord2D {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "({}, {})", self.x, self.y)
+    }
+}
+
+impl DAMType for Coord2D {
+    fn dam_size(&self) -> usize {
+        self.x.dam_size() + self.y.dam_size()
+    }
+}
+
+/// A node's position in a three dimensional mesh or torus, with the same conventions as [`Coord2D`]. Nodes are
+/// numbered row by row within each layer, and layer by layer.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Coord3D {
+    pub x: u16,
+    pub y: u16,
+    pub z: u16,
+}
+
+impl Coord3D {
+    pub fn new(x: u16, y: u16, z: u16) -> Self {
+        Self { x, y, z }
+    }
+
+    pub fn manhattan_distance(&self, other: &Self) -> u32 {
+        self.x.abs_diff(other.x) as u32
+            + self.y.abs_diff(other.y) as u32
+            + self.z.abs_diff(other.z) as u32
+    }
+
+    pub fn within(&self, bounds: Self) -> bool {
+        self.x < bounds.x && self.y < bounds.y && self.z < bounds.z
+    }
+
+    pub fn step(&self, direction: Dir3D, bounds: Self) -> Option<Self> {
+        let (x, y, z) = (self.x, self.y, self.z);
+        match direction {
+            Dir3D::PlusX => step(x, true, bounds.x).map(|x| Self { x, y, z }),
+            Dir3D::MinusX => step(x, false, bounds.x).map(|x| Self { x, y, z }),
+            Dir3D::PlusY => step(y, true, bounds.y).map(|y| Self { x, y, z }),
+            Dir3D::MinusY => step(y, false, bounds.y).map(|y| Self { x, y, z }),
+            Dir3D::PlusZ => step(z, true, bounds.z).map(|z| Self { x, y, z }),
+            Dir3D::MinusZ => step(z, false, bounds.z).map(|z| Self { x, y, z }),
+        }
+    }
+
+    pub fn wrapping_step(&self, direction: Dir3D, bounds: Self) -> Self {
+        let (x, y, z) = (self.x, self.y, self.z);
+        match direction {
+            Dir3D::PlusX => Self::new(wrapping_step(x, true, bounds.x), y, z),
+            Dir3D::MinusX => Self::new(wrapping_step(x, false, bounds.x), y, z),
+            Dir3D::PlusY => Self::new(x, wrapping_step(y, true, bounds.y), z),
+            Dir3D::MinusY => Self::new(x, wrapping_step(y, false, bounds.y), z),
+            Dir3D::PlusZ => Self::new(x, y, wrapping_step(z, true, bounds.z)),
+            Dir3D::MinusZ => Self::new(x, y, wrapping_step(z, false, bounds.z)),
+        }
+    }
+
+    pub fn neighbors(&self, bounds: Self) -> Vec<(Dir3D, Self)> {
+        Dir3D::ALL
+            .into_iter()
+            .filter_map(|direction| Some((direction, self.step(direction, bounds)?)))
+            .collect()
+    }
+
+    pub fn to_index(&self, bounds: Self) -> usize {
+        assert!(
+            self.within(bounds),
+            "{} is outside of the {}x{}x{} mesh!",
+            self,
+            bounds.x,
+            bounds.y,
+            bounds.z
+        );
+        let (width, height) = (bounds.x as usize, bounds.y as usize);
+        (self.z as usize * height + self.y as usize) * width + self.x as usize
+    }
+
+    pub fn from_index(index: usize, bounds: Self) -> Self {
+        let (width, height) = (bounds.x as usize, bounds.y as usize);
+        let position = Self::new(
+            (index % width) as u16,
+            (index / width % height) as u16,
+            (index / width / height) as u16,
+        );
+        assert!(
+            position.within(bounds),
+            "Node {} is outside of the {}x{}x{} mesh!",
+            index,
+            bounds.x,
+            bounds.y,
+            bounds.z
+        );
+        position
+    }
+}
+
+impl Ord for Coord3D {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        (self.z, self.y, self.x).cmp(&(other.z, other.y, other.x))
+    }
+}
+
+impl PartialOrd for Coord3D {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl std::fmt::Display for Coord3D {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "({}, {}, {})", self.x, self.y, self.z)
+    }
+}
+
+impl DAMType for Coord3D {
+    fn dam_size(&self) -> usize {
+        self.x.dam_size() + self.y.dam_size() + self.z.dam_size()
+    }
+}
+
+/// One of the endpoints attached to a node which has more than one of them, numbered from 0 within the node. Locations
+/// sort by node first, and then by endpoint.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Concentrated<LT> {
+    pub node: LT,
+    pub local: u16,
+}
+
+impl<LT> Concentrated<LT> {
+    pub fn new(node: LT, local: u16) -> Self {
+        Self { node, local }
+    }
+}
+
+impl<LT: std::fmt::Display> std::fmt::Display for Concentrated<LT> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}", self.node, self.local)
+    }
+}
+
+impl<LT: DAMType> DAMType for Concentrated<LT> {
+    fn dam_size(&self) -> usize {
+        self.node.dam_size() + self.local.dam_size()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Coord2D, Coord3D, Dir2D, Dir3D};
+
+    #[test]
+    fn manhattan_distance_test() {
+        let (a, b) = (Coord2D::new(1, 5), Coord2D::new(4, 2));
+        assert_eq!(a.manhattan_distance(&b), 6);
+        assert_eq!(b.manhattan_distance(&a), 6);
+        assert_eq!(a.manhattan_distance(&a), 0);
+        // Far enough apart that the distance doesn't fit in a coordinate.
+        let corner = Coord2D::new(u16::MAX, u16::MAX);
+        assert_eq!(
+            Coord2D::default().manhattan_distance(&corner),
+            2 * u16::MAX as u32
+        );
+
+        let (a, b) = (Coord3D::new(0, 3, 1), Coord3D::new(2, 1, 4));
+        assert_eq!(a.manhattan_distance(&b), 7);
+    }
+
+    #[test]
+    fn edge_neighbors_test() {
+        let bounds = Coord2D::new(3, 3);
+        let directions = |node: Coord2D| -> Vec<_> {
+            node.neighbors(bounds)
+                .into_iter()
+                .map(|(direction, _)| direction)
+                .collect()
+        };
+        assert_eq!(directions(Coord2D::new(0, 0)), [Dir2D::PlusX, Dir2D::PlusY]);
+        assert_eq!(
+            directions(Coord2D::new(2, 1)),
+            [Dir2D::MinusX, Dir2D::PlusY, Dir2D::MinusY]
+        );
+        assert_eq!(directions(Coord2D::new(1, 1)), Dir2D::ALL);
+        assert_eq!(
+            Coord2D::new(1, 1).neighbors(bounds),
+            [
+                (Dir2D::PlusX, Coord2D::new(2, 1)),
+                (Dir2D::MinusX, Coord2D::new(0, 1)),
+                (Dir2D::PlusY, Coord2D::new(1, 2)),
+                (Dir2D::MinusY, Coord2D::new(1, 0)),
+            ]
+        );
+
+        // A torus has no edges.
+        let corner = Coord2D::new(2, 0);
+        assert_eq!(
+            corner.wrapping_step(Dir2D::PlusX, bounds),
+            Coord2D::new(0, 0)
+        );
+        assert_eq!(
+            corner.wrapping_step(Dir2D::MinusY, bounds),
+            Coord2D::new(2, 2)
+        );
+
+        let bounds = Coord3D::new(2, 2, 2);
+        let corner = Coord3D::new(1, 0, 1);
+        assert_eq!(
+            corner.neighbors(bounds),
+            [
+                (Dir3D::MinusX, Coord3D::new(0, 0, 1)),
+                (Dir3D::PlusY, Coord3D::new(1, 1, 1)),
+                (Dir3D::MinusZ, Coord3D::new(1, 0, 0)),
+            ]
+        );
+        for (direction, neighbor) in corner.neighbors(bounds) {
+            assert_eq!(neighbor.step(direction.opposite(), bounds), Some(corner));
+        }
+    }
+
+    #[test]
+    fn index_round_trip_test() {
+        let bounds = Coord2D::new(5, 3);
+        let nodes: Vec<_> = (0..15).map(|i| Coord2D::from_index(i, bounds)).collect();
+        assert_eq!(nodes[6], Coord2D::new(1, 1));
+        for (index, node) in nodes.iter().enumerate() {
+            assert_eq!(node.to_index(bounds), index);
+        }
+        // Sorting goes by index.
+        assert!(nodes.windows(2).all(|pair| pair[0] < pair[1]));
+
+        let bounds = Coord3D::new(4, 3, 2);
+        let nodes: Vec<_> = (0..24).map(|i| Coord3D::from_index(i, bounds)).collect();
+        assert_eq!(nodes[17], Coord3D::new(1, 1, 1));
+        for (index, node) in nodes.iter().enumerate() {
+            assert_eq!(node.to_index(bounds), index);
+        }
+        assert!(nodes.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    #[should_panic(expected = "(5, 0) is outside of the 5x3 mesh!")]
+    fn index_outside_mesh_test() {
+        Coord2D::new(5, 0).to_index(Coord2D::new(5, 3));
+    }
+}
@@ -0,0 +1,204 @@
+use std::{
+    hash::Hash,
+    sync::{Arc, Mutex},
+};
+
+use dam::{context_tools::*, structures::SyncSendMarker};
+
+use super::{
+    policy::Policy,
+    routing::{Packet, Port, PortMap, Switch},
+};
+
+/// Counters collected by a [`LossySwitch`] while it runs.
+#[derive(Clone, Debug, Default)]
+pub struct LossyStats {
+    /// How many copies were dropped on their way from each input to each output, keyed by (input, output).
+    pub drops: fxhash::FxHashMap<(usize, usize), u64>,
+    /// How many copies made it out.
+    pub forwarded: u64,
+}
+
+/// A best-effort crossbar switch which never waits on its outputs. Every cycle it offers the head of each ready input a
+/// copy to each of its targets, and copies which find the output's channel full are dropped. Each output takes one
+/// packet per cycle, granted round robin, and inputs which lose an output to another input keep their packet for the
+/// next cycle.
+#[context_macro]
+pub struct LossySwitch<T, LT, PolicyType>
+where
+    T: DAMType,
+{
+    ports: PortMap<T>,
+
+    policy: PolicyType,
+    latency: u64,
+    stats: Arc<Mutex<LossyStats>>,
+    last_granted: Option<usize>,
+
+    _marker: SyncSendMarker<LT>,
+}
+
+impl<T: DAMType, LT, PolicyType> Context for LossySwitch<T, LT, PolicyType>
+where
+    T: Packet<LT>,
+    LT: Eq + Hash,
+    PolicyType: Policy<LT> + Sync + Send,
+{
+    fn run(&mut self) {
+        while self.ports.wait_for_input(&mut self.context_info.time) {
+            let mut ready = self.ports.ready(self.time.tick());
+            // Round robin, starting from the first port after the last one to get a packet out.
+            if let Some(last) = self.last_granted {
+                let start = ready.partition_point(|id| *id <= last);
+                ready.rotate_left(start);
+            }
+
+            let mut used_outputs = fxhash::FxHashSet::default();
+            let mut stats = self.stats.lock().unwrap();
+            for input in ready {
+                let receiver = self.ports.inputs.get(&input).unwrap();
+                let dam::channel::PeekResult::Something(ChannelElement { time: _, data }) =
+                    receiver.peek()
+                else {
+                    continue;
+                };
+                let targets = self.policy.route(&data.destination());
+                // Inputs which lost an output to someone else this cycle try again on the next one.
+                if targets.iter().any(|target| used_outputs.contains(target)) {
+                    continue;
+                }
+                receiver.dequeue(&self.time).unwrap();
+                self.last_granted = Some(input);
+                for target in targets {
+                    used_outputs.insert(target);
+                    let sent = self.ports.outputs.get(&target).unwrap().try_enqueue(
+                        &self.time,
+                        ChannelElement {
+                            time: self.time.tick() + self.latency,
+                            data: data.clone(),
+                        },
+                    );
+                    match sent {
+                        Err(dam::channel::EnqueueError::Full) => {
+                            *stats.drops.entry((input, target)).or_default() += 1
+                        }
+                        _ => stats.forwarded += 1,
+                    }
+                }
+            }
+            drop(stats);
+            self.time.incr_cycles(1);
+        }
+    }
+}
+
+impl<T: DAMType, LT, PolicyType> Switch<T> for LossySwitch<T, LT, PolicyType>
+where
+    Self: Context,
+{
+    fn add_port(&mut self, port: Port<T>) {
+        port.attach(self);
+        self.ports.insert(port);
+    }
+}
+
+impl<T: DAMType, LT, PolicyType> LossySwitch<T, LT, PolicyType>
+where
+    Self: Context,
+{
+    /// Creates a switch which sends each copy out `latency` cycles after it leaves its input.
+    pub fn new(policy: PolicyType, latency: u64) -> Self {
+        Self {
+            ports: Default::default(),
+            policy,
+            latency,
+            stats: Default::default(),
+            last_granted: None,
+            _marker: Default::default(),
+            context_info: Default::default(),
+        }
+    }
+
+    /// How many copies got out, and how many were dropped between each input and output. The handle is shared with
+    /// the switch, so it can be read once the simulation is done.
+    pub fn drops(&self) -> Arc<Mutex<LossyStats>> {
+        self.stats.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::switches::{
+        routing::SimplePacket,
+        testing::{connect, direct_policy, run_switch, stream, Sink},
+    };
+
+    use super::{LossyStats, LossySwitch};
+
+    const NUM_PACKETS: u16 = 64;
+
+    /// Streams packets at a sink which only takes one every four cycles, through a channel with room for two.
+    fn undersized_run() -> (Vec<u16>, LossyStats) {
+        let packets = (0..NUM_PACKETS).map(|payload| SimplePacket {
+            location: 1u8,
+            payload,
+        });
+        let switch = LossySwitch::new(direct_policy([1]), 1);
+        let stats = switch.drops();
+        let arrivals = run_switch(
+            [(0, stream(packets))],
+            [Sink::new(1).with_depth(2).with_period(4)],
+            connect(switch),
+        );
+
+        let received = arrivals
+            .into_iter()
+            .map(|(_, _, packet)| packet.payload)
+            .collect();
+        let stats = stats.lock().unwrap().clone();
+        (received, stats)
+    }
+
+    #[test]
+    fn drop_on_full_test() {
+        let (received, stats) = undersized_run();
+
+        let drops = stats.drops[&(0, 1)];
+        assert!(drops > 0);
+        assert_eq!(drops + stats.forwarded, NUM_PACKETS as u64);
+        // Whatever made it through is in order.
+        assert_eq!(received.len() as u64, stats.forwarded);
+        assert!(received.windows(2).all(|pair| pair[0] < pair[1]));
+
+        let (rerun_received, rerun_stats) = undersized_run();
+        assert_eq!(rerun_received, received);
+        assert_eq!(rerun_stats.drops, stats.drops);
+    }
+
+    #[test]
+    fn contention_does_not_drop_test() {
+        // Both inputs send to output 1 every cycle, and its channel has room for everything.
+        let sources = (0..2).map(|port| {
+            let packets = (0..NUM_PACKETS).map(move |payload| SimplePacket {
+                location: 1u8,
+                payload: port as u16 * 1000 + payload,
+            });
+            (port, stream(packets))
+        });
+        let switch = LossySwitch::new(direct_policy([1]), 1);
+        let stats = switch.drops();
+        let arrivals = run_switch(sources, [1], connect(switch));
+
+        let stats = stats.lock().unwrap();
+        assert!(stats.drops.is_empty(), "Dropped {:?}", stats.drops);
+        assert_eq!(stats.forwarded, 2 * NUM_PACKETS as u64);
+        // The output alternates between the two inputs while both have something to send.
+        let senders: Vec<_> = arrivals
+            .iter()
+            .map(|(_, _, packet)| packet.payload / 1000)
+            .collect();
+        assert!(senders[..NUM_PACKETS as usize]
+            .windows(2)
+            .all(|pair| pair[0] != pair[1]));
+    }
+}
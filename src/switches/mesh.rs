@@ -0,0 +1,932 @@
+use super::{
+    policy::{Policy, RouteContext, RouteDecision, VcPolicy},
+    routing::{Coord2D, Dir2D},
+};
+
+/// Which of a mesh switch's ports lead in each direction, and which one leads to the node itself.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MeshPorts {
+    pub plus_x: usize,
+    pub minus_x: usize,
+    pub plus_y: usize,
+    pub minus_y: usize,
+    pub local: usize,
+}
+
+impl MeshPorts {
+    /// The port which leads off in `direction`.
+    pub fn port(&self, direction: Dir2D) -> usize {
+        match direction {
+            Dir2D::PlusX => self.plus_x,
+            Dir2D::MinusX => self.minus_x,
+            Dir2D::PlusY => self.plus_y,
+            Dir2D::MinusY => self.minus_y,
+        }
+    }
+}
+
+/// Dimension ordered routing for a `width` by `height` mesh, as seen from the switch at `position`: packets first move
+/// along X until they line up with their destination, then along Y, and then leave through the local port.
+/// Destinations outside of the mesh aren't routable.
+#[derive(Clone, Debug)]
+pub struct XyPolicy {
+    position: Coord2D,
+    width: u16,
+    height: u16,
+    ports: MeshPorts,
+}
+
+impl XyPolicy {
+    pub fn new(position: Coord2D, width: u16, height: u16, ports: MeshPorts) -> Self {
+        assert!(
+            position.x < width && position.y < height,
+            "The switch at {:?} is outside of the {}x{} mesh!",
+            position,
+            width,
+            height
+        );
+        Self {
+            position,
+            width,
+            height,
+            ports,
+        }
+    }
+}
+
+impl Policy<Coord2D> for XyPolicy {
+    fn route(&mut self, target: &Coord2D) -> fxhash::FxHashSet<usize> {
+        self.try_route(target).into_ports_or_else(|| {
+            format!(
+                "{:?} is outside of the {}x{} mesh!",
+                target, self.width, self.height
+            )
+        })
+    }
+
+    fn try_route(&mut self, target: &Coord2D) -> RouteDecision {
+        if target.x >= self.width || target.y >= self.height {
+            return RouteDecision::Error;
+        }
+        let port = dimension_ordered(self.position, target, &self.ports, DimensionOrder::Xy);
+        RouteDecision::Forward(fxhash::FxHashSet::from_iter([port]))
+    }
+}
+
+/// Which dimension a packet travels along first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DimensionOrder {
+    /// All the way along X, then along Y.
+    Xy,
+    /// All the way along Y, then along X.
+    Yx,
+}
+
+/// The port which takes a packet at `position` one step closer to `target`, going by `order`.
+fn dimension_ordered(
+    position: Coord2D,
+    target: &Coord2D,
+    ports: &MeshPorts,
+    order: DimensionOrder,
+) -> usize {
+    let along_x = match target.x.cmp(&position.x) {
+        std::cmp::Ordering::Greater => Some(ports.plus_x),
+        std::cmp::Ordering::Less => Some(ports.minus_x),
+        std::cmp::Ordering::Equal => None,
+    };
+    let along_y = match target.y.cmp(&position.y) {
+        std::cmp::Ordering::Greater => Some(ports.plus_y),
+        std::cmp::Ordering::Less => Some(ports.minus_y),
+        std::cmp::Ordering::Equal => None,
+    };
+    let (first, second) = match order {
+        DimensionOrder::Xy => (along_x, along_y),
+        DimensionOrder::Yx => (along_y, along_x),
+    };
+    first.or(second).unwrap_or(ports.local)
+}
+
+/// O1TURN routing for a `width` by `height` mesh of VC switches, which sends each packet either XY or YX. The order
+/// is carried by the packet's VC, so that it stays the same the whole way: packets on even VCs go XY, and packets on
+/// odd ones go YX, keeping their VC from hop to hop. With two VCs, the two orders never share a buffer, which keeps
+/// the mesh deadlock free.
+///
+/// Sources pick the order when they inject a packet, and [`O1TurnPolicy::order_for`] spreads them evenly over both
+/// by a flow or sequence number.
+#[derive(Clone, Debug)]
+pub struct O1TurnPolicy {
+    position: Coord2D,
+    width: u16,
+    height: u16,
+    ports: MeshPorts,
+}
+
+impl O1TurnPolicy {
+    pub fn new(position: Coord2D, width: u16, height: u16, ports: MeshPorts) -> Self {
+        assert!(
+            position.x < width && position.y < height,
+            "The switch at {:?} is outside of the {}x{} mesh!",
+            position,
+            width,
+            height
+        );
+        Self {
+            position,
+            width,
+            height,
+            ports,
+        }
+    }
+
+    /// Picks the order for a packet by hashing `id`. Every packet with the same ID goes the same way.
+    pub fn order_for(id: u64) -> DimensionOrder {
+        match fxhash::hash64(&id) >> 63 {
+            0 => DimensionOrder::Xy,
+            _ => DimensionOrder::Yx,
+        }
+    }
+
+    /// The VC to inject a packet on, for it to be routed in `order`.
+    pub fn vc_for(order: DimensionOrder) -> usize {
+        match order {
+            DimensionOrder::Xy => 0,
+            DimensionOrder::Yx => 1,
+        }
+    }
+}
+
+impl VcPolicy<Coord2D> for O1TurnPolicy {
+    fn route_vc(&mut self, target: &Coord2D, vc: usize) -> (usize, usize) {
+        assert!(
+            target.x < self.width && target.y < self.height,
+            "{:?} is outside of the {}x{} mesh!",
+            target,
+            self.width,
+            self.height
+        );
+        let order = match vc % 2 {
+            0 => DimensionOrder::Xy,
+            _ => DimensionOrder::Yx,
+        };
+        (
+            dimension_ordered(self.position, target, &self.ports, order),
+            vc,
+        )
+    }
+}
+
+/// How many links a packet crosses between `from` and `to` on a `width` by `height` torus, going the short way around
+/// in each dimension.
+pub fn torus_distance(from: Coord2D, to: Coord2D, width: u16, height: u16) -> u16 {
+    let along = |from: u16, to: u16, size: u16| {
+        let forward = (to + size - from) % size;
+        forward.min(size - forward)
+    };
+    along(from.x, to.x, width) + along(from.y, to.y, height)
+}
+
+/// Dimension ordered routing for a `width` by `height` torus, as seen from the switch at `position`. Packets move
+/// along X and then along Y, going whichever way around is shorter, and take the plus direction when both are the
+/// same length.
+#[derive(Clone, Debug)]
+pub struct TorusPolicy {
+    position: Coord2D,
+    width: u16,
+    height: u16,
+    ports: MeshPorts,
+}
+
+impl TorusPolicy {
+    pub fn new(position: Coord2D, width: u16, height: u16, ports: MeshPorts) -> Self {
+        assert!(
+            position.x < width && position.y < height,
+            "The switch at {:?} is outside of the {}x{} torus!",
+            position,
+            width,
+            height
+        );
+        Self {
+            position,
+            width,
+            height,
+            ports,
+        }
+    }
+}
+
+impl Policy<Coord2D> for TorusPolicy {
+    fn route(&mut self, target: &Coord2D) -> fxhash::FxHashSet<usize> {
+        self.try_route(target).into_ports_or_else(|| {
+            format!(
+                "{:?} is outside of the {}x{} torus!",
+                target, self.width, self.height
+            )
+        })
+    }
+
+    fn try_route(&mut self, target: &Coord2D) -> RouteDecision {
+        if target.x >= self.width || target.y >= self.height {
+            return RouteDecision::Error;
+        }
+        // The shorter way around a ring of `size` positions, if `to` isn't where we already are.
+        let direction = |from: u16, to: u16, size: u16, plus: usize, minus: usize| {
+            let forward = (to + size - from) % size;
+            match forward {
+                0 => None,
+                _ if forward <= size - forward => Some(plus),
+                _ => Some(minus),
+            }
+        };
+        let port = direction(
+            self.position.x,
+            target.x,
+            self.width,
+            self.ports.plus_x,
+            self.ports.minus_x,
+        )
+        .or_else(|| {
+            direction(
+                self.position.y,
+                target.y,
+                self.height,
+                self.ports.plus_y,
+                self.ports.minus_y,
+            )
+        })
+        .unwrap_or(self.ports.local);
+        RouteDecision::Forward(fxhash::FxHashSet::from_iter([port]))
+    }
+}
+
+/// Minimal adaptive routing for a `width` by `height` mesh, as seen from the switch at `position`. Packets may move
+/// along either dimension which brings them closer to their destination, and take whichever of those outputs has
+/// been the least busy: first avoiding outputs which sent something last cycle, then the ones which have sent the
+/// most this window. Ties go to X. Without congestion information, packets go XY.
+#[derive(Clone, Debug)]
+pub struct MinimalAdaptivePolicy {
+    position: Coord2D,
+    width: u16,
+    height: u16,
+    ports: MeshPorts,
+}
+
+impl MinimalAdaptivePolicy {
+    pub fn new(position: Coord2D, width: u16, height: u16, ports: MeshPorts) -> Self {
+        assert!(
+            position.x < width && position.y < height,
+            "The switch at {:?} is outside of the {}x{} mesh!",
+            position,
+            width,
+            height
+        );
+        Self {
+            position,
+            width,
+            height,
+            ports,
+        }
+    }
+
+    /// The outputs which bring a packet closer to `target`, X first.
+    fn candidates(&self, target: &Coord2D) -> Vec<usize> {
+        [
+            dimension_ordered(self.position, target, &self.ports, DimensionOrder::Xy),
+            dimension_ordered(self.position, target, &self.ports, DimensionOrder::Yx),
+        ]
+        .into_iter()
+        .fold(vec![], |mut candidates, port| {
+            if !candidates.contains(&port) {
+                candidates.push(port);
+            }
+            candidates
+        })
+    }
+}
+
+impl Policy<Coord2D> for MinimalAdaptivePolicy {
+    fn route(&mut self, target: &Coord2D) -> fxhash::FxHashSet<usize> {
+        self.route_adaptive(target, &RouteContext::default())
+            .into_ports_or_else(|| {
+                format!(
+                    "{:?} is outside of the {}x{} mesh!",
+                    target, self.width, self.height
+                )
+            })
+    }
+
+    fn try_route(&mut self, target: &Coord2D) -> RouteDecision {
+        self.route_adaptive(target, &RouteContext::default())
+    }
+
+    fn route_adaptive(&mut self, target: &Coord2D, context: &RouteContext) -> RouteDecision {
+        if target.x >= self.width || target.y >= self.height {
+            return RouteDecision::Error;
+        }
+        let port = self
+            .candidates(target)
+            .into_iter()
+            .min_by_key(|port| {
+                let metrics = context.outputs.get(port).copied().unwrap_or_default();
+                (metrics.busy_last_cycle, metrics.sent)
+            })
+            .unwrap();
+        RouteDecision::Forward(fxhash::FxHashSet::from_iter([port]))
+    }
+}
+
+/// The classic turn models, each of which rules out just enough of the turns a packet could make in a mesh to keep
+/// packets from waiting on each other in a cycle, taking -X as west and +Y as north.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TurnModel {
+    /// Packets which need to go west do all of that first, and never turn west later on.
+    WestFirst,
+    /// Packets which need to go north do all of that last, and never turn out of north.
+    NorthLast,
+    /// Packets go in the negative directions first, and never turn from a positive direction to a negative one.
+    NegativeFirst,
+}
+
+/// Minimal turn model routing for a `width` by `height` mesh, as seen from the switch at `position`. Packets may take
+/// any output which brings them closer to their destination without making a turn `model` rules out, and take
+/// whichever of those has been the least busy, the same way [`MinimalAdaptivePolicy`] does. Ties go to X.
+#[derive(Clone, Debug)]
+pub struct TurnModelPolicy {
+    model: TurnModel,
+    position: Coord2D,
+    width: u16,
+    height: u16,
+    ports: MeshPorts,
+}
+
+impl TurnModelPolicy {
+    pub fn new(
+        model: TurnModel,
+        position: Coord2D,
+        width: u16,
+        height: u16,
+        ports: MeshPorts,
+    ) -> Self {
+        assert!(
+            position.x < width && position.y < height,
+            "The switch at {:?} is outside of the {}x{} mesh!",
+            position,
+            width,
+            height
+        );
+        Self {
+            model,
+            position,
+            width,
+            height,
+            ports,
+        }
+    }
+
+    /// The outputs which bring a packet closer to `target` without making a forbidden turn later on, X first.
+    pub fn candidates(&self, target: &Coord2D) -> Vec<usize> {
+        let west = target.x < self.position.x;
+        let south = target.y < self.position.y;
+        let along_x = match target.x.cmp(&self.position.x) {
+            std::cmp::Ordering::Greater => Some(self.ports.plus_x),
+            std::cmp::Ordering::Less => Some(self.ports.minus_x),
+            std::cmp::Ordering::Equal => None,
+        };
+        let along_y = match target.y.cmp(&self.position.y) {
+            std::cmp::Ordering::Greater => Some(self.ports.plus_y),
+            std::cmp::Ordering::Less => Some(self.ports.minus_y),
+            std::cmp::Ordering::Equal => None,
+        };
+        let allowed = match self.model {
+            TurnModel::WestFirst if west => [along_x, None],
+            // Going north first would leave a turn out of north for later.
+            TurnModel::NorthLast if !south && along_x.is_some() => [along_x, None],
+            TurnModel::NegativeFirst if west || south => {
+                [along_x.filter(|_| west), along_y.filter(|_| south)]
+            }
+            _ => [along_x, along_y],
+        };
+        let candidates: Vec<_> = allowed.into_iter().flatten().collect();
+        match candidates.is_empty() {
+            true => vec![self.ports.local],
+            false => candidates,
+        }
+    }
+}
+
+impl Policy<Coord2D> for TurnModelPolicy {
+    fn route(&mut self, target: &Coord2D) -> fxhash::FxHashSet<usize> {
+        self.route_adaptive(target, &RouteContext::default())
+            .into_ports_or_else(|| {
+                format!(
+                    "{:?} is outside of the {}x{} mesh!",
+                    target, self.width, self.height
+                )
+            })
+    }
+
+    fn try_route(&mut self, target: &Coord2D) -> RouteDecision {
+        self.route_adaptive(target, &RouteContext::default())
+    }
+
+    fn route_adaptive(&mut self, target: &Coord2D, context: &RouteContext) -> RouteDecision {
+        if target.x >= self.width || target.y >= self.height {
+            return RouteDecision::Error;
+        }
+        let port = self
+            .candidates(target)
+            .into_iter()
+            .min_by_key(|port| {
+                let metrics = context.outputs.get(port).copied().unwrap_or_default();
+                (metrics.busy_last_cycle, metrics.sent)
+            })
+            .unwrap();
+        RouteDecision::Forward(fxhash::FxHashSet::from_iter([port]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::switches::{
+        policy::{OutputMetrics, Policy, RouteContext, RouteDecision, VcPolicy},
+        routing::{Coord2D, SimplePacket},
+        testing::{run_mesh, run_torus},
+        SimpleSwitch,
+    };
+
+    use super::{
+        torus_distance, DimensionOrder, MeshPorts, MinimalAdaptivePolicy, O1TurnPolicy,
+        TorusPolicy, TurnModel, TurnModelPolicy, XyPolicy,
+    };
+
+    const PORTS: MeshPorts = MeshPorts {
+        local: 0,
+        plus_x: 1,
+        minus_x: 2,
+        plus_y: 3,
+        minus_y: 4,
+    };
+    const SIZE: u16 = 3;
+    const LATENCY: u64 = 2;
+
+    #[test]
+    fn corner_to_corner_test() {
+        let corners = [(0, 0), (SIZE - 1, 0), (0, SIZE - 1), (SIZE - 1, SIZE - 1)]
+            .map(|(x, y)| Coord2D { x, y });
+        let opposite = |corner: Coord2D| Coord2D {
+            x: SIZE - 1 - corner.x,
+            y: SIZE - 1 - corner.y,
+        };
+
+        // Every corner sends one packet to the corner across from it, tagged with where it came from.
+        let arrivals = run_mesh(
+            SIZE,
+            SIZE,
+            PORTS,
+            |node| match corners.iter().position(|corner| *corner == node) {
+                Some(index) => vec![(
+                    1,
+                    SimplePacket {
+                        location: opposite(node),
+                        payload: index,
+                    },
+                )],
+                None => vec![],
+            },
+            |node| SimpleSwitch::new(XyPolicy::new(node, SIZE, SIZE, PORTS), LATENCY),
+            corners.len(),
+        );
+
+        assert_eq!(arrivals.len(), corners.len());
+        for (node, time, packet) in arrivals {
+            assert_eq!(node, packet.location);
+            assert_eq!(node, opposite(corners[packet.payload]));
+            // Four links to cross, each a cycle long, and five switches to go through.
+            let hops = 2 * (SIZE as u64 - 1);
+            assert_eq!(time, 1 + hops + (hops + 1) * LATENCY);
+        }
+    }
+
+    #[test]
+    fn off_the_mesh_test() {
+        let mut policy = XyPolicy::new(Coord2D { x: 1, y: 1 }, SIZE, SIZE, PORTS);
+        assert_eq!(
+            policy.try_route(&Coord2D { x: 2, y: 0 }),
+            RouteDecision::Forward(fxhash::FxHashSet::from_iter([PORTS.plus_x]))
+        );
+        assert_eq!(
+            policy.try_route(&Coord2D { x: 1, y: 0 }),
+            RouteDecision::Forward(fxhash::FxHashSet::from_iter([PORTS.minus_y]))
+        );
+        assert_eq!(
+            policy.try_route(&Coord2D { x: 1, y: 1 }),
+            RouteDecision::Forward(fxhash::FxHashSet::from_iter([PORTS.local]))
+        );
+        assert_eq!(
+            policy.try_route(&Coord2D { x: SIZE, y: 1 }),
+            RouteDecision::Error
+        );
+    }
+
+    /// Follows a packet from `source` until `route`, which picks the port to take at each position, sends it out of
+    /// the local port. Adds one to the load of every link it crosses along the way.
+    fn trace(
+        loads: &mut fxhash::FxHashMap<(Coord2D, usize), u64>,
+        source: Coord2D,
+        mut route: impl FnMut(Coord2D) -> usize,
+    ) {
+        let mut position = source;
+        loop {
+            let port = route(position);
+            if port == PORTS.local {
+                return;
+            }
+            *loads.entry((position, port)).or_default() += 1;
+            position = match port {
+                _ if port == PORTS.plus_x => Coord2D {
+                    x: position.x + 1,
+                    ..position
+                },
+                _ if port == PORTS.minus_x => Coord2D {
+                    x: position.x - 1,
+                    ..position
+                },
+                _ if port == PORTS.plus_y => Coord2D {
+                    y: position.y + 1,
+                    ..position
+                },
+                _ => Coord2D {
+                    y: position.y - 1,
+                    ..position
+                },
+            };
+        }
+    }
+
+    #[test]
+    fn o1turn_transpose_test() {
+        const TRANSPOSE_SIZE: u16 = 8;
+        let nodes: Vec<_> = (0..TRANSPOSE_SIZE)
+            .flat_map(|y| (0..TRANSPOSE_SIZE).map(move |x| Coord2D { x, y }))
+            .collect();
+        let transpose = |node: Coord2D| Coord2D {
+            x: node.y,
+            y: node.x,
+        };
+
+        let mut xy_loads = fxhash::FxHashMap::default();
+        let mut o1turn_loads = fxhash::FxHashMap::default();
+        for (id, source) in nodes.iter().copied().enumerate() {
+            let target = transpose(source);
+            trace(&mut xy_loads, source, |position| {
+                let mut policy = XyPolicy::new(position, TRANSPOSE_SIZE, TRANSPOSE_SIZE, PORTS);
+                *policy.route(&target).iter().next().unwrap()
+            });
+
+            let vc = O1TurnPolicy::vc_for(O1TurnPolicy::order_for(id as u64));
+            trace(&mut o1turn_loads, source, |position| {
+                let mut policy = O1TurnPolicy::new(position, TRANSPOSE_SIZE, TRANSPOSE_SIZE, PORTS);
+                let (port, next_vc) = policy.route_vc(&target, vc);
+                // Packets stay on their VC, and so keep their order, the whole way.
+                assert_eq!(next_vc, vc);
+                port
+            });
+        }
+
+        // Both route minimally, so the total load is the same, but O1TURN spreads it out over more links.
+        let total = |loads: &fxhash::FxHashMap<_, u64>| loads.values().sum::<u64>();
+        let busiest = |loads: &fxhash::FxHashMap<_, u64>| *loads.values().max().unwrap();
+        assert_eq!(total(&xy_loads), total(&o1turn_loads));
+        assert!(o1turn_loads.len() > xy_loads.len());
+        assert!(
+            busiest(&o1turn_loads) < busiest(&xy_loads),
+            "O1TURN's busiest link carried {}, against {} under XY",
+            busiest(&o1turn_loads),
+            busiest(&xy_loads)
+        );
+    }
+
+    #[test]
+    fn order_for_test() {
+        let orders: Vec<_> = (0..1000).map(O1TurnPolicy::order_for).collect();
+        let yx = orders
+            .iter()
+            .filter(|order| **order == DimensionOrder::Yx)
+            .count();
+        assert!((400..600).contains(&yx), "{} of 1000 went YX", yx);
+        assert_eq!(
+            orders,
+            (0..1000).map(O1TurnPolicy::order_for).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn torus_wrap_around_test() {
+        const TORUS_SIZE: u16 = 4;
+        let origin = Coord2D { x: 0, y: 0 };
+        // The opposite corner is a step away around both edges, and the middle is two steps either way in both
+        // dimensions.
+        let targets = [
+            Coord2D { x: 3, y: 3 },
+            Coord2D { x: 2, y: 2 },
+            Coord2D { x: 3, y: 0 },
+        ];
+        assert_eq!(
+            targets.map(|target| torus_distance(origin, target, TORUS_SIZE, TORUS_SIZE)),
+            [2, 4, 1]
+        );
+
+        let arrivals = run_torus(
+            TORUS_SIZE,
+            TORUS_SIZE,
+            PORTS,
+            |node| match node == origin {
+                true => targets
+                    .iter()
+                    .enumerate()
+                    .map(|(i, target)| {
+                        let packet = SimplePacket {
+                            location: *target,
+                            payload: i,
+                        };
+                        (1 + 10 * i as u64, packet)
+                    })
+                    .collect(),
+                false => vec![],
+            },
+            |node| {
+                SimpleSwitch::new(
+                    TorusPolicy::new(node, TORUS_SIZE, TORUS_SIZE, PORTS),
+                    LATENCY,
+                )
+            },
+            targets.len(),
+        );
+
+        assert_eq!(arrivals.len(), targets.len());
+        for (node, time, packet) in arrivals {
+            assert_eq!(node, targets[packet.payload]);
+            let hops = torus_distance(origin, node, TORUS_SIZE, TORUS_SIZE) as u64;
+            let sent = 1 + 10 * packet.payload as u64;
+            assert_eq!(time - sent, hops + (hops + 1) * LATENCY);
+        }
+    }
+
+    #[test]
+    fn torus_tie_break_test() {
+        let mut policy = TorusPolicy::new(Coord2D { x: 1, y: 1 }, 4, 4, PORTS);
+        let route = |policy: &mut TorusPolicy, x, y| policy.route(&Coord2D { x, y });
+        assert_eq!(
+            route(&mut policy, 0, 1),
+            fxhash::FxHashSet::from_iter([PORTS.minus_x])
+        );
+        assert_eq!(
+            route(&mut policy, 2, 1),
+            fxhash::FxHashSet::from_iter([PORTS.plus_x])
+        );
+        // Two steps either way, so the plus direction wins.
+        assert_eq!(
+            route(&mut policy, 3, 1),
+            fxhash::FxHashSet::from_iter([PORTS.plus_x])
+        );
+        assert_eq!(
+            route(&mut policy, 1, 3),
+            fxhash::FxHashSet::from_iter([PORTS.plus_y])
+        );
+        assert_eq!(
+            route(&mut policy, 1, 0),
+            fxhash::FxHashSet::from_iter([PORTS.minus_y])
+        );
+        assert_eq!(
+            policy.try_route(&Coord2D { x: 4, y: 0 }),
+            RouteDecision::Error
+        );
+    }
+
+    #[test]
+    fn least_busy_candidate_test() {
+        let mut policy = MinimalAdaptivePolicy::new(Coord2D { x: 1, y: 1 }, SIZE, SIZE, PORTS);
+        let target = Coord2D { x: 2, y: 2 };
+        let context = |busy_x: bool, sent_x: u64, sent_y: u64| RouteContext {
+            outputs: [
+                (
+                    PORTS.plus_x,
+                    OutputMetrics {
+                        sent: sent_x,
+                        busy_last_cycle: busy_x,
+                        send_failures: 0,
+                    },
+                ),
+                (
+                    PORTS.plus_y,
+                    OutputMetrics {
+                        sent: sent_y,
+                        ..Default::default()
+                    },
+                ),
+            ]
+            .into(),
+            ..Default::default()
+        };
+        let routed = |port| RouteDecision::Forward(fxhash::FxHashSet::from_iter([port]));
+
+        assert_eq!(
+            policy.route_adaptive(&target, &context(false, 0, 0)),
+            routed(PORTS.plus_x)
+        );
+        assert_eq!(
+            policy.route_adaptive(&target, &context(true, 0, 5)),
+            routed(PORTS.plus_y)
+        );
+        assert_eq!(
+            policy.route_adaptive(&target, &context(false, 3, 2)),
+            routed(PORTS.plus_y)
+        );
+        // Packets which only have the one way to go take it, however busy it is.
+        assert_eq!(
+            policy.route_adaptive(&Coord2D { x: 2, y: 1 }, &context(true, 9, 0)),
+            routed(PORTS.plus_x)
+        );
+    }
+
+    #[test]
+    fn adaptive_hot_link_test() {
+        const HOT_SIZE: u16 = 4;
+        const NUM_PACKETS: u64 = 16;
+        // The first three nodes along the top row each stream packets to a different node in the far column. Under
+        // XY, all of them pile onto the last link along the top row.
+        let traffic = |node: Coord2D| match node {
+            Coord2D { x: 0..=2, y: 0 } => {
+                let target = Coord2D {
+                    x: HOT_SIZE - 1,
+                    y: HOT_SIZE - 1 - node.x,
+                };
+                (1..=NUM_PACKETS)
+                    .map(|sent| {
+                        let packet = SimplePacket {
+                            location: target,
+                            payload: sent,
+                        };
+                        (sent, packet)
+                    })
+                    .collect()
+            }
+            _ => vec![],
+        };
+        let mean_latency = |arrivals: Vec<(Coord2D, u64, SimplePacket<Coord2D, u64>)>| {
+            assert_eq!(arrivals.len(), 3 * NUM_PACKETS as usize);
+            assert!(arrivals
+                .iter()
+                .all(|(node, _, packet)| *node == packet.location));
+            let total: u64 = arrivals
+                .iter()
+                .map(|(_, time, packet)| time - packet.payload)
+                .sum();
+            total as f64 / arrivals.len() as f64
+        };
+
+        let xy = mean_latency(run_mesh(
+            HOT_SIZE,
+            HOT_SIZE,
+            PORTS,
+            traffic,
+            |node| SimpleSwitch::new(XyPolicy::new(node, HOT_SIZE, HOT_SIZE, PORTS), LATENCY),
+            3 * NUM_PACKETS as usize,
+        ));
+        let adaptive = mean_latency(run_mesh(
+            HOT_SIZE,
+            HOT_SIZE,
+            PORTS,
+            traffic,
+            |node| {
+                let policy = MinimalAdaptivePolicy::new(node, HOT_SIZE, HOT_SIZE, PORTS);
+                SimpleSwitch::new(policy, LATENCY)
+            },
+            3 * NUM_PACKETS as usize,
+        ));
+        assert!(
+            adaptive < xy,
+            "Mean latency: {:.2} under XY, {:.2} adaptive",
+            xy,
+            adaptive
+        );
+    }
+
+    const TURN_SIZE: u16 = 5;
+
+    /// Follows every path the turn model allows from `source` to `target`, checking that each step brings the packet
+    /// closer and that no two steps make a turn `forbidden` rules out. Returns how many paths there were.
+    fn sweep_paths(
+        model: TurnModel,
+        source: Coord2D,
+        target: Coord2D,
+        forbidden: &impl Fn(usize, usize) -> bool,
+        last: Option<usize>,
+    ) -> usize {
+        let policy = TurnModelPolicy::new(model, source, TURN_SIZE, TURN_SIZE, PORTS);
+        let mut paths = 0;
+        for port in policy.candidates(&target) {
+            if let Some(last) = last {
+                assert!(
+                    !forbidden(last, port),
+                    "{:?} turned from port {} to port {} at {:?} on the way to {:?}",
+                    model,
+                    last,
+                    port,
+                    source,
+                    target
+                );
+            }
+            let steps = [
+                (PORTS.plus_x, 1, 0),
+                (PORTS.minus_x, -1, 0),
+                (PORTS.plus_y, 0, 1),
+                (PORTS.minus_y, 0, -1),
+            ];
+            let Some((_, dx, dy)) = steps.into_iter().find(|(step, _, _)| *step == port) else {
+                // Out of the local port, which is only right once the packet is there.
+                assert_eq!(source, target);
+                paths += 1;
+                continue;
+            };
+            let next = Coord2D {
+                x: source.x.wrapping_add_signed(dx),
+                y: source.y.wrapping_add_signed(dy),
+            };
+            assert_eq!(
+                next.manhattan_distance(&target) + 1,
+                source.manhattan_distance(&target)
+            );
+            paths += sweep_paths(model, next, target, forbidden, Some(port));
+        }
+        paths
+    }
+
+    #[test]
+    fn turn_model_sweep_test() {
+        let west_first = |_: usize, to: usize| to == PORTS.minus_x;
+        let north_last = |from: usize, to: usize| from == PORTS.plus_y && to != PORTS.plus_y;
+        let negative_first = |from: usize, to: usize| {
+            [PORTS.plus_x, PORTS.plus_y].contains(&from)
+                && [PORTS.minus_x, PORTS.minus_y].contains(&to)
+        };
+        let nodes: Vec<_> = (0..TURN_SIZE)
+            .flat_map(|y| (0..TURN_SIZE).map(move |x| Coord2D { x, y }))
+            .collect();
+
+        for (model, forbidden) in [
+            (
+                TurnModel::WestFirst,
+                &west_first as &dyn Fn(usize, usize) -> bool,
+            ),
+            (TurnModel::NorthLast, &north_last),
+            (TurnModel::NegativeFirst, &negative_first),
+        ] {
+            // Going on in the same direction isn't a turn, and neither is leaving the mesh.
+            let forbidden =
+                |from: usize, to: usize| from != to && to != PORTS.local && forbidden(from, to);
+            let mut adaptive = false;
+            for source in nodes.iter().copied() {
+                for target in nodes.iter().copied() {
+                    let paths = sweep_paths(model, source, target, &forbidden, None);
+                    assert!(paths >= 1);
+                    adaptive |= paths > 1;
+                }
+            }
+            assert!(adaptive, "{:?} never had a choice", model);
+        }
+    }
+
+    #[test]
+    fn turn_model_choices_test() {
+        let center = Coord2D { x: 2, y: 2 };
+        let candidates = |model, x, y| {
+            TurnModelPolicy::new(model, center, TURN_SIZE, TURN_SIZE, PORTS)
+                .candidates(&Coord2D { x, y })
+        };
+        // North-west: only west first goes west, and only north last holds off on north.
+        assert_eq!(candidates(TurnModel::WestFirst, 0, 4), vec![PORTS.minus_x]);
+        assert_eq!(candidates(TurnModel::NorthLast, 0, 4), vec![PORTS.minus_x]);
+        assert_eq!(
+            candidates(TurnModel::NegativeFirst, 0, 4),
+            vec![PORTS.minus_x]
+        );
+        // South-east: everyone but negative first can choose.
+        assert_eq!(
+            candidates(TurnModel::WestFirst, 4, 0),
+            vec![PORTS.plus_x, PORTS.minus_y]
+        );
+        assert_eq!(
+            candidates(TurnModel::NorthLast, 4, 0),
+            vec![PORTS.plus_x, PORTS.minus_y]
+        );
+        assert_eq!(
+            candidates(TurnModel::NegativeFirst, 4, 0),
+            vec![PORTS.minus_y]
+        );
+        assert_eq!(
+            candidates(TurnModel::NegativeFirst, 2, 2),
+            vec![PORTS.local]
+        );
+    }
+}
@@ -1,3 +1,62 @@
+pub mod arbitration;
+pub mod audit;
+pub mod buffered;
+pub mod bus;
+pub mod butterfly;
+pub mod cluster;
+pub mod cut_through;
+pub mod deflection;
+pub mod hypercube;
+pub mod locations;
+pub mod lossy;
+pub mod mesh;
+pub mod output_queued;
 pub mod policy;
+pub mod ring;
 pub mod routing;
+pub mod shortest_path;
 pub mod simple;
+pub mod store_and_forward;
+pub mod table;
+pub mod tdm;
+#[cfg(test)]
+pub(crate) mod testing;
+pub mod tree;
+pub mod vc;
+pub mod voq;
+pub mod wormhole;
+
+pub use arbitration::{ArbitrationPolicy, IslipArbiter};
+pub use audit::{AuditReport, Diagnostic, NetworkAuditor, Severity};
+pub use buffered::{BufferStats, BufferedSwitch};
+pub use bus::{Bus, Targets};
+pub use butterfly::{build_butterfly, Butterfly2x2};
+pub use cluster::ClusterSwitch;
+pub use cut_through::CutThroughSwitch;
+pub use deflection::{DeflectionStats, DeflectionSwitch};
+pub use hypercube::EcubePolicy;
+pub use locations::{Concentrated, Coord2D, Coord3D, Dir2D, Dir3D};
+pub use lossy::{LossyStats, LossySwitch};
+pub use mesh::{
+    torus_distance, DimensionOrder, MeshPorts, MinimalAdaptivePolicy, O1TurnPolicy, TorusPolicy,
+    TurnModel, TurnModelPolicy, XyPolicy,
+};
+pub use output_queued::{OutputQueueStats, OutputQueuedSwitch};
+pub use policy::{
+    Bitmask, BitmaskPolicy, CachedPolicy, ChainPolicy, ChainStats, DeflectAnywhere,
+    DeflectionPolicy, EcmpPolicy, FloodPolicy, LookaheadPolicy, LpmPolicy, OutputMetrics,
+    PacketPolicy, PerClassPolicy, PolicyError, PolicyUpdate, PrefixConflict, RandomPolicy,
+    RangeOverlap, RangePolicy, RouteContext, RouteDecision, SameVc, ScheduledPolicy,
+    SharedTablePolicy, SourceRoutePolicy, SprayPolicy, TableWithDefault, UnroutableAction,
+    UpdatablePolicy, VcPolicy, WeightedPolicy,
+};
+pub use ring::{build_ring, RingArbitration, RingEndpoint, RingStop, RingTracker};
+pub use shortest_path::RoutingTableBuilder;
+pub use simple::{SimpleSwitch, SwitchStats};
+pub use store_and_forward::StoreAndForwardSwitch;
+pub use table::{TableError, TablePolicy};
+pub use tdm::{validate_slot_table, SlotConflict, SlotTable, TdmSwitch};
+pub use tree::{updown_tables, TreeLink, TreeTopo};
+pub use vc::{VcPort, VcSwitch};
+pub use voq::VoqSwitch;
+pub use wormhole::{Depacketizer, Packetizer, WormholeSwitch};
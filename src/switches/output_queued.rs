@@ -0,0 +1,257 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    hash::Hash,
+    sync::{Arc, Mutex},
+};
+
+use dam::{
+    channel::utils::{EventTime, Peekable},
+    context_tools::*,
+    structures::SyncSendMarker,
+};
+
+use super::{
+    policy::Policy,
+    routing::{Packet, Port, PortMap, Switch},
+};
+
+/// Counters collected by an [`OutputQueuedSwitch`] while it runs, keyed by output port.
+#[derive(Clone, Debug, Default)]
+pub struct OutputQueueStats {
+    /// The most packets each output's queue held at once.
+    pub peak_occupancy: fxhash::FxHashMap<usize, usize>,
+    /// How many packets were placed into each output's queue.
+    pub enqueued: fxhash::FxHashMap<usize, u64>,
+    /// How many packets left each output's queue.
+    pub drained: fxhash::FxHashMap<usize, u64>,
+}
+
+/// An idealized switch which moves every ready input straight into queues at its outputs, so that inputs never
+/// contend with each other. Each output then sends out one packet per cycle. Inputs only wait when one of the
+/// queues their packet is headed to is full.
+#[context_macro]
+pub struct OutputQueuedSwitch<T, LT, PolicyType>
+where
+    T: DAMType,
+{
+    ports: PortMap<T>,
+    queues: BTreeMap<usize, VecDeque<T>>,
+    // Routing decisions for input heads which are waiting on a full queue.
+    pending: fxhash::FxHashMap<usize, fxhash::FxHashSet<usize>>,
+
+    policy: PolicyType,
+    latency: u64,
+    queue_depth: usize,
+    stats: Arc<Mutex<OutputQueueStats>>,
+
+    _marker: SyncSendMarker<LT>,
+}
+
+impl<T: DAMType, LT, PolicyType> Context for OutputQueuedSwitch<T, LT, PolicyType>
+where
+    T: Packet<LT>,
+    LT: Eq + Hash,
+    PolicyType: Policy<LT> + Sync + Send,
+{
+    fn run(&mut self) {
+        while self.wait_for_work() {
+            self.accept();
+            self.drain();
+            self.time.incr_cycles(1);
+        }
+    }
+}
+
+impl<T: DAMType, LT, PolicyType> Switch<T> for OutputQueuedSwitch<T, LT, PolicyType>
+where
+    Self: Context,
+{
+    fn add_port(&mut self, port: Port<T>) {
+        port.attach(self);
+        self.ports.insert(port);
+    }
+}
+
+impl<T: DAMType, LT, PolicyType> OutputQueuedSwitch<T, LT, PolicyType>
+where
+    T: Packet<LT>,
+    LT: Eq + Hash,
+    PolicyType: Policy<LT> + Sync + Send,
+{
+    /// Moves the head of every ready input into the queues of its targets, if they all have room.
+    fn accept(&mut self) {
+        let tick = self.time.tick();
+        let ready: Vec<_> = self
+            .ports
+            .inputs
+            .iter()
+            .filter(|(_, chan)| matches!(chan.next_event(), EventTime::Ready(t) if t <= tick))
+            .map(|(id, _)| *id)
+            .collect();
+
+        for id in ready {
+            let targets = match self.pending.remove(&id) {
+                Some(targets) => targets,
+                None => match self.ports.inputs.get(&id).unwrap().peek() {
+                    dam::channel::PeekResult::Something(head) => {
+                        self.policy.route(&head.data.destination())
+                    }
+                    _ => panic!("Port {:?} was supposed to be ready", id),
+                },
+            };
+            let has_room = targets
+                .iter()
+                .all(|target| self.queues.get(target).map_or(0, VecDeque::len) < self.queue_depth);
+            if !has_room {
+                // Leaving the packet in the channel backs up the sender.
+                self.pending.insert(id, targets);
+                continue;
+            }
+
+            let ChannelElement { time: _, data } = self
+                .ports
+                .inputs
+                .get(&id)
+                .unwrap()
+                .dequeue(&self.time)
+                .unwrap();
+            let mut stats = self.stats.lock().unwrap();
+            for target in targets {
+                let queue = self.queues.entry(target).or_default();
+                queue.push_back(data.clone());
+                *stats.enqueued.entry(target).or_default() += 1;
+                let peak = stats.peak_occupancy.entry(target).or_default();
+                *peak = (*peak).max(queue.len());
+            }
+        }
+    }
+}
+
+impl<T: DAMType, LT, PolicyType> OutputQueuedSwitch<T, LT, PolicyType>
+where
+    Self: Context,
+{
+    /// Creates a switch with room for `queue_depth` packets at each output, which sends each packet out `latency`
+    /// cycles after it leaves its queue.
+    pub fn new(policy: PolicyType, latency: u64, queue_depth: usize) -> Self {
+        assert!(queue_depth > 0, "Queues must hold at least one packet!");
+        Self {
+            ports: Default::default(),
+            queues: Default::default(),
+            pending: Default::default(),
+            policy,
+            latency,
+            queue_depth,
+            stats: Default::default(),
+            _marker: Default::default(),
+            context_info: Default::default(),
+        }
+    }
+
+    /// How full each output queue got, and how many packets went into and out of it. The handle is shared with the
+    /// switch, so it can be read once the simulation is done.
+    pub fn stats(&self) -> Arc<Mutex<OutputQueueStats>> {
+        self.stats.clone()
+    }
+
+    /// Sends the front of every output queue, unless the output channel is full.
+    fn drain(&mut self) {
+        let mut queues = std::mem::take(&mut self.queues);
+        for (output, queue) in queues.iter_mut() {
+            let Some(data) = queue.front() else {
+                continue;
+            };
+            let result = self.ports.outputs.get(output).unwrap().try_enqueue(
+                &self.time,
+                ChannelElement {
+                    time: self.time.tick() + self.latency,
+                    data: data.clone(),
+                },
+            );
+            if let Err(dam::channel::EnqueueError::Full) = result {
+                continue;
+            }
+            queue.pop_front();
+            *self
+                .stats
+                .lock()
+                .unwrap()
+                .drained
+                .entry(*output)
+                .or_default() += 1;
+        }
+        self.queues = queues;
+    }
+
+    /// Waits until there is something to do, returning false once every input has closed and every queue has
+    /// drained.
+    fn wait_for_work(&mut self) -> bool {
+        self.queues.values().any(|queue| !queue.is_empty())
+            || self.ports.wait_for_input(&mut self.context_info.time)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dam::simulation::ProgramBuilder;
+
+    use crate::switches::{
+        routing::{Port, SimplePacket},
+        testing::{connect, direct_policy, run_switch, stream, TestPacket},
+        SimpleSwitch,
+    };
+
+    use super::OutputQueuedSwitch;
+
+    const NUM_PACKETS: u16 = 32;
+
+    /// Two inputs flood output 2. Returns the arrival time of every packet, keyed by its payload.
+    fn contended_arrivals(
+        add_switch: impl FnOnce(&mut ProgramBuilder, Vec<Port<TestPacket>>),
+    ) -> Vec<(u16, u64)> {
+        let sources = (0..2).map(|port| {
+            let packets = (0..NUM_PACKETS).map(move |i| SimplePacket {
+                location: 2u8,
+                payload: port as u16 * 1000 + i,
+            });
+            (port, stream(packets))
+        });
+        run_switch(sources, [2], add_switch)
+            .into_iter()
+            .map(|(_, time, packet)| (packet.payload, time))
+            .collect()
+    }
+
+    #[test]
+    fn output_queued_test() {
+        let switch = OutputQueuedSwitch::new(direct_policy([2]), 1, 4);
+        let stats = switch.stats();
+        let arrivals = contended_arrivals(connect(switch));
+        let stats = stats.lock().unwrap().clone();
+
+        // Both inputs stream in at twice the rate the output can drain, so the queue fills up and stays full.
+        assert_eq!(stats.peak_occupancy[&2], 4);
+        assert_eq!(stats.enqueued[&2], 2 * NUM_PACKETS as u64);
+        assert_eq!(stats.drained[&2], 2 * NUM_PACKETS as u64);
+
+        // The queue keeps each input's packets in order.
+        for port in 0..2 {
+            let from_port: Vec<_> = arrivals
+                .iter()
+                .filter(|(payload, _)| payload / 1000 == port)
+                .map(|(payload, _)| payload % 1000)
+                .collect();
+            assert_eq!(from_port, Vec::from_iter(0..NUM_PACKETS));
+        }
+    }
+
+    #[test]
+    fn matches_simple_switch_throughput_test() {
+        let finish = |arrivals: Vec<(u16, u64)>| arrivals.into_iter().map(|(_, time)| time).max();
+        let simple = contended_arrivals(connect(SimpleSwitch::new(direct_policy([2]), 1)));
+        let output_queued =
+            contended_arrivals(connect(OutputQueuedSwitch::new(direct_policy([2]), 1, 4)));
+        // Either way the output moves one packet per cycle, so the last one shows up at the same time.
+        assert_eq!(finish(simple), finish(output_queued));
+    }
+}
@@ -1,15 +1,1881 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use dam::context_tools::DAMType;
+
+use crate::random::SplitMix64;
+
+use super::routing::{ClassedPacket, Packet};
+
+/// What a policy decided to do with a packet.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RouteDecision {
+    /// Send a copy to each of the given output ports.
+    Forward(fxhash::FxHashSet<usize>),
+    /// Throw the packet away.
+    Drop,
+    /// The policy doesn't know where the packet should go.
+    Error,
+}
+
+const UNROUTABLE: &str = "Could not find appropriate routing for location!";
+
+impl RouteDecision {
+    /// The answer for [`Policy::route`], which only has a set of ports to give. Dropped packets go out of none of
+    /// them, and targets the policy doesn't know panic.
+    pub fn into_ports(self) -> fxhash::FxHashSet<usize> {
+        self.into_ports_or_else(|| UNROUTABLE.to_string())
+    }
+
+    /// Like [`RouteDecision::into_ports`], but panics with `message`, for policies which can say more about why.
+    pub fn into_ports_or_else(self, message: impl FnOnce() -> String) -> fxhash::FxHashSet<usize> {
+        match self {
+            RouteDecision::Forward(targets) => targets,
+            RouteDecision::Drop => Default::default(),
+            RouteDecision::Error => panic!("{}", message()),
+        }
+    }
+}
+
+/// How busy one of a switch's outputs has been lately.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct OutputMetrics {
+    /// How many packets the output has sent in the switch's current metrics window.
+    pub sent: u64,
+    /// Whether the output sent something on the previous cycle.
+    pub busy_last_cycle: bool,
+    /// How many times a packet was turned away by the output's channel being full, since the switch started.
+    pub send_failures: u64,
+}
+
+/// What a switch can tell its policy about its outputs and the packet being routed, for policies which need more
+/// than the destination to go by.
+#[derive(Clone, Debug, Default)]
+pub struct RouteContext {
+    /// The metrics for every output, keyed by port ID. Switches list all of their outputs here.
+    pub outputs: BTreeMap<usize, OutputMetrics>,
+    /// The [`crate::switches::routing::Flow`] the packet being routed belongs to, for switches which keep track.
+    pub flow: Option<u64>,
+    /// The cycle the packet is being routed on.
+    pub now: u64,
+    /// The input port the packet came in on.
+    pub ingress: Option<usize>,
+}
+
 /// A Policy is a (possibly) time-varying mapping between target locations and their output ports.
 pub trait Policy<LocationType> {
     fn route(&mut self, target: &LocationType) -> fxhash::FxHashSet<usize>;
+
+    /// Like [`Policy::route`], but reports unknown targets instead of panicking.
+    fn try_route(&mut self, target: &LocationType) -> RouteDecision {
+        RouteDecision::Forward(self.route(target))
+    }
+
+    /// Like [`Policy::try_route`], but also told how busy the switch's outputs have been, so that it can steer
+    /// packets around congestion. Switches route everything through here. Policies which don't adapt can leave this
+    /// alone, and are asked through [`Policy::route_at`] with the cycle in [`RouteContext::now`].
+    fn route_adaptive(&mut self, target: &LocationType, context: &RouteContext) -> RouteDecision {
+        self.route_at(target, context.now)
+    }
+
+    /// Like [`Policy::try_route`], but told which cycle it is, for policies which change over time.
+    fn route_at(&mut self, target: &LocationType, _now: u64) -> RouteDecision {
+        self.try_route(target)
+    }
+
+    /// The port a packet for `target` should leave the next switch by, once it's gone out of `output`, for switches
+    /// which route a hop ahead. Policies which don't know about the switches next door leave it to them.
+    fn next_hop(&mut self, _output: usize, _target: &LocationType) -> Option<usize> {
+        None
+    }
+}
+
+/// Plain functions and closures from a location to its ports are policies too, for routing which doesn't need a type
+/// of its own.
+impl<LocationType, F> Policy<LocationType> for F
+where
+    F: FnMut(&LocationType) -> fxhash::FxHashSet<usize>,
+{
+    fn route(&mut self, target: &LocationType) -> fxhash::FxHashSet<usize> {
+        self(target)
+    }
 }
 
 impl<LocationType: Eq + std::hash::Hash> Policy<LocationType>
     for fxhash::FxHashMap<LocationType, fxhash::FxHashSet<usize>>
 {
     fn route(&mut self, target: &LocationType) -> fxhash::FxHashSet<usize> {
-        match self.get(&target) {
-            Some(set) => set.clone(),
-            None => panic!("Could not find appropriate routing for location!"),
+        self.try_route(target).into_ports()
+    }
+
+    fn try_route(&mut self, target: &LocationType) -> RouteDecision {
+        match self.get(target) {
+            Some(set) => RouteDecision::Forward(set.clone()),
+            None => RouteDecision::Error,
+        }
+    }
+}
+
+/// Equal-cost multipath routing, which spreads flows over every port that leads to their destination. Each flow
+/// sticks to a single port, picked by hashing its ID, so that its packets can't overtake each other. Flow IDs come
+/// from the switch, which needs to be set up to read them, as with [`crate::switches::SimpleSwitch::with_flows`].
+#[derive(Clone, Debug, Default)]
+pub struct EcmpPolicy<LocationType> {
+    pub candidates: fxhash::FxHashMap<LocationType, Vec<usize>>,
+}
+
+impl<LocationType> EcmpPolicy<LocationType> {
+    pub fn new(candidates: fxhash::FxHashMap<LocationType, Vec<usize>>) -> Self {
+        assert!(
+            candidates.values().all(|ports| !ports.is_empty()),
+            "Every destination needs at least one candidate port!"
+        );
+        Self { candidates }
+    }
+}
+
+impl<LocationType: Eq + std::hash::Hash> Policy<LocationType> for EcmpPolicy<LocationType> {
+    fn route(&mut self, target: &LocationType) -> fxhash::FxHashSet<usize> {
+        self.try_route(target).into_ports()
+    }
+
+    fn try_route(&mut self, target: &LocationType) -> RouteDecision {
+        self.route_adaptive(target, &RouteContext::default())
+    }
+
+    fn route_adaptive(&mut self, target: &LocationType, context: &RouteContext) -> RouteDecision {
+        match self.candidates.get(target) {
+            Some(ports) => {
+                // Without a flow to go by, everything shares the first port.
+                let index = context
+                    .flow
+                    .map_or(0, |flow| fxhash::hash64(&flow) % ports.len() as u64);
+                RouteDecision::Forward(fxhash::FxHashSet::from_iter([ports[index as usize]]))
+            }
+            None => RouteDecision::Error,
+        }
+    }
+}
+
+/// Oblivious load balancing, which sends each packet out of a uniformly random one of the ports that lead to its
+/// destination. The choices come from a generator seeded up front, so the same seed and the same packets always get
+/// routed the same way.
+#[derive(Clone, Debug)]
+pub struct RandomPolicy<LocationType> {
+    pub candidates: fxhash::FxHashMap<LocationType, Vec<usize>>,
+    rng: SplitMix64,
+}
+
+impl<LocationType> RandomPolicy<LocationType> {
+    pub fn new(candidates: fxhash::FxHashMap<LocationType, Vec<usize>>, seed: u64) -> Self {
+        assert!(
+            candidates.values().all(|ports| !ports.is_empty()),
+            "Every destination needs at least one candidate port!"
+        );
+        Self {
+            candidates,
+            rng: SplitMix64::new(seed),
+        }
+    }
+}
+
+impl<LocationType: Eq + std::hash::Hash> Policy<LocationType> for RandomPolicy<LocationType> {
+    fn route(&mut self, target: &LocationType) -> fxhash::FxHashSet<usize> {
+        self.try_route(target).into_ports()
+    }
+
+    fn try_route(&mut self, target: &LocationType) -> RouteDecision {
+        match self.candidates.get(target) {
+            Some(ports) => {
+                let index = self.rng.below(ports.len() as u64) as usize;
+                RouteDecision::Forward(fxhash::FxHashSet::from_iter([ports[index]]))
+            }
+            None => RouteDecision::Error,
+        }
+    }
+}
+
+/// Like [`RandomPolicy`], but for paths which shouldn't all carry the same share of the traffic. Each destination's
+/// candidates come with weights, and a port is picked with probability proportional to its weight.
+#[derive(Clone, Debug)]
+pub struct WeightedPolicy<LocationType> {
+    pub candidates: fxhash::FxHashMap<LocationType, Vec<(usize, u32)>>,
+    rng: SplitMix64,
+}
+
+impl<LocationType> WeightedPolicy<LocationType> {
+    /// Takes each destination's candidates as (port, weight) pairs.
+    pub fn new(candidates: fxhash::FxHashMap<LocationType, Vec<(usize, u32)>>, seed: u64) -> Self {
+        assert!(
+            candidates.values().all(|ports| !ports.is_empty()),
+            "Every destination needs at least one candidate port!"
+        );
+        assert!(
+            candidates.values().flatten().all(|(_, weight)| *weight > 0),
+            "Weights must be positive!"
+        );
+        Self {
+            candidates,
+            rng: SplitMix64::new(seed),
+        }
+    }
+}
+
+impl<LocationType: Eq + std::hash::Hash> Policy<LocationType> for WeightedPolicy<LocationType> {
+    fn route(&mut self, target: &LocationType) -> fxhash::FxHashSet<usize> {
+        self.try_route(target).into_ports()
+    }
+
+    fn try_route(&mut self, target: &LocationType) -> RouteDecision {
+        let Some(ports) = self.candidates.get(target) else {
+            return RouteDecision::Error;
+        };
+        let total: u64 = ports.iter().map(|(_, weight)| *weight as u64).sum();
+        let mut pick = self.rng.below(total);
+        for (port, weight) in ports {
+            match pick.checked_sub(*weight as u64) {
+                Some(rest) => pick = rest,
+                None => return RouteDecision::Forward(fxhash::FxHashSet::from_iter([*port])),
+            }
+        }
+        unreachable!("The pick is always below the total weight")
+    }
+}
+
+/// Packet by packet spraying, which hands out the ports leading to each destination in turn, one per packet. Unlike
+/// [`EcmpPolicy`], packets of the same flow can take different paths, so they may well arrive out of order.
+#[derive(Clone, Debug, Default)]
+pub struct SprayPolicy<LocationType> {
+    pub candidates: fxhash::FxHashMap<LocationType, Vec<usize>>,
+    // The index of the candidate each destination goes to next.
+    cursors: fxhash::FxHashMap<LocationType, usize>,
+}
+
+impl<LocationType> SprayPolicy<LocationType> {
+    pub fn new(candidates: fxhash::FxHashMap<LocationType, Vec<usize>>) -> Self {
+        assert!(
+            candidates.values().all(|ports| !ports.is_empty()),
+            "Every destination needs at least one candidate port!"
+        );
+        Self {
+            candidates,
+            cursors: Default::default(),
+        }
+    }
+}
+
+impl<LocationType: Eq + std::hash::Hash + Clone> Policy<LocationType>
+    for SprayPolicy<LocationType>
+{
+    fn route(&mut self, target: &LocationType) -> fxhash::FxHashSet<usize> {
+        self.try_route(target).into_ports()
+    }
+
+    fn try_route(&mut self, target: &LocationType) -> RouteDecision {
+        let Some(ports) = self.candidates.get(target) else {
+            return RouteDecision::Error;
+        };
+        let cursor = self.cursors.entry(target.clone()).or_default();
+        let port = ports[*cursor];
+        *cursor = (*cursor + 1) % ports.len();
+        RouteDecision::Forward(fxhash::FxHashSet::from_iter([port]))
+    }
+}
+
+/// Switches between policies at set times, such as to reconfigure the network partway through a simulation. Each
+/// policy in the schedule takes over on its activation cycle, and stays in charge until the next one does.
+///
+/// Calls which aren't told the time go by the latest time the policy has been told about.
+#[derive(Clone, Debug)]
+pub struct ScheduledPolicy<P> {
+    schedule: Vec<(u64, P)>,
+    now: u64,
+}
+
+impl<P> ScheduledPolicy<P> {
+    /// Takes the schedule as (activation cycle, policy) pairs, in any order. Something has to be in charge from the
+    /// start, so the first policy must activate on cycle 0.
+    pub fn new(mut schedule: Vec<(u64, P)>) -> Self {
+        schedule.sort_by_key(|(activation, _)| *activation);
+        assert!(
+            schedule
+                .first()
+                .is_some_and(|(activation, _)| *activation == 0),
+            "The first policy must activate on cycle 0!"
+        );
+        assert!(
+            schedule.windows(2).all(|pair| pair[0].0 != pair[1].0),
+            "Only one policy can activate on each cycle!"
+        );
+        Self { schedule, now: 0 }
+    }
+
+    /// The policy in charge on cycle `now`.
+    fn active(&mut self, now: u64) -> &mut P {
+        self.now = self.now.max(now);
+        let index = self
+            .schedule
+            .partition_point(|(activation, _)| *activation <= now);
+        &mut self.schedule[index - 1].1
+    }
+}
+
+impl<LocationType, P: Policy<LocationType>> Policy<LocationType> for ScheduledPolicy<P> {
+    fn route(&mut self, target: &LocationType) -> fxhash::FxHashSet<usize> {
+        self.route_at(target, self.now).into_ports()
+    }
+
+    fn try_route(&mut self, target: &LocationType) -> RouteDecision {
+        self.active(self.now).try_route(target)
+    }
+
+    fn route_adaptive(&mut self, target: &LocationType, context: &RouteContext) -> RouteDecision {
+        self.active(context.now).route_adaptive(target, context)
+    }
+
+    fn route_at(&mut self, target: &LocationType, now: u64) -> RouteDecision {
+        self.active(now).route_at(target, now)
+    }
+}
+
+/// Remembers where a policy sent each destination, so that it's only asked once per destination, however many packets
+/// go there and however many times they're retried. This is only right for policies which always route a destination
+/// the same way: adaptive routing answers from the cache as well, without looking at the [`RouteContext`].
+///
+/// The inner policy can be changed through [`CachedPolicy::inner`], after which the destinations it changed for need
+/// to be invalidated, or the whole cache cleared.
+#[derive(Clone, Debug)]
+pub struct CachedPolicy<LocationType, P> {
+    pub inner: P,
+    cache: fxhash::FxHashMap<LocationType, RouteDecision>,
+}
+
+impl<LocationType: Eq + std::hash::Hash, P> CachedPolicy<LocationType, P> {
+    pub fn new(inner: P) -> Self {
+        Self {
+            inner,
+            cache: Default::default(),
+        }
+    }
+
+    /// Forgets where `target` goes, so that the inner policy is asked again next time.
+    pub fn invalidate(&mut self, target: &LocationType) {
+        self.cache.remove(target);
+    }
+
+    /// Forgets every destination.
+    pub fn clear(&mut self) {
+        self.cache.clear();
+    }
+}
+
+impl<LocationType: Eq + std::hash::Hash + Clone, P: Policy<LocationType>> Policy<LocationType>
+    for CachedPolicy<LocationType, P>
+{
+    fn route(&mut self, target: &LocationType) -> fxhash::FxHashSet<usize> {
+        self.try_route(target).into_ports()
+    }
+
+    fn try_route(&mut self, target: &LocationType) -> RouteDecision {
+        if let Some(decision) = self.cache.get(target) {
+            return decision.clone();
+        }
+        let decision = self.inner.try_route(target);
+        self.cache.insert(target.clone(), decision.clone());
+        decision
+    }
+
+    fn route_adaptive(&mut self, target: &LocationType, _context: &RouteContext) -> RouteDecision {
+        self.try_route(target)
+    }
+}
+
+/// How often each policy in a [`ChainPolicy`] was the one to route a packet.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ChainStats {
+    /// How many packets each policy routed, in chain order.
+    pub hits: Vec<u64>,
+    /// How many packets no policy in the chain could route.
+    pub exhausted: u64,
+}
+
+/// Tries each of its policies in turn, and goes with the first one which knows where the packet should go, such as a
+/// static table followed by a default gateway. Policies which drop a packet count as knowing what to do with it.
+/// Packets none of them can route aren't routable, unless the chain is set up to drop them.
+pub struct ChainPolicy<LocationType> {
+    links: Vec<Box<dyn Policy<LocationType> + Send + Sync>>,
+    drop_exhausted: bool,
+    stats: std::sync::Arc<std::sync::Mutex<ChainStats>>,
+}
+
+impl<LocationType> ChainPolicy<LocationType> {
+    pub fn new(links: Vec<Box<dyn Policy<LocationType> + Send + Sync>>) -> Self {
+        let stats = ChainStats {
+            hits: vec![0; links.len()],
+            exhausted: 0,
+        };
+        Self {
+            links,
+            drop_exhausted: false,
+            stats: std::sync::Arc::new(std::sync::Mutex::new(stats)),
+        }
+    }
+
+    /// Throws away packets none of the policies can route, instead of reporting them as unroutable.
+    pub fn with_exhausted_dropped(mut self) -> Self {
+        self.drop_exhausted = true;
+        self
+    }
+
+    /// How often each policy routed a packet. The handle is shared with the chain, so it can be read once the switch
+    /// the chain was given to is done.
+    pub fn stats(&self) -> std::sync::Arc<std::sync::Mutex<ChainStats>> {
+        self.stats.clone()
+    }
+
+    /// Goes down the chain until `decide` gets something other than an error out of one of the policies.
+    fn first(
+        &mut self,
+        mut decide: impl FnMut(&mut (dyn Policy<LocationType> + Send + Sync)) -> RouteDecision,
+    ) -> RouteDecision {
+        let mut stats = self.stats.lock().unwrap();
+        for (index, link) in self.links.iter_mut().enumerate() {
+            match decide(link.as_mut()) {
+                RouteDecision::Error => continue,
+                decision => {
+                    stats.hits[index] += 1;
+                    return decision;
+                }
+            }
+        }
+        stats.exhausted += 1;
+        match self.drop_exhausted {
+            true => RouteDecision::Drop,
+            false => RouteDecision::Error,
+        }
+    }
+}
+
+impl<LocationType> Policy<LocationType> for ChainPolicy<LocationType> {
+    fn route(&mut self, target: &LocationType) -> fxhash::FxHashSet<usize> {
+        self.try_route(target).into_ports()
+    }
+
+    fn try_route(&mut self, target: &LocationType) -> RouteDecision {
+        self.first(|link| link.try_route(target))
+    }
+
+    fn route_adaptive(&mut self, target: &LocationType, context: &RouteContext) -> RouteDecision {
+        self.first(|link| link.route_adaptive(target, context))
+    }
+}
+
+/// Policies which need to see the whole packet to route it, rather than just its destination. Switches built with
+/// [`crate::switches::SimpleSwitch::with_packet_routing`] route through these instead of [`Policy`].
+pub trait PacketPolicy<T> {
+    fn route_packet(&mut self, packet: &T) -> fxhash::FxHashSet<usize>;
+
+    fn try_route_packet(&mut self, packet: &T) -> RouteDecision {
+        RouteDecision::Forward(self.route_packet(packet))
+    }
+
+    fn route_packet_adaptive(&mut self, packet: &T, _context: &RouteContext) -> RouteDecision {
+        self.try_route_packet(packet)
+    }
+}
+
+/// Routes each class of service by a policy of its own, such as a plane of low-latency ports for control traffic and
+/// the wide ports for data. Packets of a class with no policy aren't routable.
+///
+/// Locations routed without their packets, as by [`crate::switches::SimpleSwitch::validate_policy`], go by the
+/// default class if there is one, and aren't routable otherwise.
+pub struct PerClassPolicy<LocationType> {
+    classes: fxhash::FxHashMap<u8, Box<dyn Policy<LocationType> + Send + Sync>>,
+    default_class: Option<u8>,
+}
+
+impl<LocationType> PerClassPolicy<LocationType> {
+    pub fn new() -> Self {
+        Self {
+            classes: Default::default(),
+            default_class: None,
+        }
+    }
+
+    /// Routes packets of `class` by `policy`, in place of any policy the class already had.
+    pub fn with_class(
+        mut self,
+        class: u8,
+        policy: impl Policy<LocationType> + Send + Sync + 'static,
+    ) -> Self {
+        self.classes.insert(class, Box::new(policy));
+        self
+    }
+
+    /// Routes bare locations as if their packets were of `class`.
+    pub fn with_default_class(mut self, class: u8) -> Self {
+        self.default_class = Some(class);
+        self
+    }
+
+    fn decide(
+        &mut self,
+        class: Option<u8>,
+        decide: impl FnOnce(&mut (dyn Policy<LocationType> + Send + Sync)) -> RouteDecision,
+    ) -> RouteDecision {
+        match class.and_then(|class| self.classes.get_mut(&class)) {
+            Some(policy) => decide(policy.as_mut()),
+            None => RouteDecision::Error,
+        }
+    }
+}
+
+impl<LocationType> Default for PerClassPolicy<LocationType> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<LocationType> Policy<LocationType> for PerClassPolicy<LocationType> {
+    fn route(&mut self, target: &LocationType) -> fxhash::FxHashSet<usize> {
+        self.try_route(target).into_ports()
+    }
+
+    fn try_route(&mut self, target: &LocationType) -> RouteDecision {
+        self.decide(self.default_class, |policy| policy.try_route(target))
+    }
+
+    fn route_adaptive(&mut self, target: &LocationType, context: &RouteContext) -> RouteDecision {
+        self.decide(self.default_class, |policy| {
+            policy.route_adaptive(target, context)
+        })
+    }
+}
+
+impl<LocationType, T: Packet<LocationType> + ClassedPacket> PacketPolicy<T>
+    for PerClassPolicy<LocationType>
+{
+    fn route_packet(&mut self, packet: &T) -> fxhash::FxHashSet<usize> {
+        self.try_route_packet(packet).into_ports()
+    }
+
+    fn try_route_packet(&mut self, packet: &T) -> RouteDecision {
+        self.decide(Some(packet.class()), |policy| {
+            policy.try_route(&packet.destination())
+        })
+    }
+
+    fn route_packet_adaptive(&mut self, packet: &T, context: &RouteContext) -> RouteDecision {
+        self.decide(Some(packet.class()), |policy| {
+            policy.route_adaptive(&packet.destination(), context)
+        })
+    }
+}
+
+/// Routes a hop ahead, by knowing the policy of the switch on the other end of each output. Packets are routed by
+/// `local` as usual, and the next switch's port is whatever the policy behind their output would pick for them. That's
+/// only right if the neighbors make the same decisions as the policies here, so this is for policies which always
+/// route a destination the same way. Packets the next switch would multicast or couldn't route get no port, and that
+/// switch routes them itself.
+#[derive(Clone, Debug, Default)]
+pub struct LookaheadPolicy<P> {
+    pub local: P,
+    /// The policy of the switch behind each output, keyed by port ID.
+    pub neighbors: fxhash::FxHashMap<usize, P>,
+}
+
+impl<P> LookaheadPolicy<P> {
+    pub fn new(local: P, neighbors: fxhash::FxHashMap<usize, P>) -> Self {
+        Self { local, neighbors }
+    }
+}
+
+impl<LocationType, P: Policy<LocationType>> Policy<LocationType> for LookaheadPolicy<P> {
+    fn route(&mut self, target: &LocationType) -> fxhash::FxHashSet<usize> {
+        self.local.route(target)
+    }
+
+    fn try_route(&mut self, target: &LocationType) -> RouteDecision {
+        self.local.try_route(target)
+    }
+
+    fn route_adaptive(&mut self, target: &LocationType, context: &RouteContext) -> RouteDecision {
+        self.local.route_adaptive(target, context)
+    }
+
+    fn route_at(&mut self, target: &LocationType, now: u64) -> RouteDecision {
+        self.local.route_at(target, now)
+    }
+
+    fn next_hop(&mut self, output: usize, target: &LocationType) -> Option<usize> {
+        match self.neighbors.get_mut(&output)?.try_route(target) {
+            RouteDecision::Forward(ports) if ports.len() == 1 => ports.into_iter().next(),
+            _ => None,
+        }
+    }
+}
+
+/// Floods every packet out of all of the switch's outputs, except the one leading back to where the packet came in.
+/// The ports come from the [`RouteContext`], so this only routes through [`Policy::route_adaptive`]; asked any other
+/// way, it has no ports to go by.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FloodPolicy;
+
+impl<LocationType> Policy<LocationType> for FloodPolicy {
+    fn route(&mut self, _target: &LocationType) -> fxhash::FxHashSet<usize> {
+        panic!("Flooding needs to know the switch's outputs!")
+    }
+
+    fn try_route(&mut self, _target: &LocationType) -> RouteDecision {
+        RouteDecision::Error
+    }
+
+    fn route_adaptive(&mut self, _target: &LocationType, context: &RouteContext) -> RouteDecision {
+        let targets = context
+            .outputs
+            .keys()
+            .copied()
+            .filter(|port| Some(*port) != context.ingress)
+            .collect();
+        RouteDecision::Forward(targets)
+    }
+}
+
+/// Sends each packet out of the port its path names next, for
+/// [`crate::switches::routing::SourceRoutedPacket`]s. Packets whose path has run out, or names a port the switch
+/// doesn't have, aren't routable.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SourceRoutePolicy;
+
+impl Policy<Option<usize>> for SourceRoutePolicy {
+    fn route(&mut self, target: &Option<usize>) -> fxhash::FxHashSet<usize> {
+        self.try_route(target)
+            .into_ports_or_else(|| "The packet's path has run out!".to_string())
+    }
+
+    fn try_route(&mut self, target: &Option<usize>) -> RouteDecision {
+        match target {
+            Some(port) => RouteDecision::Forward(fxhash::FxHashSet::from_iter([*port])),
+            None => RouteDecision::Error,
+        }
+    }
+
+    fn route_adaptive(&mut self, target: &Option<usize>, context: &RouteContext) -> RouteDecision {
+        match target {
+            Some(port) if !context.outputs.contains_key(port) => RouteDecision::Error,
+            _ => self.try_route(target),
+        }
+    }
+}
+
+/// Locations which are bitmasks, for [`BitmaskPolicy`].
+pub trait Bitmask {
+    fn bits(&self) -> u64;
+}
+
+impl Bitmask for u8 {
+    fn bits(&self) -> u64 {
+        *self as u64
+    }
+}
+
+impl Bitmask for u16 {
+    fn bits(&self) -> u64 {
+        *self as u64
+    }
+}
+
+impl Bitmask for u32 {
+    fn bits(&self) -> u64 {
+        *self as u64
+    }
+}
+
+impl Bitmask for u64 {
+    fn bits(&self) -> u64 {
+        *self
+    }
+}
+
+/// Multicast routing for locations which are bitmasks of endpoints, where bit `i` being set means the packet goes to
+/// the port at `ports[i]`. Masks with bits set past the end of `ports` aren't routable. Masks with no bits set aren't
+/// routable either, unless the policy is set up to drop them.
+#[derive(Clone, Debug, Default)]
+pub struct BitmaskPolicy {
+    pub ports: Vec<usize>,
+    drop_empty: bool,
+}
+
+impl BitmaskPolicy {
+    pub fn new(ports: Vec<usize>) -> Self {
+        assert!(ports.len() <= 64, "Bitmasks only have 64 bits!");
+        Self {
+            ports,
+            drop_empty: false,
+        }
+    }
+
+    /// Throws away packets addressed to nobody, instead of reporting them as unroutable.
+    pub fn with_empty_dropped(mut self) -> Self {
+        self.drop_empty = true;
+        self
+    }
+}
+
+impl<LocationType: Bitmask> Policy<LocationType> for BitmaskPolicy {
+    fn route(&mut self, target: &LocationType) -> fxhash::FxHashSet<usize> {
+        self.try_route(target).into_ports()
+    }
+
+    fn try_route(&mut self, target: &LocationType) -> RouteDecision {
+        let mask = target.bits();
+        if mask == 0 {
+            return match self.drop_empty {
+                true => RouteDecision::Drop,
+                false => RouteDecision::Error,
+            };
+        }
+        if (64 - mask.leading_zeros()) as usize > self.ports.len() {
+            return RouteDecision::Error;
+        }
+        let targets = (0..self.ports.len())
+            .filter(|bit| mask & (1 << bit) != 0)
+            .map(|bit| self.ports[bit])
+            .collect();
+        RouteDecision::Forward(targets)
+    }
+}
+
+/// Longest prefix match routing, for hierarchical locations like (chip, tile, core). Each entry covers every
+/// location starting with its prefix, and a location goes wherever the longest prefix it starts with does. A prefix of
+/// length zero covers everything, which makes it a default route. Locations no prefix covers aren't routable.
+#[derive(Clone, Debug)]
+pub struct LpmPolicy<const N: usize, E> {
+    // The entries for each prefix length, from 0 up to N.
+    prefixes: Vec<fxhash::FxHashMap<Vec<E>, fxhash::FxHashSet<usize>>>,
+}
+
+impl<const N: usize, E: Eq + std::hash::Hash + Clone> LpmPolicy<N, E> {
+    pub fn new() -> Self {
+        Self {
+            prefixes: (0..=N).map(|_| Default::default()).collect(),
+        }
+    }
+
+    /// Routes every location starting with `prefix` to `ports`, unless a longer prefix takes it. Adding the same entry
+    /// twice is fine, but a prefix can't be given a second, different set of ports.
+    pub fn insert(
+        &mut self,
+        prefix: &[E],
+        ports: fxhash::FxHashSet<usize>,
+    ) -> Result<(), PrefixConflict<E>> {
+        assert!(
+            prefix.len() <= N,
+            "Prefixes can't be longer than the locations they match!"
+        );
+        let entries = &mut self.prefixes[prefix.len()];
+        match entries.get(prefix) {
+            Some(existing) if *existing != ports => Err(PrefixConflict {
+                prefix: prefix.to_vec(),
+                existing: existing.clone(),
+            }),
+            _ => {
+                entries.insert(prefix.to_vec(), ports);
+                Ok(())
+            }
+        }
+    }
+}
+
+impl<const N: usize, E: Eq + std::hash::Hash + Clone> Default for LpmPolicy<N, E> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize, E: Eq + std::hash::Hash> Policy<[E; N]> for LpmPolicy<N, E> {
+    fn route(&mut self, target: &[E; N]) -> fxhash::FxHashSet<usize> {
+        self.try_route(target).into_ports()
+    }
+
+    fn try_route(&mut self, target: &[E; N]) -> RouteDecision {
+        (0..=N)
+            .rev()
+            .find_map(|length| self.prefixes[length].get(&target[..length]))
+            .map_or(RouteDecision::Error, |ports| {
+                RouteDecision::Forward(ports.clone())
+            })
+    }
+}
+
+/// A prefix which was already routed somewhere else, turned away by [`LpmPolicy::insert`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PrefixConflict<E> {
+    pub prefix: Vec<E>,
+    pub existing: fxhash::FxHashSet<usize>,
+}
+
+impl<E: std::fmt::Debug> std::fmt::Display for PrefixConflict<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "The prefix {:?} is already routed to {:?}",
+            self.prefix, self.existing
+        )
+    }
+}
+
+impl<E: std::fmt::Debug> std::error::Error for PrefixConflict<E> {}
+
+/// Routing for dense address spaces, where each contiguous range of locations goes to the same ports, so that the
+/// table only needs an entry per range. Lookups take logarithmic time in the number of ranges. Locations in the gaps
+/// between ranges aren't routable, unless the policy is given a default for them or set up to drop them.
+#[derive(Clone, Debug)]
+pub struct RangePolicy<LocationType> {
+    // Each range's first location, mapped to its last location and its ports.
+    ranges: BTreeMap<LocationType, (LocationType, fxhash::FxHashSet<usize>)>,
+    gaps: RouteDecision,
+}
+
+impl<LocationType: Ord + Clone> RangePolicy<LocationType> {
+    pub fn new() -> Self {
+        Self {
+            ranges: Default::default(),
+            gaps: RouteDecision::Error,
+        }
+    }
+
+    /// Routes every location in `range` to `ports`. Ranges can't overlap any which are already in the table.
+    pub fn insert(
+        &mut self,
+        range: std::ops::RangeInclusive<LocationType>,
+        ports: fxhash::FxHashSet<usize>,
+    ) -> Result<(), RangeOverlap<LocationType>> {
+        let (start, end) = range.into_inner();
+        assert!(start <= end, "Ranges must not be empty!");
+        // The range starting at or before this one, and the first one starting after it, are the only ones which
+        // could overlap it.
+        let before = self
+            .ranges
+            .range(..=&start)
+            .next_back()
+            .filter(|(_, (last, _))| *last >= start);
+        let after = self
+            .ranges
+            .range((
+                std::ops::Bound::Excluded(&start),
+                std::ops::Bound::Unbounded,
+            ))
+            .next()
+            .filter(|(first, _)| **first <= end);
+        if let Some((first, (last, _))) = before.or(after) {
+            return Err(RangeOverlap {
+                range: start..=end,
+                existing: first.clone()..=last.clone(),
+            });
+        }
+        self.ranges.insert(start, (end, ports));
+        Ok(())
+    }
+
+    /// Sends locations which aren't in any range to `ports`.
+    pub fn with_default(mut self, ports: fxhash::FxHashSet<usize>) -> Self {
+        self.gaps = RouteDecision::Forward(ports);
+        self
+    }
+
+    /// Throws away packets for locations which aren't in any range, instead of reporting them as unroutable.
+    pub fn with_gaps_dropped(mut self) -> Self {
+        self.gaps = RouteDecision::Drop;
+        self
+    }
+}
+
+impl<LocationType: Ord + Clone> Default for RangePolicy<LocationType> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<LocationType: Ord> Policy<LocationType> for RangePolicy<LocationType> {
+    fn route(&mut self, target: &LocationType) -> fxhash::FxHashSet<usize> {
+        self.try_route(target).into_ports()
+    }
+
+    fn try_route(&mut self, target: &LocationType) -> RouteDecision {
+        match self.ranges.range(..=target).next_back() {
+            Some((_, (last, ports))) if last >= target => RouteDecision::Forward(ports.clone()),
+            _ => self.gaps.clone(),
+        }
+    }
+}
+
+/// A range which overlapped one already in the table, turned away by [`RangePolicy::insert`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RangeOverlap<LocationType> {
+    pub range: std::ops::RangeInclusive<LocationType>,
+    pub existing: std::ops::RangeInclusive<LocationType>,
+}
+
+impl<LocationType: std::fmt::Debug> std::fmt::Display for RangeOverlap<LocationType> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "The range {:?} overlaps {:?}", self.range, self.existing)
+    }
+}
+
+impl<LocationType: std::fmt::Debug> std::error::Error for RangeOverlap<LocationType> {}
+
+/// A routing table which sends everything it doesn't list to a default set of ports, like a default gateway.
+#[derive(Clone, Debug, Default)]
+pub struct TableWithDefault<LocationType> {
+    pub table: fxhash::FxHashMap<LocationType, fxhash::FxHashSet<usize>>,
+    pub default: fxhash::FxHashSet<usize>,
+}
+
+impl<LocationType> TableWithDefault<LocationType> {
+    pub fn new(
+        table: fxhash::FxHashMap<LocationType, fxhash::FxHashSet<usize>>,
+        default: fxhash::FxHashSet<usize>,
+    ) -> Self {
+        Self { table, default }
+    }
+}
+
+impl<LocationType: Eq + std::hash::Hash> Policy<LocationType> for TableWithDefault<LocationType> {
+    fn route(&mut self, target: &LocationType) -> fxhash::FxHashSet<usize> {
+        self.table.get(target).unwrap_or(&self.default).clone()
+    }
+}
+
+/// A routing table which many switches can share, so that a large network only needs to build and store it once.
+/// Cloning the policy only clones the handle to the table.
+#[derive(Clone, Debug, Default)]
+pub struct SharedTablePolicy<LocationType> {
+    table: Arc<fxhash::FxHashMap<LocationType, fxhash::FxHashSet<usize>>>,
+}
+
+impl<LocationType> SharedTablePolicy<LocationType> {
+    pub fn new(table: Arc<fxhash::FxHashMap<LocationType, fxhash::FxHashSet<usize>>>) -> Self {
+        Self { table }
+    }
+
+    pub fn table(&self) -> &Arc<fxhash::FxHashMap<LocationType, fxhash::FxHashSet<usize>>> {
+        &self.table
+    }
+}
+
+impl<LocationType> From<fxhash::FxHashMap<LocationType, fxhash::FxHashSet<usize>>>
+    for SharedTablePolicy<LocationType>
+{
+    fn from(table: fxhash::FxHashMap<LocationType, fxhash::FxHashSet<usize>>) -> Self {
+        Self::new(Arc::new(table))
+    }
+}
+
+impl<LocationType: Eq + std::hash::Hash> Policy<LocationType> for SharedTablePolicy<LocationType> {
+    fn route(&mut self, target: &LocationType) -> fxhash::FxHashSet<usize> {
+        self.try_route(target).into_ports()
+    }
+
+    fn try_route(&mut self, target: &LocationType) -> RouteDecision {
+        match self.table.get(target) {
+            Some(set) => RouteDecision::Forward(set.clone()),
+            None => RouteDecision::Error,
+        }
+    }
+}
+
+/// A change to a routing table, sent to a switch's control port. Updates take effect from the cycle they're stamped
+/// with.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PolicyUpdate<LocationType> {
+    pub destination: LocationType,
+    /// The ports to route the destination to from now on, or nothing to take its entry out of the table.
+    pub ports: Option<fxhash::FxHashSet<usize>>,
+}
+
+impl<LocationType> PolicyUpdate<LocationType> {
+    pub fn insert(destination: LocationType, ports: fxhash::FxHashSet<usize>) -> Self {
+        Self {
+            destination,
+            ports: Some(ports),
+        }
+    }
+
+    pub fn remove(destination: LocationType) -> Self {
+        Self {
+            destination,
+            ports: None,
+        }
+    }
+}
+
+impl<LocationType: DAMType> DAMType for PolicyUpdate<LocationType> {
+    fn dam_size(&self) -> usize {
+        let ports = self
+            .ports
+            .iter()
+            .flatten()
+            .map(DAMType::dam_size)
+            .sum::<usize>();
+        self.destination.dam_size() + ports
+    }
+}
+
+/// Policies backed by a table which can be changed while the simulation runs, through a switch's control port.
+pub trait UpdatablePolicy<LocationType> {
+    fn apply(&mut self, update: PolicyUpdate<LocationType>);
+}
+
+impl<LocationType: Eq + std::hash::Hash> UpdatablePolicy<LocationType>
+    for fxhash::FxHashMap<LocationType, fxhash::FxHashSet<usize>>
+{
+    fn apply(&mut self, update: PolicyUpdate<LocationType>) {
+        match update.ports {
+            Some(ports) => {
+                self.insert(update.destination, ports);
+            }
+            None => {
+                self.remove(&update.destination);
+            }
+        }
+    }
+}
+
+impl<LocationType: Eq + std::hash::Hash> UpdatablePolicy<LocationType>
+    for TableWithDefault<LocationType>
+{
+    fn apply(&mut self, update: PolicyUpdate<LocationType>) {
+        self.table.apply(update);
+    }
+}
+
+impl<LocationType: Eq + std::hash::Hash, P: UpdatablePolicy<LocationType>>
+    UpdatablePolicy<LocationType> for CachedPolicy<LocationType, P>
+{
+    fn apply(&mut self, update: PolicyUpdate<LocationType>) {
+        self.invalidate(&update.destination);
+        self.inner.apply(update);
+    }
+}
+
+/// A policy for virtual channel switches, which picks both the output port and the VC to use on it.
+/// Packets on VC switches are always unicast.
+pub trait VcPolicy<LocationType> {
+    /// Returns the (output port, output VC) for a packet arriving on `vc`.
+    fn route_vc(&mut self, target: &LocationType, vc: usize) -> (usize, usize);
+}
+
+impl<LocationType: Eq + std::hash::Hash> VcPolicy<LocationType>
+    for fxhash::FxHashMap<LocationType, (usize, usize)>
+{
+    fn route_vc(&mut self, target: &LocationType, _vc: usize) -> (usize, usize) {
+        match self.get(target) {
+            Some(route) => *route,
+            None => panic!("{}", UNROUTABLE),
+        }
+    }
+}
+
+/// Adapts a unicast [`Policy`] for VC switches, keeping every packet on the VC it arrived on.
+#[derive(Clone, Debug, Default)]
+pub struct SameVc<P>(pub P);
+
+impl<LocationType, P: Policy<LocationType>> VcPolicy<LocationType> for SameVc<P> {
+    fn route_vc(&mut self, target: &LocationType, vc: usize) -> (usize, usize) {
+        let targets = self.0.route(target);
+        assert_eq!(targets.len(), 1, "VC switches can't multicast!");
+        (targets.into_iter().next().unwrap(), vc)
+    }
+}
+
+/// A policy for deflection switches, which tells the ports a packet would like to leave through apart from the ones it
+/// can be pushed out of when it loses those. Packets on deflection switches are always unicast.
+pub trait DeflectionPolicy<LocationType> {
+    /// The ports which bring a packet closer to `target`, any one of which will do.
+    fn preferred(&mut self, target: &LocationType) -> fxhash::FxHashSet<usize>;
+
+    /// Whether a packet for `target` which lost all of its preferred ports may be deflected out of `port`. Every port is
+    /// fair game unless the policy says otherwise.
+    fn acceptable(&mut self, _target: &LocationType, _port: usize) -> bool {
+        true
+    }
+}
+
+/// Adapts a [`Policy`] for deflection switches, preferring the ports it routes to and deflecting onto any other.
+#[derive(Clone, Debug, Default)]
+pub struct DeflectAnywhere<P>(pub P);
+
+impl<LocationType, P: Policy<LocationType>> DeflectionPolicy<LocationType> for DeflectAnywhere<P> {
+    fn preferred(&mut self, target: &LocationType) -> fxhash::FxHashSet<usize> {
+        self.0.route(target)
+    }
+}
+
+/// What a switch does with packets which its policy can't route.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum UnroutableAction {
+    /// Bring down the simulation.
+    #[default]
+    Panic,
+    /// Throw the packet away, counting it in the switch's drop counters.
+    Drop,
+    /// Send the packet out of the given port instead. If the switch has no such output, the switch stops as it would
+    /// under [`UnroutableAction::Error`].
+    Divert(usize),
+    /// Stop the switch, leaving an [`UnroutableError`] behind in its stats.
+    Error,
+}
+
+impl UnroutableAction {
+    /// Settles what to do with a packet given its policy's decision, falling back on this action when the policy
+    /// couldn't route it. `has_output` reports whether a port exists. Diverting to a port which doesn't exist is
+    /// treated like having no route at all, so whatever comes back as [`RouteDecision::Error`] should stop the switch.
+    pub fn resolve(
+        self,
+        decision: RouteDecision,
+        has_output: impl Fn(usize) -> bool,
+    ) -> RouteDecision {
+        match decision {
+            RouteDecision::Error => match self {
+                UnroutableAction::Panic => panic!("{}", UNROUTABLE),
+                UnroutableAction::Drop => RouteDecision::Drop,
+                UnroutableAction::Divert(port) if has_output(port) => {
+                    RouteDecision::Forward(fxhash::FxHashSet::from_iter([port]))
+                }
+                UnroutableAction::Divert(_) | UnroutableAction::Error => RouteDecision::Error,
+            },
+            decision => decision,
+        }
+    }
+}
+
+/// Records where and when a switch gave up on a packet it couldn't route.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct UnroutableError {
+    pub port: usize,
+    pub cycle: u64,
+}
+
+impl std::fmt::Display for UnroutableError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Could not find appropriate routing for the packet on port {} at cycle {}",
+            self.port, self.cycle
+        )
+    }
+}
+
+impl std::error::Error for UnroutableError {}
+
+/// A destination a switch's policy couldn't route properly, found by
+/// [`crate::switches::SimpleSwitch::validate_policy`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PolicyError<LocationType> {
+    /// The policy sent `destination` to `port`, which the switch has no output for.
+    MissingPort {
+        destination: LocationType,
+        port: usize,
+    },
+    /// The policy had no route for `destination`, and the switch would have stopped on it.
+    Unroutable { destination: LocationType },
+}
+
+impl<LocationType: std::fmt::Debug> std::fmt::Display for PolicyError<LocationType> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PolicyError::MissingPort { destination, port } => write!(
+                f,
+                "{:?} is routed to port {}, which the switch has no output for",
+                destination, port
+            ),
+            PolicyError::Unroutable { destination } => {
+                write!(
+                    f,
+                    "Could not find appropriate routing for {:?}",
+                    destination
+                )
+            }
+        }
+    }
+}
+
+impl<LocationType: std::fmt::Debug> std::error::Error for PolicyError<LocationType> {}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use dam::context_tools::DAMType;
+    use fxhash::{FxHashMap, FxHashSet};
+
+    use crate::switches::{
+        routing::{Classed, Packet, SimplePacket, SourcedPacket},
+        testing::{connect, run_switch, stream},
+        SimpleSwitch,
+    };
+
+    use super::{
+        BitmaskPolicy, CachedPolicy, ChainPolicy, ChainStats, EcmpPolicy, FloodPolicy, LpmPolicy,
+        PacketPolicy, PerClassPolicy, Policy, PrefixConflict, RandomPolicy, RangeOverlap,
+        RangePolicy, RouteContext, RouteDecision, ScheduledPolicy, SharedTablePolicy, SprayPolicy,
+        TableWithDefault, WeightedPolicy,
+    };
+
+    const NUM_FLOWS: u64 = 1000;
+    const CANDIDATES: [usize; 3] = [1, 2, 3];
+    const DESTINATION: u8 = 9;
+
+    #[derive(Clone, Copy, Debug, Default)]
+    struct SourcedTestPacket {
+        source: u8,
+        payload: u16,
+    }
+
+    impl Packet<u8> for SourcedTestPacket {
+        fn destination(&self) -> u8 {
+            DESTINATION
+        }
+    }
+
+    impl SourcedPacket<u8> for SourcedTestPacket {
+        fn source(&self) -> u8 {
+            self.source
+        }
+    }
+
+    impl DAMType for SourcedTestPacket {
+        fn dam_size(&self) -> usize {
+            self.source.dam_size() + self.payload.dam_size()
+        }
+    }
+
+    fn ecmp() -> EcmpPolicy<u8> {
+        EcmpPolicy::new(FxHashMap::from_iter([(DESTINATION, CANDIDATES.to_vec())]))
+    }
+
+    #[test]
+    fn into_ports_test() {
+        let ports = FxHashSet::from_iter([1, 2]);
+        assert_eq!(RouteDecision::Forward(ports.clone()).into_ports(), ports);
+        assert!(RouteDecision::Drop.into_ports().is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "Could not find appropriate routing for location!")]
+    fn unknown_target_test() {
+        ecmp().route(&(DESTINATION + 1));
+    }
+
+    #[test]
+    fn table_with_default_test() {
+        let local =
+            FxHashMap::from_iter((1u8..4).map(|dst| (dst, FxHashSet::from_iter([dst as usize]))));
+        let mut policy = TableWithDefault::new(local, FxHashSet::from_iter([0]));
+
+        for dst in 1..4 {
+            assert_eq!(policy.route(&dst), FxHashSet::from_iter([dst as usize]));
+        }
+        for dst in [0, 4, 200] {
+            assert_eq!(policy.route(&dst), FxHashSet::from_iter([0]));
+        }
+        assert_eq!(
+            policy.try_route(&200),
+            RouteDecision::Forward(FxHashSet::from_iter([0]))
+        );
+    }
+
+    #[test]
+    fn shared_table_test() {
+        const SWITCHES: usize = 16;
+        let table: fxhash::FxHashMap<_, _> = (0..SWITCHES as u8)
+            .map(|destination| {
+                (
+                    destination,
+                    FxHashSet::from_iter([destination as usize % 4]),
+                )
+            })
+            .collect();
+        let table = std::sync::Arc::new(table);
+        let mut policies: Vec<_> = (0..SWITCHES)
+            .map(|_| SharedTablePolicy::new(table.clone()))
+            .collect();
+        drop(table);
+
+        // Every switch holds a handle to the one table, which outlives the builder's copy of it.
+        assert_eq!(std::sync::Arc::strong_count(policies[0].table()), SWITCHES);
+        for policy in policies.iter_mut() {
+            for destination in 0..SWITCHES as u8 {
+                let expected = FxHashSet::from_iter([destination as usize % 4]);
+                assert_eq!(policy.route(&destination), expected);
+            }
+            assert!(matches!(
+                policy.try_route(&(SWITCHES as u8)),
+                RouteDecision::Error
+            ));
+        }
+    }
+
+    #[test]
+    fn flows_spread_evenly_test() {
+        let mut policy = ecmp();
+        let mut counts = FxHashMap::<usize, u64>::default();
+        for flow in 0..NUM_FLOWS {
+            let context = RouteContext {
+                flow: Some(flow),
+                ..Default::default()
+            };
+            let decisions: Vec<_> = (0..4)
+                .map(|_| policy.route_adaptive(&DESTINATION, &context))
+                .collect();
+            // Every packet of a flow takes the same port.
+            assert!(decisions.windows(2).all(|pair| pair[0] == pair[1]));
+            let RouteDecision::Forward(ports) = &decisions[0] else {
+                panic!("{:?} wasn't routed", decisions[0]);
+            };
+            assert_eq!(ports.len(), 1);
+            *counts.entry(*ports.iter().next().unwrap()).or_default() += 1;
+        }
+
+        let fair_share = NUM_FLOWS / CANDIDATES.len() as u64;
+        for port in CANDIDATES {
+            let count = counts.get(&port).copied().unwrap_or(0);
+            assert!(
+                count.abs_diff(fair_share) < fair_share / 10,
+                "Port {} carried {} of {} flows",
+                port,
+                count,
+                NUM_FLOWS
+            );
+        }
+    }
+
+    #[test]
+    fn flows_stay_in_order_test() {
+        const NUM_PACKETS: u16 = 64;
+        // Packets from eight sources interleaved on one input, each source being a flow of its own.
+        let packets = (0..NUM_PACKETS).map(|payload| SourcedTestPacket {
+            source: (payload % 8) as u8,
+            payload,
+        });
+        let arrivals = run_switch(
+            [(0, stream(packets))],
+            CANDIDATES,
+            connect(SimpleSwitch::new(ecmp(), 1).with_flows()),
+        );
+
+        assert_eq!(arrivals.len(), NUM_PACKETS as usize);
+        let mut ports = FxHashMap::default();
+        let mut last_seen = FxHashMap::default();
+        for (port, _, packet) in &arrivals {
+            assert_eq!(*ports.entry(packet.source).or_insert(*port), *port);
+            if let Some(last) = last_seen.insert(packet.source, packet.payload) {
+                assert!(last < packet.payload);
+            }
+        }
+        // The flows don't all end up on the same port.
+        let used: FxHashSet<_> = ports.values().collect();
+        assert!(used.len() > 1);
+    }
+
+    const NUM_ROUTES: u64 = 10_000;
+
+    fn random(seed: u64) -> RandomPolicy<u8> {
+        RandomPolicy::new(
+            FxHashMap::from_iter([(DESTINATION, CANDIDATES.to_vec())]),
+            seed,
+        )
+    }
+
+    /// The port each of `NUM_ROUTES` packets to the destination is sent out of.
+    fn random_routes(seed: u64) -> Vec<usize> {
+        let mut policy = random(seed);
+        (0..NUM_ROUTES)
+            .map(|_| {
+                let ports = policy.route(&DESTINATION);
+                assert_eq!(ports.len(), 1);
+                ports.into_iter().next().unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn random_fair_share_test() {
+        let mut counts = FxHashMap::<usize, u64>::default();
+        for port in random_routes(7) {
+            *counts.entry(port).or_default() += 1;
+        }
+        let fair_share = NUM_ROUTES / CANDIDATES.len() as u64;
+        for port in CANDIDATES {
+            let count = counts.get(&port).copied().unwrap_or(0);
+            assert!(
+                count.abs_diff(fair_share) < fair_share * 3 / 100,
+                "Port {} carried {} of {} packets",
+                port,
+                count,
+                NUM_ROUTES
+            );
+        }
+        assert_eq!(
+            random(7).try_route(&(DESTINATION + 1)),
+            RouteDecision::Error
+        );
+    }
+
+    // A fast link taking most of the traffic, and a backup taking the rest.
+    const WEIGHTS: [(usize, u32); 2] = [(1, 80), (2, 20)];
+
+    /// The port each of `NUM_ROUTES` packets to the destination is sent out of, by weight.
+    fn weighted_routes(seed: u64) -> Vec<usize> {
+        let mut policy = WeightedPolicy::new(
+            FxHashMap::from_iter([(DESTINATION, WEIGHTS.to_vec())]),
+            seed,
+        );
+        (0..NUM_ROUTES)
+            .map(|_| policy.route(&DESTINATION).into_iter().next().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn weighted_split_test() {
+        let routes = weighted_routes(7);
+        for (port, weight) in WEIGHTS {
+            let share = routes.iter().filter(|routed| **routed == port).count() as f64;
+            let share = share / NUM_ROUTES as f64;
+            assert!(
+                (share - weight as f64 / 100.0).abs() < 0.02,
+                "Port {} carried {:.3} of the traffic",
+                port,
+                share
+            );
+        }
+        assert_eq!(weighted_routes(42), weighted_routes(42));
+    }
+
+    #[test]
+    #[should_panic(expected = "Weights must be positive!")]
+    fn zero_weight_test() {
+        WeightedPolicy::new(FxHashMap::from_iter([(DESTINATION, vec![(1, 0)])]), 0);
+    }
+
+    #[test]
+    fn spray_rotation_test() {
+        const SPRAY_PORTS: [usize; 4] = [3, 1, 4, 2];
+        let mut policy = SprayPolicy::new(FxHashMap::from_iter([
+            (DESTINATION, SPRAY_PORTS.to_vec()),
+            (DESTINATION + 1, vec![0]),
+        ]));
+
+        let mut counts = FxHashMap::<usize, u64>::default();
+        for i in 0..100 {
+            // Other destinations don't move this one's cursor along.
+            assert_eq!(policy.route(&(DESTINATION + 1)), FxHashSet::from_iter([0]));
+            let ports = policy.route(&DESTINATION);
+            assert_eq!(ports, FxHashSet::from_iter([SPRAY_PORTS[i % 4]]));
+            *counts.entry(SPRAY_PORTS[i % 4]).or_default() += 1;
+        }
+        assert!(SPRAY_PORTS.iter().all(|port| counts[port] == 25));
+        assert_eq!(policy.try_route(&0), RouteDecision::Error);
+    }
+
+    #[test]
+    fn random_reproducible_test() {
+        assert_eq!(random_routes(42), random_routes(42));
+        assert_ne!(random_routes(42), random_routes(43));
+    }
+
+    const SWITCH_OVER: u64 = 10;
+
+    /// Sends destination 0 out of port 1 until [`SWITCH_OVER`], and out of port 2 from then on.
+    fn scheduled() -> ScheduledPolicy<FxHashMap<u8, FxHashSet<usize>>> {
+        let table = |port| FxHashMap::from_iter([(0, FxHashSet::from_iter([port]))]);
+        ScheduledPolicy::new(vec![(SWITCH_OVER, table(2)), (0, table(1))])
+    }
+
+    #[test]
+    fn scheduled_route_at_test() {
+        let mut policy = scheduled();
+        let forward = |port| RouteDecision::Forward(FxHashSet::from_iter([port]));
+        assert_eq!(policy.route_at(&0, 0), forward(1));
+        assert_eq!(policy.route_at(&0, SWITCH_OVER - 1), forward(1));
+        assert_eq!(policy.route_at(&0, SWITCH_OVER), forward(2));
+        // Untimed routes go by the latest time so far.
+        assert_eq!(policy.route(&0), FxHashSet::from_iter([2]));
+        assert_eq!(policy.try_route(&1), RouteDecision::Error);
+    }
+
+    /// Goes by nothing but the time it is given, sending everything out of port 1 until [`SWITCH_OVER`], and out of
+    /// port 2 from then on.
+    struct TimedPolicy;
+
+    impl Policy<u8> for TimedPolicy {
+        fn route(&mut self, _target: &u8) -> FxHashSet<usize> {
+            panic!("TimedPolicy needs to be told the time!")
+        }
+
+        fn route_at(&mut self, _target: &u8, now: u64) -> RouteDecision {
+            let port = match now < SWITCH_OVER {
+                true => 1,
+                false => 2,
+            };
+            RouteDecision::Forward(FxHashSet::from_iter([port]))
+        }
+    }
+
+    /// Sends a packet a cycle through a switch with `policy`, and checks that those sent before [`SWITCH_OVER`] went
+    /// out of port 1 and the rest out of port 2.
+    fn check_switch_over(policy: impl Policy<u8> + Send + Sync + 'static) {
+        // One packet a cycle, each carrying the cycle it was sent on.
+        let packets = (1..2 * SWITCH_OVER as u16).map(|sent| SimplePacket {
+            location: 0u8,
+            payload: sent,
+        });
+        let arrivals = run_switch(
+            [(0, stream(packets))],
+            [1, 2],
+            connect(SimpleSwitch::new(policy, 1)),
+        );
+
+        assert_eq!(arrivals.len(), 2 * SWITCH_OVER as usize - 1);
+        for (port, _, packet) in arrivals {
+            let expected = match (packet.payload as u64) < SWITCH_OVER {
+                true => 1,
+                false => 2,
+            };
+            assert_eq!(port, expected, "{:?} went out of the wrong port", packet);
+        }
+    }
+
+    #[test]
+    fn scheduled_switch_over_test() {
+        check_switch_over(scheduled());
+    }
+
+    #[test]
+    fn timed_switch_over_test() {
+        check_switch_over(TimedPolicy);
+        // Scheduled policies pass the time on to the policy in charge.
+        check_switch_over(ScheduledPolicy::new(vec![(0, TimedPolicy)]));
+    }
+
+    // Bit i goes to port 10 + i.
+    const MASK_PORTS: [usize; 4] = [10, 11, 12, 13];
+
+    #[test]
+    fn bitmask_fan_out_test() {
+        let packet = SimplePacket {
+            location: 0b1010u64,
+            payload: 7u16,
+        };
+        let switch = SimpleSwitch::new(BitmaskPolicy::new(MASK_PORTS.to_vec()), 1);
+        let arrivals = run_switch([(0, vec![(1, packet)])], MASK_PORTS, connect(switch));
+
+        let ports: Vec<_> = arrivals.iter().map(|(port, _, _)| *port).collect();
+        assert_eq!(ports, vec![11, 13]);
+        assert!(arrivals.iter().all(|(_, _, arrived)| *arrived == packet));
+    }
+
+    #[test]
+    fn bitmask_unroutable_test() {
+        let mut policy = BitmaskPolicy::new(MASK_PORTS.to_vec());
+        assert_eq!(policy.try_route(&0u16), RouteDecision::Error);
+        // Bit 4 has no port.
+        assert_eq!(policy.try_route(&0b10001u32), RouteDecision::Error);
+        assert_eq!(
+            policy.try_route(&0b1111u8),
+            RouteDecision::Forward(FxHashSet::from_iter(MASK_PORTS))
+        );
+
+        let mut policy = policy.with_empty_dropped();
+        assert_eq!(policy.try_route(&0u64), RouteDecision::Drop);
+    }
+
+    const UPLINK: usize = 0;
+
+    /// Chip 2's tiles go through the uplink, except tile 3, which has port 5, and its core 1, which has port 6.
+    fn hierarchy() -> LpmPolicy<3, u8> {
+        let mut policy = LpmPolicy::new();
+        policy.insert(&[2], FxHashSet::from_iter([UPLINK])).unwrap();
+        policy.insert(&[2, 3], FxHashSet::from_iter([5])).unwrap();
+        policy
+            .insert(&[2, 3, 1], FxHashSet::from_iter([6]))
+            .unwrap();
+        policy
+    }
+
+    #[test]
+    fn longest_prefix_test() {
+        let mut policy = hierarchy();
+        // An exact match.
+        assert_eq!(policy.route(&[2, 3, 1]), FxHashSet::from_iter([6]));
+        // Partial matches, which fall back on the longest prefix they have.
+        assert_eq!(policy.route(&[2, 3, 0]), FxHashSet::from_iter([5]));
+        assert_eq!(policy.route(&[2, 4, 1]), FxHashSet::from_iter([UPLINK]));
+        assert_eq!(policy.try_route(&[1, 3, 1]), RouteDecision::Error);
+
+        // A zero length prefix picks up everything else.
+        policy.insert(&[], FxHashSet::from_iter([9])).unwrap();
+        assert_eq!(policy.route(&[1, 3, 1]), FxHashSet::from_iter([9]));
+        assert_eq!(policy.route(&[2, 3, 1]), FxHashSet::from_iter([6]));
+    }
+
+    #[test]
+    fn prefix_conflict_test() {
+        let mut policy = hierarchy();
+        assert_eq!(policy.insert(&[2, 3], FxHashSet::from_iter([5])), Ok(()));
+        assert_eq!(
+            policy.insert(&[2, 3], FxHashSet::from_iter([7])),
+            Err(PrefixConflict {
+                prefix: vec![2, 3],
+                existing: FxHashSet::from_iter([5]),
+            })
+        );
+        assert_eq!(policy.route(&[2, 3, 0]), FxHashSet::from_iter([5]));
+    }
+
+    #[test]
+    fn cached_policy_test() {
+        const NUM_DESTINATIONS: u8 = 3;
+        // Every input sends to every destination over and over, and contends for the outputs while doing so.
+        let calls = Arc::new(Mutex::new(FxHashMap::<u8, usize>::default()));
+        let counted = calls.clone();
+        let inner = move |dst: &u8| {
+            *counted.lock().unwrap().entry(*dst).or_default() += 1;
+            FxHashSet::from_iter([*dst as usize])
+        };
+        let sources = (0..4).map(|port| {
+            let packets = (0..24).map(|i| SimplePacket {
+                location: (i % NUM_DESTINATIONS as u16) as u8,
+                payload: i,
+            });
+            (port, stream(packets))
+        });
+        let switch = SimpleSwitch::new(CachedPolicy::new(inner), 1);
+        let arrivals = run_switch(sources, 0..NUM_DESTINATIONS as usize, connect(switch));
+
+        assert_eq!(arrivals.len(), 4 * 24);
+        let calls = calls.lock().unwrap();
+        assert_eq!(
+            *calls,
+            FxHashMap::from_iter((0..NUM_DESTINATIONS).map(|dst| (dst, 1)))
+        );
+    }
+
+    #[test]
+    fn cache_invalidation_test() {
+        let mut policy =
+            CachedPolicy::new(FxHashMap::from_iter([(1u8, FxHashSet::from_iter([1]))]));
+        assert_eq!(policy.route(&1), FxHashSet::from_iter([1]));
+
+        policy.inner.insert(1, FxHashSet::from_iter([2]));
+        policy.inner.insert(2, FxHashSet::from_iter([3]));
+        // Until it's invalidated, the old route sticks.
+        assert_eq!(policy.route(&1), FxHashSet::from_iter([1]));
+        policy.invalidate(&1);
+        assert_eq!(policy.route(&1), FxHashSet::from_iter([2]));
+        assert_eq!(policy.route(&2), FxHashSet::from_iter([3]));
+
+        policy.inner.clear();
+        assert_eq!(policy.route(&2), FxHashSet::from_iter([3]));
+        policy.clear();
+        assert_eq!(policy.try_route(&2), RouteDecision::Error);
+    }
+
+    /// A table of local destinations, then one for the destinations behind the gateway on port 9.
+    fn gateway_chain() -> ChainPolicy<u8> {
+        let local = FxHashMap::from_iter([(1u8, FxHashSet::from_iter([1]))]);
+        let gateway = FxHashMap::from_iter((10u8..20).map(|dst| (dst, FxHashSet::from_iter([9]))));
+        ChainPolicy::new(vec![Box::new(local), Box::new(gateway)])
+    }
+
+    #[test]
+    fn chain_fallback_test() {
+        let mut policy = gateway_chain();
+        let stats = policy.stats();
+        // The first policy knows this one.
+        assert_eq!(policy.route(&1), FxHashSet::from_iter([1]));
+        // The first one misses, and the second picks it up.
+        assert_eq!(policy.route(&12), FxHashSet::from_iter([9]));
+        assert_eq!(policy.route(&19), FxHashSet::from_iter([9]));
+        assert_eq!(
+            *stats.lock().unwrap(),
+            ChainStats {
+                hits: vec![1, 2],
+                exhausted: 0
+            }
+        );
+    }
+
+    #[test]
+    fn chain_exhausted_test() {
+        let mut policy = gateway_chain();
+        assert_eq!(policy.try_route(&2), RouteDecision::Error);
+
+        let mut policy = gateway_chain().with_exhausted_dropped();
+        let stats = policy.stats();
+        assert_eq!(policy.try_route(&2), RouteDecision::Drop);
+        assert_eq!(policy.route(&200), FxHashSet::default());
+        assert_eq!(
+            *stats.lock().unwrap(),
+            ChainStats {
+                hits: vec![0, 0],
+                exhausted: 2
+            }
+        );
+    }
+
+    /// Three blocks of 1024 addresses, with a gap between the second and third.
+    fn address_blocks() -> RangePolicy<u32> {
+        let mut policy = RangePolicy::new();
+        policy.insert(0..=1023, FxHashSet::from_iter([0])).unwrap();
+        policy
+            .insert(1024..=2047, FxHashSet::from_iter([1]))
+            .unwrap();
+        policy
+            .insert(4096..=5119, FxHashSet::from_iter([2]))
+            .unwrap();
+        policy
+    }
+
+    #[test]
+    fn range_lookup_test() {
+        let mut policy = address_blocks().with_gaps_dropped();
+        for (address, port) in [
+            (0, 0),
+            (1023, 0),
+            (1024, 1),
+            (2047, 1),
+            (4096, 2),
+            (5119, 2),
+        ] {
+            assert_eq!(policy.route(&address), FxHashSet::from_iter([port]));
+        }
+        for address in [2048, 3000, 4095, 5120, u32::MAX] {
+            assert_eq!(policy.try_route(&address), RouteDecision::Drop);
+        }
+
+        let mut policy = address_blocks();
+        assert_eq!(policy.try_route(&3000), RouteDecision::Error);
+        let mut policy = policy.with_default(FxHashSet::from_iter([7]));
+        assert_eq!(policy.route(&3000), FxHashSet::from_iter([7]));
+    }
+
+    #[test]
+    fn range_overlap_test() {
+        let mut policy = address_blocks();
+        // Overlapping the end of one range, the start of another, and one whole.
+        for (range, existing) in [
+            (2000..=2100, 1024..=2047),
+            (3000..=4096, 4096..=5119),
+            (0..=9999, 0..=1023),
+        ] {
+            assert_eq!(
+                policy.insert(range.clone(), FxHashSet::from_iter([3])),
+                Err(RangeOverlap { range, existing })
+            );
+        }
+        // Filling the gap exactly is fine.
+        assert_eq!(
+            policy.insert(2048..=4095, FxHashSet::from_iter([3])),
+            Ok(())
+        );
+        assert_eq!(policy.route(&3000), FxHashSet::from_iter([3]));
+    }
+
+    const CONTROL: u8 = 0;
+    const DATA: u8 = 1;
+
+    /// Control traffic goes out of port 1 whatever its destination, and data is split over ports 2 and 3.
+    fn per_class() -> PerClassPolicy<u8> {
+        PerClassPolicy::new()
+            .with_class(CONTROL, |_: &u8| FxHashSet::from_iter([1]))
+            .with_class(DATA, |destination: &u8| {
+                FxHashSet::from_iter([2 + *destination as usize % 2])
+            })
+    }
+
+    #[test]
+    fn per_class_lookup_test() {
+        let mut policy = per_class();
+        let packet = |class| Classed {
+            packet: SimplePacket {
+                location: 3u8,
+                payload: 0u16,
+            },
+            class,
+        };
+        assert_eq!(
+            policy.route_packet(&packet(CONTROL)),
+            FxHashSet::from_iter([1])
+        );
+        assert_eq!(
+            policy.route_packet(&packet(DATA)),
+            FxHashSet::from_iter([3])
+        );
+        assert!(matches!(
+            policy.try_route_packet(&packet(2)),
+            RouteDecision::Error
+        ));
+
+        // Bare locations need a default class to go by.
+        assert!(matches!(policy.try_route(&3), RouteDecision::Error));
+        let mut policy = policy.with_default_class(DATA);
+        assert_eq!(policy.route(&3), FxHashSet::from_iter([3]));
+    }
+
+    #[test]
+    fn per_class_dispatch_test() {
+        const NUM_PACKETS: u16 = 16;
+        let packets = (0..NUM_PACKETS).map(|i| Classed {
+            packet: SimplePacket {
+                location: (i / 2) as u8,
+                payload: i,
+            },
+            class: (i % 2) as u8,
+        });
+        let switch = SimpleSwitch::new(per_class(), 1).with_packet_routing();
+        let arrivals = run_switch([(0, stream(packets))], 1..4, connect(switch));
+
+        assert_eq!(arrivals.len(), NUM_PACKETS as usize);
+        let ports = |class| -> FxHashSet<_> {
+            arrivals
+                .iter()
+                .filter(|(_, _, packet)| packet.class == class)
+                .map(|(port, _, _)| *port)
+                .collect()
+        };
+        assert_eq!(ports(CONTROL), FxHashSet::from_iter([1]));
+        assert_eq!(ports(DATA), FxHashSet::from_iter([2, 3]));
+    }
+
+    #[test]
+    fn flood_except_ingress_test() {
+        // Every port sends one packet; each should come out of the other three.
+        const NUM_PORTS: usize = 4;
+        let sources = (0..NUM_PORTS).map(|port| {
+            let packet = SimplePacket {
+                location: 0u8,
+                payload: port as u16,
+            };
+            (port, vec![(1, packet)])
+        });
+        let arrivals = run_switch(
+            sources,
+            0..NUM_PORTS,
+            connect(SimpleSwitch::new(FloodPolicy, 1)),
+        );
+
+        assert_eq!(arrivals.len(), NUM_PORTS * (NUM_PORTS - 1));
+        for sender in 0..NUM_PORTS {
+            let mut receivers: Vec<_> = arrivals
+                .iter()
+                .filter(|(_, _, packet)| packet.payload as usize == sender)
+                .map(|(port, _, _)| *port)
+                .collect();
+            receivers.sort();
+            let others: Vec<_> = (0..NUM_PORTS).filter(|port| *port != sender).collect();
+            assert_eq!(receivers, others);
         }
     }
 }
@@ -0,0 +1,399 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+use dam::{
+    channel::utils::{EventTime, Peekable},
+    context_tools::*,
+    simulation::ProgramBuilder,
+    structures::SyncSendMarker,
+};
+
+use super::routing::Packet;
+
+/// Who gets the downstream link when traffic already on the ring and a newly injected packet both want it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RingArbitration {
+    /// Packets already on the ring always go first, so that injecting can never stall the ring.
+    #[default]
+    ThroughFirst,
+    /// Injected packets always go first.
+    InjectFirst,
+    /// The two take turns.
+    RoundRobin,
+}
+
+/// Keeps count of what is left to do on a ring, since its stops keep each other alive and never close on their own.
+/// Stops which share a tracker shut down together once every one of them has run out of packets to inject, and
+/// every packet has been ejected.
+#[derive(Debug, Default)]
+pub struct RingTracker {
+    open_injectors: AtomicUsize,
+    in_flight: AtomicUsize,
+}
+
+impl RingTracker {
+    fn idle(&self) -> bool {
+        self.open_injectors.load(Ordering::SeqCst) == 0
+            && self.in_flight.load(Ordering::SeqCst) == 0
+    }
+}
+
+// Where a packet came from, and where it is going.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Source {
+    Upstream,
+    Inject,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Destination {
+    Downstream,
+    Eject,
+}
+
+/// A stop on a unidirectional ring. Packets for the stop's own node are ejected, and everything else moves on to the
+/// next stop, one packet per cycle on each link. Every hop, including the one off the ring, takes `latency` cycles.
+#[context_macro]
+pub struct RingStop<T: DAMType, LT> {
+    node: LT,
+    upstream: Receiver<T>,
+    downstream: Sender<T>,
+    inject: Receiver<T>,
+    eject: Sender<T>,
+
+    latency: u64,
+    arbitration: RingArbitration,
+    last_granted: Option<Source>,
+    tracker: Option<Arc<RingTracker>>,
+    inject_closed: bool,
+
+    _marker: SyncSendMarker<LT>,
+}
+
+impl<T: DAMType, LT> Context for RingStop<T, LT>
+where
+    T: Packet<LT>,
+    LT: PartialEq + Sync + Send,
+{
+    fn run(&mut self) {
+        while let Some(ready) = self.advance_to_next_event() {
+            let mut used = vec![];
+            for source in self.arbitrate(ready) {
+                let chan = match source {
+                    Source::Upstream => &self.upstream,
+                    Source::Inject => &self.inject,
+                };
+                let data = match chan.peek() {
+                    dam::channel::PeekResult::Something(ChannelElement { time: _, data }) => data,
+                    _ => panic!("{:?} was supposed to be ready", source),
+                };
+                let destination = if data.destination() == self.node {
+                    Destination::Eject
+                } else {
+                    Destination::Downstream
+                };
+                if used.contains(&destination) {
+                    continue;
+                }
+                used.push(destination);
+                if source == Source::Upstream || destination == Destination::Downstream {
+                    // Whoever wins the downstream link gets to go next time around.
+                    self.last_granted = Some(source);
+                }
+
+                let _ = chan.dequeue(&self.time);
+                if let Some(tracker) = &self.tracker {
+                    if source == Source::Inject {
+                        tracker.in_flight.fetch_add(1, Ordering::SeqCst);
+                    }
+                }
+                let output = match destination {
+                    Destination::Downstream => &self.downstream,
+                    Destination::Eject => &self.eject,
+                };
+                output
+                    .enqueue(
+                        &self.time,
+                        ChannelElement {
+                            time: self.time.tick() + self.latency,
+                            data,
+                        },
+                    )
+                    .unwrap();
+                if let Some(tracker) = &self.tracker {
+                    if destination == Destination::Eject {
+                        tracker.in_flight.fetch_sub(1, Ordering::SeqCst);
+                    }
+                }
+            }
+            self.time.incr_cycles(1);
+        }
+    }
+}
+
+impl<T: DAMType, LT> RingStop<T, LT>
+where
+    Self: Context,
+{
+    /// Creates the stop for `node`. Packets come in from the previous stop through `upstream`, and from the node
+    /// itself through `inject`, and leave through `downstream` and `eject` respectively.
+    pub fn new(
+        node: LT,
+        latency: u64,
+        upstream: Receiver<T>,
+        downstream: Sender<T>,
+        inject: Receiver<T>,
+        eject: Sender<T>,
+    ) -> Self {
+        let stop = Self {
+            node,
+            upstream,
+            downstream,
+            inject,
+            eject,
+            latency,
+            arbitration: Default::default(),
+            last_granted: None,
+            tracker: None,
+            inject_closed: false,
+            _marker: Default::default(),
+            context_info: Default::default(),
+        };
+        stop.upstream.attach_receiver(&stop);
+        stop.inject.attach_receiver(&stop);
+        stop.downstream.attach_sender(&stop);
+        stop.eject.attach_sender(&stop);
+        stop
+    }
+
+    /// Sets who goes first when injected packets contend with traffic already on the ring.
+    pub fn with_arbitration(mut self, arbitration: RingArbitration) -> Self {
+        self.arbitration = arbitration;
+        self
+    }
+
+    /// Lets the stop shut down once the ring as a whole is done. Without one, the stop only finishes once both of its
+    /// inputs have closed, which never happens on a closed ring.
+    pub fn with_tracker(mut self, tracker: Arc<RingTracker>) -> Self {
+        tracker.open_injectors.fetch_add(1, Ordering::SeqCst);
+        self.tracker = Some(tracker);
+        self
+    }
+
+    /// Orders the ready inputs by who gets the first shot at the outputs.
+    fn arbitrate(&self, ready: Vec<Source>) -> Vec<Source> {
+        let inject_first = match self.arbitration {
+            RingArbitration::ThroughFirst => false,
+            RingArbitration::InjectFirst => true,
+            RingArbitration::RoundRobin => self.last_granted == Some(Source::Upstream),
+        };
+        let mut ready = ready;
+        ready.sort_by_key(|source| (*source == Source::Inject) != inject_first);
+        ready
+    }
+
+    /// Waits until at least one input has a packet, and returns the ready inputs. Returns None once there is nothing
+    /// left to do.
+    fn advance_to_next_event(&mut self) -> Option<Vec<Source>> {
+        loop {
+            if !self.inject_closed && matches!(self.inject.peek(), dam::channel::PeekResult::Closed)
+            {
+                self.inject_closed = true;
+                if let Some(tracker) = &self.tracker {
+                    tracker.open_injectors.fetch_sub(1, Ordering::SeqCst);
+                }
+            }
+            let upstream_closed = matches!(self.upstream.peek(), dam::channel::PeekResult::Closed);
+            let ring_idle = self.tracker.as_ref().is_some_and(|tracker| tracker.idle());
+            if self.inject_closed && (upstream_closed || ring_idle) {
+                return None;
+            }
+
+            match self.upstream.next_event().min(self.inject.next_event()) {
+                EventTime::Ready(t) => {
+                    self.time.advance(t);
+                    let t = self.time.tick();
+                    let is_ready = |chan: &Receiver<T>| match chan.peek() {
+                        dam::channel::PeekResult::Something(x) => x.time <= t,
+                        _ => false,
+                    };
+                    return Some(
+                        [
+                            (Source::Upstream, &self.upstream),
+                            (Source::Inject, &self.inject),
+                        ]
+                        .into_iter()
+                        .filter(|(_, chan)| is_ready(chan))
+                        .map(|(source, _)| source)
+                        .collect(),
+                    );
+                }
+                EventTime::Nothing(t) => self.time.advance(t + 1),
+                // Something closed since we last checked, which the next pass takes care of.
+                EventTime::Closed => {}
+            }
+        }
+    }
+}
+
+/// The ends of a ring stop which face its node.
+pub struct RingEndpoint<T: Clone> {
+    /// Sends packets onto the ring.
+    pub inject: Sender<T>,
+    /// Receives the packets addressed to this node.
+    pub eject: Receiver<T>,
+}
+
+/// Builds a ring with one stop per node, in the given order, and returns each node's endpoint in the same order.
+/// The stops shut down once every endpoint's `inject` has closed and every packet has been delivered.
+pub fn build_ring<'a, T, LT>(
+    ctx: &mut ProgramBuilder<'a>,
+    nodes: Vec<LT>,
+    latency: u64,
+) -> Vec<RingEndpoint<T>>
+where
+    T: DAMType + Packet<LT> + 'a,
+    LT: PartialEq + Sync + Send + 'a,
+{
+    let tracker = Arc::new(RingTracker::default());
+    let n = nodes.len();
+    let (mut downstreams, mut upstreams): (Vec<_>, Vec<_>) =
+        (0..n).map(|_| ctx.unbounded()).unzip();
+    // Stop i sends on link i, and listens to link i - 1.
+    upstreams.rotate_right(1);
+
+    let mut endpoints = vec![];
+    for ((node, downstream), upstream) in
+        nodes.into_iter().zip(downstreams.drain(..)).zip(upstreams)
+    {
+        let (inject_snd, inject_rcv) = ctx.unbounded();
+        let (eject_snd, eject_rcv) = ctx.unbounded();
+        ctx.add_child(
+            RingStop::new(node, latency, upstream, downstream, inject_rcv, eject_snd)
+                .with_tracker(tracker.clone()),
+        );
+        endpoints.push(RingEndpoint {
+            inject: inject_snd,
+            eject: eject_rcv,
+        });
+    }
+    endpoints
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use dam::{
+        context_tools::ChannelElement, simulation::ProgramBuilder, structures::Time,
+        utility_contexts::*,
+    };
+
+    use crate::switches::routing::SimplePacket;
+
+    use super::build_ring;
+
+    const NUM_NODES: u8 = 8;
+    const LATENCY: u64 = 2;
+
+    type TestPacket = SimplePacket<u8, u16>;
+
+    /// Builds an 8 node ring where every node sends `traffic(node)` as (send time, destination), and returns
+    /// (source, destination, send time, arrival time) for everything which was delivered.
+    fn ring_run(traffic: impl Fn(u8) -> Vec<(u64, u8)>) -> Vec<(u8, u8, u64, u64)> {
+        let mut ctx = ProgramBuilder::default();
+        let endpoints = build_ring::<TestPacket, u8>(&mut ctx, (0..NUM_NODES).collect(), LATENCY);
+
+        let arrivals = Arc::new(Mutex::new(vec![]));
+        for (node, endpoint) in (0..NUM_NODES).zip(endpoints) {
+            let packets = traffic(node);
+            let inject = endpoint.inject;
+            let mut source = FunctionContext::new();
+            inject.attach_sender(&source);
+            source.set_run(move |time| {
+                for (send_time, location) in &packets {
+                    inject
+                        .enqueue(
+                            time,
+                            ChannelElement {
+                                time: Time::new(*send_time),
+                                // Remember who sent it, and when.
+                                data: SimplePacket {
+                                    location: *location,
+                                    payload: node as u16 * 1000 + *send_time as u16,
+                                },
+                            },
+                        )
+                        .unwrap();
+                }
+            });
+            ctx.add_child(source);
+
+            let eject = endpoint.eject;
+            let mut sink = FunctionContext::new();
+            eject.attach_receiver(&sink);
+            let sink_log = arrivals.clone();
+            sink.set_run(move |time| {
+                while let Ok(ChannelElement { time, data }) = eject.dequeue(time) {
+                    sink_log.lock().unwrap().push((
+                        (data.payload / 1000) as u8,
+                        data.location,
+                        (data.payload % 1000) as u64,
+                        time.time(),
+                    ));
+                }
+            });
+            ctx.add_child(sink);
+        }
+
+        ctx.initialize(Default::default())
+            .unwrap()
+            .run(Default::default());
+
+        let mut arrivals = arrivals.lock().unwrap().clone();
+        arrivals.sort();
+        arrivals
+    }
+
+    #[test]
+    fn all_to_all_test() {
+        let arrivals = ring_run(|node| {
+            (0..NUM_NODES)
+                .filter(|destination| *destination != node)
+                .map(|destination| (1, destination))
+                .collect()
+        });
+
+        let delivered: Vec<_> = arrivals
+            .iter()
+            .map(|(source, destination, _, _)| (*source, *destination))
+            .collect();
+        let expected: Vec<_> = (0..NUM_NODES)
+            .flat_map(|source| {
+                (0..NUM_NODES)
+                    .filter(move |destination| *destination != source)
+                    .map(move |destination| (source, destination))
+            })
+            .collect();
+        assert_eq!(delivered, expected);
+    }
+
+    #[test]
+    fn hop_latency_test() {
+        // Node 0 sends one packet to every other node, on different cycles so that they never contend for a link.
+        let arrivals = ring_run(|node| match node {
+            0 => (1..NUM_NODES)
+                .map(|destination| (destination as u64, destination))
+                .collect(),
+            _ => vec![],
+        });
+
+        assert_eq!(arrivals.len(), NUM_NODES as usize - 1);
+        for (_, destination, sent, arrived) in arrivals {
+            // One hop per stop along the way, plus one more off the ring.
+            assert_eq!(arrived - sent, (destination as u64 + 1) * LATENCY);
+        }
+    }
+}
@@ -1,20 +1,251 @@
-use dam::context_tools::*;
+use std::collections::{BTreeMap, VecDeque};
+
+use dam::{
+    channel::utils::{EventTime, Peekable},
+    context_tools::*,
+};
+
+pub use super::locations::{Concentrated, Coord2D, Coord3D, Dir2D, Dir3D};
 
 pub trait Packet<LocationType> {
     fn destination(&self) -> LocationType;
 }
 
+/// Packets whose destination can be rewritten, possibly into a different kind of location, for moving them between
+/// networks which address things differently.
+pub trait MutablePacket<LocationType> {
+    /// The packet with its new destination, which is the same type of packet if the location type stays the same.
+    type Output;
+    fn with_destination(self, destination: LocationType) -> Self::Output;
+}
+
+/// `#[derive(Packet)]` implements [`Packet`] for a struct with the field marked `#[packet(destination)]` as its
+/// destination, and `#[derive(DAMType)]` sizes a struct as the sum of its fields.
+#[cfg(feature = "derive")]
+pub use dam_networks_derive::{DAMType, Packet};
+
+/// ```
+/// use dam_networks::switches::routing::{DAMType, Packet};
+///
+/// #[derive(Clone, Debug, Default, Packet, DAMType)]
+/// struct Request {
+///     #[packet(destination)]
+///     target: u16,
+///     address: u64,
+/// }
+/// ```
+///
+/// Packets need a destination:
+///
+/// ```compile_fail
+/// use dam_networks::switches::routing::Packet;
+///
+/// #[derive(Packet)]
+/// struct Request {
+///     target: u16,
+///     address: u64,
+/// }
+/// ```
+///
+/// And only one:
+///
+/// ```compile_fail
+/// use dam_networks::switches::routing::Packet;
+///
+/// #[derive(Packet)]
+/// struct Request {
+///     #[packet(destination)]
+///     target: u16,
+///     #[packet(destination)]
+///     address: u64,
+/// }
+/// ```
+#[cfg(all(doctest, feature = "derive"))]
+pub struct DeriveExamples;
+
+/// Packets which know where they came from.
+pub trait SourcedPacket<LocationType> {
+    fn source(&self) -> LocationType;
+}
+
+/// Packets which belong to a flow, for policies which keep each flow on a single path. Packets which know their
+/// source belong to the flow between their source and destination, identified by hashing the two together.
+pub trait Flow<LocationType> {
+    fn flow_id(&self) -> u64;
+}
+
+impl<LT: std::hash::Hash, T: Packet<LT> + SourcedPacket<LT>> Flow<LT> for T {
+    fn flow_id(&self) -> u64 {
+        fxhash::hash64(&(self.source(), self.destination()))
+    }
+}
+
+/// Packets which carry a priority, for switches which arbitrate on it. Larger values win.
+pub trait PriorityPacket {
+    fn priority(&self) -> u32;
+}
+
+/// Packets which carry a hop count. Switches built with `with_hop_limit` treat it as a budget, and drop packets
+/// instead of letting them circle forever, while deflection switches count up how far a packet has come.
+pub trait HopCounted {
+    fn hops(&self) -> u8;
+    fn with_hops(self, hops: u8) -> Self;
+}
+
+/// Packets which carry the path they take through the network, as the output port to leave each switch by. Switches
+/// built with `with_source_routes` use up one hop of the path on the way through.
+pub trait SourceRouted {
+    fn advance(&mut self);
+}
+
+/// Packets which carry the port they should leave the next switch by, worked out a hop ahead by the switch before.
+/// Switches built with `with_lookahead` go by the port when there is one, and fill in the one for the switch after.
+pub trait Lookahead {
+    fn next_port(&self) -> Option<usize>;
+    fn set_next_port(&mut self, port: Option<usize>);
+}
+
+/// Packets which make up a burst of consecutive elements, which switches built with `with_bursts` keep together.
+pub trait Burst {
+    /// How many more elements of the burst follow this one. Packets on their own, and the last element of a burst,
+    /// return 0.
+    fn remaining(&self) -> usize;
+}
+
+/// Packets which travel on a virtual channel, for switches which keep a separate buffer per VC.
+pub trait VcPacket {
+    fn vc(&self) -> usize;
+    fn with_vc(self, vc: usize) -> Self;
+}
+
+/// Packets which carry a set of destinations, to be delivered to each of them. Switches built with
+/// `with_multicast_destinations` route every destination and send one copy out of each output involved, carrying only
+/// the destinations behind that output.
+pub trait MultiDestination<LocationType> {
+    fn destinations(&self) -> Vec<LocationType>;
+    fn with_destinations(self, destinations: Vec<LocationType>) -> Self;
+}
+
+/// Packets numbered in the order they were sent, so that reordering along the way can be spotted.
+pub trait Sequenced {
+    fn seq(&self) -> u64;
+}
+
+/// Packets which know the cycle they entered the network on, for measuring how long they took to get through it.
+pub trait Timestamped {
+    fn injected_at(&self) -> u64;
+    fn stamp(self, injected_at: u64) -> Self;
+}
+
+/// Packets which carry an ID unique to them, for following them through the network.
+pub trait Identified {
+    fn id(&self) -> u64;
+    fn with_id(self, id: u64) -> Self;
+}
+
+/// Packets which carry a class of service, for policies which route each class differently.
+pub trait ClassedPacket {
+    fn class(&self) -> u8;
+}
+
 pub struct Port<ElementType: Clone> {
     pub id: usize,
     pub input: Option<Receiver<ElementType>>,
     pub output: Option<Sender<ElementType>>,
 }
 
+impl<ElementType: DAMType> Port<ElementType> {
+    /// Attaches whichever halves of the port are present to the context which is about to own them.
+    pub(crate) fn attach(&self, owner: &dyn Context) {
+        if let Some(rcv) = &self.input {
+            rcv.attach_receiver(owner);
+        }
+        if let Some(snd) = &self.output {
+            snd.attach_sender(owner);
+        }
+    }
+}
+
 pub trait Switch<ElementType: Clone> {
+    /// Registers a port with the switch. Either half of the port may be omitted.
     fn add_port(&mut self, port: Port<ElementType>);
 }
 
-#[derive(Copy, Clone, Debug, Default)]
+/// The channels a switch has been given, kept sorted by port ID.
+pub(crate) struct PortMap<ElementType: Clone> {
+    pub inputs: BTreeMap<usize, Receiver<ElementType>>,
+    pub outputs: BTreeMap<usize, Sender<ElementType>>,
+}
+
+impl<ElementType: Clone> Default for PortMap<ElementType> {
+    fn default() -> Self {
+        Self {
+            inputs: Default::default(),
+            outputs: Default::default(),
+        }
+    }
+}
+
+impl<ElementType: DAMType> PortMap<ElementType> {
+    /// Takes over both halves of an already attached port.
+    pub fn insert(&mut self, port: Port<ElementType>) {
+        if let Some(rcv) = port.input {
+            assert!(
+                self.inputs.insert(port.id, rcv).is_none(),
+                "Input port was already occupied!"
+            );
+        }
+        if let Some(snd) = port.output {
+            assert!(
+                self.outputs.insert(port.id, snd).is_none(),
+                "Output port was already occupied!"
+            );
+        }
+    }
+
+    /// Waits until at least one input has something on it, retiring inputs as they close. Returns false once every
+    /// input has closed.
+    pub fn wait_for_input(&mut self, time: &mut TimeManager) -> bool {
+        loop {
+            self.inputs
+                .retain(|_, chan| !matches!(chan.peek(), dam::channel::PeekResult::Closed));
+            if self.inputs.is_empty() {
+                return false;
+            }
+
+            match self
+                .inputs
+                .values()
+                .map(|chan| chan.next_event())
+                .min()
+                .unwrap()
+            {
+                EventTime::Ready(t) => {
+                    time.advance(t);
+                    return true;
+                }
+                EventTime::Nothing(t) => time.advance(t + 1),
+                // Something closed since we last checked, which the next pass takes care of.
+                EventTime::Closed => {}
+            }
+        }
+    }
+
+    /// The inputs whose head has arrived by `tick`, in port order.
+    pub fn ready(&self, tick: Time) -> Vec<usize> {
+        self.inputs
+            .iter()
+            .filter(|(_, chan)| match chan.peek() {
+                dam::channel::PeekResult::Something(x) => x.time <= tick,
+                _ => false,
+            })
+            .map(|(id, _)| *id)
+            .collect()
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct SimplePacket<LocationType, PayloadType> {
     pub location: LocationType,
     pub payload: PayloadType,
@@ -26,8 +257,735 @@ impl<LT: Clone, PT> Packet<LT> for SimplePacket<LT, PT> {
     }
 }
 
+impl<LT, NewLT, PT> MutablePacket<NewLT> for SimplePacket<LT, PT> {
+    type Output = SimplePacket<NewLT, PT>;
+
+    fn with_destination(self, destination: NewLT) -> Self::Output {
+        SimplePacket {
+            location: destination,
+            payload: self.payload,
+        }
+    }
+}
+
 impl<LT: DAMType, PT: DAMType> DAMType for SimplePacket<LT, PT> {
     fn dam_size(&self) -> usize {
         self.location.dam_size() + self.payload.dam_size()
     }
 }
+
+/// A packet tunneled through a network with its own addresses, as the payload of an outer packet addressed in them.
+pub type Encapsulated<OuterLocationType, Inner> = SimplePacket<OuterLocationType, Inner>;
+
+/// A [`SimplePacket`] which also carries the location it was sent from.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SourcedSimplePacket<LocationType, PayloadType> {
+    pub source: LocationType,
+    pub location: LocationType,
+    pub payload: PayloadType,
+}
+
+impl<LT: Clone, PT> Packet<LT> for SourcedSimplePacket<LT, PT> {
+    fn destination(&self) -> LT {
+        self.location.clone()
+    }
+}
+
+impl<LT: Clone, PT> SourcedPacket<LT> for SourcedSimplePacket<LT, PT> {
+    fn source(&self) -> LT {
+        self.source.clone()
+    }
+}
+
+/// The source stays as it was, so the location type can't change.
+impl<LT, PT> MutablePacket<LT> for SourcedSimplePacket<LT, PT> {
+    type Output = Self;
+
+    fn with_destination(self, destination: LT) -> Self::Output {
+        Self {
+            location: destination,
+            ..self
+        }
+    }
+}
+
+impl<LT: DAMType, PT: DAMType> DAMType for SourcedSimplePacket<LT, PT> {
+    fn dam_size(&self) -> usize {
+        self.source.dam_size() + self.location.dam_size() + self.payload.dam_size()
+    }
+}
+
+/// A request sent from `source` to `location`, to be answered by a [`Response`] with the same `txn_id`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Request<LocationType, PayloadType> {
+    pub source: LocationType,
+    pub location: LocationType,
+    pub txn_id: u64,
+    pub payload: PayloadType,
+}
+
+impl<LT, PT> Request<LT, PT> {
+    /// The response to this request, sent back from where it went to where it came from.
+    pub fn respond<RT>(self, payload: RT) -> Response<LT, RT> {
+        Response {
+            source: self.location,
+            location: self.source,
+            txn_id: self.txn_id,
+            payload,
+        }
+    }
+}
+
+impl<LT: Clone, PT> Packet<LT> for Request<LT, PT> {
+    fn destination(&self) -> LT {
+        self.location.clone()
+    }
+}
+
+impl<LT: Clone, PT> SourcedPacket<LT> for Request<LT, PT> {
+    fn source(&self) -> LT {
+        self.source.clone()
+    }
+}
+
+/// The source stays as it was, so the location type can't change.
+impl<LT, PT> MutablePacket<LT> for Request<LT, PT> {
+    type Output = Self;
+
+    fn with_destination(self, destination: LT) -> Self::Output {
+        Self {
+            location: destination,
+            ..self
+        }
+    }
+}
+
+impl<LT: DAMType, PT: DAMType> DAMType for Request<LT, PT> {
+    fn dam_size(&self) -> usize {
+        self.source.dam_size()
+            + self.location.dam_size()
+            + self.txn_id.dam_size()
+            + self.payload.dam_size()
+    }
+}
+
+/// The answer to the [`Request`] with the same `txn_id`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Response<LocationType, PayloadType> {
+    pub source: LocationType,
+    pub location: LocationType,
+    pub txn_id: u64,
+    pub payload: PayloadType,
+}
+
+impl<LT: Clone, PT> Packet<LT> for Response<LT, PT> {
+    fn destination(&self) -> LT {
+        self.location.clone()
+    }
+}
+
+impl<LT: Clone, PT> SourcedPacket<LT> for Response<LT, PT> {
+    fn source(&self) -> LT {
+        self.source.clone()
+    }
+}
+
+impl<LT, PT> MutablePacket<LT> for Response<LT, PT> {
+    type Output = Self;
+
+    fn with_destination(self, destination: LT) -> Self::Output {
+        Self {
+            location: destination,
+            ..self
+        }
+    }
+}
+
+impl<LT: DAMType, PT: DAMType> DAMType for Response<LT, PT> {
+    fn dam_size(&self) -> usize {
+        self.source.dam_size()
+            + self.location.dam_size()
+            + self.txn_id.dam_size()
+            + self.payload.dam_size()
+    }
+}
+
+/// Counts the packets between each (source, destination) pair.
+pub fn traffic_matrix<'a, LT: Eq + std::hash::Hash, T: Packet<LT> + SourcedPacket<LT> + 'a>(
+    packets: impl IntoIterator<Item = &'a T>,
+) -> fxhash::FxHashMap<(LT, LT), usize> {
+    let mut matrix = fxhash::FxHashMap::default();
+    for packet in packets {
+        *matrix
+            .entry((packet.source(), packet.destination()))
+            .or_default() += 1;
+    }
+    matrix
+}
+
+/// A packet which carries its source, and its place among the packets sent from there.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SequencedPacket<LocationType, PayloadType> {
+    pub source: LocationType,
+    pub location: LocationType,
+    pub seq: u64,
+    pub payload: PayloadType,
+}
+
+impl<LT: Clone, PT> Packet<LT> for SequencedPacket<LT, PT> {
+    fn destination(&self) -> LT {
+        self.location.clone()
+    }
+}
+
+impl<LT: Clone, PT> SourcedPacket<LT> for SequencedPacket<LT, PT> {
+    fn source(&self) -> LT {
+        self.source.clone()
+    }
+}
+
+impl<LT, PT> Sequenced for SequencedPacket<LT, PT> {
+    fn seq(&self) -> u64 {
+        self.seq
+    }
+}
+
+impl<LT: DAMType, PT: DAMType> DAMType for SequencedPacket<LT, PT> {
+    fn dam_size(&self) -> usize {
+        self.source.dam_size()
+            + self.location.dam_size()
+            + self.seq.dam_size()
+            + self.payload.dam_size()
+    }
+}
+
+/// Tags a packet with the cycle it entered the network on.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TimedPacket<P> {
+    pub packet: P,
+    pub injected_at: u64,
+}
+
+impl<LT, P: Packet<LT>> Packet<LT> for TimedPacket<P> {
+    fn destination(&self) -> LT {
+        self.packet.destination()
+    }
+}
+
+impl<LT, P: MutablePacket<LT>> MutablePacket<LT> for TimedPacket<P> {
+    type Output = TimedPacket<P::Output>;
+
+    fn with_destination(self, destination: LT) -> Self::Output {
+        TimedPacket {
+            packet: self.packet.with_destination(destination),
+            injected_at: self.injected_at,
+        }
+    }
+}
+
+impl<P> Timestamped for TimedPacket<P> {
+    fn injected_at(&self) -> u64 {
+        self.injected_at
+    }
+
+    fn stamp(self, injected_at: u64) -> Self {
+        Self {
+            injected_at,
+            ..self
+        }
+    }
+}
+
+impl<P: DAMType> DAMType for TimedPacket<P> {
+    fn dam_size(&self) -> usize {
+        self.packet.dam_size() + self.injected_at.dam_size()
+    }
+}
+
+/// Tags a packet with an ID of its own.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct IdentifiedPacket<P> {
+    pub packet: P,
+    pub id: u64,
+}
+
+impl<LT, P: Packet<LT>> Packet<LT> for IdentifiedPacket<P> {
+    fn destination(&self) -> LT {
+        self.packet.destination()
+    }
+}
+
+impl<LT, P: MutablePacket<LT>> MutablePacket<LT> for IdentifiedPacket<P> {
+    type Output = IdentifiedPacket<P::Output>;
+
+    fn with_destination(self, destination: LT) -> Self::Output {
+        IdentifiedPacket {
+            packet: self.packet.with_destination(destination),
+            id: self.id,
+        }
+    }
+}
+
+impl<P> Identified for IdentifiedPacket<P> {
+    fn id(&self) -> u64 {
+        self.id
+    }
+
+    fn with_id(self, id: u64) -> Self {
+        Self { id, ..self }
+    }
+}
+
+impl<P: DAMType> DAMType for IdentifiedPacket<P> {
+    fn dam_size(&self) -> usize {
+        self.packet.dam_size() + self.id.dam_size()
+    }
+}
+
+/// A packet bound for every one of its destinations.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MulticastPacket<LocationType, PayloadType> {
+    pub destinations: Vec<LocationType>,
+    pub payload: PayloadType,
+}
+
+/// The first of the packet's destinations, for switches which only look at one.
+impl<LT: Clone, PT> Packet<LT> for MulticastPacket<LT, PT> {
+    fn destination(&self) -> LT {
+        self.destinations
+            .first()
+            .expect("Multicast packets must have a destination to be routed by one!")
+            .clone()
+    }
+}
+
+impl<LT: Clone, PT> MultiDestination<LT> for MulticastPacket<LT, PT> {
+    fn destinations(&self) -> Vec<LT> {
+        self.destinations.clone()
+    }
+
+    fn with_destinations(self, destinations: Vec<LT>) -> Self {
+        Self {
+            destinations,
+            ..self
+        }
+    }
+}
+
+impl<LT: DAMType, PT: DAMType> DAMType for MulticastPacket<LT, PT> {
+    fn dam_size(&self) -> usize {
+        let destinations = self
+            .destinations
+            .iter()
+            .map(DAMType::dam_size)
+            .sum::<usize>();
+        destinations + self.payload.dam_size()
+    }
+}
+
+/// A message body of any number of bytes, whose size is honest about its length, so that switches modeling bandwidth
+/// take longer over longer messages.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Payload(pub Vec<u8>);
+
+impl DAMType for Payload {
+    fn dam_size(&self) -> usize {
+        self.0.iter().map(DAMType::dam_size).sum()
+    }
+}
+
+/// A packet carrying a variable-length message.
+pub type BytesPacket<LocationType> = SimplePacket<LocationType, Payload>;
+
+/// Packets for each of `destinations` in turn, with payloads as many bytes long as `size` says, for feeding to a
+/// generator. `size` is called once per packet, so it can draw from whatever distribution of message sizes is wanted.
+pub fn sized_packets<LT>(
+    destinations: impl IntoIterator<Item = LT>,
+    mut size: impl FnMut() -> usize,
+) -> impl Iterator<Item = BytesPacket<LT>> {
+    destinations.into_iter().map(move |location| SimplePacket {
+        location,
+        payload: Payload(vec![0; size()]),
+    })
+}
+
+/// Payloads which can be cut up into 64-bit words to be carried by [`TaggedFlit`]s, and put back together again.
+pub trait Words {
+    fn to_words(&self) -> Vec<u64>;
+    fn from_words(words: &[u64]) -> Self;
+}
+
+impl Words for u8 {
+    fn to_words(&self) -> Vec<u64> {
+        vec![*self as u64]
+    }
+
+    fn from_words(words: &[u64]) -> Self {
+        words[0] as u8
+    }
+}
+
+impl Words for u16 {
+    fn to_words(&self) -> Vec<u64> {
+        vec![*self as u64]
+    }
+
+    fn from_words(words: &[u64]) -> Self {
+        words[0] as u16
+    }
+}
+
+impl Words for u32 {
+    fn to_words(&self) -> Vec<u64> {
+        vec![*self as u64]
+    }
+
+    fn from_words(words: &[u64]) -> Self {
+        words[0] as u32
+    }
+}
+
+impl Words for u64 {
+    fn to_words(&self) -> Vec<u64> {
+        vec![*self]
+    }
+
+    fn from_words(words: &[u64]) -> Self {
+        words[0]
+    }
+}
+
+/// The length in bytes goes first, followed by the bytes themselves, eight to a word.
+impl Words for Payload {
+    fn to_words(&self) -> Vec<u64> {
+        let bytes = self.0.chunks(8).map(|chunk| {
+            let mut word = [0; 8];
+            word[..chunk.len()].copy_from_slice(chunk);
+            u64::from_le_bytes(word)
+        });
+        std::iter::once(self.0.len() as u64).chain(bytes).collect()
+    }
+
+    fn from_words(words: &[u64]) -> Self {
+        let length = words[0] as usize;
+        let mut bytes: Vec<_> = words[1..]
+            .iter()
+            .flat_map(|word| word.to_le_bytes())
+            .collect();
+        bytes.truncate(length);
+        Payload(bytes)
+    }
+}
+
+/// The pieces of a packet, for networks which move them separately: a head with the length of the packet in words,
+/// body flits with the words themselves, and a tail to close it out. Every flit carries the ID of its packet, so
+/// that flits of different packets can be told apart when they're interleaved, and its destination, so that it can
+/// be routed on its own.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum TaggedFlit<LocationType> {
+    Head {
+        destination: LocationType,
+        packet_id: u64,
+        length: usize,
+    },
+    Body {
+        destination: LocationType,
+        packet_id: u64,
+        words: Vec<u64>,
+    },
+    Tail {
+        destination: LocationType,
+        packet_id: u64,
+    },
+}
+
+impl<LT> TaggedFlit<LT> {
+    pub fn packet_id(&self) -> u64 {
+        match self {
+            TaggedFlit::Head { packet_id, .. }
+            | TaggedFlit::Body { packet_id, .. }
+            | TaggedFlit::Tail { packet_id, .. } => *packet_id,
+        }
+    }
+}
+
+impl<LT: Default> Default for TaggedFlit<LT> {
+    fn default() -> Self {
+        Self::Tail {
+            destination: LT::default(),
+            packet_id: 0,
+        }
+    }
+}
+
+impl<LT: Clone> Packet<LT> for TaggedFlit<LT> {
+    fn destination(&self) -> LT {
+        match self {
+            TaggedFlit::Head { destination, .. }
+            | TaggedFlit::Body { destination, .. }
+            | TaggedFlit::Tail { destination, .. } => destination.clone(),
+        }
+    }
+}
+
+impl<LT: DAMType> DAMType for TaggedFlit<LT> {
+    fn dam_size(&self) -> usize {
+        match self {
+            TaggedFlit::Head {
+                destination,
+                packet_id,
+                length,
+            } => destination.dam_size() + packet_id.dam_size() + length.dam_size(),
+            TaggedFlit::Body {
+                destination,
+                packet_id,
+                words,
+            } => {
+                let words = words.iter().map(DAMType::dam_size).sum::<usize>();
+                destination.dam_size() + packet_id.dam_size() + words
+            }
+            TaggedFlit::Tail {
+                destination,
+                packet_id,
+            } => destination.dam_size() + packet_id.dam_size(),
+        }
+    }
+}
+
+/// Wraps a packet with a hop count, which switches built with `with_hop_limit` spend as it passes through them, and
+/// deflection switches add to.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct HoppedPacket<P> {
+    pub packet: P,
+    pub hops: u8,
+}
+
+impl<LT, P: Packet<LT>> Packet<LT> for HoppedPacket<P> {
+    fn destination(&self) -> LT {
+        self.packet.destination()
+    }
+}
+
+impl<LT, P: MutablePacket<LT>> MutablePacket<LT> for HoppedPacket<P> {
+    type Output = HoppedPacket<P::Output>;
+
+    fn with_destination(self, destination: LT) -> Self::Output {
+        HoppedPacket {
+            packet: self.packet.with_destination(destination),
+            hops: self.hops,
+        }
+    }
+}
+
+impl<P> HopCounted for HoppedPacket<P> {
+    fn hops(&self) -> u8 {
+        self.hops
+    }
+
+    fn with_hops(self, hops: u8) -> Self {
+        Self { hops, ..self }
+    }
+}
+
+impl<P: DAMType> DAMType for HoppedPacket<P> {
+    fn dam_size(&self) -> usize {
+        self.packet.dam_size() + self.hops.dam_size()
+    }
+}
+
+/// A packet on its way through an intermediate node, as in Valiant routing. While `intermediate` is set, that's where
+/// the packet is headed; once it gets there, something at the node has to clear it, like a
+/// [`crate::contexts::PhaseFlip`], and the packet carries on to `final_dest`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TwoPhasePacket<LocationType, PayloadType> {
+    pub intermediate: Option<LocationType>,
+    pub final_dest: LocationType,
+    pub payload: PayloadType,
+}
+
+impl<LT, PT> TwoPhasePacket<LT, PT> {
+    /// Starts the second phase, which takes the packet straight to its final destination.
+    pub fn flipped(self) -> Self {
+        Self {
+            intermediate: None,
+            ..self
+        }
+    }
+}
+
+impl<LT: Clone, PT> Packet<LT> for TwoPhasePacket<LT, PT> {
+    fn destination(&self) -> LT {
+        self.intermediate
+            .clone()
+            .unwrap_or_else(|| self.final_dest.clone())
+    }
+}
+
+impl<LT: DAMType, PT: DAMType> DAMType for TwoPhasePacket<LT, PT> {
+    fn dam_size(&self) -> usize {
+        self.intermediate.as_ref().map_or(0, DAMType::dam_size)
+            + self.final_dest.dam_size()
+            + self.payload.dam_size()
+    }
+}
+
+/// A packet which was given its whole path through the network when it was injected. Its destination is the port it
+/// should leave the next switch by, or nothing once the path has run out.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SourceRoutedPacket<PayloadType> {
+    pub path: VecDeque<usize>,
+    pub payload: PayloadType,
+}
+
+impl<PT> Packet<Option<usize>> for SourceRoutedPacket<PT> {
+    fn destination(&self) -> Option<usize> {
+        self.path.front().copied()
+    }
+}
+
+impl<PT> SourceRouted for SourceRoutedPacket<PT> {
+    fn advance(&mut self) {
+        self.path.pop_front();
+    }
+}
+
+impl<PT: DAMType> DAMType for SourceRoutedPacket<PT> {
+    fn dam_size(&self) -> usize {
+        self.path.iter().map(DAMType::dam_size).sum::<usize>() + self.payload.dam_size()
+    }
+}
+
+/// Wraps a packet with the port it should leave the next switch by, for lookahead routing. Packets are injected
+/// without one, and the first switch routes them as usual.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LookaheadPacket<P> {
+    pub packet: P,
+    pub next_port: Option<usize>,
+}
+
+impl<LT, P: Packet<LT>> Packet<LT> for LookaheadPacket<P> {
+    fn destination(&self) -> LT {
+        self.packet.destination()
+    }
+}
+
+impl<P> Lookahead for LookaheadPacket<P> {
+    fn next_port(&self) -> Option<usize> {
+        self.next_port
+    }
+
+    fn set_next_port(&mut self, port: Option<usize>) {
+        self.next_port = port;
+    }
+}
+
+impl<P: DAMType> DAMType for LookaheadPacket<P> {
+    fn dam_size(&self) -> usize {
+        self.packet.dam_size() + self.next_port.as_ref().map_or(0, DAMType::dam_size)
+    }
+}
+
+/// Tags a packet with the virtual channel it travels on.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Vc<P> {
+    pub packet: P,
+    pub vc: usize,
+}
+
+impl<LT, P: Packet<LT>> Packet<LT> for Vc<P> {
+    fn destination(&self) -> LT {
+        self.packet.destination()
+    }
+}
+
+impl<LT, P: MutablePacket<LT>> MutablePacket<LT> for Vc<P> {
+    type Output = Vc<P::Output>;
+
+    fn with_destination(self, destination: LT) -> Self::Output {
+        Vc {
+            packet: self.packet.with_destination(destination),
+            vc: self.vc,
+        }
+    }
+}
+
+impl<P> VcPacket for Vc<P> {
+    fn vc(&self) -> usize {
+        self.vc
+    }
+
+    fn with_vc(self, vc: usize) -> Self {
+        Self { vc, ..self }
+    }
+}
+
+impl<P: DAMType> DAMType for Vc<P> {
+    fn dam_size(&self) -> usize {
+        self.packet.dam_size() + self.vc.dam_size()
+    }
+}
+
+/// Tags a packet with its class of service.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Classed<P> {
+    pub packet: P,
+    pub class: u8,
+}
+
+impl<LT, P: Packet<LT>> Packet<LT> for Classed<P> {
+    fn destination(&self) -> LT {
+        self.packet.destination()
+    }
+}
+
+impl<P> ClassedPacket for Classed<P> {
+    fn class(&self) -> u8 {
+        self.class
+    }
+}
+
+impl<P: DAMType> DAMType for Classed<P> {
+    fn dam_size(&self) -> usize {
+        self.packet.dam_size() + self.class.dam_size()
+    }
+}
+
+/// The pieces a packet is broken up into for flit-level switching. The head flit carries the destination, and the
+/// body and tail flits carry the payload. The tail flit closes out the packet.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Flit<LocationType, PayloadType> {
+    Head(LocationType),
+    Body(PayloadType),
+    Tail(PayloadType),
+}
+
+impl<LT: Default, PT> Default for Flit<LT, PT> {
+    fn default() -> Self {
+        Self::Head(LT::default())
+    }
+}
+
+impl<LT: DAMType, PT: DAMType> DAMType for Flit<LT, PT> {
+    fn dam_size(&self) -> usize {
+        match self {
+            Flit::Head(destination) => destination.dam_size(),
+            Flit::Body(payload) | Flit::Tail(payload) => payload.dam_size(),
+        }
+    }
+}
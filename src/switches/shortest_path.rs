@@ -0,0 +1,209 @@
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BinaryHeap},
+    hash::Hash,
+};
+
+use fxhash::{FxHashMap, FxHashSet};
+
+/// Works out shortest-path routing tables for switches wired up by hand. Switches are numbered by their IDs, and are
+/// described by the edges between them and the endpoints hanging off of them.
+///
+/// Every switch gets a table with the ports on all of its shortest paths to each endpoint, by the total cost of the
+/// edges along them. A table used as is forwards packets out of every one of those ports at once, so where there is
+/// more than one, [`RoutingTableBuilder::build_candidates`] gives them to pick from instead, as
+/// [`crate::switches::EcmpPolicy`] does. A switch with no way to an endpoint has no entry for it.
+#[derive(Clone, Debug)]
+pub struct RoutingTableBuilder<LT> {
+    // Each switch's edges, as the port they leave through, the switch on the other end, and what they cost.
+    neighbors: BTreeMap<usize, Vec<(usize, usize, u64)>>,
+    endpoints: Vec<(LT, usize, usize)>,
+}
+
+impl<LT> Default for RoutingTableBuilder<LT> {
+    fn default() -> Self {
+        Self {
+            neighbors: Default::default(),
+            endpoints: vec![],
+        }
+    }
+}
+
+impl<LT: Clone + Eq + Hash> RoutingTableBuilder<LT> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds an edge between `port_a` on `switch_a` and `port_b` on `switch_b`, which costs `cost` to cross either way.
+    pub fn with_edge(
+        mut self,
+        switch_a: usize,
+        port_a: usize,
+        switch_b: usize,
+        port_b: usize,
+        cost: u64,
+    ) -> Self {
+        assert_ne!(
+            switch_a, switch_b,
+            "Edges must join two different switches!"
+        );
+        self.neighbors
+            .entry(switch_a)
+            .or_default()
+            .push((port_a, switch_b, cost));
+        self.neighbors
+            .entry(switch_b)
+            .or_default()
+            .push((port_b, switch_a, cost));
+        self
+    }
+
+    /// Puts the endpoint for `location` on port `port` of `switch`.
+    pub fn with_endpoint(mut self, location: LT, switch: usize, port: usize) -> Self {
+        assert!(
+            self.endpoints
+                .iter()
+                .all(|(other, _, _)| *other != location),
+            "Every endpoint needs a location of its own!"
+        );
+        self.neighbors.entry(switch).or_default();
+        self.endpoints.push((location, switch, port));
+        self
+    }
+
+    /// A routing table for every switch, with every port on a shortest path to each endpoint.
+    pub fn build(&self) -> FxHashMap<usize, FxHashMap<LT, FxHashSet<usize>>> {
+        self.build_candidates()
+            .into_iter()
+            .map(|(switch, table)| {
+                let table = table
+                    .into_iter()
+                    .map(|(location, ports)| (location, FxHashSet::from_iter(ports)))
+                    .collect();
+                (switch, table)
+            })
+            .collect()
+    }
+
+    /// The same tables as [`RoutingTableBuilder::build`], with the ports for each endpoint in increasing order, to pick
+    /// one of them from.
+    pub fn build_candidates(&self) -> FxHashMap<usize, FxHashMap<LT, Vec<usize>>> {
+        let mut tables: FxHashMap<_, _> = self
+            .neighbors
+            .keys()
+            .map(|switch| (*switch, FxHashMap::default()))
+            .collect();
+        for (location, target, port) in &self.endpoints {
+            let distances = self.distances_to(*target);
+            for (switch, table) in tables.iter_mut() {
+                let mut ports = match switch == target {
+                    true => vec![*port],
+                    false => match distances.get(switch) {
+                        Some(distance) => self.neighbors[switch]
+                            .iter()
+                            .filter(|(_, next, cost)| {
+                                distances.get(next).map(|next| next + cost) == Some(*distance)
+                            })
+                            .map(|(out, _, _)| *out)
+                            .collect(),
+                        None => continue,
+                    },
+                };
+                ports.sort_unstable();
+                ports.dedup();
+                table.insert(location.clone(), ports);
+            }
+        }
+        tables
+    }
+
+    /// What it costs to get from every switch which can reach `target` to it, by Dijkstra's algorithm.
+    fn distances_to(&self, target: usize) -> FxHashMap<usize, u64> {
+        let mut distances = FxHashMap::default();
+        let mut frontier = BinaryHeap::from([Reverse((0, target))]);
+        while let Some(Reverse((distance, switch))) = frontier.pop() {
+            if distances.contains_key(&switch) {
+                continue;
+            }
+            distances.insert(switch, distance);
+            for (_, next, cost) in &self.neighbors[&switch] {
+                if !distances.contains_key(next) {
+                    frontier.push(Reverse((distance + cost, *next)));
+                }
+            }
+        }
+        distances
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fxhash::{FxHashMap, FxHashSet};
+
+    use super::RoutingTableBuilder;
+
+    /// A square of switches 0-1-2-3, where going from 0 to 2 costs the same either way around, and a slow edge from
+    /// 1 to 3 that no shortest path takes. Each switch has one endpoint, named after it, on port 0.
+    fn square() -> RoutingTableBuilder<char> {
+        (0..4)
+            .fold(RoutingTableBuilder::new(), |builder, switch| {
+                builder.with_endpoint((b'a' + switch as u8) as char, switch, 0)
+            })
+            .with_edge(0, 1, 1, 2, 1)
+            .with_edge(1, 1, 2, 2, 1)
+            .with_edge(2, 1, 3, 2, 1)
+            .with_edge(3, 1, 0, 2, 1)
+            .with_edge(1, 3, 3, 3, 5)
+    }
+
+    fn ports<const N: usize>(ports: [usize; N]) -> FxHashSet<usize> {
+        FxHashSet::from_iter(ports)
+    }
+
+    #[test]
+    fn square_tables_test() {
+        let tables = square().build();
+        assert_eq!(tables.len(), 4);
+        let expected: FxHashMap<_, _> = [
+            ('a', ports([0])),
+            ('b', ports([1])),
+            // Both ways around are two hops.
+            ('c', ports([1, 2])),
+            ('d', ports([2])),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(tables[&0], expected);
+        let expected: FxHashMap<_, _> = [
+            ('a', ports([2])),
+            ('b', ports([0])),
+            ('c', ports([1])),
+            // Going around through 0 or 2 costs 2, so the direct edge, which costs 5, is left out.
+            ('d', ports([1, 2])),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(tables[&1], expected);
+    }
+
+    #[test]
+    fn candidates_test() {
+        let candidates = square().build_candidates();
+        assert_eq!(candidates[&0][&'c'], vec![1, 2]);
+        assert_eq!(candidates[&2][&'a'], vec![1, 2]);
+        assert_eq!(candidates[&3][&'d'], vec![0]);
+    }
+
+    #[test]
+    fn unreachable_endpoint_test() {
+        let tables = RoutingTableBuilder::new()
+            .with_endpoint('a', 0, 0)
+            .with_endpoint('b', 1, 0)
+            .with_endpoint('c', 2, 0)
+            .with_edge(0, 1, 1, 1, 1)
+            .build();
+        assert_eq!(tables[&0].get(&'b'), Some(&ports([1])));
+        assert_eq!(tables[&0].get(&'c'), None);
+        assert_eq!(tables[&2].len(), 1);
+    }
+}
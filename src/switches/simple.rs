@@ -1,23 +1,140 @@
-use std::hash::Hash;
+use std::{
+    collections::BTreeMap,
+    hash::Hash,
+    sync::{Arc, Mutex},
+};
 
-use dam::{channel::utils::Peekable, context_tools::*, structures::SyncSendMarker};
-use fxhash::FxHashSet;
+use dam::{
+    channel::utils::{EventTime, Peekable},
+    context_tools::*,
+    structures::SyncSendMarker,
+};
 
 use super::{
-    policy::Policy,
-    routing::{Packet, Port},
+    arbitration::{ArbitrationPolicy, IslipArbiter},
+    policy::{
+        PacketPolicy, Policy, PolicyError, PolicyUpdate, RouteContext, RouteDecision,
+        UnroutableAction, UnroutableError, UpdatablePolicy,
+    },
+    routing::{
+        Burst, Flow, HopCounted, Lookahead, MultiDestination, Packet, Port, PortMap,
+        PriorityPacket, SourceRouted, Switch,
+    },
 };
 
+/// Counters collected by a [`SimpleSwitch`] while it runs.
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SwitchStats {
+    /// Number of cycles each input had a packet held back because one of its targets was full.
+    pub retry_cycles: fxhash::FxHashMap<usize, u64>,
+    /// Number of packets dropped at each input, whether by the policy, for lack of a route, or because they ran out
+    /// of hops.
+    pub dropped: fxhash::FxHashMap<usize, u64>,
+    /// Number of packets which carried no destinations at all, under `with_multicast_destinations`. These are counted
+    /// in `dropped` as well.
+    pub empty_multicasts: u64,
+    /// Why the switch stopped early, under [`UnroutableAction::Error`].
+    pub error: Option<UnroutableError>,
+}
+
+// Reads and rewrites a packet's remaining hops.
+type HopAccessors<T> = (fn(&T) -> u8, fn(T, u8) -> T);
+
+// Reads and rewrites the port a packet should leave the next switch by.
+type LookaheadAccessors<T> = (fn(&T) -> Option<usize>, fn(&mut T, Option<usize>));
+
+// Rewrites a packet on its way out, given the ports it's going to.
+type ForwardHook<T> = fn(&mut T, &fxhash::FxHashSet<usize>);
+
+// Reads and rewrites the destinations a packet carries.
+type MulticastAccessors<T, LT> = (fn(&T) -> Vec<LT>, fn(T, Vec<LT>) -> T);
+
+// Routes a packet by the whole of it, rather than just its destination.
+type PacketRouter<T, PolicyType> = fn(&mut PolicyType, &T, &RouteContext) -> RouteDecision;
+
+// The switch's end of a control port, which keeps the type of location the updates are for out of the switch's type.
+trait ControlPort<PolicyType>: Send + Sync {
+    fn next_event(&self) -> EventTime;
+
+    /// Applies every update which is due by now, in the order they were sent.
+    fn apply_due(&self, time: &TimeManager, policy: &mut PolicyType);
+}
+
+struct Control<LT: DAMType> {
+    updates: Receiver<PolicyUpdate<LT>>,
+}
+
+impl<LT: DAMType, PolicyType: UpdatablePolicy<LT>> ControlPort<PolicyType> for Control<LT> {
+    fn next_event(&self) -> EventTime {
+        self.updates.next_event()
+    }
+
+    fn apply_due(&self, time: &TimeManager, policy: &mut PolicyType) {
+        while let dam::channel::PeekResult::Something(ChannelElement { time: due, data: _ }) =
+            self.updates.peek()
+        {
+            if due > time.tick() {
+                return;
+            }
+            let ChannelElement { time: _, data } = self.updates.dequeue(time).unwrap();
+            policy.apply(data);
+        }
+    }
+}
+
+/// A crossbar switch which forwards every ready, non-conflicting input once per cycle.
 #[context_macro]
 pub struct SimpleSwitch<T, LT, PolicyType>
 where
     T: DAMType,
 {
-    in_map: fxhash::FxHashMap<usize, Receiver<T>>,
-    out_map: fxhash::FxHashMap<usize, Sender<T>>,
+    // Ports are kept sorted so that every run considers them in the same order.
+    ports: PortMap<T>,
 
     policy: PolicyType,
+    control: Option<Box<dyn ControlPort<PolicyType>>>,
+    unroutable: UnroutableAction,
     latency: u64,
+    output_latency: fxhash::FxHashMap<usize, u64>,
+    // Link widths in bits per cycle, for outputs which model serialization.
+    output_width: fxhash::FxHashMap<usize, usize>,
+    // The first cycle on which each output is done serializing its previous packet.
+    busy_until: fxhash::FxHashMap<usize, Time>,
+    // Extra cycles spent in the output pipelines, on top of the latency.
+    pipeline_stages: u64,
+    // How many cycles a packet ties up its outputs' pipelines for, if they are modeled.
+    initiation_interval: Option<u64>,
+    max_forwards_per_cycle: usize,
+    output_speedup: usize,
+    partial_multicast: bool,
+    // Routing decisions for each input's head packet, narrowed down to the targets which still need a copy.
+    pending: fxhash::FxHashMap<usize, RouteDecision>,
+    stats: Arc<Mutex<SwitchStats>>,
+    // What the policy gets told about how busy each output has been.
+    route_context: RouteContext,
+    metrics_window: u64,
+    // The window the outputs' sent counts are for, and when each output last sent something.
+    current_window: u64,
+    last_sent: fxhash::FxHashMap<usize, Time>,
+
+    arbitration: ArbitrationPolicy,
+    matcher: Option<IslipArbiter>,
+    last_granted: Option<usize>,
+    weights: fxhash::FxHashMap<usize, u32>,
+    credits: fxhash::FxHashMap<usize, u32>,
+    priority: Option<fn(&T) -> u32>,
+    hop_count: Option<HopAccessors<T>>,
+    flow: Option<fn(&T) -> u64>,
+    on_forward: Option<ForwardHook<T>>,
+    lookahead: Option<LookaheadAccessors<T>>,
+    packet_router: Option<PacketRouter<T, PolicyType>>,
+    multicast: Option<MulticastAccessors<T, LT>>,
+    // For each input routed by its packet's destinations, the positions of the destinations behind each output.
+    fanout: fxhash::FxHashMap<usize, fxhash::FxHashMap<usize, Vec<usize>>>,
+    burst: Option<fn(&T) -> usize>,
+    // Which input each output is reserved for until the rest of its burst has gone through.
+    burst_owners: fxhash::FxHashMap<usize, usize>,
 
     _marker: SyncSendMarker<LT>,
 }
@@ -34,129 +151,807 @@ where
                 Event::Quit => return,
                 Event::Ready(set) => set,
             };
+            self.refresh_route_context();
 
-            let mut occupied_outputs = fxhash::FxHashSet::default();
-            for input_port in ready {
-                let data = match self.in_map.get(&input_port).unwrap().peek() {
-                    dam::channel::PeekResult::Something(ChannelElement { time: _, data }) => data,
-                    _ => panic!("Port {:?} was supposed to be ready", input_port),
+            // How many packets each output has accepted so far this cycle.
+            let mut occupied_outputs = fxhash::FxHashMap::<usize, usize>::default();
+            let mut forwarded = 0;
+            let mut heads = self.arbitrate(ready);
+            if self.matcher.is_some() {
+                heads = self.islip_match(heads);
+            }
+            for (input_port, ChannelElement { time: _, data }) in heads {
+                // Whoever didn't make it this cycle stays queued for the next one.
+                if forwarded == self.max_forwards_per_cycle {
+                    break;
+                }
+                let data = match self.hop_count {
+                    Some((hops, with_hops)) => {
+                        let hops_left = hops(&data).saturating_sub(1);
+                        if hops_left == 0 {
+                            self.drop_head(input_port);
+                            continue;
+                        }
+                        with_hops(data, hops_left)
+                    }
+                    None => data,
                 };
-                let targets = self.policy.route(&data.destination());
-                let is_ready = occupied_outputs.intersection(&targets).count() == 0;
-                if !is_ready {
-                    continue;
+                let decision = match self.pending.remove(&input_port) {
+                    Some(decision) => decision,
+                    None => self.route(input_port, &data),
+                };
+                let decision = self
+                    .unroutable
+                    .resolve(decision, |port| self.ports.outputs.contains_key(&port));
+                let targets = match decision {
+                    RouteDecision::Forward(targets) => targets,
+                    RouteDecision::Drop => {
+                        self.drop_head(input_port);
+                        continue;
+                    }
+                    RouteDecision::Error => {
+                        self.stats.lock().unwrap().error = Some(UnroutableError {
+                            port: input_port,
+                            cycle: self.time.tick().time(),
+                        });
+                        return;
+                    }
+                };
+                if !self.partial_multicast {
+                    let blocked = !targets
+                        .iter()
+                        .all(|target| self.is_free(input_port, *target, &occupied_outputs));
+                    // Every target has to have room before anyone gets a copy, so that the packet goes out to all of
+                    // them at once or not at all.
+                    let full = !blocked
+                        && targets.iter().any(|target| {
+                            self.ports.outputs.get(target).unwrap().is_full(&self.time)
+                        });
+                    if full {
+                        self.count_retry(input_port);
+                    }
+                    if blocked || full {
+                        // Hang on to the routing decision, so that the policy is only consulted once per packet.
+                        self.pending
+                            .insert(input_port, RouteDecision::Forward(targets));
+                        continue;
+                    }
                 }
 
-                // Pop it off since it's ready.
-                let _ = self.in_map.get(&input_port).unwrap().dequeue(&self.time);
-
-                targets.iter().for_each(|x| {
-                    let _ = self
-                        .out_map
-                        .get(x)
-                        .unwrap()
-                        .wait_until_available(&self.time);
-                });
+                let data = match self.on_forward {
+                    Some(on_forward) => {
+                        let mut data = data;
+                        on_forward(&mut data, &targets);
+                        data
+                    }
+                    None => data,
+                };
 
-                targets.iter().for_each(|x| {
-                    let _ = self.out_map.get(x).unwrap().enqueue(
+                // Offer a copy to every free target without blocking, so that a single full output can't stall the
+                // whole switch. Whoever can't take it yet gets another shot on a later cycle.
+                let mut remaining = fxhash::FxHashSet::default();
+                let mut delivered = false;
+                let mut refused = false;
+                for target in targets {
+                    if !self.is_free(input_port, target, &occupied_outputs) {
+                        remaining.insert(target);
+                        continue;
+                    }
+                    let latency = self
+                        .output_latency
+                        .get(&target)
+                        .copied()
+                        .unwrap_or(self.latency);
+                    let serialization = self.serialization_cycles(target, &data);
+                    let mut copy = data.clone();
+                    if let Some((destinations, with_destinations)) = self.multicast {
+                        if let Some(behind) = self
+                            .fanout
+                            .get(&input_port)
+                            .and_then(|fanout| fanout.get(&target))
+                        {
+                            let kept = destinations(&copy)
+                                .into_iter()
+                                .enumerate()
+                                .filter(|(index, _)| behind.contains(index))
+                                .map(|(_, destination)| destination)
+                                .collect();
+                            copy = with_destinations(copy, kept);
+                        }
+                    }
+                    if let Some((_, set_next_port)) = self.lookahead {
+                        set_next_port(&mut copy, self.policy.next_hop(target, &data.destination()));
+                    }
+                    let result = self.ports.outputs.get(&target).unwrap().try_enqueue(
                         &self.time,
                         ChannelElement {
-                            time: self.time.tick() + self.latency,
-                            data: data.clone(),
+                            time: self.time.tick() + latency + self.pipeline_stages + serialization,
+                            data: copy,
                         },
                     );
-                });
+                    match result {
+                        Err(dam::channel::EnqueueError::Full) => {
+                            refused = true;
+                            remaining.insert(target);
+                            self.route_context
+                                .outputs
+                                .entry(target)
+                                .or_default()
+                                .send_failures += 1;
+                        }
+                        _ => {
+                            delivered = true;
+                            self.route_context.outputs.entry(target).or_default().sent += 1;
+                            self.last_sent.insert(target, self.time.tick());
+                            *occupied_outputs.entry(target).or_default() += 1;
+                            let occupancy =
+                                serialization.max(self.initiation_interval.unwrap_or(0));
+                            if occupancy > 0 {
+                                self.busy_until.insert(target, self.time.tick() + occupancy);
+                            }
+                            if let Some(burst_remaining) = self.burst {
+                                if burst_remaining(&data) > 0 {
+                                    self.burst_owners.insert(target, input_port);
+                                } else {
+                                    self.burst_owners.remove(&target);
+                                }
+                            }
+                        }
+                    }
+                }
+
+                if refused {
+                    self.count_retry(input_port);
+                }
+                if remaining.is_empty() {
+                    // Pop it off since everyone has their copy.
+                    self.fanout.remove(&input_port);
+                    let _ = self
+                        .ports
+                        .inputs
+                        .get(&input_port)
+                        .unwrap()
+                        .dequeue(&self.time);
+                } else {
+                    self.pending
+                        .insert(input_port, RouteDecision::Forward(remaining));
+                }
+                if !delivered {
+                    continue;
+                }
 
-                // Add the targets to the occupied set.
-                occupied_outputs.extend(targets);
+                forwarded += 1;
+                self.last_granted = Some(input_port);
+                if let Some(credits) = self.credits.get_mut(&input_port) {
+                    *credits = credits.saturating_sub(1);
+                }
             }
             self.time.incr_cycles(1);
         }
     }
 }
 
+impl<T: DAMType, LT, PolicyType> Switch<T> for SimpleSwitch<T, LT, PolicyType>
+where
+    Self: Context,
+{
+    fn add_port(&mut self, port: Port<T>) {
+        port.attach(self);
+        self.ports.insert(port);
+    }
+}
+
+impl<T: DAMType, LT, PolicyType> SimpleSwitch<T, LT, PolicyType>
+where
+    T: Packet<LT>,
+    LT: Eq + Hash,
+    PolicyType: Policy<LT> + Sync + Send,
+{
+    /// Asks the policy where the packet which came in on `input_port` should go, filling in the parts of the route
+    /// context which are particular to the packet.
+    fn route(&mut self, input_port: usize, data: &T) -> RouteDecision {
+        if let Some(port) = self.lookahead.and_then(|(next_port, _)| next_port(data)) {
+            return RouteDecision::Forward(fxhash::FxHashSet::from_iter([port]));
+        }
+        self.route_context.flow = self.flow.map(|flow| flow(data));
+        self.route_context.ingress = Some(input_port);
+        if let Some((destinations, _)) = self.multicast {
+            return self.route_destinations(input_port, destinations(data));
+        }
+        match self.packet_router {
+            Some(route_packet) => route_packet(&mut self.policy, data, &self.route_context),
+            None => self
+                .policy
+                .route_adaptive(&data.destination(), &self.route_context),
+        }
+    }
+
+    /// Routes each of a packet's destinations, and sends it out of every output any of them go to. Remembers which
+    /// destinations are behind each output, so that the copies can be cut down to just those. The packet is only
+    /// unroutable if one of its destinations is.
+    fn route_destinations(&mut self, input_port: usize, destinations: Vec<LT>) -> RouteDecision {
+        if destinations.is_empty() {
+            self.stats.lock().unwrap().empty_multicasts += 1;
+            return RouteDecision::Drop;
+        }
+        let mut fanout = fxhash::FxHashMap::<usize, Vec<usize>>::default();
+        for (index, destination) in destinations.iter().enumerate() {
+            // Repeats of a destination are already on their way with the first one.
+            if destinations[..index].contains(destination) {
+                continue;
+            }
+            let ports = match self.policy.route_adaptive(destination, &self.route_context) {
+                RouteDecision::Forward(ports) => ports,
+                RouteDecision::Drop => continue,
+                RouteDecision::Error => return RouteDecision::Error,
+            };
+            for port in ports {
+                fanout.entry(port).or_default().push(index);
+            }
+        }
+        if fanout.is_empty() {
+            return RouteDecision::Drop;
+        }
+        let targets = fanout.keys().copied().collect();
+        self.fanout.insert(input_port, fanout);
+        RouteDecision::Forward(targets)
+    }
+
+    /// Checks that the policy sends every one of `destinations` to outputs the switch has, before the simulation
+    /// starts, so that a mistake in a routing table shows up as the destination and port it's about instead of
+    /// somewhere in the middle of the run. Destinations the policy can't route are only a problem if the switch would
+    /// stop on them, or if it would divert them to a port it doesn't have. Add the ports first.
+    ///
+    /// The policy is asked the same way it would be during the run, as if each destination came in on no port in
+    /// particular, so policies which keep state see these as real lookups.
+    pub fn validate_policy(
+        &mut self,
+        destinations: impl IntoIterator<Item = LT>,
+    ) -> Result<(), PolicyError<LT>> {
+        let context = RouteContext {
+            outputs: self
+                .ports
+                .outputs
+                .keys()
+                .map(|port| (*port, Default::default()))
+                .collect(),
+            ..Default::default()
+        };
+        for destination in destinations {
+            let ports = match self.policy.route_adaptive(&destination, &context) {
+                RouteDecision::Forward(ports) => ports,
+                RouteDecision::Drop => continue,
+                RouteDecision::Error => match self.unroutable {
+                    UnroutableAction::Drop => continue,
+                    UnroutableAction::Divert(port) => fxhash::FxHashSet::from_iter([port]),
+                    UnroutableAction::Panic | UnroutableAction::Error => {
+                        return Err(PolicyError::Unroutable { destination })
+                    }
+                },
+            };
+            let mut ports: Vec<_> = ports.into_iter().collect();
+            ports.sort_unstable();
+            if let Some(port) = ports
+                .into_iter()
+                .find(|port| !self.ports.outputs.contains_key(port))
+            {
+                return Err(PolicyError::MissingPort { destination, port });
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs the iSLIP matching over the arbitrated heads. Matched inputs go first, and unicast packets which didn't
+    /// get matched sit this cycle out. Multicast packets don't take part in the matching; they go out afterwards if
+    /// all of their targets are still free.
+    fn islip_match(
+        &mut self,
+        heads: Vec<(usize, ChannelElement<T>)>,
+    ) -> Vec<(usize, ChannelElement<T>)> {
+        let mut requests = BTreeMap::new();
+        for (input_port, head) in &heads {
+            if !self.pending.contains_key(input_port) {
+                let decision = self.route(*input_port, &head.data);
+                self.pending.insert(*input_port, decision);
+            }
+            if let RouteDecision::Forward(targets) = &self.pending[input_port] {
+                if targets.len() == 1 {
+                    requests.insert(*input_port, targets.clone());
+                }
+            }
+        }
+        // Outputs which are still busy serializing, or held by someone else's burst, can't be matched.
+        let idle = fxhash::FxHashMap::default();
+        requests.retain(|input_port, targets| {
+            targets
+                .iter()
+                .all(|target| self.is_free(*input_port, *target, &idle))
+        });
+
+        let matches = self.matcher.as_mut().unwrap().schedule(&requests);
+        let (mut matched, others): (Vec<_>, Vec<_>) = heads
+            .into_iter()
+            .filter(|(input_port, _)| {
+                matches.contains_key(input_port) || !requests.contains_key(input_port)
+            })
+            .partition(|(input_port, _)| matches.contains_key(input_port));
+        matched.extend(others);
+        matched
+    }
+}
+
+impl<T: DAMType, LT, PolicyType> SimpleSwitch<T, LT, PolicyType>
+where
+    Self: Context,
+    T: PriorityPacket,
+{
+    /// Lets the highest priority packet win whenever inputs contend for an output.
+    /// Ties between packets of equal priority are broken by the arbitration policy.
+    pub fn with_priority_arbitration(mut self) -> Self {
+        self.priority = Some(T::priority);
+        self
+    }
+}
+
+impl<T: DAMType, LT, PolicyType> SimpleSwitch<T, LT, PolicyType>
+where
+    Self: Context,
+    T: HopCounted,
+{
+    /// Spends one of each packet's hops as it passes through the switch.
+    /// Packets which run out are dropped instead of being forwarded, and counted in [`SwitchStats::dropped`].
+    pub fn with_hop_limit(mut self) -> Self {
+        self.hop_count = Some((T::hops, T::with_hops));
+        self
+    }
+}
+
+impl<T: DAMType, LT, PolicyType> SimpleSwitch<T, LT, PolicyType>
+where
+    Self: Context,
+    T: Flow<LT>,
+{
+    /// Tells the policy which flow each packet belongs to, through [`RouteContext::flow`], for policies like
+    /// [`crate::switches::EcmpPolicy`] which keep flows together.
+    pub fn with_flows(mut self) -> Self {
+        self.flow = Some(T::flow_id);
+        self
+    }
+}
+
+impl<T: DAMType, LT, PolicyType> SimpleSwitch<T, LT, PolicyType>
+where
+    Self: Context,
+    T: SourceRouted,
+{
+    /// Uses up the first hop of each packet's path as it goes out, so that the next switch sees the rest. Pair this
+    /// with a [`crate::switches::SourceRoutePolicy`], which sends packets where their paths say.
+    pub fn with_source_routes(mut self) -> Self {
+        self.on_forward = Some(|packet, _| packet.advance());
+        self
+    }
+}
+
+impl<T: DAMType, LT, PolicyType> SimpleSwitch<T, LT, PolicyType>
+where
+    Self: Context,
+    LT: DAMType + 'static,
+    PolicyType: UpdatablePolicy<LT>,
+{
+    /// Has the switch take updates to its routing table from `updates`, as the simulation runs. Each update is applied
+    /// once the switch reaches the cycle it's stamped with, before anything is routed on that cycle, so that packets
+    /// go by whichever table is in place when they're routed. Packets which were routed before an update and are still
+    /// waiting for their outputs keep going where they were sent.
+    pub fn set_control_port(&mut self, updates: Receiver<PolicyUpdate<LT>>) {
+        updates.attach_receiver(self);
+        self.control = Some(Box::new(Control { updates }));
+    }
+}
+
+impl<T: DAMType, LT, PolicyType> SimpleSwitch<T, LT, PolicyType>
+where
+    Self: Context,
+    T: Lookahead,
+{
+    /// Routes a hop ahead: packets which carry the port to leave by go straight there without asking the policy,
+    /// and every copy going out is given the port for the switch after, by [`Policy::next_hop`]. Pair this with a
+    /// [`crate::switches::LookaheadPolicy`], and take a cycle off of the latency for the routing done in advance.
+    pub fn with_lookahead(mut self) -> Self {
+        self.lookahead = Some((T::next_port, T::set_next_port));
+        self
+    }
+}
+
+impl<T: DAMType, LT, PolicyType> SimpleSwitch<T, LT, PolicyType>
+where
+    Self: Context,
+    PolicyType: PacketPolicy<T>,
+{
+    /// Routes each packet by [`PacketPolicy::route_packet_adaptive`], which sees the whole packet, instead of by its
+    /// destination alone. Pair this with a [`crate::switches::PerClassPolicy`] to route by class of service.
+    pub fn with_packet_routing(mut self) -> Self {
+        self.packet_router = Some(PolicyType::route_packet_adaptive);
+        self
+    }
+}
+
+impl<T: DAMType, LT, PolicyType> SimpleSwitch<T, LT, PolicyType>
+where
+    Self: Context,
+    T: MultiDestination<LT>,
+{
+    /// Routes packets by every destination they carry, rather than by [`Packet::destination`]. Each output any of the
+    /// destinations go to gets a single copy, which carries only the destinations behind that output, so that
+    /// switches further on don't deliver the others a second time. Packets with no destinations are dropped.
+    pub fn with_multicast_destinations(mut self) -> Self {
+        self.multicast = Some((T::destinations, T::with_destinations));
+        self
+    }
+}
+
+impl<T: DAMType, LT, PolicyType> SimpleSwitch<T, LT, PolicyType>
+where
+    Self: Context,
+    T: Burst,
+{
+    /// Keeps an output reserved for the input which started a burst on it, until the last element of the burst has
+    /// gone through, so that bursts from different inputs never interleave.
+    pub fn with_bursts(mut self) -> Self {
+        self.burst = Some(T::remaining);
+        self
+    }
+}
+
 enum Event {
     Quit,
-    Ready(fxhash::FxHashSet<usize>),
+    Ready(Vec<usize>),
 }
 
 impl<T: DAMType, LT, PolicyType> SimpleSwitch<T, LT, PolicyType>
 where
     Self: Context,
 {
+    /// Creates a switch which forwards each packet `latency` cycles after it is routed.
+    ///
+    /// The element type `T` must implement [`Packet<LT>`] so that the switch can read its destination,
+    /// and `PolicyType` must implement [`Policy<LT>`] (as well as `Sync + Send`) to map that destination
+    /// onto the port IDs registered through [`Switch::add_port`].
     pub fn new(policy: PolicyType, latency: u64) -> Self {
         Self {
-            in_map: Default::default(),
-            out_map: Default::default(),
+            ports: Default::default(),
             policy,
+            control: None,
+            unroutable: Default::default(),
             latency,
+            output_latency: Default::default(),
+            output_width: Default::default(),
+            busy_until: Default::default(),
+            pipeline_stages: 0,
+            initiation_interval: None,
+            max_forwards_per_cycle: usize::MAX,
+            output_speedup: 1,
+            partial_multicast: false,
+            pending: Default::default(),
+            stats: Default::default(),
+            route_context: Default::default(),
+            metrics_window: 16,
+            current_window: 0,
+            last_sent: Default::default(),
+            arbitration: Default::default(),
+            matcher: None,
+            last_granted: None,
+            weights: Default::default(),
+            credits: Default::default(),
+            priority: None,
+            hop_count: None,
+            flow: None,
+            on_forward: None,
+            lookahead: None,
+            packet_router: None,
+            multicast: None,
+            fanout: Default::default(),
+            burst: None,
+            burst_owners: Default::default(),
             _marker: Default::default(),
             context_info: Default::default(),
         }
     }
 
-    pub fn add_port(&mut self, port: Port<T>) {
-        let id = port.id;
-        if let Some(rcv) = port.input {
-            rcv.attach_receiver(self);
-            assert!(
-                self.in_map.insert(id, rcv).is_none(),
-                "Input port was already occupied!"
-            );
+    /// Sets what happens to packets whose destination the policy doesn't know. By default, the switch panics.
+    pub fn with_unroutable(mut self, unroutable: UnroutableAction) -> Self {
+        self.unroutable = unroutable;
+        self
+    }
+
+    /// Has `on_forward` rewrite each packet once it's been routed, just before its copies go out, given the ports
+    /// they're going to. Packets which have to wait for their outputs are rewritten afresh each time they try.
+    pub fn with_on_forward(mut self, on_forward: ForwardHook<T>) -> Self {
+        self.on_forward = Some(on_forward);
+        self
+    }
+
+    /// Sets the order in which contending inputs are considered each cycle.
+    pub fn with_arbitration(mut self, arbitration: ArbitrationPolicy) -> Self {
+        self.arbitration = arbitration;
+        self
+    }
+
+    /// Matches inputs to outputs with the given arbiter each cycle, instead of greedily scanning the inputs in
+    /// arbitration order.
+    pub fn with_arbiter(mut self, arbiter: IslipArbiter) -> Self {
+        self.matcher = Some(arbiter);
+        self
+    }
+
+    /// Limits how many packets the switch fabric can move per cycle, regardless of how many ports are free.
+    pub fn with_max_forwards_per_cycle(mut self, max_forwards_per_cycle: usize) -> Self {
+        assert!(
+            max_forwards_per_cycle > 0,
+            "The switch must be able to forward at least one packet per cycle!"
+        );
+        self.max_forwards_per_cycle = max_forwards_per_cycle;
+        self
+    }
+
+    /// Lets each output accept packets from up to `output_speedup` different inputs per cycle.
+    pub fn with_output_speedup(mut self, output_speedup: usize) -> Self {
+        assert!(
+            output_speedup > 0,
+            "Outputs must be able to accept at least one packet per cycle!"
+        );
+        self.output_speedup = output_speedup;
+        self
+    }
+
+    /// Lets multicast packets go out to whichever of their targets are free, instead of waiting for all of them.
+    /// The packet stays at the head of its input until every target has received a copy.
+    pub fn with_partial_multicast(mut self) -> Self {
+        self.partial_multicast = true;
+        self
+    }
+
+    /// Models the path to each output as a pipeline with `stages` stages, which takes in a new packet every
+    /// `initiation_interval` cycles. The stages add to whichever latency the output has, switch-wide or set with
+    /// [`SimpleSwitch::set_output_latency`], and the outputs packets go out through won't take another packet until
+    /// the interval is up.
+    pub fn with_pipeline(mut self, stages: u64, initiation_interval: u64) -> Self {
+        assert!(
+            initiation_interval > 0,
+            "Initiation intervals must be positive!"
+        );
+        self.pipeline_stages = stages;
+        self.initiation_interval = Some(initiation_interval);
+        self
+    }
+
+    /// Sets how many cycles the sent counts which adaptive policies see are kept over. Every `cycles` cycles, the
+    /// counts start over from zero. Defaults to 16.
+    pub fn with_metrics_window(mut self, cycles: u64) -> Self {
+        assert!(cycles > 0, "Metrics windows must be positive!");
+        self.metrics_window = cycles;
+        self
+    }
+
+    /// Brings the time and output metrics handed to the policy up to the current cycle.
+    fn refresh_route_context(&mut self) {
+        let tick = self.time.tick();
+        self.route_context.now = tick.time();
+        let window = tick.time() / self.metrics_window;
+        let new_window = window != self.current_window;
+        self.current_window = window;
+        for port in self.ports.outputs.keys() {
+            let metrics = self.route_context.outputs.entry(*port).or_default();
+            if new_window {
+                metrics.sent = 0;
+            }
+            metrics.busy_last_cycle = self
+                .last_sent
+                .get(port)
+                .is_some_and(|sent| *sent + 1 == tick);
         }
-        if let Some(snd) = port.output {
-            snd.attach_sender(self);
-            assert!(
-                self.out_map.insert(id, snd).is_none(),
-                "Output port was already occupied!"
-            );
+    }
+
+    /// A handle to the switch's counters, which remains readable after the simulation has run.
+    pub fn stats(&self) -> Arc<Mutex<SwitchStats>> {
+        self.stats.clone()
+    }
+
+    /// Overrides the switch-wide latency for packets leaving through the given output port.
+    pub fn set_output_latency(&mut self, port: usize, latency: u64) {
+        self.output_latency.insert(port, latency);
+    }
+
+    /// Models the given output as a link which moves `bits_per_cycle` bits each cycle. Packets leaving through it
+    /// take an extra `ceil(dam_size / bits_per_cycle)` cycles to arrive, and keep the output busy for that long.
+    pub fn set_output_width(&mut self, port: usize, bits_per_cycle: usize) {
+        assert!(bits_per_cycle > 0, "Output widths must be positive!");
+        self.output_width.insert(port, bits_per_cycle);
+    }
+
+    /// Sets how many grants an input receives per frame under [`ArbitrationPolicy::WeightedRoundRobin`].
+    /// Ports default to a weight of 1.
+    pub fn set_port_weight(&mut self, id: usize, weight: u32) {
+        assert!(weight > 0, "Port weights must be positive!");
+        self.weights.insert(id, weight);
+    }
+
+    /// Peeks the head of every ready input, and orders them by who gets the first shot at the outputs.
+    fn arbitrate(&mut self, ready: Vec<usize>) -> Vec<(usize, ChannelElement<T>)> {
+        let mut heads: Vec<_> = ready
+            .into_iter()
+            .map(|id| match self.ports.inputs.get(&id).unwrap().peek() {
+                dam::channel::PeekResult::Something(head) => (id, head),
+                _ => panic!("Port {:?} was supposed to be ready", id),
+            })
+            .collect();
+
+        match self.arbitration {
+            ArbitrationPolicy::Unordered => {}
+            ArbitrationPolicy::RoundRobin => self.round_robin_order(&mut heads),
+            ArbitrationPolicy::WeightedRoundRobin => {
+                // Start a new frame once none of the contenders have any grants left in the current one.
+                if heads
+                    .iter()
+                    .all(|(id, _)| self.credits.get(id).copied().unwrap_or(0) == 0)
+                {
+                    self.credits = self
+                        .ports
+                        .inputs
+                        .keys()
+                        .map(|id| (*id, self.weights.get(id).copied().unwrap_or(1)))
+                        .collect();
+                }
+                self.round_robin_order(&mut heads);
+                // Stable, so inputs with grants left stay in round-robin order amongst themselves.
+                heads.sort_by_key(|(id, _)| self.credits.get(id).copied().unwrap_or(0) == 0);
+            }
+            ArbitrationPolicy::OldestFirst => {
+                heads.sort_unstable_by_key(|(id, head)| (head.time, *id));
+            }
+        }
+
+        if let Some(priority) = self.priority {
+            // This is a stable sort, so ties keep the order chosen by the arbitration policy.
+            heads.sort_by_key(|(_, head)| std::cmp::Reverse(priority(&head.data)));
+        }
+        heads
+    }
+
+    /// Throws away the packet at the head of the given input.
+    fn drop_head(&mut self, input_port: usize) {
+        let _ = self
+            .ports
+            .inputs
+            .get(&input_port)
+            .unwrap()
+            .dequeue(&self.time);
+        self.pending.remove(&input_port);
+        self.fanout.remove(&input_port);
+        *self
+            .stats
+            .lock()
+            .unwrap()
+            .dropped
+            .entry(input_port)
+            .or_default() += 1;
+    }
+
+    /// Notes that the given input's packet was held back this cycle by a full output.
+    fn count_retry(&self, input_port: usize) {
+        *self
+            .stats
+            .lock()
+            .unwrap()
+            .retry_cycles
+            .entry(input_port)
+            .or_default() += 1;
+    }
+
+    fn is_free(
+        &self,
+        input_port: usize,
+        target: usize,
+        occupied_outputs: &fxhash::FxHashMap<usize, usize>,
+    ) -> bool {
+        occupied_outputs.get(&target).copied().unwrap_or(0) < self.output_speedup
+            && self
+                .burst_owners
+                .get(&target)
+                .is_none_or(|owner| *owner == input_port)
+            && self
+                .busy_until
+                .get(&target)
+                .is_none_or(|busy_until| *busy_until <= self.time.tick())
+    }
+
+    fn serialization_cycles(&self, target: usize, data: &T) -> u64 {
+        match self.output_width.get(&target) {
+            Some(width) => data.dam_size().div_ceil(*width) as u64,
+            None => 0,
+        }
+    }
+
+    fn round_robin_order(&self, heads: &mut [(usize, ChannelElement<T>)]) {
+        heads.sort_unstable_by_key(|(id, _)| *id);
+        if let Some(last) = self.last_granted {
+            // Start the scan from the first port after the one which won most recently.
+            let start = heads.partition_point(|(id, _)| *id <= last);
+            heads.rotate_left(start);
         }
     }
 
     fn advance_to_next_event(&mut self) -> Event {
-        if self.in_map.is_empty() {
-            return Event::Quit;
-        }
-        if self.in_map.len() == 1 {
-            if let Some((id, rcv)) = self.in_map.iter().next() {
-                return match rcv.peek_next(&self.time) {
-                    Ok(_) => Event::Ready(FxHashSet::from_iter(std::iter::once(*id))),
-                    Err(_) => Event::Quit,
-                };
-            } else {
-                unreachable!("We just checked that the in map had one element");
-            }
-        } else {
-            loop {
-                // Loop over all of the channels, jumping forward until at least one of them is ready.
-                let next_event = self.in_map.values().map(|chan| chan.next_event()).min().unwrap();
-
-                match next_event {
-                    dam::channel::utils::EventTime::Ready(t) => {
-                        // Hop ourselves forward to the ready time.
-                        self.time.advance(t);
-                        // Now filter the channels to see which ones were ready
-                        return Event::Ready(
-                            self.in_map
-                                .values()
-                                .enumerate()
-                                .filter(|(_, chan)| match chan.peek() {
-                                    // Get all of the channels which had something on them and are ready
-                                    dam::channel::PeekResult::Something(x) if x.time <= t => true,
-                                    _ => false,
-                                })
-                                // Get the index of those channels
-                                .map(|(ind, _)| ind)
-                                .collect(),
-                        );
+        loop {
+            // Retire inputs which have closed and been drained, so that the rest can keep going without them.
+            self.ports
+                .inputs
+                .retain(|_, chan| !matches!(chan.peek(), dam::channel::PeekResult::Closed));
+            if self
+                .control
+                .as_ref()
+                .is_some_and(|control| matches!(control.next_event(), EventTime::Closed))
+            {
+                self.control = None;
+            }
+            if self.ports.inputs.is_empty() {
+                return Event::Quit;
+            }
+            // Updates can't be waited on alongside a single input, so the control port takes the long way around.
+            if self.ports.inputs.len() == 1 && self.control.is_none() {
+                if let Some((id, rcv)) = self.ports.inputs.iter().next() {
+                    match rcv.peek_next(&self.time) {
+                        Ok(_) => return Event::Ready(vec![*id]),
+                        // Closed, so the next pass will retire it.
+                        Err(_) => continue,
+                    }
+                } else {
+                    unreachable!("We just checked that the in map had one element");
+                }
+            }
+
+            // Loop over all of the channels, jumping forward until at least one of them is ready.
+            let next_event = self
+                .ports
+                .inputs
+                .values()
+                .map(|chan| chan.next_event())
+                .chain(self.control.iter().map(|control| control.next_event()))
+                .min()
+                .unwrap();
+
+            match next_event {
+                dam::channel::utils::EventTime::Ready(t) => {
+                    // Hop ourselves forward to the ready time.
+                    self.time.advance(t);
+                    // We may already have been past t, in which case anything which arrived up until now is ready too.
+                    let t = self.time.tick();
+                    if let Some(control) = &self.control {
+                        control.apply_due(&self.context_info.time, &mut self.policy);
+                    }
+                    // Now filter the channels to see which ones were ready
+                    let ready: Vec<_> = self
+                        .ports
+                        .inputs
+                        .iter()
+                        .filter(|(_, chan)| match chan.peek() {
+                            // Get all of the channels which had something on them and are ready
+                            dam::channel::PeekResult::Something(x) if x.time <= t => true,
+                            _ => false,
+                        })
+                        // Get the port IDs of those channels
+                        .map(|(id, _)| *id)
+                        .collect();
+                    // It may only have been an update which was ready.
+                    if !ready.is_empty() {
+                        return Event::Ready(ready);
                     }
-                    // If there's nothing ready, hop forward one tick after.
-                    // We could be a bit more intelligent w.r.t. the channels' latency, but that feels error prone
-                    // and I'm not sure if we need to do that right now. (10/29/23)
-                    dam::channel::utils::EventTime::Nothing(t) => self.time.advance(t + 1),
-                    dam::channel::utils::EventTime::Closed => return Event::Quit,
                 }
+                // If there's nothing ready, hop forward one tick after.
+                // We could be a bit more intelligent w.r.t. the channels' latency, but that feels error prone
+                // and I'm not sure if we need to do that right now. (10/29/23)
+                dam::channel::utils::EventTime::Nothing(t) => self.time.advance(t + 1),
+                // Something closed since we last checked, which the next pass takes care of.
+                dam::channel::utils::EventTime::Closed => {}
             }
         }
     }
@@ -164,10 +959,34 @@ where
 
 #[cfg(test)]
 mod tests {
-    use dam::{simulation::{ProgramBuilder, DotConvertible}, utility_contexts::*, context_tools::ChannelElement};
+    use dam::{
+        context_tools::{ChannelElement, DAMType},
+        simulation::{DotConvertible, ProgramBuilder},
+        structures::Time,
+        utility_contexts::*,
+    };
     use fxhash::FxHashSet;
+    use std::sync::{Arc, Mutex};
 
-    use crate::switches::{routing::{SimplePacket, Port}, simple::SimpleSwitch};
+    use crate::contexts::{CheckOrder, CheckerSink};
+    use crate::switches::{
+        arbitration::{ArbitrationPolicy, IslipArbiter},
+        mesh::{MeshPorts, XyPolicy},
+        policy::{
+            LookaheadPolicy, PolicyError, PolicyUpdate, SourceRoutePolicy, UnroutableAction,
+            UnroutableError,
+        },
+        routing::{
+            self, sized_packets, traffic_matrix, Burst, Coord2D, HoppedPacket, LookaheadPacket,
+            MulticastPacket, Packet, Port, PriorityPacket, SimplePacket, SourceRoutedPacket,
+            SourcedSimplePacket, Switch,
+        },
+        simple::SimpleSwitch,
+        simple::SwitchStats,
+        testing::{
+            connect, direct_policy, run_mesh, run_network, run_switch, stream, Sink, TestPacket,
+        },
+    };
 
     #[test]
     fn simple_switch_test() {
@@ -211,7 +1030,11 @@ mod tests {
         ctx.add_child(comp);
 
         let (switch2check_snd, switch2check_rcv) = ctx.unbounded();
-        ctx.add_child(ConsumerContext::new(switch2check_rcv));
+        // Packet i comes back from comp with i + i + 100, and nothing overtakes anything else on the way.
+        let expected = (0..NUM_PACKETS).map(|i| SimplePacket { location: 2u8, payload: i + i + 100 }).collect();
+        let checker = CheckerSink::new(switch2check_rcv, expected).with_order(CheckOrder::ExactOrder);
+        let report = checker.report();
+        ctx.add_child(checker);
 
         switch.add_port(Port { id: 2, input: None, output: Some(switch2check_snd) });
         ctx.add_child(switch);
@@ -221,6 +1044,1353 @@ mod tests {
         let executed = initialized.run(Default::default());
 
         assert_eq!(NUM_PACKETS as u64 + 4, executed.elapsed_cycles().unwrap().time());
+        let report = report.lock().unwrap();
+        assert!(report.passed(), "{:?}", report);
+
+    }
+
+    #[test]
+    fn noncontiguous_port_test() {
+        const NUM_PACKETS: u16 = 256;
+
+        let mut ctx = ProgramBuilder::default();
+
+        // Port 7 is input-only, port 42 is bidirectional, and port 100 is output-only. Every location goes out of
+        // the port with the same ID.
+        let policy = |dst: &u8| FxHashSet::from_iter([*dst as usize]);
+        let mut switch = SimpleSwitch::new(policy, 1);
+
+        let (gen7_snd, gen7_rcv) = ctx.unbounded();
+        ctx.add_child(GeneratorContext::new(
+            || {
+                (0..NUM_PACKETS).map(|i| SimplePacket {
+                    location: 100u8,
+                    payload: i,
+                })
+            },
+            gen7_snd,
+        ));
+        let (gen42_snd, gen42_rcv) = ctx.unbounded();
+        ctx.add_child(GeneratorContext::new(
+            || {
+                (0..NUM_PACKETS).map(|i| SimplePacket {
+                    location: 42u8,
+                    payload: i,
+                })
+            },
+            gen42_snd,
+        ));
+
+        let (out42_snd, out42_rcv) = ctx.unbounded();
+        ctx.add_child(CheckerContext::new(
+            || {
+                (0..NUM_PACKETS).map(|i| SimplePacket {
+                    location: 42u8,
+                    payload: i,
+                })
+            },
+            out42_rcv,
+        ));
+        let (out100_snd, out100_rcv) = ctx.unbounded();
+        ctx.add_child(CheckerContext::new(
+            || {
+                (0..NUM_PACKETS).map(|i| SimplePacket {
+                    location: 100u8,
+                    payload: i,
+                })
+            },
+            out100_rcv,
+        ));
+
+        switch.add_port(Port {
+            id: 7,
+            input: Some(gen7_rcv),
+            output: None,
+        });
+        switch.add_port(Port {
+            id: 42,
+            input: Some(gen42_rcv),
+            output: Some(out42_snd),
+        });
+        switch.add_port(Port {
+            id: 100,
+            input: None,
+            output: Some(out100_snd),
+        });
+        ctx.add_child(switch);
 
+        ctx.initialize(Default::default())
+            .unwrap()
+            .run(Default::default());
+    }
+
+    /// Has input `src` send `count` packets to `location`, each carrying its own port as the payload.
+    fn tagged(src: usize, location: u8, count: u16) -> (usize, Vec<(u64, TestPacket)>) {
+        let packets = (0..count).map(|_| SimplePacket {
+            location,
+            payload: src as u16,
+        });
+        (src, stream(packets))
+    }
+
+    #[test]
+    fn round_robin_fairness_test() {
+        const NUM_PACKETS: u16 = 512;
+
+        let switch = SimpleSwitch::new(direct_policy([2]), 1)
+            .with_arbitration(ArbitrationPolicy::RoundRobin);
+        // Both inputs tag their packets with their own port and hammer output 2 every cycle.
+        let sources = (0..2).map(|src| tagged(src, 2, NUM_PACKETS));
+        let received: Vec<_> = run_switch(sources, [2], connect(switch))
+            .into_iter()
+            .map(|(_, _, packet)| packet.payload)
+            .collect();
+        assert_eq!(received.len(), 2 * NUM_PACKETS as usize);
+
+        // While both inputs are backlogged, each should get half of the output bandwidth.
+        let window = &received[..NUM_PACKETS as usize];
+        let from_zero = window.iter().filter(|src| **src == 0).count() as i64;
+        assert!(
+            (from_zero - NUM_PACKETS as i64 / 2).abs() <= 1,
+            "Port 0 received {from_zero} of the first {NUM_PACKETS} grants"
+        );
+    }
+
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    struct PriorityTestPacket {
+        location: u8,
+        priority: u32,
+        // Index of the packet within its stream, i.e. the cycle it was generated on.
+        sent: u64,
+    }
+
+    impl Packet<u8> for PriorityTestPacket {
+        fn destination(&self) -> u8 {
+            self.location
+        }
+    }
+
+    impl PriorityPacket for PriorityTestPacket {
+        fn priority(&self) -> u32 {
+            self.priority
+        }
+    }
+
+    impl DAMType for PriorityTestPacket {
+        fn dam_size(&self) -> usize {
+            self.location.dam_size() + self.priority.dam_size() + self.sent.dam_size()
+        }
+    }
+
+    #[test]
+    fn priority_arbitration_test() {
+        const NUM_PACKETS: u64 = 256;
+
+        let switch = SimpleSwitch::new(direct_policy([2]), 1).with_priority_arbitration();
+
+        // Port 0 carries bulk traffic, port 1 carries control traffic, and both target output 2 every cycle.
+        let sources = [(0usize, 0u32), (1, 1)].map(|(port, priority)| {
+            let packets = (0..NUM_PACKETS).map(move |sent| PriorityTestPacket {
+                location: 2,
+                priority,
+                sent,
+            });
+            (port, stream(packets))
+        });
+        let latencies: Vec<_> = run_switch(sources, [2], connect(switch))
+            .into_iter()
+            .map(|(_, arrival, packet)| (packet.priority, arrival - packet.sent))
+            .collect();
+        let latencies_of = |priority| {
+            latencies
+                .iter()
+                .filter(move |(prio, _)| *prio == priority)
+                .map(|(_, latency)| *latency)
+                .collect::<Vec<_>>()
+        };
+
+        let control = latencies_of(1);
+        assert_eq!(control.len(), NUM_PACKETS as usize);
+        assert_eq!(
+            control.iter().min(),
+            control.iter().max(),
+            "Control latency should stay flat"
+        );
+
+        let bulk = latencies_of(0);
+        assert_eq!(bulk.len(), NUM_PACKETS as usize);
+        // Bulk traffic only gets through once the control stream has drained.
+        assert!(
+            *bulk.iter().min().unwrap() >= control[0] + NUM_PACKETS / 2,
+            "Bulk traffic should back up behind control traffic: {bulk:?}"
+        );
+    }
+
+    #[test]
+    fn oldest_first_arbitration_test() {
+        const BURST: u16 = 6;
+
+        let switch = SimpleSwitch::new(direct_policy([2]), 1)
+            .with_arbitration(ArbitrationPolicy::OldestFirst);
+
+        // Port 1 dumps a whole burst at cycle 1, so most of it has to wait for output 2.
+        let burst = (0..BURST)
+            .map(|payload| {
+                let packet = SimplePacket {
+                    location: 2u8,
+                    payload,
+                };
+                (1, packet)
+            })
+            .collect();
+        // Port 0 shows up a couple of cycles later, while the burst is still draining.
+        let fresh = vec![(
+            3,
+            SimplePacket {
+                location: 2u8,
+                payload: 100,
+            },
+        )];
+        let received: Vec<_> = run_switch([(0, fresh), (1, burst)], [2], connect(switch))
+            .into_iter()
+            .map(|(_, _, packet)| packet.payload)
+            .collect();
+
+        // Every packet of the burst has been waiting longer than the fresh one, so they should all win.
+        assert_eq!(received, (0..BURST).chain([100]).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn weighted_round_robin_test() {
+        const NUM_PACKETS: u16 = 512;
+
+        let mut switch = SimpleSwitch::new(direct_policy([2]), 1)
+            .with_arbitration(ArbitrationPolicy::WeightedRoundRobin);
+        switch.set_port_weight(0, 3);
+
+        let sources = (0..2).map(|src| tagged(src, 2, NUM_PACKETS));
+        let received: Vec<_> = run_switch(sources, [2], connect(switch))
+            .into_iter()
+            .map(|(_, _, packet)| packet.payload)
+            .collect();
+
+        // While both inputs are backlogged, port 0 should get three grants for every one of port 1's.
+        let window = &received[..NUM_PACKETS as usize];
+        let from_zero = window.iter().filter(|src| **src == 0).count() as i64;
+        assert!(
+            (from_zero - 3 * NUM_PACKETS as i64 / 4).abs() <= 2,
+            "Port 0 received {from_zero} of the first {NUM_PACKETS} grants"
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn zero_port_weight_test() {
+        let policy = fxhash::FxHashMap::from_iter([(2u8, FxHashSet::from_iter([2usize]))]);
+        let mut switch = SimpleSwitch::<SimplePacket<u8, u16>, _, _>::new(policy, 1);
+        switch.set_port_weight(0, 0);
+    }
+
+    #[test]
+    fn deterministic_port_order_test() {
+        const NUM_PACKETS: u16 = 16;
+
+        let mut ctx = ProgramBuilder::default();
+
+        let policy = fxhash::FxHashMap::from_iter([(1u8, FxHashSet::from_iter([1usize]))]);
+        let mut switch = SimpleSwitch::new(policy, 1);
+
+        // Registered out of order, and with IDs spread out, so that hashed storage would visit them in some other
+        // order.
+        let sources = [900usize, 7, 42];
+        for port in sources {
+            let (snd, rcv) = ctx.unbounded();
+            ctx.add_child(GeneratorContext::new(
+                move || {
+                    (0..NUM_PACKETS).map(move |_| SimplePacket {
+                        location: 1u8,
+                        payload: port as u16,
+                    })
+                },
+                snd,
+            ));
+            switch.add_port(Port {
+                id: port,
+                input: Some(rcv),
+                output: None,
+            });
+        }
+
+        // Everyone floods output 1, and the lowest port wins every time, so the streams come out one after another.
+        let (out_snd, out_rcv) = ctx.unbounded();
+        ctx.add_child(CheckerContext::new(
+            || {
+                [7u16, 42, 900].into_iter().flat_map(|port| {
+                    (0..NUM_PACKETS).map(move |_| SimplePacket {
+                        location: 1u8,
+                        payload: port,
+                    })
+                })
+            },
+            out_rcv,
+        ));
+        switch.add_port(Port {
+            id: 1,
+            input: None,
+            output: Some(out_snd),
+        });
+        ctx.add_child(switch);
+
+        ctx.initialize(Default::default())
+            .unwrap()
+            .run(Default::default());
+    }
+
+    #[test]
+    fn max_forwards_per_cycle_test() {
+        const NUM_INPUTS: usize = 8;
+        const NUM_PACKETS: u16 = 128;
+
+        // Every input has its own output, so the only contention is for the fabric itself.
+        let run_with_limit = |limit| {
+            let mut ctx = ProgramBuilder::default();
+
+            let policy = fxhash::FxHashMap::from_iter(
+                (0..NUM_INPUTS).map(|i| (i as u8, FxHashSet::from_iter([i]))),
+            );
+            let mut switch = SimpleSwitch::new(policy, 1).with_max_forwards_per_cycle(limit);
+
+            for port in 0..NUM_INPUTS {
+                let (in_snd, in_rcv) = ctx.unbounded();
+                ctx.add_child(GeneratorContext::new(
+                    move || {
+                        (0..NUM_PACKETS).map(move |payload| SimplePacket {
+                            location: port as u8,
+                            payload,
+                        })
+                    },
+                    in_snd,
+                ));
+                let (out_snd, out_rcv) = ctx.unbounded();
+                ctx.add_child(CheckerContext::new(
+                    move || {
+                        (0..NUM_PACKETS).map(move |payload| SimplePacket {
+                            location: port as u8,
+                            payload,
+                        })
+                    },
+                    out_rcv,
+                ));
+                switch.add_port(Port {
+                    id: port,
+                    input: Some(in_rcv),
+                    output: Some(out_snd),
+                });
+            }
+            ctx.add_child(switch);
+
+            ctx.initialize(Default::default())
+                .unwrap()
+                .run(Default::default())
+                .elapsed_cycles()
+                .unwrap()
+                .time()
+        };
+
+        for limit in [1, 2, 4, 8] {
+            let expected = (NUM_INPUTS as u64 * NUM_PACKETS as u64) / limit as u64;
+            let elapsed = run_with_limit(limit);
+            assert!(
+                elapsed.abs_diff(expected) <= 8,
+                "With a limit of {limit}, expected roughly {expected} cycles but took {elapsed}"
+            );
+        }
+    }
+
+    #[test]
+    fn output_speedup_test() {
+        // Two inputs each send a single packet to output 2 on the same cycle, and we look at when each arrived.
+        let arrivals_with_speedup = |speedup| {
+            let switch = SimpleSwitch::new(direct_policy([2]), 1).with_output_speedup(speedup);
+            let sources = (0..2).map(|src| tagged(src, 2, 1));
+            run_switch(sources, [2], connect(switch))
+                .into_iter()
+                .map(|(_, arrival, _)| arrival)
+                .collect::<Vec<_>>()
+        };
+
+        let serialized = arrivals_with_speedup(1);
+        assert_eq!(serialized.len(), 2);
+        assert_eq!(serialized[0] + 1, serialized[1]);
+
+        let parallel = arrivals_with_speedup(2);
+        assert_eq!(parallel.len(), 2);
+        assert_eq!(parallel[0], parallel[1]);
+        assert_eq!(parallel[0], serialized[0]);
+    }
+
+    #[test]
+    fn partial_multicast_test() {
+        const CONTENDED_PACKETS: u16 = 32;
+        const MULTICAST_PACKETS: u16 = 4;
+
+        // Returns when the first multicast copy reached port 2, and how many packets port 4 received.
+        let run = |partial| {
+            let policy = fxhash::FxHashMap::from_iter([
+                (4u8, FxHashSet::from_iter([4usize])),
+                (10, FxHashSet::from_iter([2usize, 3, 4])),
+            ]);
+            let mut switch = SimpleSwitch::new(policy, 1);
+            if partial {
+                switch = switch.with_partial_multicast();
+            }
+
+            // Port 0 keeps output 4 busy, and wins it every cycle since it's the lower port.
+            let contender = (0..CONTENDED_PACKETS).map(|payload| SimplePacket {
+                location: 4u8,
+                payload,
+            });
+            let multicast = (0..MULTICAST_PACKETS).map(|payload| SimplePacket {
+                location: 10u8,
+                payload,
+            });
+            let arrivals = run_switch(
+                [(0, stream(contender)), (1, stream(multicast))],
+                2..5,
+                connect(switch),
+            );
+
+            let at_port = |port| arrivals.iter().filter(move |(dst, _, _)| *dst == port);
+            assert_eq!(at_port(2).count(), MULTICAST_PACKETS as usize);
+            assert_eq!(at_port(3).count(), MULTICAST_PACKETS as usize);
+            let first_at_two = at_port(2).map(|(_, time, _)| *time).min().unwrap();
+            (first_at_two, at_port(4).count())
+        };
+
+        let (first_at_two, at_four) = run(false);
+        assert!(first_at_two >= CONTENDED_PACKETS as u64);
+        assert_eq!(at_four, (CONTENDED_PACKETS + MULTICAST_PACKETS) as usize);
+
+        let (first_at_two, at_four) = run(true);
+        assert!(first_at_two < CONTENDED_PACKETS as u64 / 2);
+        assert_eq!(at_four, (CONTENDED_PACKETS + MULTICAST_PACKETS) as usize);
+    }
+
+    #[test]
+    fn full_output_does_not_stall_switch_test() {
+        const MULTICAST_PACKETS: u16 = 8;
+        const STREAM_PACKETS: u16 = 64;
+        const SLOW_PERIOD: u64 = 10;
+
+        let policy = fxhash::FxHashMap::from_iter([
+            (3u8, FxHashSet::from_iter([3usize])),
+            (10, FxHashSet::from_iter([1usize, 2])),
+        ]);
+        let switch = SimpleSwitch::new(policy, 1);
+        let stats = switch.stats();
+
+        // Port 0 multicasts to ports 1 and 2, while port 1 streams to port 3.
+        let multicast = (0..MULTICAST_PACKETS).map(|payload| SimplePacket {
+            location: 10u8,
+            payload,
+        });
+        let to_three = (0..STREAM_PACKETS).map(|payload| SimplePacket {
+            location: 3u8,
+            payload,
+        });
+        // Port 2 only has room for a single packet, and drains it slowly, while port 1 takes whatever it gets.
+        let sinks = [
+            Sink::new(1),
+            Sink::new(2).with_depth(1).with_period(SLOW_PERIOD),
+            Sink::new(3),
+        ];
+        let arrivals = run_switch(
+            [(0, stream(multicast)), (1, stream(to_three))],
+            sinks,
+            connect(switch),
+        );
+        let at_port = |port| {
+            arrivals
+                .iter()
+                .filter(|(target, _, _)| *target == port)
+                .map(|(_, arrival, packet)| (packet.payload, *arrival))
+                .collect::<Vec<_>>()
+        };
+
+        // Each packet goes out to both ports on the same cycle, which has to wait for the slow one to make room.
+        assert_eq!(at_port(2).len(), MULTICAST_PACKETS as usize);
+        assert_eq!(at_port(1), at_port(2));
+
+        // The stream never touches the slow port, so it should flow at full rate.
+        let stream_arrivals = at_port(3);
+        assert_eq!(stream_arrivals.len(), STREAM_PACKETS as usize);
+        assert!(stream_arrivals.last().unwrap().1 <= STREAM_PACKETS as u64 + 4);
+
+        let stats = stats.lock().unwrap();
+        assert!(stats.retry_cycles.get(&0).copied().unwrap_or(0) > 0);
+        assert!(!stats.retry_cycles.contains_key(&1));
+    }
+
+    #[test]
+    fn per_output_latency_test() {
+        let policy = fxhash::FxHashMap::from_iter([(10u8, FxHashSet::from_iter([1usize, 2]))]);
+        let mut switch = SimpleSwitch::new(policy, 1);
+        // Port 2 is the long link to the memory controller.
+        switch.set_output_latency(2, 4);
+
+        let packet = SimplePacket {
+            location: 10u8,
+            payload: 0u16,
+        };
+        let arrivals = run_switch([(0, stream([packet]))], 1..3, connect(switch));
+        match arrivals[..] {
+            [(1, local, _), (2, remote, _)] => assert_eq!(remote - local, 3),
+            _ => panic!("Expected one copy on each port, got {arrivals:?}"),
+        }
+    }
+
+    #[test]
+    fn pipeline_initiation_interval_test() {
+        const NUM_PACKETS: u16 = 16;
+        const STAGES: u64 = 4;
+        const INITIATION_INTERVAL: u64 = 3;
+
+        let switch =
+            SimpleSwitch::new(direct_policy(2..4), 1).with_pipeline(STAGES, INITIATION_INTERVAL);
+
+        // Each input floods an output of its own.
+        let sources = (0..2).map(|port| {
+            let packets = (0..NUM_PACKETS).map(move |payload| SimplePacket {
+                location: port as u8 + 2,
+                payload,
+            });
+            (port, stream(packets))
+        });
+        let arrivals = run_switch(sources, 2..4, connect(switch));
+        let flow = |port| {
+            arrivals
+                .iter()
+                .filter(|(target, _, _)| *target == port)
+                .map(|(_, time, _)| *time)
+                .collect::<Vec<_>>()
+        };
+        // The generators send their first packets on cycle 1, which then go through the switch's latency and every
+        // stage.
+        assert_eq!(flow(2)[0], 1 + 1 + STAGES);
+        // Back to back packets to the same output wait out the interval, but the two outputs don't wait on each
+        // other.
+        assert!(flow(2)
+            .windows(2)
+            .all(|pair| pair[1] - pair[0] == INITIATION_INTERVAL));
+        assert_eq!(flow(2), flow(3));
+    }
+
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    struct SizedTestPacket {
+        location: u8,
+        id: u16,
+        // Stands in for a variable-length payload.
+        bits: usize,
+    }
+
+    impl Packet<u8> for SizedTestPacket {
+        fn destination(&self) -> u8 {
+            self.location
+        }
+    }
+
+    impl DAMType for SizedTestPacket {
+        fn dam_size(&self) -> usize {
+            self.bits
+        }
+    }
+
+    /// Sends a packet of `large_bits` followed by an 8-bit packet over an 8-bit wide output, returning the arrival
+    /// times of the two.
+    fn serialized_arrivals(large_bits: usize) -> (u64, u64) {
+        let mut switch = SimpleSwitch::new(direct_policy([1]), 1);
+        switch.set_output_width(1, 8);
+
+        let packets = [(0, large_bits), (1, 8)]
+            .map(|(id, bits)| {
+                let packet = SizedTestPacket {
+                    location: 1,
+                    id,
+                    bits,
+                };
+                (1, packet)
+            })
+            .to_vec();
+        let arrivals: Vec<_> = run_switch([(0, packets)], [1], connect(switch))
+            .into_iter()
+            .map(|(_, arrival, packet)| (packet.id, arrival))
+            .collect();
+        match arrivals[..] {
+            [(0, large), (1, small)] => (large, small),
+            _ => panic!("Expected the large packet then the small one, got {arrivals:?}"),
+        }
+    }
+
+    #[test]
+    fn serialization_delay_test() {
+        let (short_large, short_small) = serialized_arrivals(32);
+        let (long_large, long_small) = serialized_arrivals(64);
+
+        // An extra 32 bits is four more cycles on the wire.
+        assert_eq!(long_large - short_large, 4);
+        // The small packet waits for the large one to finish serializing, then takes a cycle of its own.
+        assert_eq!(short_small - short_large, 1);
+        assert_eq!(long_small - short_small, 4);
+    }
+
+    #[test]
+    fn payload_serialization_test() {
+        const SIZES: [usize; 2] = [64, 1024];
+        let mut switch = SimpleSwitch::new(direct_policy([1]), 1);
+        switch.set_output_width(1, 8);
+
+        let mut sizes = SIZES.into_iter();
+        let packets = sized_packets([1u8; 2], move || sizes.next().unwrap());
+        let arrivals = run_switch([(0, stream(packets))], [1], connect(switch));
+
+        // The second packet goes out as soon as the first is off the wire, so the gap between them is how long it took.
+        let serializations = [arrivals[0].1 - 2, arrivals[1].1 - arrivals[0].1];
+        for ((serialization, size), (_, _, packet)) in
+            serializations.iter().zip(SIZES).zip(&arrivals)
+        {
+            assert_eq!(packet.payload.0.len(), size);
+            // A cycle for the location, and one for each byte.
+            assert_eq!(*serialization, 1 + size as u64);
+        }
+    }
+
+    #[derive(Clone, Debug, Default, PartialEq, routing::Packet, routing::DAMType)]
+    struct DerivedPacket<LT> {
+        payload: u32,
+        #[packet(destination)]
+        target: LT,
+    }
+
+    #[test]
+    fn derived_packet_test() {
+        let packets: Vec<_> = (0..8)
+            .map(|payload| DerivedPacket {
+                payload,
+                target: 1 + payload as u8 % 2,
+            })
+            .collect();
+        assert_eq!(packets[0].destination(), 1);
+        assert_eq!(packets[0].dam_size(), 40);
+
+        let switch = SimpleSwitch::new(direct_policy([1, 2]), 1);
+        let arrivals = run_switch([(0, stream(packets.clone()))], [1, 2], connect(switch));
+        assert_eq!(arrivals.len(), packets.len());
+        for (port, _, packet) in arrivals {
+            assert_eq!(port as u8, packet.target);
+            assert!(packets.contains(&packet));
+        }
+    }
+
+    #[test]
+    fn hop_limit_breaks_routing_loop_test() {
+        const NUM_PACKETS: u64 = 16;
+        let mut ctx = ProgramBuilder::default();
+
+        // Both switches send everything over the link to the other one, so packets go round in circles.
+        let looping_policy =
+            || fxhash::FxHashMap::from_iter([(7u8, FxHashSet::from_iter([1usize]))]);
+        let mut left = SimpleSwitch::new(looping_policy(), 1).with_hop_limit();
+        let mut right = SimpleSwitch::new(looping_policy(), 1).with_hop_limit();
+        let left_stats = left.stats();
+        let right_stats = right.stats();
+
+        let (gen_snd, gen_rcv) = ctx.unbounded();
+        ctx.add_child(GeneratorContext::new(
+            || {
+                (0..NUM_PACKETS).map(|i| HoppedPacket {
+                    packet: SimplePacket {
+                        location: 7u8,
+                        payload: i,
+                    },
+                    hops: 4,
+                })
+            },
+            gen_snd,
+        ));
+        left.add_port(Port {
+            id: 0,
+            input: Some(gen_rcv),
+            output: None,
+        });
+
+        let (l2r_snd, l2r_rcv) = ctx.unbounded();
+        let (link2l_snd, link2l_rcv) = ctx.unbounded();
+        left.add_port(Port {
+            id: 1,
+            input: Some(link2l_rcv),
+            output: Some(l2r_snd),
+        });
+        right.add_port(Port {
+            id: 0,
+            input: Some(l2r_rcv),
+            output: None,
+        });
+
+        // The way back goes through a link which DAM can retire, since a cycle of channels never closes by itself.
+        // It shuts down once every packet has been dropped, and so would keep the simulation spinning without hop
+        // counting.
+        let (r2link_snd, r2link_rcv) = ctx.unbounded();
+        right.add_port(Port {
+            id: 1,
+            input: None,
+            output: Some(r2link_snd),
+        });
+        let relayed = Arc::new(Mutex::new(0));
+        let mut link = FunctionContext::new();
+        r2link_rcv.attach_receiver(&link);
+        link2l_snd.attach_sender(&link);
+        let link_count = relayed.clone();
+        let (link_left_stats, link_right_stats) = (left_stats.clone(), right_stats.clone());
+        link.set_run(move |time| loop {
+            if let dam::channel::PeekResult::Something(_) = r2link_rcv.peek() {
+                let ChannelElement { time: _, data } = r2link_rcv.dequeue(time).unwrap();
+                link2l_snd
+                    .enqueue(
+                        time,
+                        ChannelElement {
+                            time: time.tick() + 1,
+                            data,
+                        },
+                    )
+                    .unwrap();
+                *link_count.lock().unwrap() += 1;
+            } else {
+                let dropped: u64 = [&link_left_stats, &link_right_stats]
+                    .iter()
+                    .flat_map(|stats| {
+                        stats
+                            .lock()
+                            .unwrap()
+                            .dropped
+                            .values()
+                            .copied()
+                            .collect::<Vec<_>>()
+                    })
+                    .sum();
+                if dropped == NUM_PACKETS {
+                    return;
+                }
+            }
+            time.incr_cycles(1);
+        });
+        ctx.add_child(link);
+        ctx.add_child(left);
+        ctx.add_child(right);
+
+        ctx.initialize(Default::default())
+            .unwrap()
+            .run(Default::default());
+
+        // Four hops buys left -> right -> left, and the packet dies on its second visit to the right switch.
+        assert_eq!(*relayed.lock().unwrap(), NUM_PACKETS);
+        assert!(left_stats.lock().unwrap().dropped.is_empty());
+        assert_eq!(
+            right_stats.lock().unwrap().dropped,
+            fxhash::FxHashMap::from_iter([(0, NUM_PACKETS)])
+        );
+    }
+
+    #[test]
+    fn closed_input_test() {
+        const LONG_STREAM: u16 = 256;
+        let mut ctx = ProgramBuilder::default();
+
+        let policy = fxhash::FxHashMap::from_iter([(1u8, FxHashSet::from_iter([2usize]))]);
+        let mut switch = SimpleSwitch::new(policy, 1);
+
+        // Port 0 runs dry almost immediately, while port 1 keeps going.
+        for (port, payloads) in [(0, 0..4), (1, 1000..1000 + LONG_STREAM)] {
+            let (snd, rcv) = ctx.unbounded();
+            ctx.add_child(GeneratorContext::new(
+                move || {
+                    payloads.clone().map(|payload| SimplePacket {
+                        location: 1u8,
+                        payload,
+                    })
+                },
+                snd,
+            ));
+            switch.add_port(Port {
+                id: port,
+                input: Some(rcv),
+                output: None,
+            });
+        }
+
+        let (out_snd, out_rcv) = ctx.unbounded();
+        switch.add_port(Port {
+            id: 2,
+            input: None,
+            output: Some(out_snd),
+        });
+        ctx.add_child(switch);
+        ctx.add_child(CheckerContext::new(
+            || {
+                (0..4)
+                    .chain(1000..1000 + LONG_STREAM)
+                    .map(|payload| SimplePacket {
+                        location: 1u8,
+                        payload,
+                    })
+            },
+            out_rcv,
+        ));
+
+        ctx.initialize(Default::default())
+            .unwrap()
+            .run(Default::default());
+    }
+
+    /// Sends one packet to an unknown destination among three good ones, returning what showed up on each output.
+    fn unroutable_run(unroutable: UnroutableAction) -> (Vec<(usize, u16)>, SwitchStats) {
+        let switch = SimpleSwitch::new(direct_policy([1]), 1).with_unroutable(unroutable);
+        let stats = switch.stats();
+
+        let packets = [1u8, 1, 9, 1]
+            .into_iter()
+            .zip(0u16..)
+            .map(|(location, payload)| SimplePacket { location, payload });
+        // Port 2 is the catch-all for diverted packets.
+        let arrivals = run_switch([(0, stream(packets))], 1..3, connect(switch))
+            .into_iter()
+            .map(|(port, _, packet)| (port, packet.payload))
+            .collect();
+        let stats = stats.lock().unwrap().clone();
+        (arrivals, stats)
+    }
+
+    #[test]
+    fn drop_unroutable_test() {
+        let (arrivals, stats) = unroutable_run(UnroutableAction::Drop);
+        assert_eq!(arrivals, vec![(1, 0), (1, 1), (1, 3)]);
+        assert_eq!(stats.dropped, fxhash::FxHashMap::from_iter([(0, 1)]));
+        assert_eq!(stats.error, None);
+    }
+
+    #[test]
+    fn divert_unroutable_test() {
+        let (mut arrivals, stats) = unroutable_run(UnroutableAction::Divert(2));
+        arrivals.sort();
+        assert_eq!(arrivals, vec![(1, 0), (1, 1), (1, 3), (2, 2)]);
+        assert!(stats.dropped.is_empty());
+    }
+
+    #[test]
+    fn divert_to_missing_port_test() {
+        // Nothing is attached to port 7, so the diverted packet has nowhere to go.
+        let (arrivals, stats) = unroutable_run(UnroutableAction::Divert(7));
+        assert_eq!(arrivals, vec![(1, 0), (1, 1)]);
+        assert!(matches!(
+            stats.error,
+            Some(UnroutableError { port: 0, cycle: _ })
+        ));
+    }
+
+    #[test]
+    fn error_unroutable_test() {
+        let (arrivals, stats) = unroutable_run(UnroutableAction::Error);
+        // Everything up until the bad packet made it through before the switch stopped.
+        assert_eq!(arrivals, vec![(1, 0), (1, 1)]);
+        assert!(matches!(
+            stats.error,
+            Some(UnroutableError { port: 0, cycle: _ })
+        ));
+    }
+
+    /// Sends `NUM_PACKETS` packets with the given path through a chain of three source routed switches, where the first
+    /// switch's port 2 leads to the second, whose port 1 leads to the third, whose port 0 is the way out. Returns the
+    /// packets which made it out, and the stats of each switch.
+    fn source_routed_run(path: &[usize]) -> (Vec<SourceRoutedPacket<u16>>, Vec<SwitchStats>) {
+        const NUM_PACKETS: u16 = 8;
+        let mut ctx = ProgramBuilder::default();
+
+        let (inject_snd, mut input) = ctx.unbounded();
+        let packets: Vec<_> = (0..NUM_PACKETS)
+            .map(|payload| SourceRoutedPacket {
+                path: path.iter().copied().collect(),
+                payload,
+            })
+            .collect();
+        ctx.add_child(GeneratorContext::new(
+            move || packets.clone().into_iter(),
+            inject_snd,
+        ));
+
+        let mut stats = vec![];
+        for exit in [2, 1, 0] {
+            let mut switch = SimpleSwitch::new(SourceRoutePolicy, 1)
+                .with_source_routes()
+                .with_unroutable(UnroutableAction::Error);
+            stats.push(switch.stats());
+            let (snd, rcv) = ctx.unbounded();
+            switch.add_port(Port {
+                id: exit,
+                input: None,
+                output: Some(snd),
+            });
+            switch.add_port(Port {
+                id: 3,
+                input: Some(input),
+                output: None,
+            });
+            ctx.add_child(switch);
+            input = rcv;
+        }
+
+        let arrivals = Arc::new(Mutex::new(vec![]));
+        let mut sink = FunctionContext::new();
+        input.attach_receiver(&sink);
+        let sink_log = arrivals.clone();
+        sink.set_run(move |time| {
+            while let Ok(ChannelElement { time: _, data }) = input.dequeue(time) {
+                sink_log.lock().unwrap().push(data);
+            }
+        });
+        ctx.add_child(sink);
+
+        ctx.initialize(Default::default())
+            .unwrap()
+            .run(Default::default());
+        let arrivals = arrivals.lock().unwrap().clone();
+        let stats = stats
+            .iter()
+            .map(|stats| stats.lock().unwrap().clone())
+            .collect();
+        (arrivals, stats)
+    }
+
+    #[test]
+    fn source_routed_chain_test() {
+        let (arrivals, stats) = source_routed_run(&[2, 1, 0]);
+        let payloads: Vec<_> = arrivals.iter().map(|packet| packet.payload).collect();
+        assert_eq!(payloads, (0..8).collect::<Vec<_>>());
+        // Every switch used up its hop of the path.
+        assert!(arrivals.iter().all(|packet| packet.path.is_empty()));
+        assert!(stats.iter().all(|stats| stats.error.is_none()));
+    }
+
+    #[test]
+    fn truncated_source_route_test() {
+        // The path runs out at the last switch.
+        let (arrivals, stats) = source_routed_run(&[2, 1]);
+        assert!(arrivals.is_empty());
+        assert!(stats[2].error.is_some());
+
+        // The second switch has no port 7.
+        let (arrivals, stats) = source_routed_run(&[2, 7, 0]);
+        assert!(arrivals.is_empty());
+        assert!(stats[1].error.is_some());
+    }
+
+    #[test]
+    fn lookahead_port_test() {
+        // The policy would send both packets out of port 1, but the first one already knows to take port 2.
+        let packets = [Some(2), None].map(|next_port| LookaheadPacket {
+            packet: SimplePacket {
+                location: 1u8,
+                payload: next_port.is_some() as u16,
+            },
+            next_port,
+        });
+        let neighbors = fxhash::FxHashMap::from_iter([(1, direct_policy(0..3))]);
+        let switch = SimpleSwitch::new(LookaheadPolicy::new(direct_policy(0..3), neighbors), 1)
+            .with_lookahead();
+        let arrivals = run_switch([(0, stream(packets))], 1..3, connect(switch));
+
+        let arrivals: Vec<_> = arrivals
+            .into_iter()
+            .map(|(port, _, packet)| (port, packet.packet.payload, packet.next_port))
+            .collect();
+        // Only the switch behind port 1 is known, and it would send the packet on out of its own port 1.
+        assert_eq!(arrivals, vec![(2, 1, None), (1, 0, Some(1))]);
+    }
+
+    #[test]
+    fn lookahead_mesh_test() {
+        const SIZE: u16 = 3;
+        const PORTS: MeshPorts = MeshPorts {
+            local: 0,
+            plus_x: 1,
+            minus_x: 2,
+            plus_y: 3,
+            minus_y: 4,
+        };
+        let opposite = |node: Coord2D| Coord2D {
+            x: SIZE - 1 - node.x,
+            y: SIZE - 1 - node.y,
+        };
+        // Every node but the center sends a packet to the node across from it, tagged with where it came from. None
+        // of them want the same output at the same time.
+        let traffic = |node: Coord2D| {
+            let packet = LookaheadPacket {
+                packet: SimplePacket {
+                    location: opposite(node),
+                    payload: node,
+                },
+                next_port: None,
+            };
+            match node == opposite(node) {
+                true => vec![],
+                false => vec![(1, packet)],
+            }
+        };
+        let xy = |node| XyPolicy::new(node, SIZE, SIZE, PORTS);
+        let total = (SIZE * SIZE - 1) as usize;
+
+        let routed = run_mesh(
+            SIZE,
+            SIZE,
+            PORTS,
+            traffic,
+            |node| SimpleSwitch::new(xy(node), 2),
+            total,
+        );
+        let lookahead = run_mesh(
+            SIZE,
+            SIZE,
+            PORTS,
+            traffic,
+            |node: Coord2D| {
+                let neighbors = [
+                    (PORTS.plus_x, node.x + 1 < SIZE, node.x + 1, node.y),
+                    (PORTS.minus_x, node.x > 0, node.x.wrapping_sub(1), node.y),
+                    (PORTS.plus_y, node.y + 1 < SIZE, node.x, node.y + 1),
+                    (PORTS.minus_y, node.y > 0, node.x, node.y.wrapping_sub(1)),
+                ]
+                .into_iter()
+                .filter(|(_, exists, _, _)| *exists)
+                .map(|(port, _, x, y)| (port, xy(Coord2D { x, y })))
+                .collect();
+                SimpleSwitch::new(LookaheadPolicy::new(xy(node), neighbors), 1).with_lookahead()
+            },
+            total,
+        );
+
+        // Every node gets exactly one packet, so line them up by where they came out.
+        let by_node = |mut arrivals: Vec<(Coord2D, u64, _)>| {
+            arrivals.sort_by_key(|(node, _, _)| (node.y, node.x));
+            arrivals
+        };
+        let (routed, lookahead) = (by_node(routed), by_node(lookahead));
+        assert_eq!(routed.len(), total);
+        assert_eq!(lookahead.len(), total);
+        for ((node, time, packet), (lookahead_node, lookahead_time, lookahead_packet)) in
+            routed.into_iter().zip(lookahead)
+        {
+            assert_eq!(node, lookahead_node);
+            assert_eq!(packet.packet, lookahead_packet.packet);
+            // Every switch along the way, including the last one, routed a cycle sooner.
+            let source = packet.packet.payload;
+            let switches = source.manhattan_distance(&node) + 1;
+            assert_eq!(time - lookahead_time, switches as u64);
+            // The last switch had nobody behind its local port to route ahead for.
+            assert_eq!(lookahead_packet.next_port, None);
+        }
+    }
+
+    #[test]
+    fn multicast_destinations_test() {
+        // Locations 0 and 1 hang off of ports 0 and 1 of switch 0, locations 2 and 3 off of the same ports of switch 1,
+        // and the switches are linked by their ports 2.
+        let switches = (0..2u8)
+            .map(|switch| {
+                let policy = (0..4u8)
+                    .map(|location| match location / 2 == switch {
+                        true => (location, FxHashSet::from_iter([location as usize % 2])),
+                        false => (location, FxHashSet::from_iter([2])),
+                    })
+                    .collect::<fxhash::FxHashMap<_, _>>();
+                SimpleSwitch::new(policy, 1).with_multicast_destinations()
+            })
+            .collect();
+        let endpoints = [(0, 0), (0, 1), (1, 0), (1, 1)];
+        // Location 2 is listed twice, but should still only get one copy.
+        let traffic = |endpoint: usize| match endpoint {
+            0 => vec![(
+                1,
+                MulticastPacket {
+                    destinations: vec![1u8, 2, 3, 2],
+                    payload: 7u16,
+                },
+            )],
+            _ => vec![],
+        };
+        let arrivals = run_network(switches, &[(0, 2, 1, 2)], &endpoints, traffic, 3);
+
+        let mut deliveries: Vec<_> = arrivals
+            .into_iter()
+            .map(|(endpoint, _, packet)| (endpoint, packet.destinations, packet.payload))
+            .collect();
+        deliveries.sort();
+        // Each copy only carries the destinations behind the port it went out of.
+        assert_eq!(
+            deliveries,
+            vec![(1, vec![1], 7), (2, vec![2], 7), (3, vec![3], 7)]
+        );
+    }
+
+    #[test]
+    fn empty_multicast_test() {
+        let packets = [vec![], vec![1u8, 1], vec![]].map(|destinations| MulticastPacket {
+            destinations,
+            payload: 0u16,
+        });
+        let switch = SimpleSwitch::new(direct_policy(1..3), 1).with_multicast_destinations();
+        let stats = switch.stats();
+        let arrivals = run_switch([(0, stream(packets))], 1..3, connect(switch));
+
+        // Repeats on the same port make for a single copy, and packets with nowhere to go are counted.
+        assert_eq!(arrivals.len(), 1);
+        assert_eq!(arrivals[0].0, 1);
+        assert_eq!(arrivals[0].2.destinations, vec![1]);
+        let stats = stats.lock().unwrap();
+        assert_eq!(stats.empty_multicasts, 2);
+        assert_eq!(stats.dropped[&0], 2);
+    }
+
+    #[test]
+    fn traffic_matrix_test() {
+        // Ports 0 to 2 each send a different number of packets to each of ports 3 to 5.
+        let sent = |source: u8, destination: u8| (source + 1) * (destination - 2);
+        let sources = (0..3).map(|source| {
+            let packets = (3..6).flat_map(move |destination| {
+                (0..sent(source, destination)).map(move |payload| SourcedSimplePacket {
+                    source,
+                    location: destination,
+                    payload,
+                })
+            });
+            (source as usize, stream(packets))
+        });
+        let arrivals = run_switch(
+            sources,
+            3..6,
+            connect(SimpleSwitch::new(direct_policy(3..6), 1)),
+        );
+
+        let matrix = traffic_matrix(arrivals.iter().map(|(_, _, packet)| packet));
+        let expected = (0..3)
+            .flat_map(|source| (3..6).map(move |destination| (source, destination)))
+            .map(|(source, destination)| {
+                ((source, destination), sent(source, destination) as usize)
+            })
+            .collect();
+        assert_eq!(matrix, expected);
+    }
+
+    #[test]
+    fn control_port_test() {
+        const SWITCH_OVER: u64 = 100;
+        const REMOVAL: u64 = 150;
+        // A packet for destination 5 every ten cycles, for two hundred cycles.
+        let packets = (1..20).map(|i| {
+            let packet = SimplePacket {
+                location: 5u8,
+                payload: i * 10,
+            };
+            (i as u64 * 10, packet)
+        });
+        let policy = fxhash::FxHashMap::from_iter([(5u8, FxHashSet::from_iter([1]))]);
+        let mut switch = SimpleSwitch::new(policy, 1).with_unroutable(UnroutableAction::Drop);
+        let stats = switch.stats();
+        let arrivals = run_switch([(0, packets.collect())], 1..3, |ctx, ports| {
+            // Destination 5 moves to port 2, and then goes away altogether.
+            let (updates_snd, updates_rcv) = ctx.unbounded();
+            let updates = [
+                (
+                    SWITCH_OVER,
+                    PolicyUpdate::insert(5, FxHashSet::from_iter([2])),
+                ),
+                (REMOVAL, PolicyUpdate::remove(5)),
+            ];
+            let mut controller = FunctionContext::new();
+            updates_snd.attach_sender(&controller);
+            controller.set_run(move |time| {
+                for (at, update) in updates.clone() {
+                    updates_snd
+                        .enqueue(time, ChannelElement::new(Time::new(at), update))
+                        .unwrap();
+                }
+            });
+            ctx.add_child(controller);
+            switch.set_control_port(updates_rcv);
+            connect(switch)(ctx, ports)
+        });
+
+        let arrivals: Vec<_> = arrivals
+            .into_iter()
+            .map(|(port, time, packet)| (port, time, packet.payload as u64))
+            .collect();
+        let expected: Vec<_> = (1..REMOVAL / 10)
+            .map(|i| i * 10)
+            .map(|sent| match sent < SWITCH_OVER {
+                true => (1, sent + 1, sent),
+                false => (2, sent + 1, sent),
+            })
+            .collect();
+        assert_eq!(arrivals, expected);
+        // Everything after the removal had nowhere to go.
+        assert_eq!(stats.lock().unwrap().dropped[&0], 5);
+    }
+
+    /// A switch with outputs 0 to 3, whose table sends destinations 0 to 3 out of the port with the same ID, and
+    /// destination 5 out of port 9.
+    fn misconfigured_switch(
+        ctx: &mut ProgramBuilder,
+    ) -> SimpleSwitch<TestPacket, u8, fxhash::FxHashMap<u8, FxHashSet<usize>>> {
+        let mut policy = direct_policy(0..4);
+        policy.insert(5, FxHashSet::from_iter([9]));
+        let mut switch = SimpleSwitch::new(policy, 1);
+        for id in 0..4 {
+            let (snd, _) = ctx.unbounded();
+            switch.add_port(Port {
+                id,
+                input: None,
+                output: Some(snd),
+            });
+        }
+        switch
+    }
+
+    #[test]
+    fn validate_policy_test() {
+        let mut ctx = ProgramBuilder::default();
+        let mut switch = misconfigured_switch(&mut ctx);
+        assert_eq!(switch.validate_policy(0..4), Ok(()));
+
+        let error = switch.validate_policy(0..6).unwrap_err();
+        assert_eq!(error, PolicyError::Unroutable { destination: 4 });
+        let error = switch.validate_policy([0, 5, 1]).unwrap_err();
+        assert_eq!(
+            error,
+            PolicyError::MissingPort {
+                destination: 5,
+                port: 9
+            }
+        );
+        assert_eq!(
+            error.to_string(),
+            "5 is routed to port 9, which the switch has no output for"
+        );
+
+        // Destinations which would be dropped are fine, but diverting them needs the port to be there.
+        let mut switch = misconfigured_switch(&mut ctx).with_unroutable(UnroutableAction::Drop);
+        assert_eq!(switch.validate_policy(0..5), Ok(()));
+        let mut switch =
+            misconfigured_switch(&mut ctx).with_unroutable(UnroutableAction::Divert(7));
+        assert_eq!(
+            switch.validate_policy(0..5),
+            Err(PolicyError::MissingPort {
+                destination: 4,
+                port: 7
+            })
+        );
+    }
+
+    /// Runs a 4x4 switch where every input streams unicast packets, with each cycle's packets forming a different
+    /// permutation of the outputs, and returns how many have been delivered `NUM_PACKETS` cycles in.
+    fn permutation_run(arbiter: Option<IslipArbiter>) -> usize {
+        const NUM_PACKETS: u16 = 64;
+
+        let mut switch = SimpleSwitch::new(direct_policy(0..4), 1);
+        if let Some(arbiter) = arbiter {
+            switch = switch.with_arbiter(arbiter);
+        }
+
+        let sources = (0..4).map(|port| {
+            let packets = (0..NUM_PACKETS).map(move |payload| SimplePacket {
+                location: (port as u8 ^ payload as u8) % 4,
+                payload,
+            });
+            (port, stream(packets))
+        });
+        run_switch(sources, 0..4, connect(switch))
+            .into_iter()
+            .filter(|(_, arrival, _)| *arrival <= NUM_PACKETS as u64)
+            .count()
+    }
+
+    #[test]
+    fn islip_matching_test() {
+        // Every output has exactly one requester each cycle, so a matching which loses any throughput here is broken.
+        let greedy = permutation_run(None);
+        let islip = permutation_run(Some(IslipArbiter::new(2)));
+        assert!(greedy > 0);
+        assert!(islip >= greedy, "iSLIP delivered {islip}, greedy {greedy}");
+    }
+
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    struct BurstTestPacket {
+        location: u8,
+        source: u8,
+        remaining: usize,
+    }
+
+    impl Packet<u8> for BurstTestPacket {
+        fn destination(&self) -> u8 {
+            self.location
+        }
+    }
+
+    impl Burst for BurstTestPacket {
+        fn remaining(&self) -> usize {
+            self.remaining
+        }
+    }
+
+    impl DAMType for BurstTestPacket {
+        fn dam_size(&self) -> usize {
+            self.location.dam_size() + self.source.dam_size() + self.remaining.dam_size()
+        }
+    }
+
+    #[test]
+    fn burst_test() {
+        const BURST_LENGTH: usize = 4;
+        const NUM_BURSTS: usize = 3;
+
+        // Round robin would alternate between the inputs on every element if it weren't for the bursts.
+        let switch = SimpleSwitch::new(direct_policy([2]), 1)
+            .with_arbitration(ArbitrationPolicy::RoundRobin)
+            .with_bursts();
+
+        let sources = (0..2).map(|port| {
+            let packets = (0..NUM_BURSTS).flat_map(move |_| {
+                (0..BURST_LENGTH)
+                    .rev()
+                    .map(move |remaining| BurstTestPacket {
+                        location: 2,
+                        source: port as u8,
+                        remaining,
+                    })
+            });
+            (port, stream(packets))
+        });
+        let received: Vec<_> = run_switch(sources, [2], connect(switch))
+            .into_iter()
+            .map(|(_, _, packet)| packet)
+            .collect();
+
+        assert_eq!(received.len(), 2 * NUM_BURSTS * BURST_LENGTH);
+        for burst in received.chunks(BURST_LENGTH) {
+            assert!(
+                burst.iter().all(|packet| packet.source == burst[0].source),
+                "Burst was interleaved: {burst:?}"
+            );
+            let remaining: Vec<_> = burst.iter().map(|packet| packet.remaining).collect();
+            assert_eq!(remaining, vec![3, 2, 1, 0]);
+        }
+        // Round robin still hands the output back and forth between bursts.
+        assert_ne!(received[0].source, received[BURST_LENGTH].source);
     }
 }
@@ -0,0 +1,277 @@
+use std::hash::Hash;
+
+use dam::{
+    channel::utils::{EventTime, Peekable},
+    context_tools::*,
+    structures::SyncSendMarker,
+};
+
+use super::{
+    policy::Policy,
+    routing::{Packet, Port, PortMap, Switch},
+};
+
+/// A crossbar switch which has to receive each packet in full before it can forward any of it.
+///
+/// Each input holds a single packet. The packet at the front of an input only becomes eligible
+/// `ceil(dam_size / input_width)` cycles after it shows up, and its copies take another
+/// `ceil(dam_size / output_width)` cycles on top of the latency to arrive, during which the output is busy.
+/// [`super::SimpleSwitch`] on the other hand starts sending a packet as soon as its head is in, which makes it act
+/// like a cut-through switch.
+#[context_macro]
+pub struct StoreAndForwardSwitch<T, LT, PolicyType>
+where
+    T: DAMType,
+{
+    ports: PortMap<T>,
+
+    policy: PolicyType,
+    latency: u64,
+    // Link widths in bits per cycle.
+    input_width: usize,
+    output_width: usize,
+    // When the packet at the front of each input has been received in full.
+    received_at: fxhash::FxHashMap<usize, Time>,
+    // The targets which still need a copy of each input's packet.
+    pending: fxhash::FxHashMap<usize, fxhash::FxHashSet<usize>>,
+    // The first cycle on which each output is done serializing its previous packet.
+    busy_until: fxhash::FxHashMap<usize, Time>,
+
+    _marker: SyncSendMarker<LT>,
+}
+
+impl<T: DAMType, LT, PolicyType> Context for StoreAndForwardSwitch<T, LT, PolicyType>
+where
+    T: Packet<LT>,
+    LT: Eq + Hash,
+    PolicyType: Policy<LT> + Sync + Send,
+{
+    fn run(&mut self) {
+        while self.advance_to_next_event() {
+            let tick = self.time.tick();
+            let ready: Vec<_> = self
+                .ports
+                .inputs
+                .keys()
+                .filter(|id| self.received_at.get(id).is_some_and(|time| *time <= tick))
+                .copied()
+                .collect();
+
+            for id in ready {
+                let data = match self.ports.inputs.get(&id).unwrap().peek() {
+                    dam::channel::PeekResult::Something(ChannelElement { time: _, data }) => data,
+                    _ => panic!("Port {:?} was supposed to be ready", id),
+                };
+                let mut targets = match self.pending.remove(&id) {
+                    Some(targets) => targets,
+                    None => self.policy.route(&data.destination()),
+                };
+
+                let serialization = data.dam_size().div_ceil(self.output_width) as u64;
+                targets.retain(|target| {
+                    if self
+                        .busy_until
+                        .get(target)
+                        .is_some_and(|busy_until| *busy_until > tick)
+                    {
+                        return true;
+                    }
+                    let result = self.ports.outputs.get(target).unwrap().try_enqueue(
+                        &self.time,
+                        ChannelElement {
+                            time: tick + self.latency + serialization,
+                            data: data.clone(),
+                        },
+                    );
+                    if let Err(dam::channel::EnqueueError::Full) = result {
+                        return true;
+                    }
+                    self.busy_until.insert(*target, tick + serialization.max(1));
+                    false
+                });
+
+                if targets.is_empty() {
+                    let _ = self.ports.inputs.get(&id).unwrap().dequeue(&self.time);
+                    self.received_at.remove(&id);
+                } else {
+                    self.pending.insert(id, targets);
+                }
+            }
+            self.time.incr_cycles(1);
+        }
+    }
+}
+
+impl<T: DAMType, LT, PolicyType> Switch<T> for StoreAndForwardSwitch<T, LT, PolicyType>
+where
+    Self: Context,
+{
+    fn add_port(&mut self, port: Port<T>) {
+        port.attach(self);
+        self.ports.insert(port);
+    }
+}
+
+impl<T: DAMType, LT, PolicyType> StoreAndForwardSwitch<T, LT, PolicyType>
+where
+    Self: Context,
+{
+    /// Creates a switch whose links move `input_width` bits per cycle on the way in, and `output_width` bits per
+    /// cycle on the way out. Each copy arrives `latency` cycles after it has been serialized.
+    pub fn new(policy: PolicyType, latency: u64, input_width: usize, output_width: usize) -> Self {
+        assert!(input_width > 0, "Input widths must be positive!");
+        assert!(output_width > 0, "Output widths must be positive!");
+        Self {
+            ports: Default::default(),
+            policy,
+            latency,
+            input_width,
+            output_width,
+            received_at: Default::default(),
+            pending: Default::default(),
+            busy_until: Default::default(),
+            _marker: Default::default(),
+            context_info: Default::default(),
+        }
+    }
+
+    /// Waits until the packet at the front of some input has been received in full. Returns false once every input
+    /// has closed.
+    fn advance_to_next_event(&mut self) -> bool {
+        loop {
+            self.ports
+                .inputs
+                .retain(|_, chan| !matches!(chan.peek(), dam::channel::PeekResult::Closed));
+            if self.ports.inputs.is_empty() {
+                return false;
+            }
+
+            // Packets start being received once they are at the front of their input, and have shown up.
+            let tick = self.time.tick();
+            for (id, chan) in &self.ports.inputs {
+                if self.received_at.contains_key(id) {
+                    continue;
+                }
+                if let dam::channel::PeekResult::Something(ChannelElement { time, data }) =
+                    chan.peek()
+                {
+                    let receive = data.dam_size().div_ceil(self.input_width) as u64;
+                    self.received_at.insert(*id, time.max(tick) + receive);
+                }
+            }
+
+            // Inputs which are still empty might yet deliver something which finishes before the packets we have.
+            let next_arrival = self
+                .ports
+                .inputs
+                .iter()
+                .filter(|(id, _)| !self.received_at.contains_key(id))
+                .filter_map(|(_, chan)| match chan.next_event() {
+                    EventTime::Ready(t) => Some(t),
+                    EventTime::Nothing(t) => Some(t + 1),
+                    EventTime::Closed => None,
+                })
+                .min();
+            let next_received = self.received_at.values().min().copied();
+            if let Some(received) = next_received {
+                if next_arrival.is_none_or(|arrival| received <= arrival) {
+                    self.time.advance(received);
+                    return true;
+                }
+            }
+            // With no arrival to wait for, an input closed, which the next pass takes care of.
+            if let Some(arrival) = next_arrival {
+                self.time.advance(arrival);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dam::{
+        context_tools::{Context, DAMType},
+        simulation::ProgramBuilder,
+    };
+
+    use crate::switches::{
+        routing::{Packet, Port, Switch},
+        testing::{direct_policy, run_switch},
+        SimpleSwitch,
+    };
+
+    use super::StoreAndForwardSwitch;
+
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    struct TestPacket {
+        location: u8,
+    }
+
+    impl Packet<u8> for TestPacket {
+        fn destination(&self) -> u8 {
+            self.location
+        }
+    }
+
+    impl DAMType for TestPacket {
+        fn dam_size(&self) -> usize {
+            // 64 bytes.
+            512
+        }
+    }
+
+    const LATENCY: u64 = 1;
+    const WIDTH: usize = 64;
+
+    /// Sends a 64 byte packet through two switches in a row, and returns when it shows up at the end.
+    fn two_hop_arrival<S: Switch<TestPacket> + Context + 'static>(
+        make_switch: impl Fn() -> S,
+    ) -> u64 {
+        let source = vec![(1, TestPacket { location: 1 })];
+        let arrivals = run_switch([(0, source)], [1], |ctx: &mut ProgramBuilder, ports| {
+            let (mid_snd, mid_rcv) = ctx.unbounded();
+            let mut ports = ports.into_iter();
+            let input = ports.next().unwrap().input;
+            let output = ports.next().unwrap().output;
+            for (input, output) in [(input, Some(mid_snd)), (Some(mid_rcv), output)] {
+                let mut switch = make_switch();
+                switch.add_port(Port {
+                    id: 0,
+                    input,
+                    output: None,
+                });
+                switch.add_port(Port {
+                    id: 1,
+                    input: None,
+                    output,
+                });
+                ctx.add_child(switch);
+            }
+        });
+        let [(_, arrival, _)] = arrivals[..] else {
+            panic!("Expected exactly one arrival, got {arrivals:?}");
+        };
+        arrival
+    }
+
+    #[test]
+    fn store_and_forward_test() {
+        let cut_through = two_hop_arrival(|| {
+            let mut switch = SimpleSwitch::new(direct_policy([1]), LATENCY);
+            switch.set_output_width(1, WIDTH);
+            switch
+        });
+        let store_and_forward = two_hop_arrival(|| {
+            StoreAndForwardSwitch::new(direct_policy([1]), LATENCY, WIDTH, WIDTH)
+        });
+
+        let packet = TestPacket::default();
+        let serialization = packet.dam_size().div_ceil(WIDTH) as u64;
+        assert!(serialization > 1);
+        // Both switches pay the latency and serialize onto their outputs, but only one of them waits for the whole
+        // packet to arrive before it starts.
+        let base = 1 + 2 * LATENCY;
+        assert_eq!(cut_through - base, 2 * serialization);
+        assert_eq!(store_and_forward - base, 2 * (cut_through - base));
+    }
+}
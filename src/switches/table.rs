@@ -0,0 +1,441 @@
+use std::{fmt::Display, hash::Hash, iter::Peekable, path::Path, str::Chars, str::FromStr};
+
+use fxhash::{FxHashMap, FxHashSet};
+
+/// Why a routing table couldn't be loaded.
+#[derive(Debug)]
+pub enum TableError {
+    /// The file couldn't be read.
+    Io(std::io::Error),
+    /// A row of the table didn't make sense. Lines are counted from 1.
+    Malformed { line: usize, reason: String },
+}
+
+impl Display for TableError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TableError::Io(error) => write!(f, "Could not read the routing table: {}", error),
+            TableError::Malformed { line, reason } => write!(f, "Line {}: {}", line, reason),
+        }
+    }
+}
+
+impl std::error::Error for TableError {}
+
+impl From<std::io::Error> for TableError {
+    fn from(error: std::io::Error) -> Self {
+        TableError::Io(error)
+    }
+}
+
+/// Routing tables which can be read from and written out to files, for tables generated outside of the simulation.
+/// Each row maps a destination to the ports it goes out of, and a destination may only have one row.
+///
+/// In CSV, every row is a line of the form `destination,port[,port...]`. Blank lines, and lines starting with `#`, are
+/// skipped. In JSON, the table is a single object, whose keys are destinations and whose values are arrays of ports:
+/// `{"3": [0, 1], "4": [2]}`. Destinations are parsed with [`FromStr`], so they can be whatever the location type
+/// reads from text.
+pub trait TablePolicy<LocationType>: Sized {
+    fn parse_csv(text: &str) -> Result<Self, TableError>;
+    fn parse_json(text: &str) -> Result<Self, TableError>;
+
+    /// Writes the table out as CSV, with one row per destination. Rows are sorted, so that the same table always comes
+    /// out the same.
+    fn to_csv(&self) -> String
+    where
+        LocationType: Display;
+
+    /// Writes the table out as JSON, with one destination per line. Rows are sorted, as with [`TablePolicy::to_csv`].
+    fn to_json(&self) -> String
+    where
+        LocationType: Display;
+
+    fn from_csv(path: impl AsRef<Path>) -> Result<Self, TableError> {
+        Self::parse_csv(&std::fs::read_to_string(path)?)
+    }
+
+    fn from_json(path: impl AsRef<Path>) -> Result<Self, TableError> {
+        Self::parse_json(&std::fs::read_to_string(path)?)
+    }
+}
+
+impl<LocationType> TablePolicy<LocationType> for FxHashMap<LocationType, FxHashSet<usize>>
+where
+    LocationType: FromStr + Eq + Hash,
+    LocationType::Err: Display,
+{
+    fn parse_csv(text: &str) -> Result<Self, TableError> {
+        let mut table = TableBuilder::default();
+        for (index, row) in text.lines().enumerate() {
+            let line = index + 1;
+            let row = row.trim();
+            if row.is_empty() || row.starts_with('#') {
+                continue;
+            }
+            let mut fields = row.split(',').map(str::trim);
+            let destination = fields.next().unwrap();
+            let ports = fields
+                .map(|field| parse_port(field, line))
+                .collect::<Result<FxHashSet<_>, _>>()?;
+            table.insert(destination, ports, line)?;
+        }
+        Ok(table.finish())
+    }
+
+    fn parse_json(text: &str) -> Result<Self, TableError> {
+        let mut reader = JsonReader {
+            chars: text.chars().peekable(),
+            line: 1,
+        };
+        let mut table = TableBuilder::default();
+        reader.expect('{')?;
+        if reader.peek() == Some('}') {
+            reader.next();
+        } else {
+            loop {
+                let line = reader.line_after_whitespace();
+                let destination = reader.key()?;
+                reader.expect(':')?;
+                let ports = reader.ports()?;
+                table.insert(&destination, ports, line)?;
+                match reader.next() {
+                    Some(',') => continue,
+                    Some('}') => break,
+                    found => return Err(reader.unexpected("',' or '}'", found)),
+                }
+            }
+        }
+        if reader.peek().is_some() {
+            let found = reader.next();
+            return Err(reader.unexpected("the end of the table", found));
+        }
+        Ok(table.finish())
+    }
+
+    fn to_csv(&self) -> String
+    where
+        LocationType: Display,
+    {
+        let rows = sorted_rows(self, |destination, ports| {
+            let ports: Vec<_> = ports.iter().map(usize::to_string).collect();
+            format!("{},{}", destination, ports.join(","))
+        });
+        rows.into_iter().map(|row| row + "\n").collect()
+    }
+
+    fn to_json(&self) -> String
+    where
+        LocationType: Display,
+    {
+        let rows = sorted_rows(self, |destination, ports| {
+            let ports: Vec<_> = ports.iter().map(usize::to_string).collect();
+            let destination = destination.replace('\\', "\\\\").replace('"', "\\\"");
+            format!("  \"{}\": [{}]", destination, ports.join(", "))
+        });
+        match rows.is_empty() {
+            true => "{}\n".to_string(),
+            false => format!("{{\n{}\n}}\n", rows.join(",\n")),
+        }
+    }
+}
+
+/// Formats every row of `table` with `format`, given the destination as text and the ports in order, and sorts them.
+fn sorted_rows<LT: Display>(
+    table: &FxHashMap<LT, FxHashSet<usize>>,
+    format: impl Fn(&str, &[usize]) -> String,
+) -> Vec<String> {
+    let mut rows: Vec<_> = table
+        .iter()
+        .map(|(destination, ports)| {
+            let mut ports: Vec<_> = ports.iter().copied().collect();
+            ports.sort();
+            format(&destination.to_string(), &ports)
+        })
+        .collect();
+    rows.sort();
+    rows
+}
+
+fn parse_port(text: &str, line: usize) -> Result<usize, TableError> {
+    text.parse().map_err(|_| TableError::Malformed {
+        line,
+        reason: format!("Expected a port number, found {:?}", text),
+    })
+}
+
+/// Collects rows, turning away destinations which already have one.
+struct TableBuilder<LT> {
+    // Along with the line each row was on, for pointing at the first one when another turns up.
+    rows: FxHashMap<LT, (FxHashSet<usize>, usize)>,
+}
+
+impl<LT> Default for TableBuilder<LT> {
+    fn default() -> Self {
+        Self {
+            rows: Default::default(),
+        }
+    }
+}
+
+impl<LT> TableBuilder<LT>
+where
+    LT: FromStr + Eq + Hash,
+    LT::Err: Display,
+{
+    fn insert(
+        &mut self,
+        destination: &str,
+        ports: FxHashSet<usize>,
+        line: usize,
+    ) -> Result<(), TableError> {
+        let malformed = |reason| Err(TableError::Malformed { line, reason });
+        if ports.is_empty() {
+            return malformed(format!("{:?} has no ports", destination));
+        }
+        let location = match destination.parse() {
+            Ok(location) => location,
+            Err(error) => {
+                return malformed(format!(
+                    "Could not parse destination {:?}: {}",
+                    destination, error
+                ))
+            }
+        };
+        if let Some((_, first)) = self.rows.get(&location) {
+            return malformed(format!(
+                "{:?} already has a row, on line {}",
+                destination, first
+            ));
+        }
+        self.rows.insert(location, (ports, line));
+        Ok(())
+    }
+
+    fn finish(self) -> FxHashMap<LT, FxHashSet<usize>> {
+        self.rows
+            .into_iter()
+            .map(|(location, (ports, _))| (location, ports))
+            .collect()
+    }
+}
+
+/// Just enough of a JSON reader for routing tables, which keeps track of the line it's on.
+struct JsonReader<'a> {
+    chars: Peekable<Chars<'a>>,
+    line: usize,
+}
+
+impl JsonReader<'_> {
+    fn next(&mut self) -> Option<char> {
+        self.skip_whitespace();
+        self.take()
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.skip_whitespace();
+        self.chars.peek().copied()
+    }
+
+    fn take(&mut self) -> Option<char> {
+        let next = self.chars.next();
+        if next == Some('\n') {
+            self.line += 1;
+        }
+        next
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.chars.peek().is_some_and(|c| c.is_whitespace()) {
+            self.take();
+        }
+    }
+
+    fn line_after_whitespace(&mut self) -> usize {
+        self.skip_whitespace();
+        self.line
+    }
+
+    fn unexpected(&self, expected: &str, found: Option<char>) -> TableError {
+        let reason = match found {
+            Some(found) => format!("Expected {}, found {:?}", expected, found),
+            None => format!("Expected {}, but the table ended", expected),
+        };
+        TableError::Malformed {
+            line: self.line,
+            reason,
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), TableError> {
+        match self.next() {
+            Some(found) if found == expected => Ok(()),
+            found => Err(self.unexpected(&format!("{:?}", expected), found)),
+        }
+    }
+
+    /// A destination: either a string, or a bare number.
+    fn key(&mut self) -> Result<String, TableError> {
+        match self.peek() {
+            Some('"') => {
+                self.take();
+                let mut key = String::new();
+                loop {
+                    match self.take() {
+                        Some('"') => return Ok(key),
+                        Some('\\') => match self.take() {
+                            Some(escaped @ ('"' | '\\' | '/')) => key.push(escaped),
+                            found => return Err(self.unexpected("'\"', '\\\\' or '/'", found)),
+                        },
+                        Some('\n') | None => {
+                            return Err(TableError::Malformed {
+                                line: self.line,
+                                reason: "Unterminated string".to_string(),
+                            })
+                        }
+                        Some(c) => key.push(c),
+                    }
+                }
+            }
+            Some(c) if c.is_ascii_digit() || c == '-' => Ok(self.token()),
+            found => Err(self.unexpected("a destination", found)),
+        }
+    }
+
+    /// Everything up to the next bit of punctuation or whitespace.
+    fn token(&mut self) -> String {
+        let mut token = String::new();
+        while let Some(c) = self.chars.peek().copied() {
+            if c.is_whitespace() || ",:[]{}\"".contains(c) {
+                break;
+            }
+            token.push(c);
+            self.take();
+        }
+        token
+    }
+
+    fn ports(&mut self) -> Result<FxHashSet<usize>, TableError> {
+        self.expect('[')?;
+        let mut ports = FxHashSet::default();
+        if self.peek() == Some(']') {
+            self.take();
+            return Ok(ports);
+        }
+        loop {
+            self.skip_whitespace();
+            let line = self.line;
+            ports.insert(parse_port(&self.token(), line)?);
+            match self.next() {
+                Some(',') => continue,
+                Some(']') => return Ok(ports),
+                found => return Err(self.unexpected("',' or ']'", found)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use fxhash::{FxHashMap, FxHashSet};
+
+    use super::{TableError, TablePolicy};
+
+    type Table = FxHashMap<u16, FxHashSet<usize>>;
+
+    /// A table with a few hundred destinations, some of them multicast.
+    fn table() -> Table {
+        (0..300u16)
+            .map(|destination| {
+                let ports = match destination % 7 {
+                    0 => vec![1, 4, 9],
+                    _ => vec![destination as usize % 16],
+                };
+                (destination, FxHashSet::from_iter(ports))
+            })
+            .collect()
+    }
+
+    /// A file in the temporary directory, which is removed once the test is done with it.
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str, contents: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("{}-{}", std::process::id(), name));
+            std::fs::write(&path, contents).unwrap();
+            Self(path)
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn csv_round_trip_test() {
+        let file = TempFile::new("table.csv", &table().to_csv());
+        assert_eq!(Table::from_csv(&file.0).unwrap(), table());
+
+        // Hand written tables can have comments, blank lines and spaces.
+        let text = "# destination, ports\n\n3, 1, 2\n 4,0\n";
+        let expected = Table::from_iter([
+            (3, FxHashSet::from_iter([1, 2])),
+            (4, FxHashSet::from_iter([0])),
+        ]);
+        assert_eq!(Table::parse_csv(text).unwrap(), expected);
+    }
+
+    #[test]
+    fn json_round_trip_test() {
+        let file = TempFile::new("table.json", &table().to_json());
+        assert_eq!(Table::from_json(&file.0).unwrap(), table());
+
+        // Destinations can be bare numbers too.
+        let text = "{\"3\": [1, 2], 4: [0]}";
+        let expected = Table::from_iter([
+            (3, FxHashSet::from_iter([1, 2])),
+            (4, FxHashSet::from_iter([0])),
+        ]);
+        assert_eq!(Table::parse_json(text).unwrap(), expected);
+        assert_eq!(Table::parse_json(" {} ").unwrap(), Table::default());
+    }
+
+    fn malformed(result: Result<Table, TableError>) -> (usize, String) {
+        match result {
+            Err(TableError::Malformed { line, reason }) => (line, reason),
+            other => panic!("Expected a malformed table, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn duplicate_destination_test() {
+        let (line, reason) = malformed(Table::parse_csv("3,1\n4,2\n3,0\n"));
+        assert_eq!(line, 3);
+        assert_eq!(reason, "\"3\" already has a row, on line 1");
+
+        let (line, reason) = malformed(Table::parse_json("{\n  \"3\": [1],\n  \"3\": [0]\n}"));
+        assert_eq!(line, 3);
+        assert_eq!(reason, "\"3\" already has a row, on line 2");
+    }
+
+    #[test]
+    fn malformed_row_test() {
+        assert_eq!(
+            malformed(Table::parse_csv("3,1\n4,x\n")),
+            (2, "Expected a port number, found \"x\"".to_string())
+        );
+        assert_eq!(malformed(Table::parse_csv("3,1\nfour,2\n")).0, 2);
+        assert_eq!(malformed(Table::parse_csv("3\n")).0, 1);
+        assert_eq!(
+            malformed(Table::parse_json("{\n  \"3\": [1]\n  \"4\": [2]\n}")),
+            (3, "Expected ',' or '}', found '\"'".to_string())
+        );
+        assert!(matches!(
+            Table::from_csv("/this/table/does/not/exist.csv"),
+            Err(TableError::Io(_))
+        ));
+    }
+}
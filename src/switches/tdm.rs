@@ -0,0 +1,191 @@
+use std::collections::BTreeMap;
+
+use dam::context_tools::*;
+
+use super::routing::{Port, PortMap, Switch};
+
+/// Which input each output listens to in every slot of a [`TdmSwitch`]'s frame, as `table[slot][input] = output`.
+/// Inputs without an entry in a slot don't send anything during it.
+pub type SlotTable = Vec<fxhash::FxHashMap<usize, usize>>;
+
+/// Two inputs were given the same output in the same slot.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SlotConflict {
+    pub slot: usize,
+    pub output: usize,
+    pub inputs: (usize, usize),
+}
+
+impl std::fmt::Display for SlotConflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Inputs {} and {} both have output {} in slot {}",
+            self.inputs.0, self.inputs.1, self.output, self.slot
+        )
+    }
+}
+
+impl std::error::Error for SlotConflict {}
+
+/// Checks that no output is handed to more than one input in any slot.
+pub fn validate_slot_table(table: &SlotTable) -> Result<(), SlotConflict> {
+    for (slot, grants) in table.iter().enumerate() {
+        let mut owners = BTreeMap::new();
+        // Sorted, so that the same table always reports the same conflict.
+        let sorted: BTreeMap<_, _> = grants.iter().collect();
+        for (input, output) in sorted {
+            if let Some(other) = owners.insert(*output, *input) {
+                return Err(SlotConflict {
+                    slot,
+                    output: *output,
+                    inputs: (other, *input),
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// A circuit switched crossbar which follows a repeating slot table instead of looking at its packets. On cycle `t`,
+/// every input listed in slot `t % table.len()` sends its head packet to the output it was given, no matter where the
+/// packet is headed. Packets wait at their input until a slot comes around for them.
+#[context_macro]
+pub struct TdmSwitch<T>
+where
+    T: DAMType,
+{
+    ports: PortMap<T>,
+
+    table: SlotTable,
+    latency: u64,
+}
+
+impl<T: DAMType> Context for TdmSwitch<T> {
+    fn run(&mut self) {
+        while self.ports.wait_for_input(&mut self.context_info.time) {
+            let tick = self.time.tick();
+            let slot = (tick.time() % self.table.len() as u64) as usize;
+            for (input, output) in &self.table[slot] {
+                let Some(chan) = self.ports.inputs.get(input) else {
+                    continue;
+                };
+                let data = match chan.peek() {
+                    dam::channel::PeekResult::Something(ChannelElement { time, data })
+                        if time <= tick =>
+                    {
+                        data
+                    }
+                    _ => continue,
+                };
+                let result = self.ports.outputs.get(output).unwrap().try_enqueue(
+                    &self.time,
+                    ChannelElement {
+                        time: tick + self.latency,
+                        data,
+                    },
+                );
+                // The packet missed its slot, and waits for the next frame.
+                if let Err(dam::channel::EnqueueError::Full) = result {
+                    continue;
+                }
+                let _ = chan.dequeue(&self.time);
+            }
+            self.time.incr_cycles(1);
+        }
+    }
+}
+
+impl<T: DAMType> Switch<T> for TdmSwitch<T> {
+    fn add_port(&mut self, port: Port<T>) {
+        port.attach(self);
+        self.ports.insert(port);
+    }
+}
+
+impl<T: DAMType> TdmSwitch<T>
+where
+    Self: Context,
+{
+    /// Creates a switch which runs through `table` over and over, one slot per cycle, and forwards each packet
+    /// `latency` cycles after its slot. Panics if the table can't be followed, see [`validate_slot_table`].
+    pub fn new(table: SlotTable, latency: u64) -> Self {
+        assert!(!table.is_empty(), "Slot tables need at least one slot!");
+        if let Err(conflict) = validate_slot_table(&table) {
+            panic!("{}", conflict);
+        }
+        Self {
+            ports: Default::default(),
+            table,
+            latency,
+            context_info: Default::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::switches::{
+        routing::SimplePacket,
+        testing::{connect, run_switch},
+    };
+
+    use super::{validate_slot_table, SlotConflict, SlotTable, TdmSwitch};
+
+    const NUM_PACKETS: u64 = 16;
+    const FRAME: u64 = 4;
+
+    fn table() -> SlotTable {
+        // Inputs 0 and 1 share output 2, in slots 0 and 2.
+        vec![
+            fxhash::FxHashMap::from_iter([(0, 2)]),
+            Default::default(),
+            fxhash::FxHashMap::from_iter([(1, 2)]),
+            Default::default(),
+        ]
+    }
+
+    #[test]
+    fn fixed_latency_test() {
+        // Both inputs send a packet at the start of every frame.
+        let sources = (0..2u16).map(|port| {
+            let packets = (0..NUM_PACKETS).map(|i| {
+                let packet = SimplePacket {
+                    // The switch doesn't care where packets say they're going.
+                    location: 7u8,
+                    payload: port,
+                };
+                (i * FRAME + 1, packet)
+            });
+            (port as usize, packets.collect())
+        });
+        let arrivals = run_switch(sources, [2], connect(TdmSwitch::new(table(), 1)));
+
+        for (port, expected_latency) in [(0, 4), (1, 2)] {
+            let latencies: Vec<_> = arrivals
+                .iter()
+                .filter(|(_, _, packet)| packet.payload == port)
+                .enumerate()
+                .map(|(i, (_, time, _))| time - (i as u64 * FRAME + 1))
+                .collect();
+            // Every packet in a flow waits exactly as long for its slot.
+            assert_eq!(latencies, vec![expected_latency; NUM_PACKETS as usize]);
+        }
+    }
+
+    #[test]
+    fn slot_conflict_test() {
+        assert_eq!(validate_slot_table(&table()), Ok(()));
+
+        let mut conflicted = table();
+        conflicted[2].insert(0, 2);
+        assert_eq!(
+            validate_slot_table(&conflicted),
+            Err(SlotConflict {
+                slot: 2,
+                output: 2,
+                inputs: (0, 1)
+            })
+        );
+    }
+}
@@ -0,0 +1,494 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use dam::{
+    channel::utils::{EventTime, Peekable},
+    context_tools::*,
+    simulation::ProgramBuilder,
+    structures::Time,
+    utility_contexts::FunctionContext,
+};
+
+use super::{
+    mesh::MeshPorts,
+    routing::{Coord2D, Dir2D, Port, SimplePacket, Switch},
+};
+
+/// The packet most of the switch tests send around.
+pub(crate) type TestPacket = SimplePacket<u8, u16>;
+
+/// Something a sink took off of the switch: the output port, the cycle it was stamped with, and the packet.
+pub(crate) type Arrival<T> = (usize, u64, T);
+
+/// Routes each location in `ports` to the output with the same ID.
+pub(crate) fn direct_policy(
+    ports: impl IntoIterator<Item = usize>,
+) -> fxhash::FxHashMap<u8, fxhash::FxHashSet<usize>> {
+    ports
+        .into_iter()
+        .map(|port| (port as u8, fxhash::FxHashSet::from_iter([port])))
+        .collect()
+}
+
+/// Times packets the way a [`dam::utility_contexts::GeneratorContext`] would, one per cycle starting on cycle 1.
+pub(crate) fn stream<T>(packets: impl IntoIterator<Item = T>) -> Vec<(u64, T)> {
+    (1..).zip(packets).collect()
+}
+
+/// An output port of the switch under test, and how quickly it gets drained.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Sink {
+    port: usize,
+    depth: Option<usize>,
+    period: u64,
+}
+
+impl Sink {
+    /// Drains `port` through an unbounded channel, as fast as packets show up.
+    pub fn new(port: usize) -> Self {
+        Self {
+            port,
+            depth: None,
+            period: 0,
+        }
+    }
+
+    /// Connects the sink through a channel which only has room for `depth` packets.
+    pub fn with_depth(mut self, depth: usize) -> Self {
+        self.depth = Some(depth);
+        self
+    }
+
+    /// Has the sink spend `period` cycles on each packet before it takes the next one.
+    pub fn with_period(mut self, period: u64) -> Self {
+        self.period = period;
+        self
+    }
+}
+
+impl From<usize> for Sink {
+    fn from(port: usize) -> Self {
+        Sink::new(port)
+    }
+}
+
+/// Hands every port to `switch`, and adds it to the program. Meant as the last argument to [`run_switch`].
+pub(crate) fn connect<T: DAMType, S: Switch<T> + Context + 'static>(
+    mut switch: S,
+) -> impl FnOnce(&mut ProgramBuilder, Vec<Port<T>>) {
+    move |ctx, ports| {
+        ports.into_iter().for_each(|port| switch.add_port(port));
+        ctx.add_child(switch);
+    }
+}
+
+fn port<T: Clone>(ports: &mut BTreeMap<usize, Port<T>>, id: usize) -> &mut Port<T> {
+    ports.entry(id).or_insert_with(|| Port {
+        id,
+        input: None,
+        output: None,
+    })
+}
+
+/// Sends each source's packets into its port at the cycles they are paired with, and drains every sink. The ports
+/// are handed to `add_switch` in port order, with both halves filled in for ports which are both a source and a
+/// sink. Returns everything the sinks took, ordered by when it arrived and then by port.
+pub(crate) fn run_switch<T: DAMType>(
+    sources: impl IntoIterator<Item = (usize, Vec<(u64, T)>)>,
+    sinks: impl IntoIterator<Item = impl Into<Sink>>,
+    add_switch: impl FnOnce(&mut ProgramBuilder, Vec<Port<T>>),
+) -> Vec<Arrival<T>> {
+    let mut ctx = ProgramBuilder::default();
+    let mut ports = BTreeMap::new();
+
+    for (id, packets) in sources {
+        let (snd, rcv) = ctx.unbounded();
+        let mut source = FunctionContext::new();
+        snd.attach_sender(&source);
+        source.set_run(move |time| {
+            for (at, data) in packets {
+                snd.enqueue(
+                    time,
+                    ChannelElement {
+                        time: Time::new(at),
+                        data,
+                    },
+                )
+                .unwrap();
+            }
+        });
+        ctx.add_child(source);
+        port(&mut ports, id).input = Some(rcv);
+    }
+
+    let arrivals = Arc::new(Mutex::new(vec![]));
+    for sink in sinks {
+        let sink: Sink = sink.into();
+        let (snd, rcv) = match sink.depth {
+            Some(depth) => ctx.bounded(depth),
+            None => ctx.unbounded(),
+        };
+        let mut context = FunctionContext::new();
+        rcv.attach_receiver(&context);
+        let sink_log = arrivals.clone();
+        context.set_run(move |time| {
+            while let Ok(element) = rcv.dequeue(time) {
+                sink_log
+                    .lock()
+                    .unwrap()
+                    .push((sink.port, element.time.time(), element.data));
+                time.incr_cycles(sink.period);
+            }
+        });
+        ctx.add_child(context);
+        port(&mut ports, sink.port).output = Some(snd);
+    }
+
+    add_switch(&mut ctx, ports.into_values().collect());
+    ctx.initialize(Default::default())
+        .unwrap()
+        .run(Default::default());
+
+    let mut arrivals = arrivals.lock().unwrap().clone();
+    // Stable, so each port's packets stay in the order they were taken.
+    arrivals.sort_by_key(|(port, time, _)| (*time, *port));
+    arrivals
+}
+
+/// Counts packets as they reach their destination, so that networks full of cycles of channels, which never close by
+/// themselves, know when they are done.
+pub(crate) struct Deliveries {
+    delivered: AtomicUsize,
+    expected: usize,
+}
+
+impl Deliveries {
+    pub fn new(expected: usize) -> Arc<Self> {
+        Arc::new(Self {
+            delivered: AtomicUsize::new(0),
+            expected,
+        })
+    }
+
+    pub fn record(&self) {
+        self.delivered.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn done(&self) -> bool {
+        self.delivered.load(Ordering::SeqCst) >= self.expected
+    }
+}
+
+/// Carries packets from one switch to the next, a cycle later. Links shut down once every packet has been delivered,
+/// and the switches on either end follow once all of their links have.
+pub(crate) fn link<'a, T: DAMType + 'a>(
+    ctx: &mut ProgramBuilder<'a>,
+    from: Receiver<T>,
+    to: Sender<T>,
+    deliveries: Arc<Deliveries>,
+) {
+    let mut link = FunctionContext::new();
+    from.attach_receiver(&link);
+    to.attach_sender(&link);
+    link.set_run(move |time| {
+        while !deliveries.done() {
+            match from.next_event() {
+                EventTime::Ready(_) => {
+                    let ChannelElement { time: _, data } = from.dequeue(time).unwrap();
+                    to.enqueue(
+                        time,
+                        ChannelElement {
+                            time: time.tick() + 1,
+                            data,
+                        },
+                    )
+                    .unwrap();
+                }
+                EventTime::Nothing(t) => time.advance(t + 1),
+                EventTime::Closed => return,
+            }
+        }
+    });
+    ctx.add_child(link);
+}
+
+/// Builds a `width` by `height` mesh out of the switches `make_switch` hands back for each position, joined by
+/// [`link`]s on the ports in `ports`. Each node sends `traffic(node)` into its local port, as (cycle, packet) pairs,
+/// and the run ends once `expected` packets have come back out of local ports. Returns where and when each of them
+/// came out, ordered by time and then by position.
+pub(crate) fn run_mesh<T: DAMType, S: Switch<T> + Context + 'static>(
+    width: u16,
+    height: u16,
+    ports: MeshPorts,
+    traffic: impl Fn(Coord2D) -> Vec<(u64, T)>,
+    make_switch: impl FnMut(Coord2D) -> S,
+    expected: usize,
+) -> Vec<(Coord2D, u64, T)> {
+    let add_port = |_: &mut ProgramBuilder, _, switch: &mut S, port| switch.add_port(port);
+    run_grid(
+        width,
+        height,
+        false,
+        ports,
+        traffic,
+        make_switch,
+        add_port,
+        expected,
+    )
+}
+
+/// Like [`run_mesh`], but leaves adding the ports to each switch to `add_port`, which gets the position of the switch
+/// along with the port the mesh would have added, and can put contexts of its own in between. Anything it puts on an
+/// input shuts down once the link or source feeding that input has.
+pub(crate) fn run_mesh_with_ports<T: DAMType, S: Switch<T> + Context + 'static>(
+    width: u16,
+    height: u16,
+    ports: MeshPorts,
+    traffic: impl Fn(Coord2D) -> Vec<(u64, T)>,
+    make_switch: impl FnMut(Coord2D) -> S,
+    add_port: impl FnMut(&mut ProgramBuilder, Coord2D, &mut S, Port<T>),
+    expected: usize,
+) -> Vec<(Coord2D, u64, T)> {
+    run_grid(
+        width,
+        height,
+        false,
+        ports,
+        traffic,
+        make_switch,
+        add_port,
+        expected,
+    )
+}
+
+/// Like [`run_mesh`], but with links around the edges too.
+pub(crate) fn run_torus<T: DAMType, S: Switch<T> + Context + 'static>(
+    width: u16,
+    height: u16,
+    ports: MeshPorts,
+    traffic: impl Fn(Coord2D) -> Vec<(u64, T)>,
+    make_switch: impl FnMut(Coord2D) -> S,
+    expected: usize,
+) -> Vec<(Coord2D, u64, T)> {
+    let add_port = |_: &mut ProgramBuilder, _, switch: &mut S, port| switch.add_port(port);
+    run_grid(
+        width,
+        height,
+        true,
+        ports,
+        traffic,
+        make_switch,
+        add_port,
+        expected,
+    )
+}
+
+/// Builds a network out of `switches`, numbered by their index, with a [`link`] each way for every
+/// (switch, port, switch, port) in `links`. Endpoint `i` sits on the (switch, port) at `endpoints[i]`, and sends
+/// `traffic(i)` into it as (cycle, packet) pairs. The run ends once `expected` packets have come back out of
+/// endpoints, and returns which endpoint each of them came out of and when, ordered by time and then by endpoint.
+pub(crate) fn run_network<T: DAMType, S: Switch<T> + Context + 'static>(
+    mut switches: Vec<S>,
+    links: &[(usize, usize, usize, usize)],
+    endpoints: &[(usize, usize)],
+    traffic: impl Fn(usize) -> Vec<(u64, T)>,
+    expected: usize,
+) -> Vec<(usize, u64, T)> {
+    let mut ctx = ProgramBuilder::default();
+    let deliveries = Deliveries::new(expected);
+    let arrivals = Arc::new(Mutex::new(vec![]));
+
+    for (endpoint, (switch, port)) in endpoints.iter().copied().enumerate() {
+        let (inject_snd, inject_rcv) = ctx.unbounded();
+        let packets = traffic(endpoint);
+        let mut source = FunctionContext::new();
+        inject_snd.attach_sender(&source);
+        source.set_run(move |time| {
+            for (at, data) in packets {
+                inject_snd
+                    .enqueue(
+                        time,
+                        ChannelElement {
+                            time: Time::new(at),
+                            data,
+                        },
+                    )
+                    .unwrap();
+            }
+        });
+        ctx.add_child(source);
+
+        let (eject_snd, eject_rcv) = ctx.unbounded();
+        let mut sink = FunctionContext::new();
+        eject_rcv.attach_receiver(&sink);
+        let (sink_log, sink_deliveries) = (arrivals.clone(), deliveries.clone());
+        sink.set_run(move |time| {
+            while let Ok(element) = eject_rcv.dequeue(time) {
+                sink_log
+                    .lock()
+                    .unwrap()
+                    .push((endpoint, element.time.time(), element.data));
+                sink_deliveries.record();
+            }
+        });
+        ctx.add_child(sink);
+
+        switches[switch].add_port(Port {
+            id: port,
+            input: Some(inject_rcv),
+            output: Some(eject_snd),
+        });
+    }
+
+    for (a, a_port, b, b_port) in links.iter().copied() {
+        for (from, from_port, to, to_port) in [(a, a_port, b, b_port), (b, b_port, a, a_port)] {
+            let (out_snd, out_rcv) = ctx.unbounded();
+            let (in_snd, in_rcv) = ctx.unbounded();
+            switches[from].add_port(Port {
+                id: from_port,
+                input: None,
+                output: Some(out_snd),
+            });
+            switches[to].add_port(Port {
+                id: to_port,
+                input: Some(in_rcv),
+                output: None,
+            });
+            link(&mut ctx, out_rcv, in_snd, deliveries.clone());
+        }
+    }
+    switches
+        .into_iter()
+        .for_each(|switch| ctx.add_child(switch));
+
+    ctx.initialize(Default::default())
+        .unwrap()
+        .run(Default::default());
+
+    let mut arrivals = arrivals.lock().unwrap().clone();
+    arrivals.sort_by_key(|(endpoint, time, _)| (*time, *endpoint));
+    arrivals
+}
+
+#[allow(clippy::too_many_arguments)]
+fn run_grid<T: DAMType, S: Switch<T> + Context + 'static>(
+    width: u16,
+    height: u16,
+    wrap_around: bool,
+    ports: MeshPorts,
+    traffic: impl Fn(Coord2D) -> Vec<(u64, T)>,
+    mut make_switch: impl FnMut(Coord2D) -> S,
+    mut add_port: impl FnMut(&mut ProgramBuilder, Coord2D, &mut S, Port<T>),
+    expected: usize,
+) -> Vec<(Coord2D, u64, T)> {
+    let mut ctx = ProgramBuilder::default();
+    let deliveries = Deliveries::new(expected);
+    let arrivals = Arc::new(Mutex::new(vec![]));
+    let nodes: Vec<_> = (0..height)
+        .flat_map(|y| (0..width).map(move |x| Coord2D { x, y }))
+        .collect();
+
+    let mut switches = BTreeMap::new();
+    for node in nodes.iter().copied() {
+        let mut switch = make_switch(node);
+
+        let (inject_snd, inject_rcv) = ctx.unbounded();
+        let packets = traffic(node);
+        let mut source = FunctionContext::new();
+        inject_snd.attach_sender(&source);
+        source.set_run(move |time| {
+            for (at, data) in packets {
+                inject_snd
+                    .enqueue(
+                        time,
+                        ChannelElement {
+                            time: Time::new(at),
+                            data,
+                        },
+                    )
+                    .unwrap();
+            }
+        });
+        ctx.add_child(source);
+
+        let (eject_snd, eject_rcv) = ctx.unbounded();
+        let mut sink = FunctionContext::new();
+        eject_rcv.attach_receiver(&sink);
+        let (sink_log, sink_deliveries) = (arrivals.clone(), deliveries.clone());
+        sink.set_run(move |time| {
+            while let Ok(element) = eject_rcv.dequeue(time) {
+                sink_log
+                    .lock()
+                    .unwrap()
+                    .push((node, element.time.time(), element.data));
+                sink_deliveries.record();
+            }
+        });
+        ctx.add_child(sink);
+
+        add_port(
+            &mut ctx,
+            node,
+            &mut switch,
+            Port {
+                id: ports.local,
+                input: Some(inject_rcv),
+                output: Some(eject_snd),
+            },
+        );
+        switches.insert((node.x, node.y), switch);
+    }
+
+    for node in nodes {
+        let bounds = Coord2D::new(width, height);
+        for direction in Dir2D::ALL {
+            let neighbor = match wrap_around {
+                true => node.wrapping_step(direction, bounds),
+                false => match node.step(direction, bounds) {
+                    Some(neighbor) => neighbor,
+                    None => continue,
+                },
+            };
+            let (out_port, in_port) = (ports.port(direction), ports.port(direction.opposite()));
+            let (out_snd, out_rcv) = ctx.unbounded();
+            let (in_snd, in_rcv) = ctx.unbounded();
+            add_port(
+                &mut ctx,
+                node,
+                switches.get_mut(&(node.x, node.y)).unwrap(),
+                Port {
+                    id: out_port,
+                    input: None,
+                    output: Some(out_snd),
+                },
+            );
+            add_port(
+                &mut ctx,
+                neighbor,
+                switches.get_mut(&(neighbor.x, neighbor.y)).unwrap(),
+                Port {
+                    id: in_port,
+                    input: Some(in_rcv),
+                    output: None,
+                },
+            );
+            link(&mut ctx, out_rcv, in_snd, deliveries.clone());
+        }
+    }
+    switches
+        .into_values()
+        .for_each(|switch| ctx.add_child(switch));
+
+    ctx.initialize(Default::default())
+        .unwrap()
+        .run(Default::default());
+
+    let mut arrivals = arrivals.lock().unwrap().clone();
+    arrivals.sort_by_key(|(node, time, _)| (*time, node.y, node.x));
+    arrivals
+}
@@ -0,0 +1,266 @@
+use std::hash::Hash;
+
+use fxhash::{FxHashMap, FxHashSet};
+
+/// A link between a switch and one of its parents, given as the port on either end of it.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct TreeLink {
+    pub child: usize,
+    /// The port on the child which leads up to the parent.
+    pub up_port: usize,
+    pub parent: usize,
+    /// The port on the parent which leads down to the child.
+    pub down_port: usize,
+}
+
+/// Describes a tree of switches, numbered by their IDs, as the links between each one and its parents. Switches may
+/// have more than one parent, as they do in fat-trees, as long as no switch ends up as its own ancestor.
+#[derive(Clone, Debug, Default)]
+pub struct TreeTopo {
+    links: Vec<TreeLink>,
+}
+
+impl TreeTopo {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a link from `up_port` on `child` to `down_port` on `parent`.
+    pub fn with_link(
+        mut self,
+        child: usize,
+        up_port: usize,
+        parent: usize,
+        down_port: usize,
+    ) -> Self {
+        assert!(
+            !self.ancestors(parent).contains(&child),
+            "Links must not make a switch its own ancestor!"
+        );
+        self.links.push(TreeLink {
+            child,
+            up_port,
+            parent,
+            down_port,
+        });
+        self
+    }
+
+    pub fn links(&self) -> &[TreeLink] {
+        &self.links
+    }
+
+    /// The switch itself, and every switch that can be reached from it by only going up.
+    fn ancestors(&self, switch: usize) -> FxHashSet<usize> {
+        self.closure(switch, |link| (link.child, link.parent))
+    }
+
+    /// The switch itself, and every switch that can be reached from it by only going down.
+    fn descendants(&self, switch: usize) -> FxHashSet<usize> {
+        self.closure(switch, |link| (link.parent, link.child))
+    }
+
+    fn closure(
+        &self,
+        switch: usize,
+        step: impl Fn(&TreeLink) -> (usize, usize),
+    ) -> FxHashSet<usize> {
+        let mut reached = FxHashSet::from_iter([switch]);
+        let mut frontier = vec![switch];
+        while let Some(current) = frontier.pop() {
+            for link in self.links.iter() {
+                let (from, to) = step(link);
+                if from == current && reached.insert(to) {
+                    frontier.push(to);
+                }
+            }
+        }
+        reached
+    }
+}
+
+/// Generates a routing table for every switch in `tree` which implements up*/down* routing: packets go up until they
+/// reach a switch with their endpoint below it, and then only go down. Since no packet ever turns from a down link
+/// back onto an up link, there can be no cycles of packets waiting on each other, and so no deadlocks.
+///
+/// Each endpoint is given as its location, the switch it hangs off of, and the port on that switch which leads to it.
+/// When there is more than one way up or down, as in a fat-tree, the table picks one of them by the endpoint's index,
+/// so that the destinations are spread evenly over the links. A switch with no way to an endpoint has no entry for it.
+pub fn updown_tables<LT: Clone + Eq + Hash>(
+    tree: &TreeTopo,
+    endpoints: &[(LT, usize, usize)],
+) -> FxHashMap<usize, FxHashMap<LT, FxHashSet<usize>>> {
+    let switches: FxHashSet<_> = tree
+        .links
+        .iter()
+        .flat_map(|link| [link.child, link.parent])
+        .chain(endpoints.iter().map(|(_, switch, _)| *switch))
+        .collect();
+    let descendants: FxHashMap<_, _> = switches
+        .iter()
+        .map(|switch| (*switch, tree.descendants(*switch)))
+        .collect();
+    // Whether a packet at `switch` can get to `target` by going up and then down.
+    let reaches = |switch: usize, target: usize| {
+        tree.ancestors(switch)
+            .iter()
+            .any(|ancestor| descendants[ancestor].contains(&target))
+    };
+
+    let mut tables: FxHashMap<_, _> = switches
+        .iter()
+        .map(|switch| (*switch, FxHashMap::default()))
+        .collect();
+    for (switch, table) in tables.iter_mut() {
+        for (index, (location, target, port)) in endpoints.iter().enumerate() {
+            let mut candidates: Vec<_> = if target == switch {
+                vec![*port]
+            } else if descendants[switch].contains(target) {
+                tree.links
+                    .iter()
+                    .filter(|link| {
+                        link.parent == *switch && descendants[&link.child].contains(target)
+                    })
+                    .map(|link| link.down_port)
+                    .collect()
+            } else {
+                tree.links
+                    .iter()
+                    .filter(|link| link.child == *switch && reaches(link.parent, *target))
+                    .map(|link| link.up_port)
+                    .collect()
+            };
+            if candidates.is_empty() {
+                continue;
+            }
+            candidates.sort_unstable();
+            candidates.dedup();
+            let chosen = candidates[index % candidates.len()];
+            table.insert(location.clone(), FxHashSet::from_iter([chosen]));
+        }
+    }
+    tables
+}
+
+#[cfg(test)]
+mod tests {
+    use dam::context_tools::*;
+    use fxhash::FxHashSet;
+
+    use crate::switches::{
+        routing::Packet,
+        testing::{run_network, stream},
+        SimpleSwitch,
+    };
+
+    use super::{updown_tables, TreeTopo};
+
+    // Ports from here up lead to parents, and everything below leads down, to children or endpoints.
+    const UP: usize = 10;
+    const ROOTS: [usize; 2] = [0, 1];
+    const LEAVES: [usize; 2] = [2, 3];
+    const ENDPOINTS_PER_LEAF: usize = 2;
+    const ROUNDS: u16 = 4;
+
+    /// Remembers every port it was sent out of on its way through the network.
+    #[derive(Clone, Debug, Default)]
+    struct TracedPacket {
+        location: u8,
+        payload: u16,
+        ports: Vec<usize>,
+    }
+
+    impl Packet<u8> for TracedPacket {
+        fn destination(&self) -> u8 {
+            self.location
+        }
+    }
+
+    impl DAMType for TracedPacket {
+        fn dam_size(&self) -> usize {
+            self.location.dam_size()
+                + self.payload.dam_size()
+                + self.ports.iter().map(|port| port.dam_size()).sum::<usize>()
+        }
+    }
+
+    /// A two level fat-tree, where every leaf has a link up to every root.
+    fn fat_tree() -> TreeTopo {
+        let mut tree = TreeTopo::new();
+        for (down_port, leaf) in LEAVES.into_iter().enumerate() {
+            for (up, root) in ROOTS.into_iter().enumerate() {
+                tree = tree.with_link(leaf, UP + up, root, down_port);
+            }
+        }
+        tree
+    }
+
+    /// Endpoint `i` hangs off of port `i % ENDPOINTS_PER_LEAF` of leaf `i / ENDPOINTS_PER_LEAF`.
+    fn endpoints() -> Vec<(u8, usize, usize)> {
+        (0..LEAVES.len() * ENDPOINTS_PER_LEAF)
+            .map(|i| {
+                let leaf = LEAVES[i / ENDPOINTS_PER_LEAF];
+                (i as u8, leaf, i % ENDPOINTS_PER_LEAF)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn updown_tables_test() {
+        let tables = updown_tables(&fat_tree(), &endpoints());
+        // Endpoints on the same leaf never go up, and the rest are spread over both roots.
+        assert_eq!(tables[&2][&1], FxHashSet::from_iter([1]));
+        assert_eq!(tables[&2][&2], FxHashSet::from_iter([UP]));
+        assert_eq!(tables[&2][&3], FxHashSet::from_iter([UP + 1]));
+        // The roots have everything below them.
+        assert_eq!(tables[&1][&0], FxHashSet::from_iter([0]));
+        assert_eq!(tables[&1][&3], FxHashSet::from_iter([1]));
+    }
+
+    #[test]
+    fn updown_all_to_all_test() {
+        let endpoints = endpoints();
+        let count = endpoints.len() as u16;
+        let total = ROUNDS as usize * endpoints.len() * (endpoints.len() - 1);
+        let mut tables = updown_tables(&fat_tree(), &endpoints);
+
+        let switches = (0..ROOTS.len() + LEAVES.len())
+            .map(|id| {
+                SimpleSwitch::new(tables.remove(&id).unwrap(), 1)
+                    .with_on_forward(|packet: &mut TracedPacket, ports| packet.ports.extend(ports))
+            })
+            .collect();
+        let links: Vec<_> = fat_tree()
+            .links()
+            .iter()
+            .map(|link| (link.child, link.up_port, link.parent, link.down_port))
+            .collect();
+        let traffic = |source: usize| {
+            let packets = (0..ROUNDS * count)
+                .filter(|i| (i % count) as usize != source)
+                .map(|i| TracedPacket {
+                    location: (i % count) as u8,
+                    payload: source as u16 * 100 + i,
+                    ports: vec![],
+                });
+            stream(packets)
+        };
+        let attachments: Vec<_> = endpoints
+            .iter()
+            .map(|(_, leaf, port)| (*leaf, *port))
+            .collect();
+        let arrivals = run_network(switches, &links, &attachments, traffic, total);
+
+        assert_eq!(arrivals.len(), total);
+        for (endpoint, _, packet) in arrivals.iter() {
+            assert_eq!(*endpoint as u8, packet.location);
+            // Up links first, then down links, and never back up again.
+            let mut ports = packet.ports.iter().skip_while(|port| **port >= UP);
+            assert!(
+                ports.all(|port| *port < UP),
+                "{:?} went down and then up",
+                packet
+            );
+        }
+    }
+}
@@ -0,0 +1,345 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    hash::Hash,
+};
+
+use dam::{
+    channel::utils::{EventTime, Peekable},
+    context_tools::*,
+    structures::SyncSendMarker,
+};
+
+use super::{
+    policy::VcPolicy,
+    routing::{Packet, VcPacket},
+};
+
+/// A physical port of a [`VcSwitch`], with one channel per virtual channel in each direction.
+/// Either half may be left empty, but otherwise needs exactly one channel per VC.
+pub struct VcPort<ElementType: Clone> {
+    pub id: usize,
+    pub inputs: Vec<Receiver<ElementType>>,
+    pub outputs: Vec<Sender<ElementType>>,
+}
+
+// Identifies a single VC of a physical port, as (port, VC).
+type VcId = (usize, usize);
+
+/// A virtual channel router. Each input VC has a buffer of its own, so that a VC which can't make progress doesn't
+/// hold up the others sharing its physical port.
+///
+/// Every cycle, the head of each input VC first competes for the output VC which its policy picked, and holds onto
+/// it once won. Input VCs holding an output VC then compete for their physical output, which moves one packet per
+/// cycle, as does each physical input. Both stages are round robin.
+#[context_macro]
+pub struct VcSwitch<T, LT, PolicyType>
+where
+    T: DAMType,
+{
+    in_map: BTreeMap<VcId, Receiver<T>>,
+    out_map: BTreeMap<VcId, Sender<T>>,
+    buffers: BTreeMap<VcId, VecDeque<T>>,
+
+    // Where the head of each input VC is headed.
+    routes: fxhash::FxHashMap<VcId, VcId>,
+    // Which input VC each output VC has been allocated to.
+    owners: fxhash::FxHashMap<VcId, VcId>,
+    // The last input VC to win each output VC, and each physical output.
+    last_allocated: fxhash::FxHashMap<VcId, VcId>,
+    last_granted: fxhash::FxHashMap<usize, VcId>,
+
+    policy: PolicyType,
+    latency: u64,
+    num_vcs: usize,
+    vc_depth: usize,
+
+    _marker: SyncSendMarker<LT>,
+}
+
+impl<T: DAMType, LT, PolicyType> Context for VcSwitch<T, LT, PolicyType>
+where
+    T: Packet<LT> + VcPacket,
+    LT: Eq + Hash,
+    PolicyType: VcPolicy<LT> + Sync + Send,
+{
+    fn run(&mut self) {
+        while self.wait_for_work() {
+            self.fill_buffers();
+            self.allocate_vcs();
+            self.allocate_switch();
+            self.time.incr_cycles(1);
+        }
+    }
+}
+
+impl<T: DAMType, LT, PolicyType> VcSwitch<T, LT, PolicyType>
+where
+    T: Packet<LT> + VcPacket,
+    LT: Eq + Hash,
+    PolicyType: VcPolicy<LT> + Sync + Send,
+{
+    /// Moves one ready packet from each input VC into its buffer.
+    fn fill_buffers(&mut self) {
+        let tick = self.time.tick();
+        let arrivals: Vec<_> = self
+            .in_map
+            .iter()
+            .filter(|(id, chan)| {
+                // Packets without room stay in the channel, which backs up the sender on that VC alone.
+                self.buffers.get(id).map_or(0, VecDeque::len) < self.vc_depth
+                    && matches!(chan.next_event(), EventTime::Ready(t) if t <= tick)
+            })
+            .map(|(id, _)| *id)
+            .collect();
+
+        for id in arrivals {
+            let ChannelElement { time: _, data } =
+                self.in_map.get(&id).unwrap().dequeue(&self.time).unwrap();
+            self.buffers.entry(id).or_default().push_back(data);
+        }
+    }
+
+    /// Routes each new head, and hands every free output VC to one of the input VCs asking for it.
+    fn allocate_vcs(&mut self) {
+        let mut requests = BTreeMap::<VcId, Vec<VcId>>::new();
+        for (input, buffer) in &self.buffers {
+            let Some(head) = buffer.front() else {
+                continue;
+            };
+            let route = *self
+                .routes
+                .entry(*input)
+                .or_insert_with(|| self.policy.route_vc(&head.destination(), input.1));
+            if !self.owners.contains_key(&route) {
+                requests.entry(route).or_default().push(*input);
+            }
+        }
+
+        for (output, requesters) in requests {
+            let winner = round_robin(requesters, self.last_allocated.get(&output))[0];
+            self.owners.insert(output, winner);
+            self.last_allocated.insert(output, winner);
+        }
+    }
+}
+
+impl<T: DAMType, LT, PolicyType> VcSwitch<T, LT, PolicyType>
+where
+    Self: Context,
+    T: VcPacket,
+{
+    /// Creates a switch with `num_vcs` virtual channels per port, each of which buffers up to `vc_depth` packets at
+    /// the input. Packets go out `latency` cycles after winning their output.
+    pub fn new(policy: PolicyType, latency: u64, num_vcs: usize, vc_depth: usize) -> Self {
+        assert!(num_vcs > 0, "Ports need at least one virtual channel!");
+        assert!(vc_depth > 0, "Buffers must hold at least one packet!");
+        Self {
+            in_map: Default::default(),
+            out_map: Default::default(),
+            buffers: Default::default(),
+            routes: Default::default(),
+            owners: Default::default(),
+            last_allocated: Default::default(),
+            last_granted: Default::default(),
+            policy,
+            latency,
+            num_vcs,
+            vc_depth,
+            _marker: Default::default(),
+            context_info: Default::default(),
+        }
+    }
+
+    /// Registers a physical port with the switch. Either half of the port may be left empty.
+    pub fn add_port(&mut self, port: VcPort<T>) {
+        let id = port.id;
+        assert!(
+            port.inputs.is_empty() || port.inputs.len() == self.num_vcs,
+            "Ports need one input channel per VC!"
+        );
+        assert!(
+            port.outputs.is_empty() || port.outputs.len() == self.num_vcs,
+            "Ports need one output channel per VC!"
+        );
+        for (vc, rcv) in port.inputs.into_iter().enumerate() {
+            rcv.attach_receiver(self);
+            assert!(
+                self.in_map.insert((id, vc), rcv).is_none(),
+                "Input port was already occupied!"
+            );
+        }
+        for (vc, snd) in port.outputs.into_iter().enumerate() {
+            snd.attach_sender(self);
+            assert!(
+                self.out_map.insert((id, vc), snd).is_none(),
+                "Output port was already occupied!"
+            );
+        }
+    }
+
+    /// Sends at most one packet through each physical output, and out of each physical input.
+    fn allocate_switch(&mut self) {
+        let mut candidates = BTreeMap::<usize, Vec<VcId>>::new();
+        for (output, input) in &self.owners {
+            candidates.entry(output.0).or_default().push(*input);
+        }
+
+        let mut used_inputs = fxhash::FxHashSet::default();
+        for (output_port, inputs) in candidates {
+            // Whoever can't go because their output VC is full steps aside for the next in line.
+            for input in round_robin(inputs, self.last_granted.get(&output_port)) {
+                if used_inputs.contains(&input.0) {
+                    continue;
+                }
+                let output = self.routes[&input];
+                let data = self.buffers[&input].front().unwrap().clone();
+                let result = self.out_map.get(&output).unwrap().try_enqueue(
+                    &self.time,
+                    ChannelElement {
+                        time: self.time.tick() + self.latency,
+                        data: data.with_vc(output.1),
+                    },
+                );
+                if let Err(dam::channel::EnqueueError::Full) = result {
+                    continue;
+                }
+
+                self.buffers.get_mut(&input).unwrap().pop_front();
+                self.routes.remove(&input);
+                self.owners.remove(&output);
+                self.last_granted.insert(output_port, input);
+                used_inputs.insert(input.0);
+                break;
+            }
+        }
+    }
+
+    /// Waits until there is something to do, returning false once every input has closed and every buffer has
+    /// drained.
+    fn wait_for_work(&mut self) -> bool {
+        loop {
+            self.in_map
+                .retain(|_, chan| !matches!(chan.peek(), dam::channel::PeekResult::Closed));
+            if self.buffers.values().any(|buffer| !buffer.is_empty()) {
+                return true;
+            }
+            if self.in_map.is_empty() {
+                return false;
+            }
+
+            match self
+                .in_map
+                .values()
+                .map(|chan| chan.next_event())
+                .min()
+                .unwrap()
+            {
+                EventTime::Ready(t) => {
+                    self.time.advance(t);
+                    return true;
+                }
+                EventTime::Nothing(t) => self.time.advance(t + 1),
+                // Something closed since we last checked, which the next pass takes care of.
+                EventTime::Closed => {}
+            }
+        }
+    }
+}
+
+/// Orders the contenders by ID, starting from the one after the last winner.
+fn round_robin(mut contenders: Vec<VcId>, last: Option<&VcId>) -> Vec<VcId> {
+    contenders.sort();
+    if let Some(last) = last {
+        let start = contenders.partition_point(|id| id <= last);
+        contenders.rotate_left(start);
+    }
+    contenders
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use dam::{context_tools::ChannelElement, simulation::ProgramBuilder, utility_contexts::*};
+
+    use crate::switches::routing::{SimplePacket, Vc};
+
+    use super::{VcPort, VcSwitch};
+
+    const NUM_PACKETS: u16 = 16;
+    const STALL: u64 = 200;
+
+    #[test]
+    fn independent_vcs_test() {
+        let mut ctx = ProgramBuilder::default();
+
+        // Both flows share physical port 0 on the way in and physical port 1 on the way out, on different VCs.
+        let policy = fxhash::FxHashMap::from_iter([(0u8, (1, 0)), (1u8, (1, 1))]);
+        let mut switch = VcSwitch::new(policy, 1, 2, 2);
+
+        let mut inputs = vec![];
+        for vc in 0..2 {
+            let (snd, rcv) = ctx.unbounded();
+            ctx.add_child(GeneratorContext::new(
+                move || {
+                    (0..NUM_PACKETS).map(move |payload| Vc {
+                        packet: SimplePacket {
+                            location: vc as u8,
+                            payload,
+                        },
+                        vc,
+                    })
+                },
+                snd,
+            ));
+            inputs.push(rcv);
+        }
+        switch.add_port(VcPort {
+            id: 0,
+            inputs,
+            outputs: vec![],
+        });
+
+        // VC 0's downstream doesn't drain anything for a long while, and has barely any room.
+        let arrivals = Arc::new(Mutex::new(vec![]));
+        let mut outputs = vec![];
+        for vc in 0..2 {
+            let (snd, rcv) = ctx.bounded::<Vc<SimplePacket<u8, u16>>>(1);
+            let mut sink = FunctionContext::new();
+            rcv.attach_receiver(&sink);
+            let sink_log = arrivals.clone();
+            sink.set_run(move |time| {
+                if vc == 0 {
+                    time.incr_cycles(STALL);
+                }
+                while let Ok(ChannelElement { time: _, data }) = rcv.dequeue(time) {
+                    sink_log.lock().unwrap().push((data, time.tick().time()));
+                }
+            });
+            ctx.add_child(sink);
+            outputs.push(snd);
+        }
+        switch.add_port(VcPort {
+            id: 1,
+            inputs: vec![],
+            outputs,
+        });
+        ctx.add_child(switch);
+
+        ctx.initialize(Default::default())
+            .unwrap()
+            .run(Default::default());
+
+        let arrivals = arrivals.lock().unwrap().clone();
+        let flow = |vc| {
+            arrivals
+                .iter()
+                .filter(move |(packet, _)| packet.vc == vc)
+                .map(|(_, time)| *time)
+        };
+        assert!(flow(0).all(|time| time >= STALL));
+        // VC 1 streams through at full rate while VC 0 is stuck.
+        assert_eq!(flow(1).count(), NUM_PACKETS as usize);
+        assert!(flow(1).all(|time| time < STALL / 2));
+    }
+}
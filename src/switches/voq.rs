@@ -0,0 +1,286 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    hash::Hash,
+};
+
+use dam::{
+    channel::utils::{EventTime, Peekable},
+    context_tools::*,
+    structures::SyncSendMarker,
+};
+
+use super::{
+    arbitration::IslipArbiter,
+    policy::Policy,
+    routing::{Packet, Port, PortMap, Switch},
+};
+
+/// The virtual output queues of a single input.
+struct InputQueues<T> {
+    // One queue per output, holding (packet ID, packet).
+    voqs: BTreeMap<usize, VecDeque<(u64, T)>>,
+    // How many copies of each packet still need to go out. A packet holds onto its buffer space until they all have.
+    copies_left: fxhash::FxHashMap<u64, usize>,
+    next_id: u64,
+}
+
+impl<T> Default for InputQueues<T> {
+    fn default() -> Self {
+        Self {
+            voqs: Default::default(),
+            copies_left: Default::default(),
+            next_id: 0,
+        }
+    }
+}
+
+/// A switch which sorts the packets from each input into a queue per output as they arrive, so that packets never
+/// wait behind others headed to a different output. Every cycle, the non-empty queues are matched to their outputs
+/// with iSLIP, so that each input and each output moves at most one packet.
+#[context_macro]
+pub struct VoqSwitch<T, LT, PolicyType>
+where
+    T: DAMType,
+{
+    ports: PortMap<T>,
+    queues: BTreeMap<usize, InputQueues<T>>,
+
+    policy: PolicyType,
+    latency: u64,
+    buffer_depth: usize,
+    arbiter: IslipArbiter,
+
+    _marker: SyncSendMarker<LT>,
+}
+
+impl<T: DAMType, LT, PolicyType> Context for VoqSwitch<T, LT, PolicyType>
+where
+    T: Packet<LT>,
+    LT: Eq + Hash,
+    PolicyType: Policy<LT> + Sync + Send,
+{
+    fn run(&mut self) {
+        while self.wait_for_work() {
+            self.fill_queues();
+            self.forward();
+            self.time.incr_cycles(1);
+        }
+    }
+}
+
+impl<T: DAMType, LT, PolicyType> Switch<T> for VoqSwitch<T, LT, PolicyType>
+where
+    Self: Context,
+{
+    fn add_port(&mut self, port: Port<T>) {
+        port.attach(self);
+        self.ports.insert(port);
+    }
+}
+
+impl<T: DAMType, LT, PolicyType> VoqSwitch<T, LT, PolicyType>
+where
+    T: Packet<LT>,
+    LT: Eq + Hash,
+    PolicyType: Policy<LT> + Sync + Send,
+{
+    /// Moves one ready packet from each input into the queues of the outputs it is routed to.
+    fn fill_queues(&mut self) {
+        let tick = self.time.tick();
+        let arrivals: Vec<_> = self
+            .ports
+            .inputs
+            .iter()
+            .filter(|(id, chan)| {
+                // Packets without room stay in the channel, which backs up the sender.
+                self.queues
+                    .get(id)
+                    .map_or(0, |queues| queues.copies_left.len())
+                    < self.buffer_depth
+                    && matches!(chan.next_event(), EventTime::Ready(t) if t <= tick)
+            })
+            .map(|(id, _)| *id)
+            .collect();
+
+        for id in arrivals {
+            let ChannelElement { time: _, data } = self
+                .ports
+                .inputs
+                .get(&id)
+                .unwrap()
+                .dequeue(&self.time)
+                .unwrap();
+            let targets = self.policy.route(&data.destination());
+            if targets.is_empty() {
+                continue;
+            }
+            let queues = self.queues.entry(id).or_default();
+            let packet_id = queues.next_id;
+            queues.next_id += 1;
+            queues.copies_left.insert(packet_id, targets.len());
+            for target in targets {
+                queues
+                    .voqs
+                    .entry(target)
+                    .or_default()
+                    .push_back((packet_id, data.clone()));
+            }
+        }
+    }
+}
+
+impl<T: DAMType, LT, PolicyType> VoqSwitch<T, LT, PolicyType>
+where
+    Self: Context,
+{
+    /// Creates a switch which buffers up to `buffer_depth` packets per input, and forwards each packet `latency`
+    /// cycles after it leaves its queue. A multicast packet takes up a single slot until all of its copies are out.
+    pub fn new(policy: PolicyType, latency: u64, buffer_depth: usize) -> Self {
+        assert!(buffer_depth > 0, "Buffers must hold at least one packet!");
+        Self {
+            ports: Default::default(),
+            queues: Default::default(),
+            policy,
+            latency,
+            buffer_depth,
+            arbiter: IslipArbiter::new(1),
+            _marker: Default::default(),
+            context_info: Default::default(),
+        }
+    }
+
+    /// Replaces the default single iteration iSLIP arbiter.
+    pub fn with_arbiter(mut self, arbiter: IslipArbiter) -> Self {
+        self.arbiter = arbiter;
+        self
+    }
+
+    fn forward(&mut self) {
+        let requests: BTreeMap<_, _> = self
+            .queues
+            .iter()
+            .map(|(input, queues)| {
+                let targets = queues
+                    .voqs
+                    .iter()
+                    .filter(|(_, voq)| !voq.is_empty())
+                    .map(|(output, _)| *output)
+                    .collect::<fxhash::FxHashSet<_>>();
+                (*input, targets)
+            })
+            .filter(|(_, targets)| !targets.is_empty())
+            .collect();
+
+        let mut all_queues = std::mem::take(&mut self.queues);
+        for (input, output) in self.arbiter.schedule(&requests) {
+            let queues = all_queues.get_mut(&input).unwrap();
+            let voq = queues.voqs.get_mut(&output).unwrap();
+            let (packet_id, data) = voq.front().unwrap().clone();
+            let result = self.ports.outputs.get(&output).unwrap().try_enqueue(
+                &self.time,
+                ChannelElement {
+                    time: self.time.tick() + self.latency,
+                    data,
+                },
+            );
+            // A full output keeps the packet at the front of its queue for another try next cycle.
+            if let Err(dam::channel::EnqueueError::Full) = result {
+                continue;
+            }
+            voq.pop_front();
+            let copies_left = queues.copies_left.get_mut(&packet_id).unwrap();
+            *copies_left -= 1;
+            if *copies_left == 0 {
+                queues.copies_left.remove(&packet_id);
+            }
+        }
+        self.queues = all_queues;
+    }
+
+    /// Waits until there is something to do, returning false once every input has closed and every queue has
+    /// drained.
+    fn wait_for_work(&mut self) -> bool {
+        self.queues
+            .values()
+            .any(|queues| !queues.copies_left.is_empty())
+            || self.ports.wait_for_input(&mut self.context_info.time)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dam::simulation::ProgramBuilder;
+    use fxhash::FxHashSet;
+
+    use crate::switches::{
+        routing::{Port, SimplePacket},
+        testing::{connect, direct_policy, run_switch, stream, TestPacket},
+        SimpleSwitch,
+    };
+
+    use super::VoqSwitch;
+
+    const NUM_PACKETS: u16 = 32;
+
+    /// Port 0 keeps output 1 busy, while port 1 sends one packet to output 1 followed by one to output 2.
+    /// Returns the arrival times of port 1's packets.
+    fn hol_arrivals(
+        add_switch: impl FnOnce(&mut ProgramBuilder, Vec<Port<TestPacket>>),
+    ) -> Vec<(u16, u64)> {
+        let sources =
+            [(0, vec![1u8; NUM_PACKETS as usize]), (1, vec![1, 2])].map(|(port, locations)| {
+                let packets = locations
+                    .into_iter()
+                    .zip(0..)
+                    .map(|(location, i)| SimplePacket {
+                        location,
+                        payload: port as u16 * 1000 + i,
+                    });
+                (port, stream(packets))
+            });
+        let mut arrivals: Vec<_> = run_switch(sources, 1..3, add_switch)
+            .into_iter()
+            .filter(|(_, _, packet)| packet.payload >= 1000)
+            .map(|(_, time, packet)| (packet.payload, time))
+            .collect();
+        arrivals.sort();
+        arrivals
+    }
+
+    #[test]
+    fn head_of_line_test() {
+        let simple = hol_arrivals(connect(SimpleSwitch::new(direct_policy(1..3), 1)));
+        let voq = hol_arrivals(connect(VoqSwitch::new(direct_policy(1..3), 1, 4)));
+
+        // Behind a blocked head, the packet for output 2 waits out the whole flood.
+        let (_, simple_second) = simple[1];
+        assert!(simple_second > NUM_PACKETS as u64);
+        // With its own queue, it goes out as soon as it arrives.
+        let (_, voq_second) = voq[1];
+        assert!(voq_second <= 4, "Arrived at {voq_second}");
+    }
+
+    #[test]
+    fn multicast_test() {
+        let packets = (0..NUM_PACKETS).map(|payload| SimplePacket {
+            location: 0u8,
+            payload,
+        });
+        // Every packet goes to both outputs, with room for only one of them at a time.
+        let policy = fxhash::FxHashMap::from_iter([(0u8, FxHashSet::from_iter([1usize, 2]))]);
+        let arrivals = run_switch(
+            [(0, stream(packets))],
+            1..3,
+            connect(VoqSwitch::new(policy, 1, 1)),
+        );
+
+        for output in 1..3 {
+            let payloads: Vec<_> = arrivals
+                .iter()
+                .filter(|(port, _, _)| *port == output)
+                .map(|(_, _, packet)| packet.payload)
+                .collect();
+            assert_eq!(payloads, Vec::from_iter(0..NUM_PACKETS));
+        }
+    }
+}
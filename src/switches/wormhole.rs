@@ -0,0 +1,319 @@
+use std::hash::Hash;
+
+use dam::context_tools::*;
+
+use super::{
+    policy::Policy,
+    routing::{Flit, Port, PortMap, SimplePacket, Switch},
+};
+
+/// A flit-level switch. A head flit reserves the path from its input to the output it is routed to, the body and
+/// tail flits behind it follow along that path one per cycle, and the tail flit releases it again. Heads headed
+/// to a reserved output wait until it has been released, with lower port IDs winning ties.
+#[context_macro]
+pub struct WormholeSwitch<LT, PT, PolicyType>
+where
+    LT: DAMType,
+    PT: DAMType,
+{
+    ports: PortMap<Flit<LT, PT>>,
+
+    // Where each waiting head flit is headed.
+    routes: fxhash::FxHashMap<usize, usize>,
+    // The output which each input currently has reserved, and the other way around.
+    paths: fxhash::FxHashMap<usize, usize>,
+    owners: fxhash::FxHashMap<usize, usize>,
+
+    policy: PolicyType,
+    latency: u64,
+}
+
+impl<LT: DAMType, PT: DAMType, PolicyType> Context for WormholeSwitch<LT, PT, PolicyType>
+where
+    LT: Eq + Hash,
+    PolicyType: Policy<LT> + Sync + Send,
+{
+    fn run(&mut self) {
+        while self.ports.wait_for_input(&mut self.context_info.time) {
+            let ready = self.ports.ready(self.time.tick());
+            let mut used_outputs = fxhash::FxHashSet::default();
+            for input in ready {
+                let flit = match self.ports.inputs.get(&input).unwrap().peek() {
+                    dam::channel::PeekResult::Something(ChannelElement { time: _, data }) => data,
+                    _ => panic!("Port {:?} was supposed to be ready", input),
+                };
+                let output = match (self.paths.get(&input), &flit) {
+                    (Some(output), Flit::Body(_) | Flit::Tail(_)) => *output,
+                    (None, Flit::Head(destination)) => {
+                        let output = *self.routes.entry(input).or_insert_with(|| {
+                            let targets = self.policy.route(destination);
+                            assert_eq!(targets.len(), 1, "Wormhole switches can't multicast!");
+                            targets.into_iter().next().unwrap()
+                        });
+                        if self.owners.contains_key(&output) {
+                            // Someone else's packet is still streaming through.
+                            continue;
+                        }
+                        output
+                    }
+                    (Some(_), Flit::Head(_)) => {
+                        panic!(
+                            "Port {:?} started a new packet before finishing the last one",
+                            input
+                        )
+                    }
+                    (None, _) => panic!("Port {:?} sent a flit without a head", input),
+                };
+                if used_outputs.contains(&output) {
+                    continue;
+                }
+
+                let result = self.ports.outputs.get(&output).unwrap().try_enqueue(
+                    &self.time,
+                    ChannelElement {
+                        time: self.time.tick() + self.latency,
+                        data: flit.clone(),
+                    },
+                );
+                if let Err(dam::channel::EnqueueError::Full) = result {
+                    continue;
+                }
+                let _ = self.ports.inputs.get(&input).unwrap().dequeue(&self.time);
+                used_outputs.insert(output);
+                match flit {
+                    Flit::Head(_) => {
+                        self.routes.remove(&input);
+                        self.paths.insert(input, output);
+                        self.owners.insert(output, input);
+                    }
+                    Flit::Body(_) => {}
+                    Flit::Tail(_) => {
+                        self.paths.remove(&input);
+                        self.owners.remove(&output);
+                    }
+                }
+            }
+            self.time.incr_cycles(1);
+        }
+    }
+}
+
+impl<LT: DAMType, PT: DAMType, PolicyType> Switch<Flit<LT, PT>>
+    for WormholeSwitch<LT, PT, PolicyType>
+where
+    Self: Context,
+{
+    fn add_port(&mut self, port: Port<Flit<LT, PT>>) {
+        port.attach(self);
+        self.ports.insert(port);
+    }
+}
+
+impl<LT: DAMType, PT: DAMType, PolicyType> WormholeSwitch<LT, PT, PolicyType>
+where
+    Self: Context,
+{
+    /// Creates a switch which forwards each flit `latency` cycles after it moves through the switch.
+    pub fn new(policy: PolicyType, latency: u64) -> Self {
+        Self {
+            ports: Default::default(),
+            routes: Default::default(),
+            paths: Default::default(),
+            owners: Default::default(),
+            policy,
+            latency,
+            context_info: Default::default(),
+        }
+    }
+}
+
+/// Breaks each packet into `flits_per_packet` flits: a head, then body flits, then a tail. Every flit after the
+/// head carries a copy of the payload. Sends one flit per cycle.
+#[context_macro]
+pub struct Packetizer<LT: DAMType, PT: DAMType> {
+    input: Receiver<SimplePacket<LT, PT>>,
+    output: Sender<Flit<LT, PT>>,
+    flits_per_packet: usize,
+}
+
+impl<LT: DAMType, PT: DAMType> Packetizer<LT, PT>
+where
+    Self: Context,
+{
+    pub fn new(
+        input: Receiver<SimplePacket<LT, PT>>,
+        output: Sender<Flit<LT, PT>>,
+        flits_per_packet: usize,
+    ) -> Self {
+        assert!(
+            flits_per_packet >= 2,
+            "Packets need at least a head and a tail flit!"
+        );
+        let packetizer = Self {
+            input,
+            output,
+            flits_per_packet,
+            context_info: Default::default(),
+        };
+        packetizer.input.attach_receiver(&packetizer);
+        packetizer.output.attach_sender(&packetizer);
+        packetizer
+    }
+}
+
+impl<LT: DAMType, PT: DAMType> Context for Packetizer<LT, PT> {
+    fn run(&mut self) {
+        while let Ok(ChannelElement { time: _, data }) = self.input.dequeue(&self.time) {
+            let body = (0..self.flits_per_packet - 2).map(|_| Flit::Body(data.payload.clone()));
+            let flits = std::iter::once(Flit::Head(data.location.clone()))
+                .chain(body)
+                .chain(std::iter::once(Flit::Tail(data.payload.clone())));
+            for flit in flits {
+                self.output
+                    .enqueue(
+                        &self.time,
+                        ChannelElement {
+                            time: self.time.tick() + 1,
+                            data: flit,
+                        },
+                    )
+                    .unwrap();
+                self.time.incr_cycles(1);
+            }
+        }
+    }
+}
+
+/// Puts packets back together from the flits made by a [`Packetizer`], sending each one on once its tail arrives.
+#[context_macro]
+pub struct Depacketizer<LT: DAMType, PT: DAMType> {
+    input: Receiver<Flit<LT, PT>>,
+    output: Sender<SimplePacket<LT, PT>>,
+}
+
+impl<LT: DAMType, PT: DAMType> Depacketizer<LT, PT>
+where
+    Self: Context,
+{
+    pub fn new(input: Receiver<Flit<LT, PT>>, output: Sender<SimplePacket<LT, PT>>) -> Self {
+        let depacketizer = Self {
+            input,
+            output,
+            context_info: Default::default(),
+        };
+        depacketizer.input.attach_receiver(&depacketizer);
+        depacketizer.output.attach_sender(&depacketizer);
+        depacketizer
+    }
+}
+
+impl<LT: DAMType, PT: DAMType> Context for Depacketizer<LT, PT> {
+    fn run(&mut self) {
+        let mut destination = None;
+        while let Ok(ChannelElement { time: _, data }) = self.input.dequeue(&self.time) {
+            match data {
+                Flit::Head(location) => {
+                    assert!(
+                        destination.replace(location).is_none(),
+                        "Got a head flit in the middle of a packet!"
+                    );
+                }
+                Flit::Body(_) => assert!(destination.is_some(), "Got a body flit without a head!"),
+                Flit::Tail(payload) => {
+                    let location = destination.take().expect("Got a tail flit without a head!");
+                    self.output
+                        .enqueue(
+                            &self.time,
+                            ChannelElement {
+                                time: self.time.tick() + 1,
+                                data: SimplePacket { location, payload },
+                            },
+                        )
+                        .unwrap();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::switches::{
+        routing::{Flit, Port, SimplePacket, Switch},
+        testing::{connect, direct_policy, run_switch, stream},
+    };
+
+    use super::{Depacketizer, Packetizer, WormholeSwitch};
+
+    const NUM_PACKETS: u16 = 32;
+
+    #[test]
+    fn reassembly_test() {
+        // Two flows of long packets share output 2, and must come out the other side whole.
+        let sources = (0..2).map(|port| {
+            let packets = (0..NUM_PACKETS).map(move |i| SimplePacket {
+                location: 2u8,
+                payload: port as u16 * 1000 + i,
+            });
+            (port, stream(packets))
+        });
+        let received = run_switch(sources, [2], |ctx, ports| {
+            let mut switch = WormholeSwitch::new(direct_policy([2]), 1);
+            for port in ports {
+                let input = port.input.map(|rcv| {
+                    let (flit_snd, flit_rcv) = ctx.unbounded();
+                    ctx.add_child(Packetizer::new(rcv, flit_snd, 5));
+                    flit_rcv
+                });
+                let output = port.output.map(|snd| {
+                    let (flit_snd, flit_rcv) = ctx.unbounded();
+                    ctx.add_child(Depacketizer::new(flit_rcv, snd));
+                    flit_snd
+                });
+                switch.add_port(Port {
+                    id: port.id,
+                    input,
+                    output,
+                });
+            }
+            ctx.add_child(switch);
+        });
+
+        for port in 0..2 {
+            let from_port: Vec<_> = received
+                .iter()
+                .filter(|(_, _, packet)| packet.payload / 1000 == port)
+                .map(|(_, _, packet)| (packet.location, packet.payload % 1000))
+                .collect();
+            assert_eq!(from_port, Vec::from_iter((0..NUM_PACKETS).map(|i| (2, i))));
+        }
+    }
+
+    #[test]
+    fn long_packet_blocks_test() {
+        const LONG_PACKET: usize = 12;
+
+        // Port 0 sends one long packet, and port 1 a short one at the same time.
+        let sources = [(0u16, LONG_PACKET), (1, 2)].map(|(port, length)| {
+            let flits = std::iter::once(Flit::Head(2u8))
+                .chain((0..length - 2).map(move |_| Flit::Body(port)))
+                .chain(std::iter::once(Flit::Tail(port)));
+            (port as usize, stream(flits))
+        });
+        let received = run_switch(
+            sources,
+            [2],
+            connect(WormholeSwitch::new(direct_policy([2]), 1)),
+        );
+
+        assert_eq!(received.len(), LONG_PACKET + 2);
+        // The long packet goes through back to back, and the short one's head waits exactly as long as it takes.
+        let (long, short) = received.split_at(LONG_PACKET);
+        assert!(long[1..]
+            .iter()
+            .all(|(_, _, flit)| matches!(flit, Flit::Body(0) | Flit::Tail(0))));
+        assert_eq!(short[0].2, Flit::Head(2));
+        assert_eq!(short[1].2, Flit::Tail(1));
+        assert_eq!(short[0].1 - long[0].1, LONG_PACKET as u64);
+    }
+}
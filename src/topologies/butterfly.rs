@@ -0,0 +1,228 @@
+use std::sync::Arc;
+
+use dam::{context_tools::*, simulation::ProgramBuilder};
+use fxhash::FxHashSet;
+
+use crate::switches::{
+    routing::{Port, SimplePacket, Switch},
+    SimpleSwitch,
+};
+
+use super::{
+    link::{switch_ports, Link, NetworkTracker},
+    MeshConfig,
+};
+
+/// The senders packets go into a butterfly through, and the receivers they come out of, each in order.
+pub type ButterflyHandles<T> = (Vec<Sender<T>>, Vec<Receiver<T>>);
+
+/// Where line `line` of a stage leads on the next one: the perfect shuffle of `stages`-bit line numbers, which rotates
+/// them left by one bit.
+fn shuffle(line: usize, stages: u32) -> usize {
+    ((line << 1) | (line >> (stages - 1))) & ((1 << stages) - 1)
+}
+
+/// Builds an omega network between `n` inputs and `n` outputs, out of `log2(n)` stages of `n / 2` two-by-two
+/// [`SimpleSwitch`]es, with a perfect shuffle in front of every stage. Packets are addressed to the index of the
+/// output they are for, and stage `s` sends them up or down by bit `log2(n) - 1 - s` of it. Returns the senders to
+/// inject packets with, and the receivers they come out of, each in order.
+///
+/// A packet which doesn't have to wait arrives `log2(n) * router_latency + (log2(n) + 1) * link_latency` cycles after
+/// it was injected. Packets headed for the same output of a switch leave it one per cycle, so permutations other than
+/// the ones the network can route all at once are held up; [`butterfly_conflicts`] says where. The network shuts down
+/// once every input has closed and every packet has come out.
+///
+/// For a network of [`crate::switches::Butterfly2x2`] elements straight through unbounded channels, see
+/// [`crate::switches::build_butterfly`].
+pub fn build_butterfly<'a, P: DAMType + 'a>(
+    ctx: &mut ProgramBuilder<'a>,
+    n: usize,
+    cfg: MeshConfig,
+) -> ButterflyHandles<SimplePacket<usize, P>> {
+    assert!(
+        n >= 2 && n.is_power_of_two(),
+        "Butterflies need a power of two inputs!"
+    );
+    assert_eq!(
+        cfg.concentration, 1,
+        "Omega networks have a single input and output on every line!"
+    );
+    let stages = n.trailing_zeros();
+    let tracker = Arc::new(NetworkTracker::default());
+    let mut switches: Vec<Vec<_>> = (0..stages)
+        .map(|stage| {
+            let bit = stages - 1 - stage;
+            (0..n / 2)
+                .map(|_| {
+                    let policy =
+                        move |destination: &usize| FxHashSet::from_iter([(destination >> bit) & 1]);
+                    SimpleSwitch::new(policy, cfg.router_latency)
+                })
+                .collect()
+        })
+        .collect();
+
+    // Line `line` of a stage is port `line % 2` of switch `line / 2`, on the way in and on the way out.
+    let inputs = (0..n)
+        .map(|input| {
+            let (inject_snd, inject_rcv) = ctx.bounded(cfg.channel_depth);
+            let (line_snd, line_rcv) = ctx.bounded(cfg.channel_depth);
+            ctx.add_child(
+                Link::new(inject_rcv, line_snd, cfg.link_latency, tracker.clone()).injecting(),
+            );
+            let line = shuffle(input, stages);
+            switches[0][line / 2].add_port(Port {
+                id: line % 2,
+                input: Some(line_rcv),
+                output: None,
+            });
+            inject_snd
+        })
+        .collect();
+    for stage in 1..stages as usize {
+        for line in 0..n {
+            let next = shuffle(line, stages);
+            let (sending, receiving) = switch_ports(
+                ctx,
+                line % 2,
+                next % 2,
+                cfg.link_latency,
+                cfg.channel_depth,
+                &tracker,
+            );
+            switches[stage - 1][line / 2].add_port(sending);
+            switches[stage][next / 2].add_port(receiving);
+        }
+    }
+    let outputs = (0..n)
+        .map(|line| {
+            let (line_snd, line_rcv) = ctx.bounded(cfg.channel_depth);
+            let (eject_snd, eject_rcv) = ctx.bounded(cfg.channel_depth);
+            ctx.add_child(
+                Link::new(line_rcv, eject_snd, cfg.link_latency, tracker.clone()).ejecting(),
+            );
+            switches[stages as usize - 1][line / 2].add_port(Port {
+                id: line % 2,
+                input: None,
+                output: Some(line_snd),
+            });
+            eject_rcv
+        })
+        .collect();
+    switches
+        .into_iter()
+        .flatten()
+        .for_each(|switch| ctx.add_child(switch));
+    (inputs, outputs)
+}
+
+/// Works out where the packets of a permutation would run into each other in an omega network built by
+/// [`build_butterfly`], where input `i` sends to output `destinations[i]`. Returns the stage and output line of every
+/// switch output which more than one of them would want, in order; the permutation can only go through all at once if
+/// there are none.
+pub fn butterfly_conflicts(n: usize, destinations: &[usize]) -> Vec<(usize, usize)> {
+    assert!(
+        n >= 2 && n.is_power_of_two(),
+        "Butterflies need a power of two inputs!"
+    );
+    assert_eq!(destinations.len(), n, "Every input needs a destination!");
+    let stages = n.trailing_zeros();
+    let mut lines: Vec<_> = (0..n).map(|input| shuffle(input, stages)).collect();
+    let mut conflicts = vec![];
+    for stage in 0..stages {
+        let bit = stages - 1 - stage;
+        let mut taken = FxHashSet::default();
+        let mut contested = FxHashSet::default();
+        for (line, destination) in lines.iter_mut().zip(destinations) {
+            *line = (*line & !1) | ((destination >> bit) & 1);
+            if !taken.insert(*line) {
+                contested.insert(*line);
+            }
+        }
+        let mut contested: Vec<_> = contested.into_iter().collect();
+        contested.sort_unstable();
+        conflicts.extend(contested.into_iter().map(|line| (stage as usize, line)));
+        if stage + 1 < stages {
+            lines
+                .iter_mut()
+                .for_each(|line| *line = shuffle(*line, stages));
+        }
+    }
+    conflicts
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use dam::{simulation::ProgramBuilder, utility_contexts::*};
+
+    use crate::switches::routing::SimplePacket;
+
+    use super::{build_butterfly, butterfly_conflicts, MeshConfig};
+
+    const N: usize = 8;
+    const STAGES: u64 = N.trailing_zeros() as u64;
+
+    /// Has every input send one packet to `destinations[input]` on cycle 1, and returns how many of them took longer
+    /// than they would have with nothing in their way.
+    fn count_delayed(destinations: &[usize]) -> usize {
+        let cfg = MeshConfig::default();
+        let mut ctx = ProgramBuilder::default();
+        let (inputs, outputs) = build_butterfly(&mut ctx, N, cfg);
+        for (input, inject) in inputs.into_iter().enumerate() {
+            let packet = SimplePacket {
+                location: destinations[input],
+                payload: input as u32,
+            };
+            ctx.add_child(GeneratorContext::new(move || [packet].into_iter(), inject));
+        }
+        let arrivals = Arc::new(Mutex::new(vec![]));
+        for (output, eject) in outputs.into_iter().enumerate() {
+            let mut sink = FunctionContext::new();
+            eject.attach_receiver(&sink);
+            let log = arrivals.clone();
+            sink.set_run(move |time| {
+                while let Ok(element) = eject.dequeue(time) {
+                    log.lock()
+                        .unwrap()
+                        .push((output, element.time.time(), element.data));
+                }
+            });
+            ctx.add_child(sink);
+        }
+        ctx.initialize(Default::default())
+            .unwrap()
+            .run(Default::default());
+
+        let arrivals = arrivals.lock().unwrap();
+        assert_eq!(arrivals.len(), N);
+        let uncontended = 1 + STAGES * cfg.router_latency + (STAGES + 1) * cfg.link_latency;
+        for (output, time, packet) in arrivals.iter() {
+            assert_eq!(packet.location, *output);
+            assert_eq!(destinations[packet.payload as usize], *output);
+            assert!(*time >= uncontended);
+        }
+        arrivals
+            .iter()
+            .filter(|(_, time, _)| *time > uncontended)
+            .count()
+    }
+
+    #[test]
+    fn identity_butterfly_test() {
+        let destinations: Vec<_> = (0..N).collect();
+        assert!(butterfly_conflicts(N, &destinations).is_empty());
+        assert_eq!(count_delayed(&destinations), 0);
+    }
+
+    #[test]
+    fn bit_reversal_butterfly_test() {
+        let destinations: Vec<_> = (0..N)
+            .map(|input| input.reverse_bits() >> (usize::BITS - STAGES as u32))
+            .collect();
+        // Inputs 0 and 4 are shuffled onto the first switch, and both want to go up out of it.
+        assert!(butterfly_conflicts(N, &destinations).contains(&(0, 0)));
+        assert!(count_delayed(&destinations) > 0);
+    }
+}
@@ -0,0 +1,187 @@
+use std::{hash::Hash, sync::Arc};
+
+use dam::{context_tools::*, simulation::ProgramBuilder};
+use fxhash::FxHashSet;
+
+use crate::switches::{
+    routing::{SimplePacket, Switch},
+    SimpleSwitch,
+};
+
+use super::{
+    link::{node_port, Endpoint, NetworkTracker},
+    MeshConfig,
+};
+
+/// Builds a single [`SimpleSwitch`] with a port for each of `n` endpoints, where packets for destination `i` go out
+/// of port `i`. Returns each endpoint's injection and ejection channels, in order.
+///
+/// A packet which doesn't have to wait arrives `router_latency + 2 * link_latency` cycles after it was injected, and
+/// packets for the same destination leave one per cycle. The switch shuts down once every injection sender has
+/// closed.
+pub fn build_crossbar<'a, P: DAMType + 'a>(
+    ctx: &mut ProgramBuilder<'a>,
+    n: usize,
+    cfg: MeshConfig,
+) -> Vec<Endpoint<SimplePacket<usize, P>>> {
+    build_crossbar_with(ctx, n, cfg, |destination: &usize| *destination)
+}
+
+/// Builds a crossbar as [`build_crossbar`] does, for destinations which `port` maps onto the index of the endpoint
+/// they belong to.
+pub fn build_crossbar_with<'a, LT, P>(
+    ctx: &mut ProgramBuilder<'a>,
+    n: usize,
+    cfg: MeshConfig,
+    port: impl Fn(&LT) -> usize + Sync + Send + 'a,
+) -> Vec<Endpoint<SimplePacket<LT, P>>>
+where
+    LT: DAMType + Eq + Hash + 'a,
+    P: DAMType + 'a,
+{
+    assert!(n > 0, "Crossbars need at least one endpoint!");
+    assert_eq!(
+        cfg.concentration, 1,
+        "Crossbars give every endpoint a port of its own!"
+    );
+    let policy = move |destination: &LT| {
+        let port = port(destination);
+        assert!(
+            port < n,
+            "Destinations must map onto one of the {} endpoints!",
+            n
+        );
+        FxHashSet::from_iter([port])
+    };
+    let mut switch = SimpleSwitch::new(policy, cfg.router_latency);
+    let tracker = Arc::new(NetworkTracker::default());
+    let endpoints = (0..n)
+        .map(|id| {
+            let (port, endpoint) =
+                node_port(ctx, id, cfg.link_latency, cfg.channel_depth, &tracker);
+            switch.add_port(port);
+            endpoint
+        })
+        .collect();
+    ctx.add_child(switch);
+    endpoints
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use dam::{simulation::ProgramBuilder, utility_contexts::*};
+
+    use crate::switches::routing::{Coord2D, SimplePacket};
+
+    use super::{build_crossbar, build_crossbar_with, Endpoint, MeshConfig};
+
+    const NUM_ENDPOINTS: usize = 8;
+
+    /// Has each endpoint send `traffic(endpoint)`, one packet per cycle from cycle 1 on, and returns where and when
+    /// each packet arrived.
+    fn run<LT: Send + Sync + 'static>(
+        mut ctx: ProgramBuilder<'static>,
+        endpoints: Vec<Endpoint<SimplePacket<LT, u32>>>,
+        traffic: impl Fn(usize) -> Vec<SimplePacket<LT, u32>>,
+    ) -> Vec<(usize, u64, SimplePacket<LT, u32>)>
+    where
+        SimplePacket<LT, u32>: dam::types::DAMType,
+    {
+        let arrivals = Arc::new(Mutex::new(vec![]));
+        for (endpoint, (inject, eject)) in endpoints.into_iter().enumerate() {
+            let packets = traffic(endpoint);
+            ctx.add_child(GeneratorContext::new(move || packets.into_iter(), inject));
+
+            let mut sink = FunctionContext::new();
+            eject.attach_receiver(&sink);
+            let log = arrivals.clone();
+            sink.set_run(move |time| {
+                while let Ok(element) = eject.dequeue(time) {
+                    log.lock()
+                        .unwrap()
+                        .push((endpoint, element.time.time(), element.data));
+                }
+            });
+            ctx.add_child(sink);
+        }
+        ctx.initialize(Default::default())
+            .unwrap()
+            .run(Default::default());
+
+        let mut arrivals = std::mem::take(&mut *arrivals.lock().unwrap());
+        arrivals.sort_by_key(|(endpoint, time, _)| (*time, *endpoint));
+        arrivals
+    }
+
+    fn uncontended(cfg: MeshConfig) -> u64 {
+        cfg.router_latency + 2 * cfg.link_latency
+    }
+
+    #[test]
+    fn all_to_all_crossbar_test() {
+        let cfg = MeshConfig::default();
+        let mut ctx = ProgramBuilder::default();
+        let endpoints = build_crossbar(&mut ctx, NUM_ENDPOINTS, cfg);
+        // On cycle k + 1, every endpoint sends to the one k over, so that no two packets want the same output.
+        let arrivals = run(ctx, endpoints, |source| {
+            (0..NUM_ENDPOINTS)
+                .map(|k| SimplePacket {
+                    location: (source + k) % NUM_ENDPOINTS,
+                    payload: k as u32,
+                })
+                .collect()
+        });
+
+        assert_eq!(arrivals.len(), NUM_ENDPOINTS * NUM_ENDPOINTS);
+        for (endpoint, time, packet) in arrivals {
+            assert_eq!(packet.location, endpoint);
+            assert_eq!(time, packet.payload as u64 + 1 + uncontended(cfg));
+        }
+    }
+
+    #[test]
+    fn crossbar_contention_test() {
+        let cfg = MeshConfig::default();
+        let mut ctx = ProgramBuilder::default();
+        let endpoints = build_crossbar(&mut ctx, NUM_ENDPOINTS, cfg);
+        let arrivals = run(ctx, endpoints, |source| {
+            vec![SimplePacket {
+                location: 0,
+                payload: source as u32,
+            }]
+        });
+
+        // Everyone sends on cycle 1, and the destination takes one packet per cycle.
+        let times: Vec<_> = arrivals.iter().map(|(_, time, _)| *time).collect();
+        let first = 1 + uncontended(cfg);
+        assert_eq!(times, Vec::from_iter(first..first + NUM_ENDPOINTS as u64));
+        assert!(arrivals.iter().all(|(endpoint, _, _)| *endpoint == 0));
+    }
+
+    #[test]
+    fn mapped_crossbar_test() {
+        let bounds = Coord2D::new(4, 2);
+        let mut ctx = ProgramBuilder::default();
+        let endpoints = build_crossbar_with(
+            &mut ctx,
+            NUM_ENDPOINTS,
+            MeshConfig::default(),
+            move |node: &Coord2D| node.to_index(bounds),
+        );
+        let arrivals = run(ctx, endpoints, |source| {
+            // Everyone sends to the endpoint across from them.
+            vec![SimplePacket {
+                location: Coord2D::from_index(NUM_ENDPOINTS - 1 - source, bounds),
+                payload: source as u32,
+            }]
+        });
+
+        assert_eq!(arrivals.len(), NUM_ENDPOINTS);
+        for (endpoint, _, packet) in arrivals {
+            assert_eq!(packet.location.to_index(bounds), endpoint);
+            assert_eq!(packet.payload as usize, NUM_ENDPOINTS - 1 - endpoint);
+        }
+    }
+}
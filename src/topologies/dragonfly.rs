@@ -0,0 +1,260 @@
+use std::sync::Arc;
+
+use dam::{context_tools::*, simulation::ProgramBuilder};
+use fxhash::{FxHashMap, FxHashSet};
+
+use crate::switches::{
+    routing::{SimplePacket, Switch},
+    SimpleSwitch,
+};
+
+use super::link::{node_port, switch_ports, Endpoint, NetworkTracker};
+
+/// How a built dragonfly is timed and buffered, with local and global links timed separately.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DragonflyConfig {
+    /// Cycles a packet spends on the link between an endpoint and its router.
+    pub host_link_latency: u64,
+    /// Cycles a packet spends on a link between two routers of the same group.
+    pub local_link_latency: u64,
+    /// Cycles a packet spends on a link between two groups.
+    pub global_link_latency: u64,
+    /// How many packets every channel in the network holds, including the ones handed back to attach to.
+    pub channel_depth: usize,
+    /// Cycles each router takes to send a packet on.
+    pub router_latency: u64,
+}
+
+impl Default for DragonflyConfig {
+    fn default() -> Self {
+        Self {
+            host_link_latency: 1,
+            local_link_latency: 1,
+            global_link_latency: 1,
+            channel_depth: 4,
+            router_latency: 1,
+        }
+    }
+}
+
+/// Builds a dragonfly out of [`SimpleSwitch`]es: `a * h + 1` groups of `a` routers, where the routers of a group are
+/// all linked to each other, and each of them has `p` endpoints and `h` global links. Every group has exactly one
+/// global link to every other one. Endpoint `port` of router `router` in group `group` is destination
+/// `(group * a + router) * p + port`, and the handles are returned in that order.
+///
+/// Packets are routed minimally: over to the router with the global link to their group, across it, and over to
+/// their own router, so that they go at most three hops between routers. Packets take local links both on their way to
+/// another group and once they get there, so once the channels fill up, they can end up waiting on each other in a
+/// cycle. The network shuts down once every injection sender has closed and every packet has been delivered.
+pub fn build_dragonfly<'a, P: DAMType + 'a>(
+    ctx: &mut ProgramBuilder<'a>,
+    a: usize,
+    p: usize,
+    h: usize,
+    cfg: DragonflyConfig,
+) -> Vec<Endpoint<SimplePacket<usize, P>>> {
+    build_dragonfly_with(ctx, a, p, h, cfg, |table| {
+        SimpleSwitch::new(table, cfg.router_latency)
+    })
+}
+
+/// Builds a dragonfly as [`build_dragonfly`] does, out of whichever switches `make_switch` builds from each router's
+/// routing table. The switches set their own latency, so `cfg.router_latency` goes unused.
+pub fn build_dragonfly_with<'a, T, S>(
+    ctx: &mut ProgramBuilder<'a>,
+    a: usize,
+    p: usize,
+    h: usize,
+    cfg: DragonflyConfig,
+    mut make_switch: impl FnMut(FxHashMap<usize, FxHashSet<usize>>) -> S,
+) -> Vec<Endpoint<T>>
+where
+    T: DAMType + 'a,
+    S: Switch<T> + Context + 'a,
+{
+    assert!(
+        a > 0 && p > 0 && h > 0,
+        "Dragonflies need at least one router per group, one endpoint per router, and one global link per router!"
+    );
+    let groups = a * h + 1;
+    // Ports 0..p lead to endpoints, the next a - 1 to the other routers of the group, and the last h to other groups.
+    let local_port = |router: usize, other: usize| match other < router {
+        true => p + other,
+        false => p + other - 1,
+    };
+    let global_port = |link: usize| p + a - 1 + link % h;
+    // Group `group` reaches each of the others through the global link numbered by how many groups it skips over.
+    let global_link = |group: usize, other: usize| match other < group {
+        true => other,
+        false => other - 1,
+    };
+
+    let mut routers = vec![];
+    for group in 0..groups {
+        for router in 0..a {
+            let table = (0..groups * a * p)
+                .map(|destination| {
+                    let (to_group, to_router) = (destination / (a * p), destination / p % a);
+                    let port = if to_group == group {
+                        match to_router == router {
+                            true => destination % p,
+                            false => local_port(router, to_router),
+                        }
+                    } else {
+                        let link = global_link(group, to_group);
+                        match link / h == router {
+                            true => global_port(link),
+                            false => local_port(router, link / h),
+                        }
+                    };
+                    (destination, FxHashSet::from_iter([port]))
+                })
+                .collect();
+            routers.push(make_switch(table));
+        }
+    }
+
+    let tracker = Arc::new(NetworkTracker::default());
+    let endpoints = (0..groups * a * p)
+        .map(|destination| {
+            let (port, endpoint) = node_port(
+                ctx,
+                destination % p,
+                cfg.host_link_latency,
+                cfg.channel_depth,
+                &tracker,
+            );
+            routers[destination / p].add_port(port);
+            endpoint
+        })
+        .collect();
+
+    let mut links = vec![];
+    for group in 0..groups {
+        for (router, other) in (0..a).flat_map(|router| (0..a).map(move |other| (router, other))) {
+            if router != other {
+                links.push((
+                    group * a + router,
+                    local_port(router, other),
+                    group * a + other,
+                    local_port(other, router),
+                    cfg.local_link_latency,
+                ));
+            }
+        }
+        for other in (0..groups).filter(|other| *other != group) {
+            let (link, back) = (global_link(group, other), global_link(other, group));
+            links.push((
+                group * a + link / h,
+                global_port(link),
+                other * a + back / h,
+                global_port(back),
+                cfg.global_link_latency,
+            ));
+        }
+    }
+    for (from, from_port, to, to_port, latency) in links {
+        let (sending, receiving) = switch_ports(
+            ctx,
+            from_port,
+            to_port,
+            latency,
+            cfg.channel_depth,
+            &tracker,
+        );
+        routers[from].add_port(sending);
+        routers[to].add_port(receiving);
+    }
+    routers.into_iter().for_each(|router| ctx.add_child(router));
+    endpoints
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use dam::{simulation::ProgramBuilder, utility_contexts::*};
+    use fxhash::FxHashSet;
+
+    use crate::switches::{
+        routing::{HoppedPacket, SimplePacket},
+        SimpleSwitch,
+    };
+
+    use super::{build_dragonfly_with, DragonflyConfig};
+
+    const A: usize = 2;
+    const P: usize = 2;
+    const H: usize = 1;
+    const ENDPOINTS: usize = (A * H + 1) * A * P;
+
+    type TestPacket = HoppedPacket<SimplePacket<usize, u32>>;
+
+    /// Counts the routers a packet has been through.
+    fn count_hop(packet: &mut TestPacket, _targets: &FxHashSet<usize>) {
+        packet.hops += 1;
+    }
+
+    #[test]
+    fn all_to_all_dragonfly_test() {
+        // Deep enough that every packet fits in any one channel, so that minimal routing can't deadlock.
+        let cfg = DragonflyConfig {
+            channel_depth: ENDPOINTS * ENDPOINTS,
+            ..Default::default()
+        };
+        let mut ctx = ProgramBuilder::default();
+        let endpoints = build_dragonfly_with(&mut ctx, A, P, H, cfg, |table| {
+            SimpleSwitch::new(table, cfg.router_latency).with_on_forward(count_hop)
+        });
+
+        let arrivals = Arc::new(Mutex::new(vec![]));
+        for (source, (inject, eject)) in endpoints.into_iter().enumerate() {
+            // Every endpoint starts off at a different destination, so that they don't all pile onto the same one.
+            let packets: Vec<_> = (1..ENDPOINTS)
+                .map(|offset| HoppedPacket {
+                    packet: SimplePacket {
+                        location: (source + offset) % ENDPOINTS,
+                        payload: source as u32,
+                    },
+                    hops: 0,
+                })
+                .collect();
+            ctx.add_child(GeneratorContext::new(move || packets.into_iter(), inject));
+
+            let mut sink = FunctionContext::new();
+            eject.attach_receiver(&sink);
+            let log = arrivals.clone();
+            sink.set_run(move |time| {
+                while let Ok(element) = eject.dequeue(time) {
+                    log.lock().unwrap().push((source, element.data));
+                }
+            });
+            ctx.add_child(sink);
+        }
+        ctx.initialize(Default::default())
+            .unwrap()
+            .run(Default::default());
+
+        let arrivals = arrivals.lock().unwrap();
+        assert_eq!(arrivals.len(), ENDPOINTS * (ENDPOINTS - 1));
+        let delivered: FxHashSet<_> = arrivals
+            .iter()
+            .map(|(endpoint, packet)| (packet.packet.payload as usize, *endpoint))
+            .collect();
+        assert_eq!(delivered.len(), arrivals.len());
+        let router = |endpoint: usize| endpoint / P;
+        let group = |endpoint: usize| endpoint / (A * P);
+        for (endpoint, packet) in arrivals.iter() {
+            let source = packet.packet.payload as usize;
+            assert_eq!(packet.packet.location, *endpoint);
+            // At most three hops between routers, so at most four routers.
+            assert!(packet.hops <= 4);
+            if router(source) == router(*endpoint) {
+                assert_eq!(packet.hops, 1);
+            } else if group(source) == group(*endpoint) {
+                assert_eq!(packet.hops, 2);
+            }
+        }
+        assert!(arrivals.iter().any(|(_, packet)| packet.hops == 4));
+    }
+}
@@ -0,0 +1,244 @@
+use std::sync::Arc;
+
+use dam::{context_tools::*, simulation::ProgramBuilder};
+use fxhash::{FxHashMap, FxHashSet};
+
+use crate::switches::{
+    routing::{SimplePacket, Switch},
+    updown_tables, SimpleSwitch, TreeTopo,
+};
+
+use super::link::{node_port, switch_ports, Endpoint, NetworkTracker};
+
+/// How a built fat-tree is timed and buffered, with the links at each level timed separately.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FatTreeConfig {
+    /// Cycles a packet spends on the link between a host and its edge switch.
+    pub host_link_latency: u64,
+    /// Cycles a packet spends on a link between an edge switch and an aggregation switch.
+    pub edge_link_latency: u64,
+    /// Cycles a packet spends on a link between an aggregation switch and a core switch.
+    pub core_link_latency: u64,
+    /// How many packets every channel in the tree holds, including the ones handed back to attach to.
+    pub channel_depth: usize,
+    /// Cycles each switch takes to send a packet on.
+    pub router_latency: u64,
+}
+
+impl Default for FatTreeConfig {
+    fn default() -> Self {
+        Self {
+            host_link_latency: 1,
+            edge_link_latency: 1,
+            core_link_latency: 1,
+            channel_depth: 4,
+            router_latency: 1,
+        }
+    }
+}
+
+/// Builds a `k`-ary fat-tree out of [`SimpleSwitch`]es: `k` pods of `k / 2` edge and `k / 2` aggregation switches
+/// each, with every edge switch linked to every aggregation switch in its pod, and `(k / 2)²` core switches, each
+/// linked to one aggregation switch in every pod. Each edge switch has `k / 2` hosts, which are numbered pod by pod
+/// and edge switch by edge switch, and the handles are returned in that order.
+///
+/// Packets are routed up*/down*, as by [`updown_tables`]. A packet between hosts on different pods goes through five
+/// switches, and one between edge switches of the same pod goes through three. The tree shuts down once every
+/// injection sender has closed and every packet has been delivered.
+pub fn build_fat_tree<'a, P: DAMType + 'a>(
+    ctx: &mut ProgramBuilder<'a>,
+    k: usize,
+    cfg: FatTreeConfig,
+) -> Vec<Endpoint<SimplePacket<usize, P>>> {
+    build_fat_tree_with(ctx, k, cfg, |table| {
+        SimpleSwitch::new(table, cfg.router_latency)
+    })
+}
+
+/// Builds a fat-tree as [`build_fat_tree`] does, out of whichever switches `make_switch` builds from each one's
+/// routing table. The switches set their own latency, so `cfg.router_latency` goes unused.
+pub fn build_fat_tree_with<'a, T, S>(
+    ctx: &mut ProgramBuilder<'a>,
+    k: usize,
+    cfg: FatTreeConfig,
+    mut make_switch: impl FnMut(FxHashMap<usize, FxHashSet<usize>>) -> S,
+) -> Vec<Endpoint<T>>
+where
+    T: DAMType + 'a,
+    S: Switch<T> + Context + 'a,
+{
+    assert!(
+        k >= 2 && k.is_multiple_of(2),
+        "Fat-trees need an even number of ports per switch!"
+    );
+    let half = k / 2;
+    // Switches are numbered edges first, then aggregation switches, then cores. Down ports come before up ports.
+    let edge = |pod: usize, index: usize| pod * half + index;
+    let aggregation = |pod: usize, index: usize| k * half + pod * half + index;
+    let core = |group: usize, index: usize| 2 * k * half + group * half + index;
+    let num_switches = 2 * k * half + half * half;
+
+    let mut tree = TreeTopo::new();
+    for pod in 0..k {
+        for (lower, upper) in (0..half).flat_map(|lower| (0..half).map(move |upper| (lower, upper)))
+        {
+            tree = tree
+                .with_link(
+                    edge(pod, lower),
+                    half + upper,
+                    aggregation(pod, upper),
+                    lower,
+                )
+                .with_link(
+                    aggregation(pod, lower),
+                    half + upper,
+                    core(lower, upper),
+                    pod,
+                );
+        }
+    }
+    let hosts: Vec<_> = (0..k * half * half)
+        .map(|host| {
+            (
+                host,
+                edge(host / (half * half), host / half % half),
+                host % half,
+            )
+        })
+        .collect();
+    let mut tables = updown_tables(&tree, &hosts);
+
+    let mut switches: Vec<_> = (0..num_switches)
+        .map(|switch| make_switch(tables.remove(&switch).unwrap()))
+        .collect();
+    let tracker = Arc::new(NetworkTracker::default());
+    let endpoints = hosts
+        .iter()
+        .map(|(_, switch, port)| {
+            let (port, endpoint) = node_port(
+                ctx,
+                *port,
+                cfg.host_link_latency,
+                cfg.channel_depth,
+                &tracker,
+            );
+            switches[*switch].add_port(port);
+            endpoint
+        })
+        .collect();
+    for link in tree.links() {
+        let latency = match link.child < k * half {
+            true => cfg.edge_link_latency,
+            false => cfg.core_link_latency,
+        };
+        for (from, from_port, to, to_port) in [
+            (link.child, link.up_port, link.parent, link.down_port),
+            (link.parent, link.down_port, link.child, link.up_port),
+        ] {
+            let (sending, receiving) = switch_ports(
+                ctx,
+                from_port,
+                to_port,
+                latency,
+                cfg.channel_depth,
+                &tracker,
+            );
+            switches[from].add_port(sending);
+            switches[to].add_port(receiving);
+        }
+    }
+    switches
+        .into_iter()
+        .for_each(|switch| ctx.add_child(switch));
+    endpoints
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use dam::{simulation::ProgramBuilder, utility_contexts::*};
+    use fxhash::FxHashSet;
+
+    use crate::{
+        random::SplitMix64,
+        switches::{
+            routing::{HoppedPacket, SimplePacket},
+            SimpleSwitch,
+        },
+    };
+
+    use super::{build_fat_tree_with, FatTreeConfig};
+
+    const K: usize = 4;
+    const HOSTS: usize = K * K * K / 4;
+
+    type TestPacket = HoppedPacket<SimplePacket<usize, u32>>;
+
+    /// Counts the switches a packet has been through.
+    fn count_hop(packet: &mut TestPacket, _targets: &FxHashSet<usize>) {
+        packet.hops += 1;
+    }
+
+    #[test]
+    fn fat_tree_permutation_test() {
+        let cfg = FatTreeConfig::default();
+        let mut ctx = ProgramBuilder::default();
+        let endpoints = build_fat_tree_with(&mut ctx, K, cfg, |table| {
+            SimpleSwitch::new(table, cfg.router_latency).with_on_forward(count_hop)
+        });
+
+        // A random permutation, so that some packets stay under their edge switch or in their pod.
+        let mut rng = SplitMix64::new(4);
+        let mut destinations: Vec<_> = (0..HOSTS).collect();
+        for i in (1..HOSTS).rev() {
+            destinations.swap(i, rng.below(i as u64 + 1) as usize);
+        }
+
+        let arrivals = Arc::new(Mutex::new(vec![]));
+        for (host, (inject, eject)) in endpoints.into_iter().enumerate() {
+            let packet = HoppedPacket {
+                packet: SimplePacket {
+                    location: destinations[host],
+                    payload: host as u32,
+                },
+                hops: 0,
+            };
+            ctx.add_child(GeneratorContext::new(move || [packet].into_iter(), inject));
+
+            let mut sink = FunctionContext::new();
+            eject.attach_receiver(&sink);
+            let log = arrivals.clone();
+            sink.set_run(move |time| {
+                while let Ok(element) = eject.dequeue(time) {
+                    log.lock().unwrap().push((host, element.data));
+                }
+            });
+            ctx.add_child(sink);
+        }
+        ctx.initialize(Default::default())
+            .unwrap()
+            .run(Default::default());
+
+        let arrivals = arrivals.lock().unwrap();
+        assert_eq!(arrivals.len(), HOSTS);
+        let pod = |host: usize| host / (K * K / 4);
+        let edge = |host: usize| host / (K / 2);
+        let mut inter_pod = 0;
+        for (host, packet) in arrivals.iter() {
+            let source = packet.packet.payload as usize;
+            assert_eq!(packet.packet.location, *host);
+            assert_eq!(destinations[source], *host);
+            let expected = if pod(source) != pod(*host) {
+                inter_pod += 1;
+                5
+            } else if edge(source) != edge(*host) {
+                3
+            } else {
+                1
+            };
+            assert_eq!(packet.hops, expected);
+        }
+        assert!(inter_pod > 0);
+    }
+}
@@ -0,0 +1,273 @@
+use std::{collections::BTreeMap, fmt::Display, hash::Hash, sync::Arc};
+
+use dam::{context_tools::*, simulation::ProgramBuilder};
+use fxhash::{FxHashMap, FxHashSet};
+
+use crate::switches::{
+    routing::{SimplePacket, Switch},
+    RoutingTableBuilder, SimpleSwitch,
+};
+
+use super::{
+    link::{node_port, switch_ports, Endpoint, NetworkTracker},
+    MeshConfig,
+};
+
+/// Which switch of a network built by [`build_from_edges`] something belongs to.
+pub type NodeId = usize;
+
+/// How a single edge of a network built by [`build_from_edges`] is timed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LinkConfig {
+    /// Cycles a packet spends on the edge, each way.
+    pub latency: u64,
+}
+
+impl Default for LinkConfig {
+    fn default() -> Self {
+        Self { latency: 1 }
+    }
+}
+
+/// Some endpoints of a network had no path between them, so it wasn't built.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UnreachableEndpoints<LT> {
+    /// Every source and destination with no path from one to the other, by destination and then by source, each in the
+    /// order the endpoints were given.
+    pub pairs: Vec<(LT, LT)>,
+}
+
+impl<LT: Display> Display for UnreachableEndpoints<LT> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "No path between")?;
+        for (index, (source, destination)) in self.pairs.iter().enumerate() {
+            if index > 0 {
+                write!(f, ",")?;
+            }
+            write!(f, " {} and {}", source, destination)?;
+        }
+        Ok(())
+    }
+}
+
+impl<LT: std::fmt::Debug + Display> std::error::Error for UnreachableEndpoints<LT> {}
+
+/// Builds a network with a [`SimpleSwitch`] for every node named in `edges` or `endpoints`. Each edge links its two
+/// nodes both ways, over links timed by its own [`LinkConfig`], and each endpoint attaches the location it names to its
+/// node. Returns the endpoints' injection and ejection channels, in the order they were given.
+///
+/// On each switch, ports are handed out to its endpoints first and then to its edges, each in the order they were
+/// given. Packets take the quickest way to their destination, counting `router_latency` for every switch they go
+/// through along with the latency of every edge, as a [`RoutingTableBuilder`] works it out, and pick the lowest port
+/// whenever there is more than one. Endpoints use `cfg.link_latency` for their own links. Nothing keeps the routes
+/// from going around a cycle of the graph, so once the channels fill up, packets can end up waiting on each other. The
+/// network shuts down once every injection sender has closed and every packet has been delivered.
+///
+/// If any endpoint can't reach another, nothing is built, and every such pair is returned instead.
+pub fn build_from_edges<'a, LT, P>(
+    ctx: &mut ProgramBuilder<'a>,
+    edges: &[(NodeId, NodeId, LinkConfig)],
+    endpoints: &[(LT, NodeId)],
+    cfg: MeshConfig,
+) -> Result<Vec<Endpoint<SimplePacket<LT, P>>>, UnreachableEndpoints<LT>>
+where
+    LT: DAMType + Eq + Hash + 'a,
+    P: DAMType + 'a,
+{
+    assert_eq!(
+        cfg.concentration, 1,
+        "Networks built from edges attach their endpoints as they are listed!"
+    );
+    let mut ports: BTreeMap<NodeId, usize> = BTreeMap::new();
+    let mut next_port = |node: NodeId| {
+        let port = ports.entry(node).or_default();
+        *port += 1;
+        *port - 1
+    };
+    let endpoint_ports: Vec<_> = endpoints.iter().map(|(_, node)| next_port(*node)).collect();
+    let edge_ports: Vec<_> = edges
+        .iter()
+        .map(|(from, to, _)| {
+            assert_ne!(from, to, "Edges must join two different nodes!");
+            (next_port(*from), next_port(*to))
+        })
+        .collect();
+
+    let builder = endpoints.iter().zip(&endpoint_ports).fold(
+        RoutingTableBuilder::new(),
+        |builder, ((location, node), port)| builder.with_endpoint(location.clone(), *node, *port),
+    );
+    let builder = edges.iter().zip(&edge_ports).fold(
+        builder,
+        |builder, ((from, to, link), (from_port, to_port))| {
+            builder.with_edge(
+                *from,
+                *from_port,
+                *to,
+                *to_port,
+                link.latency + cfg.router_latency,
+            )
+        },
+    );
+    let tables: BTreeMap<NodeId, FxHashMap<LT, FxHashSet<usize>>> = builder
+        .build_candidates()
+        .into_iter()
+        .map(|(node, table)| {
+            let table = table
+                .into_iter()
+                .map(|(location, ports)| (location, FxHashSet::from_iter([ports[0]])))
+                .collect();
+            (node, table)
+        })
+        .collect();
+    let unreachable: Vec<_> = endpoints
+        .iter()
+        .flat_map(|(destination, _)| {
+            endpoints
+                .iter()
+                .filter(|(_, node)| !tables[node].contains_key(destination))
+                .map(|(source, _)| (source.clone(), destination.clone()))
+        })
+        .collect();
+    if !unreachable.is_empty() {
+        return Err(UnreachableEndpoints { pairs: unreachable });
+    }
+
+    let mut switches: BTreeMap<_, _> = tables
+        .into_iter()
+        .map(|(node, table)| (node, SimpleSwitch::new(table, cfg.router_latency)))
+        .collect();
+    let tracker = Arc::new(NetworkTracker::default());
+    let handles = endpoints
+        .iter()
+        .zip(endpoint_ports)
+        .map(|((_, node), id)| {
+            let (port, endpoint) =
+                node_port(ctx, id, cfg.link_latency, cfg.channel_depth, &tracker);
+            switches.get_mut(node).unwrap().add_port(port);
+            endpoint
+        })
+        .collect();
+    for ((from, to, link), (from_port, to_port)) in edges.iter().zip(edge_ports) {
+        for (sender, sender_port, receiver, receiver_port) in [
+            (from, from_port, to, to_port),
+            (to, to_port, from, from_port),
+        ] {
+            let (sending, receiving) = switch_ports(
+                ctx,
+                sender_port,
+                receiver_port,
+                link.latency,
+                cfg.channel_depth,
+                &tracker,
+            );
+            switches.get_mut(sender).unwrap().add_port(sending);
+            switches.get_mut(receiver).unwrap().add_port(receiving);
+        }
+    }
+    switches
+        .into_values()
+        .for_each(|switch| ctx.add_child(switch));
+    Ok(handles)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use dam::{simulation::ProgramBuilder, utility_contexts::*};
+
+    use crate::switches::routing::SimplePacket;
+
+    use super::{build_from_edges, LinkConfig, MeshConfig, UnreachableEndpoints};
+
+    fn link(latency: u64) -> LinkConfig {
+        LinkConfig { latency }
+    }
+
+    #[test]
+    fn irregular_graph_test() {
+        // A loop of four, with a slow shortcut through node 4 and a leaf hanging off of node 3.
+        let edges = [
+            (0, 1, link(1)),
+            (1, 2, link(1)),
+            (2, 3, link(1)),
+            (3, 0, link(2)),
+            (1, 4, link(1)),
+            (4, 2, link(3)),
+            (3, 5, link(1)),
+        ];
+        // Node 2 has two endpoints of its own.
+        let endpoints: Vec<_> = (0..6)
+            .map(|node| (10 * node as u32, node))
+            .chain([(21, 2)])
+            .collect();
+        let mut ctx = ProgramBuilder::default();
+        let handles =
+            build_from_edges(&mut ctx, &edges, &endpoints, MeshConfig::default()).unwrap();
+
+        let arrivals = Arc::new(Mutex::new(vec![]));
+        for ((location, _), (inject, eject)) in endpoints.iter().zip(handles) {
+            let (location, destinations) = (*location, endpoints.clone());
+            let packets = move || {
+                destinations
+                    .into_iter()
+                    .filter(move |(destination, _)| *destination != location)
+                    .map(move |(destination, _)| SimplePacket {
+                        location: destination,
+                        payload: location,
+                    })
+            };
+            ctx.add_child(GeneratorContext::new(packets, inject));
+
+            let mut sink = FunctionContext::new();
+            eject.attach_receiver(&sink);
+            let log = arrivals.clone();
+            sink.set_run(move |time| {
+                while let Ok(element) = eject.dequeue(time) {
+                    log.lock().unwrap().push((location, element.data));
+                }
+            });
+            ctx.add_child(sink);
+        }
+        ctx.initialize(Default::default())
+            .unwrap()
+            .run(Default::default());
+
+        let mut received: Vec<_> = arrivals
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(location, packet)| {
+                assert_eq!(packet.location, *location);
+                (packet.payload, *location)
+            })
+            .collect();
+        received.sort();
+        let mut expected = vec![];
+        for (source, _) in &endpoints {
+            for (destination, _) in &endpoints {
+                if source != destination {
+                    expected.push((*source, *destination));
+                }
+            }
+        }
+        expected.sort();
+        assert_eq!(received, expected);
+    }
+
+    #[test]
+    fn unreachable_endpoints_test() {
+        let edges = [(0, 1, link(1)), (2, 3, link(1))];
+        let endpoints = [(0u32, 0), (1, 1), (2, 2)];
+        let mut ctx = ProgramBuilder::default();
+        let result =
+            build_from_edges::<u32, u32>(&mut ctx, &edges, &endpoints, MeshConfig::default());
+        match result {
+            Err(UnreachableEndpoints { pairs }) => {
+                assert_eq!(pairs, vec![(2, 0), (2, 1), (0, 2), (1, 2)])
+            }
+            Ok(_) => panic!("Nodes 0 and 1 shouldn't be able to reach node 2!"),
+        }
+    }
+}
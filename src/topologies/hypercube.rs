@@ -0,0 +1,174 @@
+use std::sync::Arc;
+
+use dam::{context_tools::*, simulation::ProgramBuilder};
+use fxhash::{FxHashMap, FxHashSet};
+
+use crate::switches::{
+    routing::{SimplePacket, Switch},
+    SimpleSwitch,
+};
+
+use super::{
+    link::{node_port, switch_ports, Endpoint, NetworkTracker},
+    MeshConfig,
+};
+
+/// The port a hypercube switch reaches its neighbour along `dimension` through, after the `concentration` ports for its
+/// endpoints.
+fn dimension_port(dimension: u32, concentration: usize) -> usize {
+    concentration + dimension as usize
+}
+
+/// Builds a `dims`-dimensional hypercube of `2^dims` [`SimpleSwitch`]es, numbered so that neighbours differ in exactly
+/// one bit. Each switch has `cfg.concentration` endpoints on its first ports, where endpoint `local` of node `node` is
+/// destination `node * concentration + local`, and then its neighbour along dimension `d` on port
+/// `concentration + d`. Returns each endpoint's injection and ejection channels, in that order.
+///
+/// Packets are routed e-cube by a table for each switch, as [`crate::switches::EcubePolicy`] would route them, fixing
+/// the bits they differ in from the lowest one up, which can't deadlock. A packet which doesn't have to wait goes as
+/// many hops as its source and destination differ in bits, and arrives
+/// `(hops + 1) * router_latency + (hops + 2) * link_latency` cycles after it was injected. The hypercube shuts down
+/// once every injection sender has closed and every packet has been delivered.
+pub fn build_hypercube<'a, P: DAMType + 'a>(
+    ctx: &mut ProgramBuilder<'a>,
+    dims: u32,
+    cfg: MeshConfig,
+) -> Vec<Endpoint<SimplePacket<usize, P>>> {
+    build_hypercube_with(ctx, dims, cfg, |table| {
+        SimpleSwitch::new(table, cfg.router_latency)
+    })
+}
+
+/// Builds a hypercube as [`build_hypercube`] does, out of whichever switches `make_switch` builds from each one's
+/// routing table. The switches set their own latency, so `cfg.router_latency` goes unused.
+pub fn build_hypercube_with<'a, T, S>(
+    ctx: &mut ProgramBuilder<'a>,
+    dims: u32,
+    cfg: MeshConfig,
+    mut make_switch: impl FnMut(FxHashMap<usize, FxHashSet<usize>>) -> S,
+) -> Vec<Endpoint<T>>
+where
+    T: DAMType + 'a,
+    S: Switch<T> + Context + 'a,
+{
+    assert!(
+        dims < usize::BITS,
+        "Hypercubes can't have more dimensions than node IDs have bits!"
+    );
+    let n = 1usize << dims;
+    let concentration = cfg.concentration;
+    assert!(concentration > 0, "Switches need at least one endpoint!");
+    let tracker = Arc::new(NetworkTracker::default());
+
+    let mut switches = vec![];
+    let mut endpoints = vec![];
+    for node in 0..n {
+        let table = (0..n * concentration)
+            .map(|destination| {
+                let port = match node ^ (destination / concentration) {
+                    0 => destination % concentration,
+                    differing => dimension_port(differing.trailing_zeros(), concentration),
+                };
+                (destination, FxHashSet::from_iter([port]))
+            })
+            .collect();
+        let mut switch = make_switch(table);
+        for local in 0..concentration {
+            let (port, handles) =
+                node_port(ctx, local, cfg.link_latency, cfg.channel_depth, &tracker);
+            switch.add_port(port);
+            endpoints.push(handles);
+        }
+        switches.push(switch);
+    }
+
+    for (node, dimension) in
+        (0..n).flat_map(|node| (0..dims).map(move |dimension| (node, dimension)))
+    {
+        let port = dimension_port(dimension, concentration);
+        let (sending, receiving) = switch_ports(
+            ctx,
+            port,
+            port,
+            cfg.link_latency,
+            cfg.channel_depth,
+            &tracker,
+        );
+        switches[node].add_port(sending);
+        switches[node ^ (1 << dimension)].add_port(receiving);
+    }
+    switches
+        .into_iter()
+        .for_each(|switch| ctx.add_child(switch));
+    endpoints
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use dam::{simulation::ProgramBuilder, utility_contexts::*};
+    use fxhash::FxHashSet;
+
+    use crate::switches::{
+        routing::{HoppedPacket, SimplePacket},
+        SimpleSwitch,
+    };
+
+    use super::{build_hypercube_with, MeshConfig};
+
+    const DIMS: u32 = 4;
+    const NUM_NODES: usize = 1 << DIMS;
+
+    type TestPacket = HoppedPacket<SimplePacket<usize, u32>>;
+
+    /// Counts the switches a packet has been through.
+    fn count_hop(packet: &mut TestPacket, _targets: &FxHashSet<usize>) {
+        packet.hops += 1;
+    }
+
+    #[test]
+    fn all_to_all_hypercube_test() {
+        let cfg = MeshConfig::default();
+        let mut ctx = ProgramBuilder::default();
+        let nodes = build_hypercube_with(&mut ctx, DIMS, cfg, |table| {
+            SimpleSwitch::new(table, cfg.router_latency).with_on_forward(count_hop)
+        });
+
+        let arrivals = Arc::new(Mutex::new(vec![]));
+        for (source, (inject, eject)) in nodes.into_iter().enumerate() {
+            let packets: Vec<_> = (1..NUM_NODES)
+                .map(|offset| HoppedPacket {
+                    packet: SimplePacket {
+                        location: (source + offset) % NUM_NODES,
+                        payload: source as u32,
+                    },
+                    hops: 0,
+                })
+                .collect();
+            ctx.add_child(GeneratorContext::new(move || packets.into_iter(), inject));
+
+            let mut sink = FunctionContext::new();
+            eject.attach_receiver(&sink);
+            let log = arrivals.clone();
+            sink.set_run(move |time| {
+                while let Ok(element) = eject.dequeue(time) {
+                    log.lock().unwrap().push((source, element.data));
+                }
+            });
+            ctx.add_child(sink);
+        }
+        ctx.initialize(Default::default())
+            .unwrap()
+            .run(Default::default());
+
+        let arrivals = arrivals.lock().unwrap();
+        assert_eq!(arrivals.len(), NUM_NODES * (NUM_NODES - 1));
+        for (node, packet) in arrivals.iter() {
+            let source = packet.packet.payload as usize;
+            assert_eq!(packet.packet.location, *node);
+            // One switch per hop, plus the one the packet started at.
+            assert_eq!(packet.hops as u32, (source ^ node).count_ones() + 1);
+        }
+    }
+}
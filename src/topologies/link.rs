@@ -0,0 +1,181 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+use dam::{
+    channel::utils::{EventTime, Peekable},
+    context_tools::*,
+    simulation::ProgramBuilder,
+};
+
+use crate::switches::routing::Port;
+
+/// The channels a node of a built network is reached through: a sender to inject packets with, and a receiver for
+/// the packets addressed to it.
+pub type Endpoint<T> = (Sender<T>, Receiver<T>);
+
+/// Keeps count of what is left to do in a built network. Its switches keep each other alive and never close on their
+/// own, so the links between them shut down together once every injecting link has closed and every packet has been
+/// ejected, and the switches follow once all of their inputs have.
+#[derive(Debug, Default)]
+pub struct NetworkTracker {
+    open_injectors: AtomicUsize,
+    in_flight: AtomicUsize,
+}
+
+impl NetworkTracker {
+    fn idle(&self) -> bool {
+        self.open_injectors.load(Ordering::SeqCst) == 0
+            && self.in_flight.load(Ordering::SeqCst) == 0
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum LinkKind {
+    Transit,
+    Inject,
+    Eject,
+}
+
+/// Carries packets from one channel to another, one per cycle, each arriving `latency` cycles after it was taken in.
+/// Links between switches run until their tracker goes idle, while links into and out of the network close along
+/// with their inputs, and keep the tracker up to date on what is in flight.
+#[context_macro]
+pub struct Link<T: DAMType> {
+    input: Receiver<T>,
+    output: Sender<T>,
+    latency: u64,
+    tracker: Arc<NetworkTracker>,
+    kind: LinkKind,
+}
+
+impl<T: DAMType> Context for Link<T> {
+    fn run(&mut self) {
+        while self.kind != LinkKind::Transit || !self.tracker.idle() {
+            match self.input.next_event() {
+                EventTime::Ready(_) => {
+                    let ChannelElement { time: _, data } = self.input.dequeue(&self.time).unwrap();
+                    if self.kind == LinkKind::Inject {
+                        self.tracker.in_flight.fetch_add(1, Ordering::SeqCst);
+                    }
+                    self.output
+                        .enqueue(
+                            &self.time,
+                            ChannelElement {
+                                time: self.time.tick() + self.latency,
+                                data,
+                            },
+                        )
+                        .unwrap();
+                    if self.kind == LinkKind::Eject {
+                        self.tracker.in_flight.fetch_sub(1, Ordering::SeqCst);
+                    }
+                    self.time.incr_cycles(1);
+                }
+                // The sender could still send something for any cycle it hasn't gone past, so wait for it to move on
+                // instead of skipping ahead, which would hold up whatever it sends for this cycle.
+                EventTime::Nothing(time) if time <= self.time.tick() => std::thread::yield_now(),
+                EventTime::Nothing(time) => self.time.advance(time),
+                EventTime::Closed => break,
+            }
+        }
+        if self.kind == LinkKind::Inject {
+            self.tracker.open_injectors.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+impl<T: DAMType> Link<T> {
+    /// Creates a link between two switches of the network `tracker` keeps count for.
+    pub fn new(
+        input: Receiver<T>,
+        output: Sender<T>,
+        latency: u64,
+        tracker: Arc<NetworkTracker>,
+    ) -> Self {
+        let link = Self {
+            input,
+            output,
+            latency,
+            tracker,
+            kind: LinkKind::Transit,
+            context_info: Default::default(),
+        };
+        link.input.attach_receiver(&link);
+        link.output.attach_sender(&link);
+        link
+    }
+
+    /// Makes this the link packets enter the network through. The network stays up until it closes.
+    pub fn injecting(mut self) -> Self {
+        assert_eq!(
+            self.kind,
+            LinkKind::Transit,
+            "Links can only lead into or out of the network!"
+        );
+        self.tracker.open_injectors.fetch_add(1, Ordering::SeqCst);
+        self.kind = LinkKind::Inject;
+        self
+    }
+
+    /// Makes this a link packets leave the network through.
+    pub fn ejecting(mut self) -> Self {
+        assert_eq!(
+            self.kind,
+            LinkKind::Transit,
+            "Links can only lead into or out of the network!"
+        );
+        self.kind = LinkKind::Eject;
+        self
+    }
+}
+
+/// Makes the port `id` a switch reaches a node through, behind an injecting and an ejecting link of `latency` cycles
+/// each. Returns the port for the switch, and the sender and receiver for the node's end.
+pub(crate) fn node_port<'a, T: DAMType + 'a>(
+    ctx: &mut ProgramBuilder<'a>,
+    id: usize,
+    latency: u64,
+    depth: usize,
+    tracker: &Arc<NetworkTracker>,
+) -> (Port<T>, Endpoint<T>) {
+    let (inject_snd, inject_rcv) = ctx.bounded(depth);
+    let (local_in_snd, local_in_rcv) = ctx.bounded(depth);
+    ctx.add_child(Link::new(inject_rcv, local_in_snd, latency, tracker.clone()).injecting());
+    let (local_out_snd, local_out_rcv) = ctx.bounded(depth);
+    let (eject_snd, eject_rcv) = ctx.bounded(depth);
+    ctx.add_child(Link::new(local_out_rcv, eject_snd, latency, tracker.clone()).ejecting());
+    let port = Port {
+        id,
+        input: Some(local_in_rcv),
+        output: Some(local_out_snd),
+    };
+    (port, (inject_snd, eject_rcv))
+}
+
+/// Links output `from` of one switch to input `to` of another, over a link of `latency` cycles. Returns the two halves
+/// of the ports, for the sending switch and then the receiving one.
+pub(crate) fn switch_ports<'a, T: DAMType + 'a>(
+    ctx: &mut ProgramBuilder<'a>,
+    from: usize,
+    to: usize,
+    latency: u64,
+    depth: usize,
+    tracker: &Arc<NetworkTracker>,
+) -> (Port<T>, Port<T>) {
+    let (out_snd, out_rcv) = ctx.bounded(depth);
+    let (in_snd, in_rcv) = ctx.bounded(depth);
+    ctx.add_child(Link::new(out_rcv, in_snd, latency, tracker.clone()));
+    let sending = Port {
+        id: from,
+        input: None,
+        output: Some(out_snd),
+    };
+    let receiving = Port {
+        id: to,
+        input: Some(in_rcv),
+        output: None,
+    };
+    (sending, receiving)
+}
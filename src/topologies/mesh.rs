@@ -0,0 +1,545 @@
+use std::{collections::BTreeMap, fmt::Display, hash::Hash, sync::Arc};
+
+use dam::{context_tools::*, simulation::ProgramBuilder};
+use fxhash::FxHashSet;
+
+use crate::switches::{
+    policy::Policy,
+    routing::{Concentrated, Coord2D, Coord3D, Dir2D, SimplePacket, Switch},
+    MeshPorts, SimpleSwitch, TorusPolicy, XyPolicy,
+};
+
+use super::link::{node_port, switch_ports, Endpoint, NetworkTracker};
+
+/// The ports every switch of a built mesh uses: its endpoints on ports `0..concentration`, the first of which its
+/// policy sees as local, and then its links.
+fn mesh_ports(concentration: usize) -> MeshPorts {
+    MeshPorts {
+        local: 0,
+        plus_x: concentration,
+        minus_x: concentration + 1,
+        plus_y: concentration + 2,
+        minus_y: concentration + 3,
+    }
+}
+
+/// How a built network is timed and buffered, and how many endpoints each of its switches has.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MeshConfig {
+    /// Cycles a packet spends on each link, whether between two neighboring switches or between a switch and one of its
+    /// endpoints.
+    pub link_latency: u64,
+    /// How many packets every channel in the mesh holds, including the ones handed back to attach to.
+    pub channel_depth: usize,
+    /// Cycles each switch takes to send a packet on.
+    pub router_latency: u64,
+    /// How many endpoints each switch has, on its first ports. Packets between two endpoints of the same switch never
+    /// leave it. Builders which give each endpoint a switch port of its own, like [`super::build_crossbar`], need
+    /// this to be 1.
+    pub concentration: usize,
+}
+
+impl Default for MeshConfig {
+    fn default() -> Self {
+        Self {
+            link_latency: 1,
+            channel_depth: 4,
+            router_latency: 1,
+            concentration: 1,
+        }
+    }
+}
+
+/// Where packets in a built mesh or torus can be addressed to: a node, which then has a single endpoint, or one of
+/// several endpoints attached to it, as a [`Concentrated`] location.
+pub trait NodeLocation: DAMType + Ord + Hash + Display {
+    type Node;
+
+    /// The node the endpoint is attached to.
+    fn node(&self) -> Self::Node;
+
+    /// Which of its node's endpoints this is.
+    fn local(&self) -> usize;
+
+    /// The endpoint numbered `local` at `node`.
+    fn attached(node: Self::Node, local: usize) -> Self;
+}
+
+impl NodeLocation for Coord2D {
+    type Node = Coord2D;
+
+    fn node(&self) -> Coord2D {
+        *self
+    }
+
+    fn local(&self) -> usize {
+        0
+    }
+
+    fn attached(node: Coord2D, local: usize) -> Self {
+        assert_eq!(
+            local, 0,
+            "Nodes addressed by their coordinates only have one endpoint!"
+        );
+        node
+    }
+}
+
+impl NodeLocation for Coord3D {
+    type Node = Coord3D;
+
+    fn node(&self) -> Coord3D {
+        *self
+    }
+
+    fn local(&self) -> usize {
+        0
+    }
+
+    fn attached(node: Coord3D, local: usize) -> Self {
+        assert_eq!(
+            local, 0,
+            "Nodes addressed by their coordinates only have one endpoint!"
+        );
+        node
+    }
+}
+
+impl<LT: DAMType + Ord + Hash + Display + Copy> NodeLocation for Concentrated<LT> {
+    type Node = LT;
+
+    fn node(&self) -> LT {
+        self.node
+    }
+
+    fn local(&self) -> usize {
+        self.local as usize
+    }
+
+    fn attached(node: LT, local: usize) -> Self {
+        Concentrated::new(node, local as u16)
+    }
+}
+
+/// Routes packets to their node as `inner` would, and then out of the port of the endpoint they are for.
+pub(super) struct ConcentratedPolicy<PolicyType> {
+    pub(super) inner: PolicyType,
+    pub(super) concentration: usize,
+}
+
+impl<LT, PolicyType> Policy<LT> for ConcentratedPolicy<PolicyType>
+where
+    LT: NodeLocation,
+    PolicyType: Policy<LT::Node>,
+{
+    fn route(&mut self, target: &LT) -> FxHashSet<usize> {
+        let targets = self.inner.route(&target.node());
+        if !targets.contains(&0) {
+            return targets;
+        }
+        assert!(
+            target.local() < self.concentration,
+            "{} is not one of the {} endpoints at its node!",
+            target,
+            self.concentration
+        );
+        FxHashSet::from_iter([target.local()])
+    }
+}
+
+/// The channels at each endpoint of a built mesh or torus: a sender to inject packets into the network with, and a
+/// receiver for the packets addressed to it.
+pub struct MeshHandles<P: DAMType, LT: DAMType = Coord2D> {
+    pub(super) nodes: BTreeMap<LT, Endpoint<SimplePacket<LT, P>>>,
+}
+
+impl<P: DAMType, LT: DAMType + Ord + Display> MeshHandles<P, LT> {
+    /// Hands over the injection and ejection channels of `endpoint`, which can only be done once per endpoint.
+    pub fn take(&mut self, endpoint: LT) -> Endpoint<SimplePacket<LT, P>> {
+        self.nodes
+            .remove(&endpoint)
+            .unwrap_or_else(|| panic!("There are no handles left for {}!", endpoint))
+    }
+}
+
+impl<P: DAMType, LT: DAMType> IntoIterator for MeshHandles<P, LT> {
+    type Item = (LT, Endpoint<SimplePacket<LT, P>>);
+    type IntoIter = std::collections::btree_map::IntoIter<LT, Endpoint<SimplePacket<LT, P>>>;
+
+    /// Every endpoint's channels, in index order, and by endpoint within each node.
+    fn into_iter(self) -> Self::IntoIter {
+        self.nodes.into_iter()
+    }
+}
+
+/// Builds a `rows` by `cols` mesh with a [`SimpleSwitch`] at each node, routing XY, and adds everything to `ctx`.
+/// Returns the channels to attach each endpoint's sources and sinks to, with `cfg.concentration` endpoints at every
+/// node, which need [`Concentrated`] locations if there is more than one.
+///
+/// A packet which doesn't have to wait arrives `(hops + 1) * router_latency + (hops + 2) * link_latency` cycles after
+/// it was injected. The mesh shuts down once every injection sender has closed and every packet has been delivered.
+pub fn build_mesh_2d<'a, LT, P>(
+    ctx: &mut ProgramBuilder<'a>,
+    rows: u16,
+    cols: u16,
+    cfg: MeshConfig,
+) -> MeshHandles<P, LT>
+where
+    LT: NodeLocation<Node = Coord2D> + 'a,
+    P: DAMType + 'a,
+{
+    let bounds = Coord2D::new(cols, rows);
+    let ports = mesh_ports(cfg.concentration);
+    build_grid(
+        ctx,
+        bounds,
+        cfg,
+        |node| XyPolicy::new(node, cols, rows, ports),
+        |node, direction| node.step(direction, bounds),
+    )
+}
+
+/// Builds a `rows` by `cols` torus with a [`SimpleSwitch`] at each node, routing with [`TorusPolicy`], and adds
+/// everything to `ctx`. Returns the channels to attach each endpoint's sources and sinks to, laid out, timed and shut
+/// down as for [`build_mesh_2d`], with hops counted by [`crate::switches::torus_distance`].
+///
+/// Only dimensions of more than two nodes get wrap-around links. Along one of two nodes, both ways around lead to the
+/// same neighbor, so each switch sends both directions over its one link there.
+///
+/// There is a single plane of channels, so packets going around a ring can end up waiting on each other in a cycle:
+/// a torus with four or more nodes along a dimension can deadlock once its channels fill up. Dimensions of up to three
+/// nodes are safe, since no packet takes more than one hop along them.
+pub fn build_torus_2d<'a, LT, P>(
+    ctx: &mut ProgramBuilder<'a>,
+    rows: u16,
+    cols: u16,
+    cfg: MeshConfig,
+) -> MeshHandles<P, LT>
+where
+    LT: NodeLocation<Node = Coord2D> + 'a,
+    P: DAMType + 'a,
+{
+    let bounds = Coord2D::new(cols, rows);
+    let mesh_ports = mesh_ports(cfg.concentration);
+    build_grid(
+        ctx,
+        bounds,
+        cfg,
+        |node| {
+            let mut ports = mesh_ports;
+            if cols == 2 {
+                let toward = if node.x == 0 {
+                    Dir2D::PlusX
+                } else {
+                    Dir2D::MinusX
+                };
+                let link = ports.port(toward);
+                (ports.plus_x, ports.minus_x) = (link, link);
+            }
+            if rows == 2 {
+                let toward = if node.y == 0 {
+                    Dir2D::PlusY
+                } else {
+                    Dir2D::MinusY
+                };
+                let link = ports.port(toward);
+                (ports.plus_y, ports.minus_y) = (link, link);
+            }
+            TorusPolicy::new(node, cols, rows, ports)
+        },
+        |node, direction| {
+            let size = match direction {
+                Dir2D::PlusX | Dir2D::MinusX => cols,
+                Dir2D::PlusY | Dir2D::MinusY => rows,
+            };
+            match size {
+                0..=2 => node.step(direction, bounds),
+                _ => Some(node.wrapping_step(direction, bounds)),
+            }
+        },
+    )
+}
+
+/// Puts a switch routed by `make_policy` at every node within `bounds`, and links each one to the node `neighbor`
+/// gives for each direction, if any.
+fn build_grid<'a, LT, P, PolicyType>(
+    ctx: &mut ProgramBuilder<'a>,
+    bounds: Coord2D,
+    cfg: MeshConfig,
+    make_policy: impl Fn(Coord2D) -> PolicyType,
+    neighbor: impl Fn(Coord2D, Dir2D) -> Option<Coord2D>,
+) -> MeshHandles<P, LT>
+where
+    LT: NodeLocation<Node = Coord2D> + 'a,
+    P: DAMType + 'a,
+    PolicyType: Policy<Coord2D> + Sync + Send + 'a,
+{
+    assert!(
+        bounds.x > 0 && bounds.y > 0,
+        "Grids need at least one node!"
+    );
+    assert!(
+        cfg.concentration > 0,
+        "Switches need at least one endpoint!"
+    );
+    let ports = mesh_ports(cfg.concentration);
+    let positions =
+        (0..bounds.x as usize * bounds.y as usize).map(|index| Coord2D::from_index(index, bounds));
+    let tracker = Arc::new(NetworkTracker::default());
+
+    let mut switches = BTreeMap::new();
+    let mut nodes = BTreeMap::new();
+    for node in positions.clone() {
+        let policy = ConcentratedPolicy {
+            inner: make_policy(node),
+            concentration: cfg.concentration,
+        };
+        let mut switch = SimpleSwitch::new(policy, cfg.router_latency);
+        for local in 0..cfg.concentration {
+            let (port, handles) =
+                node_port(ctx, local, cfg.link_latency, cfg.channel_depth, &tracker);
+            switch.add_port(port);
+            nodes.insert(LT::attached(node, local), handles);
+        }
+        switches.insert(node, switch);
+    }
+
+    for node in positions {
+        for direction in Dir2D::ALL {
+            let neighbor = match neighbor(node, direction) {
+                Some(neighbor) => neighbor,
+                None => continue,
+            };
+            let (sending, receiving) = switch_ports(
+                ctx,
+                ports.port(direction),
+                ports.port(direction.opposite()),
+                cfg.link_latency,
+                cfg.channel_depth,
+                &tracker,
+            );
+            switches.get_mut(&node).unwrap().add_port(sending);
+            switches.get_mut(&neighbor).unwrap().add_port(receiving);
+        }
+    }
+    switches
+        .into_values()
+        .for_each(|switch| ctx.add_child(switch));
+    MeshHandles { nodes }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use dam::{
+        context_tools::ChannelElement, simulation::ProgramBuilder, structures::Time,
+        utility_contexts::*,
+    };
+
+    use crate::{
+        random::SplitMix64,
+        switches::{
+            routing::{Concentrated, Coord2D, SimplePacket},
+            torus_distance,
+        },
+    };
+
+    use super::{build_mesh_2d, build_torus_2d, MeshConfig};
+
+    const SIZE: u16 = 4;
+    const PACKETS_PER_NODE: u64 = 16;
+    // Longer than any packet takes to cross the tori under test, so that rounds don't run into each other.
+    const ROUND_LENGTH: u64 = 16;
+
+    #[test]
+    fn uniform_random_mesh_test() {
+        let mut ctx = ProgramBuilder::default();
+        let handles = build_mesh_2d::<Coord2D, u64>(&mut ctx, SIZE, SIZE, MeshConfig::default());
+
+        let bounds = Coord2D::new(SIZE, SIZE);
+        let arrivals = Arc::new(Mutex::new(vec![]));
+        let mut sent = vec![];
+        for (node, (inject, eject)) in handles {
+            let source = node.to_index(bounds) as u64;
+            let mut rng = SplitMix64::new(source);
+            let packets: Vec<_> = (0..PACKETS_PER_NODE)
+                .map(|sequence| SimplePacket {
+                    location: Coord2D::from_index(
+                        rng.below(SIZE as u64 * SIZE as u64) as usize,
+                        bounds,
+                    ),
+                    // Numbered so that every packet can be told apart.
+                    payload: source * PACKETS_PER_NODE + sequence,
+                })
+                .collect();
+            sent.extend(
+                packets
+                    .iter()
+                    .map(|packet| (packet.location, packet.payload)),
+            );
+            ctx.add_child(GeneratorContext::new(move || packets.into_iter(), inject));
+
+            let mut sink = FunctionContext::new();
+            eject.attach_receiver(&sink);
+            let log = arrivals.clone();
+            sink.set_run(move |time| {
+                while let Ok(element) = eject.dequeue(time) {
+                    log.lock().unwrap().push((node, element.data));
+                }
+            });
+            ctx.add_child(sink);
+        }
+        ctx.initialize(Default::default())
+            .unwrap()
+            .run(Default::default());
+
+        let arrivals = arrivals.lock().unwrap();
+        assert!(arrivals
+            .iter()
+            .all(|(node, packet)| packet.location == *node));
+        let mut received: Vec<_> = arrivals
+            .iter()
+            .map(|(node, packet)| (*node, packet.payload))
+            .collect();
+        received.sort();
+        sent.sort();
+        assert_eq!(received, sent);
+    }
+
+    /// Sends every node a packet from every other on a `rows` by `cols` torus, checking that each one arrives as many
+    /// hops after it was sent as the torus distance says. Each round shifts every packet by the same offset, so that
+    /// they all take routes of the same shape and never contend.
+    fn all_to_all_torus(rows: u16, cols: u16) {
+        let cfg = MeshConfig::default();
+        let mut ctx = ProgramBuilder::default();
+        let handles = build_torus_2d::<Coord2D, u64>(&mut ctx, rows, cols, cfg);
+
+        let bounds = Coord2D::new(cols, rows);
+        let nodes = rows as u64 * cols as u64;
+        let arrivals = Arc::new(Mutex::new(vec![]));
+        for (node, (inject, eject)) in handles {
+            let source = node.to_index(bounds) as u64;
+            let mut generator = FunctionContext::new();
+            inject.attach_sender(&generator);
+            generator.set_run(move |time| {
+                for round in 0..nodes {
+                    let offset = Coord2D::from_index(round as usize, bounds);
+                    let packet = SimplePacket {
+                        location: Coord2D::new(
+                            (node.x + offset.x) % cols,
+                            (node.y + offset.y) % rows,
+                        ),
+                        payload: round * nodes + source,
+                    };
+                    inject
+                        .enqueue(
+                            time,
+                            ChannelElement::new(Time::new(round * ROUND_LENGTH), packet),
+                        )
+                        .unwrap();
+                }
+            });
+            ctx.add_child(generator);
+
+            let mut sink = FunctionContext::new();
+            eject.attach_receiver(&sink);
+            let log = arrivals.clone();
+            sink.set_run(move |time| {
+                while let Ok(element) = eject.dequeue(time) {
+                    log.lock()
+                        .unwrap()
+                        .push((node, element.time.time(), element.data));
+                }
+            });
+            ctx.add_child(sink);
+        }
+        ctx.initialize(Default::default())
+            .unwrap()
+            .run(Default::default());
+
+        let arrivals = arrivals.lock().unwrap();
+        assert_eq!(arrivals.len() as u64, nodes * nodes);
+        for (node, arrived_at, packet) in arrivals.iter() {
+            assert_eq!(packet.location, *node);
+            let (round, source) = (packet.payload / nodes, packet.payload % nodes);
+            let hops = torus_distance(
+                Coord2D::from_index(source as usize, bounds),
+                *node,
+                cols,
+                rows,
+            ) as u64;
+            assert_eq!(
+                arrived_at - round * ROUND_LENGTH,
+                (hops + 1) * cfg.router_latency + (hops + 2) * cfg.link_latency
+            );
+        }
+    }
+
+    #[test]
+    fn all_to_all_torus_test() {
+        all_to_all_torus(SIZE, SIZE);
+    }
+
+    #[test]
+    fn narrow_torus_test() {
+        // Two rows, which share links both ways around, and three columns, which wrap around.
+        all_to_all_torus(2, 3);
+    }
+
+    #[test]
+    fn concentrated_mesh_test() {
+        const CONCENTRATION: u16 = 4;
+        let cfg = MeshConfig {
+            concentration: CONCENTRATION as usize,
+            ..Default::default()
+        };
+        let mut ctx = ProgramBuilder::default();
+        let handles = build_mesh_2d::<Concentrated<Coord2D>, u32>(&mut ctx, 2, 2, cfg);
+
+        let arrivals = Arc::new(Mutex::new(vec![]));
+        let mut endpoints = 0;
+        for (endpoint, (inject, eject)) in handles {
+            endpoints += 1;
+            let Concentrated { node, local } = endpoint;
+            // First to the next endpoint on the same switch, on cycle 1, and then to the same one on the far corner.
+            let packets = vec![
+                SimplePacket {
+                    location: Concentrated::new(node, (local + 1) % CONCENTRATION),
+                    payload: 0,
+                },
+                SimplePacket {
+                    location: Concentrated::new(Coord2D::new(1 - node.x, 1 - node.y), local),
+                    payload: 1,
+                },
+            ];
+            ctx.add_child(GeneratorContext::new(move || packets.into_iter(), inject));
+
+            let mut sink = FunctionContext::new();
+            eject.attach_receiver(&sink);
+            let log = arrivals.clone();
+            sink.set_run(move |time| {
+                while let Ok(element) = eject.dequeue(time) {
+                    log.lock()
+                        .unwrap()
+                        .push((endpoint, element.time.time(), element.data));
+                }
+            });
+            ctx.add_child(sink);
+        }
+        ctx.initialize(Default::default())
+            .unwrap()
+            .run(Default::default());
+
+        assert_eq!(endpoints, 16);
+        let arrivals = arrivals.lock().unwrap();
+        assert_eq!(arrivals.len(), 2 * 16);
+        for (endpoint, arrived_at, packet) in arrivals.iter() {
+            assert_eq!(packet.location, *endpoint);
+            if packet.payload == 0 {
+                // Only through the switch, and the links to and from it.
+                assert_eq!(arrived_at - 1, cfg.router_latency + 2 * cfg.link_latency);
+            }
+        }
+    }
+}
@@ -0,0 +1,231 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use dam::{context_tools::*, simulation::ProgramBuilder};
+use fxhash::FxHashSet;
+
+use crate::switches::{
+    routing::{Coord3D, Dir3D, Switch},
+    SimpleSwitch,
+};
+
+use super::{
+    link::{node_port, switch_ports, NetworkTracker},
+    mesh::ConcentratedPolicy,
+    MeshHandles, NodeLocation,
+};
+
+const LOCAL: usize = 0;
+
+/// The port every switch of a built 3D mesh reaches its neighbor in `direction` through, after the `concentration`
+/// ports for its endpoints.
+fn port(direction: Dir3D, concentration: usize) -> usize {
+    concentration
+        + match direction {
+            Dir3D::PlusX => 0,
+            Dir3D::MinusX => 1,
+            Dir3D::PlusY => 2,
+            Dir3D::MinusY => 3,
+            Dir3D::PlusZ => 4,
+            Dir3D::MinusZ => 5,
+        }
+}
+
+/// How a built 3D mesh is timed and buffered, with the links along each dimension timed separately.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Mesh3DConfig {
+    /// Cycles a packet spends on the link between a switch and each of its endpoints.
+    pub local_link_latency: u64,
+    /// Cycles a packet spends on each link along X.
+    pub x_link_latency: u64,
+    /// Cycles a packet spends on each link along Y.
+    pub y_link_latency: u64,
+    /// Cycles a packet spends on each link along Z, between layers.
+    pub z_link_latency: u64,
+    /// How many packets every channel in the mesh holds, including the ones handed back to attach to.
+    pub channel_depth: usize,
+    /// Cycles each switch takes to send a packet on.
+    pub router_latency: u64,
+    /// How many endpoints each switch has, on its first ports. Packets between two endpoints of the same switch never
+    /// leave it.
+    pub concentration: usize,
+}
+
+impl Default for Mesh3DConfig {
+    fn default() -> Self {
+        Self {
+            local_link_latency: 1,
+            x_link_latency: 1,
+            y_link_latency: 1,
+            z_link_latency: 1,
+            channel_depth: 4,
+            router_latency: 1,
+            concentration: 1,
+        }
+    }
+}
+
+impl Mesh3DConfig {
+    fn link_latency(&self, direction: Dir3D) -> u64 {
+        match direction {
+            Dir3D::PlusX | Dir3D::MinusX => self.x_link_latency,
+            Dir3D::PlusY | Dir3D::MinusY => self.y_link_latency,
+            Dir3D::PlusZ | Dir3D::MinusZ => self.z_link_latency,
+        }
+    }
+}
+
+/// Builds an `x` by `y` by `z` mesh with a [`SimpleSwitch`] at each node, and adds everything to `ctx`. Packets are
+/// routed in dimension order, correcting X, then Y, then Z, which can't deadlock. Returns the channels to attach each
+/// endpoint's sources and sinks to, with `cfg.concentration` endpoints at every node, which need
+/// [`Concentrated`](crate::switches::Concentrated) locations if there is more than one.
+///
+/// A packet which doesn't have to wait arrives `(hops + 1) * router_latency + 2 * local_link_latency` cycles after it
+/// was injected, plus the latency of every link it crossed along the way. The mesh shuts down once every injection
+/// sender has closed and every packet has been delivered.
+pub fn build_mesh_3d<'a, LT, P>(
+    ctx: &mut ProgramBuilder<'a>,
+    x: u16,
+    y: u16,
+    z: u16,
+    cfg: Mesh3DConfig,
+) -> MeshHandles<P, LT>
+where
+    LT: NodeLocation<Node = Coord3D> + 'a,
+    P: DAMType + 'a,
+{
+    assert!(x > 0 && y > 0 && z > 0, "Grids need at least one node!");
+    assert!(
+        cfg.concentration > 0,
+        "Switches need at least one endpoint!"
+    );
+    let concentration = cfg.concentration;
+    let bounds = Coord3D::new(x, y, z);
+    let positions =
+        (0..x as usize * y as usize * z as usize).map(|index| Coord3D::from_index(index, bounds));
+    let tracker = Arc::new(NetworkTracker::default());
+
+    let mut switches = BTreeMap::new();
+    let mut nodes = BTreeMap::new();
+    for node in positions.clone() {
+        let policy = move |destination: &Coord3D| {
+            let direction = if destination.x != node.x {
+                Some(match destination.x > node.x {
+                    true => Dir3D::PlusX,
+                    false => Dir3D::MinusX,
+                })
+            } else if destination.y != node.y {
+                Some(match destination.y > node.y {
+                    true => Dir3D::PlusY,
+                    false => Dir3D::MinusY,
+                })
+            } else if destination.z != node.z {
+                Some(match destination.z > node.z {
+                    true => Dir3D::PlusZ,
+                    false => Dir3D::MinusZ,
+                })
+            } else {
+                None
+            };
+            FxHashSet::from_iter([
+                direction.map_or(LOCAL, |direction| port(direction, concentration))
+            ])
+        };
+        let policy = ConcentratedPolicy {
+            inner: policy,
+            concentration,
+        };
+        let mut switch = SimpleSwitch::new(policy, cfg.router_latency);
+        for local in 0..concentration {
+            let (port, handles) = node_port(
+                ctx,
+                local,
+                cfg.local_link_latency,
+                cfg.channel_depth,
+                &tracker,
+            );
+            switch.add_port(port);
+            nodes.insert(LT::attached(node, local), handles);
+        }
+        switches.insert(node, switch);
+    }
+
+    for node in positions {
+        for (direction, neighbor) in node.neighbors(bounds) {
+            let (sending, receiving) = switch_ports(
+                ctx,
+                port(direction, concentration),
+                port(direction.opposite(), concentration),
+                cfg.link_latency(direction),
+                cfg.channel_depth,
+                &tracker,
+            );
+            switches.get_mut(&node).unwrap().add_port(sending);
+            switches.get_mut(&neighbor).unwrap().add_port(receiving);
+        }
+    }
+    switches
+        .into_values()
+        .for_each(|switch| ctx.add_child(switch));
+    MeshHandles { nodes }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use dam::{simulation::ProgramBuilder, utility_contexts::*};
+
+    use crate::switches::routing::{Coord3D, SimplePacket};
+
+    use super::{build_mesh_3d, Mesh3DConfig};
+
+    #[test]
+    fn corner_to_corner_mesh_3d_test() {
+        // Vertical links are the fastest, as they would be for a stack.
+        let cfg = Mesh3DConfig {
+            x_link_latency: 3,
+            y_link_latency: 2,
+            z_link_latency: 1,
+            ..Default::default()
+        };
+        let mut ctx = ProgramBuilder::default();
+        let handles = build_mesh_3d::<Coord3D, u32>(&mut ctx, 3, 3, 2, cfg);
+
+        let (source, destination) = (Coord3D::new(0, 0, 0), Coord3D::new(2, 2, 1));
+        let arrivals = Arc::new(Mutex::new(vec![]));
+        for (node, (inject, eject)) in handles {
+            let packets = match node == source {
+                true => vec![SimplePacket {
+                    location: destination,
+                    payload: 0,
+                }],
+                false => vec![],
+            };
+            ctx.add_child(GeneratorContext::new(move || packets.into_iter(), inject));
+
+            let mut sink = FunctionContext::new();
+            eject.attach_receiver(&sink);
+            let log = arrivals.clone();
+            sink.set_run(move |time| {
+                while let Ok(element) = eject.dequeue(time) {
+                    log.lock().unwrap().push((node, element.time.time()));
+                }
+            });
+            ctx.add_child(sink);
+        }
+        ctx.initialize(Default::default())
+            .unwrap()
+            .run(Default::default());
+
+        let arrivals = arrivals.lock().unwrap();
+        assert_eq!(arrivals.len(), 1);
+        let (node, arrived_at) = arrivals[0];
+        assert_eq!(node, destination);
+        // Two hops along X, two along Y, and one along Z, through six switches, after being sent on cycle 1.
+        let hops = 2 * cfg.x_link_latency + 2 * cfg.y_link_latency + cfg.z_link_latency;
+        assert_eq!(
+            arrived_at - 1,
+            6 * cfg.router_latency + 2 * cfg.local_link_latency + hops
+        );
+    }
+}
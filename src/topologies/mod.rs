@@ -0,0 +1,23 @@
+pub mod butterfly;
+pub mod crossbar;
+pub mod dragonfly;
+pub mod fat_tree;
+pub mod graph;
+pub mod hypercube;
+pub mod link;
+pub mod mesh;
+pub mod mesh_3d;
+pub mod ring;
+pub mod tree;
+
+pub use butterfly::{build_butterfly, butterfly_conflicts, ButterflyHandles};
+pub use crossbar::{build_crossbar, build_crossbar_with};
+pub use dragonfly::{build_dragonfly, build_dragonfly_with, DragonflyConfig};
+pub use fat_tree::{build_fat_tree, build_fat_tree_with, FatTreeConfig};
+pub use graph::{build_from_edges, LinkConfig, NodeId, UnreachableEndpoints};
+pub use hypercube::{build_hypercube, build_hypercube_with};
+pub use link::{Endpoint, Link, NetworkTracker};
+pub use mesh::{build_mesh_2d, build_torus_2d, MeshConfig, MeshHandles, NodeLocation};
+pub use mesh_3d::{build_mesh_3d, Mesh3DConfig};
+pub use ring::{build_bidirectional_ring, build_ring};
+pub use tree::{build_tree, build_tree_with, TreeConfig};
@@ -0,0 +1,195 @@
+use std::sync::Arc;
+
+use dam::{context_tools::*, simulation::ProgramBuilder};
+use fxhash::{FxHashMap, FxHashSet};
+
+use crate::switches::{
+    routing::{SimplePacket, Switch},
+    SimpleSwitch,
+};
+
+use super::{
+    link::{node_port, switch_ports, Endpoint, NetworkTracker},
+    MeshConfig,
+};
+
+/// Builds a ring of `n` [`SimpleSwitch`]es, numbered `0..n`, where packets only ever go clockwise, from each node to
+/// the next. Each node has `cfg.concentration` endpoints, where endpoint `local` of node `node` is destination
+/// `node * concentration + local`. Returns each endpoint's injection and ejection channels, in that order.
+///
+/// Each hop takes `router_latency + link_latency` cycles, and a packet which doesn't have to wait arrives
+/// `(hops + 1) * router_latency + (hops + 2) * link_latency` cycles after it was injected. The ring shuts down once
+/// every injection sender has closed and every packet has been delivered. Packets can go most of the way around, so
+/// once the channels fill up, they can end up waiting on each other in a cycle.
+///
+/// For a ring of stops which arbitrate between the ring and their node, see [`crate::switches::build_ring`].
+pub fn build_ring<'a, P: DAMType + 'a>(
+    ctx: &mut ProgramBuilder<'a>,
+    n: usize,
+    cfg: MeshConfig,
+) -> Vec<Endpoint<SimplePacket<usize, P>>> {
+    build(ctx, n, cfg, false)
+}
+
+/// Builds a ring of `n` [`SimpleSwitch`]es, numbered `0..n`, with links going both ways around. Packets take
+/// whichever way is shorter, and go clockwise when both are the same length. Returns each endpoint's injection and
+/// ejection channels, laid out, timed and shut down as for [`build_ring`].
+pub fn build_bidirectional_ring<'a, P: DAMType + 'a>(
+    ctx: &mut ProgramBuilder<'a>,
+    n: usize,
+    cfg: MeshConfig,
+) -> Vec<Endpoint<SimplePacket<usize, P>>> {
+    build(ctx, n, cfg, true)
+}
+
+fn build<'a, P: DAMType + 'a>(
+    ctx: &mut ProgramBuilder<'a>,
+    n: usize,
+    cfg: MeshConfig,
+    bidirectional: bool,
+) -> Vec<Endpoint<SimplePacket<usize, P>>> {
+    assert!(n > 0, "Rings need at least one node!");
+    let concentration = cfg.concentration;
+    assert!(concentration > 0, "Switches need at least one endpoint!");
+    // Each node's endpoints come first, and then its links.
+    let (clockwise_port, counter_clockwise_port) = (concentration, concentration + 1);
+    let tracker = Arc::new(NetworkTracker::default());
+
+    let mut switches = vec![];
+    let mut endpoints = vec![];
+    for node in 0..n {
+        let table: FxHashMap<_, _> = (0..n * concentration)
+            .map(|destination| {
+                let clockwise = (destination / concentration + n - node) % n;
+                let port = match clockwise {
+                    0 => destination % concentration,
+                    _ if !bidirectional || clockwise <= n - clockwise => clockwise_port,
+                    _ => counter_clockwise_port,
+                };
+                (destination, FxHashSet::from_iter([port]))
+            })
+            .collect();
+        let mut switch = SimpleSwitch::new(table, cfg.router_latency);
+        for local in 0..concentration {
+            let (port, handles) =
+                node_port(ctx, local, cfg.link_latency, cfg.channel_depth, &tracker);
+            switch.add_port(port);
+            endpoints.push(handles);
+        }
+        switches.push(switch);
+    }
+
+    // A single node has nowhere to send anything.
+    if n > 1 {
+        for node in 0..n {
+            let next = (node + 1) % n;
+            let mut links = vec![(node, next, clockwise_port, counter_clockwise_port)];
+            if bidirectional {
+                links.push((next, node, counter_clockwise_port, clockwise_port));
+            }
+            for (from, to, from_port, to_port) in links {
+                let (sending, receiving) = switch_ports(
+                    ctx,
+                    from_port,
+                    to_port,
+                    cfg.link_latency,
+                    cfg.channel_depth,
+                    &tracker,
+                );
+                switches[from].add_port(sending);
+                switches[to].add_port(receiving);
+            }
+        }
+    }
+    switches
+        .into_iter()
+        .for_each(|switch| ctx.add_child(switch));
+    endpoints
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use dam::{simulation::ProgramBuilder, utility_contexts::*};
+
+    use crate::switches::routing::SimplePacket;
+
+    use super::{build_bidirectional_ring, build_ring, Endpoint, MeshConfig};
+
+    const NUM_NODES: usize = 8;
+
+    /// Sends a single packet from the first endpoint to the last one over `endpoints`, and returns how long it took to
+    /// get there.
+    fn send_to_last(
+        mut ctx: ProgramBuilder,
+        endpoints: Vec<Endpoint<SimplePacket<usize, u32>>>,
+    ) -> u64 {
+        let last = endpoints.len() - 1;
+        let arrivals = Arc::new(Mutex::new(vec![]));
+        for (endpoint, (inject, eject)) in endpoints.into_iter().enumerate() {
+            let packets = match endpoint {
+                0 => vec![SimplePacket {
+                    location: last,
+                    payload: 0,
+                }],
+                _ => vec![],
+            };
+            ctx.add_child(GeneratorContext::new(move || packets.into_iter(), inject));
+
+            let mut sink = FunctionContext::new();
+            eject.attach_receiver(&sink);
+            let log = arrivals.clone();
+            sink.set_run(move |time| {
+                while let Ok(element) = eject.dequeue(time) {
+                    log.lock().unwrap().push((endpoint, element.time));
+                }
+            });
+            ctx.add_child(sink);
+        }
+        ctx.initialize(Default::default())
+            .unwrap()
+            .run(Default::default());
+
+        let arrivals = arrivals.lock().unwrap();
+        assert_eq!(arrivals.len(), 1);
+        let (endpoint, arrived_at) = arrivals[0];
+        assert_eq!(endpoint, last);
+        // Generators send their first element on cycle 1.
+        arrived_at.time() - 1
+    }
+
+    /// How long a packet takes to go `hops` hops, with nothing else in its way.
+    fn uncontended(hops: u64, cfg: MeshConfig) -> u64 {
+        (hops + 1) * cfg.router_latency + (hops + 2) * cfg.link_latency
+    }
+
+    #[test]
+    fn unidirectional_ring_test() {
+        let cfg = MeshConfig::default();
+        let mut ctx = ProgramBuilder::default();
+        let nodes = build_ring(&mut ctx, NUM_NODES, cfg);
+        assert_eq!(send_to_last(ctx, nodes), uncontended(7, cfg));
+    }
+
+    #[test]
+    fn bidirectional_ring_test() {
+        let cfg = MeshConfig::default();
+        let mut ctx = ProgramBuilder::default();
+        let nodes = build_bidirectional_ring(&mut ctx, NUM_NODES, cfg);
+        assert_eq!(send_to_last(ctx, nodes), uncontended(1, cfg));
+    }
+
+    #[test]
+    fn concentrated_ring_test() {
+        let cfg = MeshConfig {
+            concentration: 2,
+            ..Default::default()
+        };
+        let mut ctx = ProgramBuilder::default();
+        let endpoints = build_ring(&mut ctx, NUM_NODES, cfg);
+        assert_eq!(endpoints.len(), 2 * NUM_NODES);
+        // The last endpoint is the second one on the last node.
+        assert_eq!(send_to_last(ctx, endpoints), uncontended(7, cfg));
+    }
+}
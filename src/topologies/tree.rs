@@ -0,0 +1,233 @@
+use std::sync::Arc;
+
+use dam::{context_tools::*, simulation::ProgramBuilder};
+use fxhash::{FxHashMap, FxHashSet};
+
+use crate::switches::{
+    routing::{SimplePacket, Switch},
+    updown_tables, SimpleSwitch, TreeTopo,
+};
+
+use super::link::{node_port, switch_ports, Endpoint, NetworkTracker};
+
+/// How a built tree is timed and buffered, level by level. Level 0 is the root, so entry `l` of each list goes for the
+/// links between the switches of level `l` and their children, and levels past the end of a list use its last entry.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TreeConfig {
+    /// Cycles a packet spends on each link below every level.
+    pub link_latencies: Vec<u64>,
+    /// How many packets every channel below each level holds, including the ones handed back to attach to.
+    pub channel_depths: Vec<usize>,
+    /// Cycles each switch takes to send a packet on.
+    pub router_latency: u64,
+}
+
+impl Default for TreeConfig {
+    fn default() -> Self {
+        Self {
+            link_latencies: vec![1],
+            channel_depths: vec![4],
+            router_latency: 1,
+        }
+    }
+}
+
+impl TreeConfig {
+    /// The latency and depth of the links below the switches of `level`.
+    fn level(&self, level: usize) -> (u64, usize) {
+        let index = |len: usize| level.min(len - 1);
+        (
+            self.link_latencies[index(self.link_latencies.len())],
+            self.channel_depths[index(self.channel_depths.len())],
+        )
+    }
+}
+
+/// Builds a tree of [`SimpleSwitch`]es, `depth` links from the root down to each leaf, where every switch has
+/// `branching` children, and the children of the bottom switches are the `branching^depth` leaves. Returns each leaf's
+/// injection and ejection channels, from left to right; packets are addressed by the same index.
+///
+/// Each switch has its children on ports `0..branching`, from left to right, and its parent on port `branching`.
+/// Packets are routed up*/down*, as by [`updown_tables`], so they only go as high as the closest switch above both
+/// ends. The tree shuts down once every injection sender has closed and every packet has been delivered.
+pub fn build_tree<'a, P: DAMType + 'a>(
+    ctx: &mut ProgramBuilder<'a>,
+    branching: usize,
+    depth: u32,
+    cfg: TreeConfig,
+) -> Vec<Endpoint<SimplePacket<usize, P>>> {
+    let router_latency = cfg.router_latency;
+    build_tree_with(ctx, branching, depth, cfg, |table| {
+        SimpleSwitch::new(table, router_latency)
+    })
+}
+
+/// Builds a tree as [`build_tree`] does, out of whichever switches `make_switch` builds from each one's routing table.
+/// The switches set their own latency, so `cfg.router_latency` goes unused.
+pub fn build_tree_with<'a, T, S>(
+    ctx: &mut ProgramBuilder<'a>,
+    branching: usize,
+    depth: u32,
+    cfg: TreeConfig,
+    mut make_switch: impl FnMut(FxHashMap<usize, FxHashSet<usize>>) -> S,
+) -> Vec<Endpoint<T>>
+where
+    T: DAMType + 'a,
+    S: Switch<T> + Context + 'a,
+{
+    assert!(
+        branching > 0 && depth > 0,
+        "Trees need at least one switch with at least one child!"
+    );
+    assert!(
+        !cfg.link_latencies.is_empty() && !cfg.channel_depths.is_empty(),
+        "Trees need a link latency and a channel depth for at least one level!"
+    );
+    let up_port = branching;
+    // Switches are numbered level by level from the root down, and from left to right within each level.
+    let first_on_level: Vec<_> = (0..=depth)
+        .scan(0, |first, level| {
+            let start = *first;
+            *first += branching.pow(level);
+            Some(start)
+        })
+        .collect();
+    let bottom = depth as usize - 1;
+    let num_switches = first_on_level[depth as usize];
+
+    let mut tree = TreeTopo::new();
+    for level in 1..depth as usize {
+        for index in 0..branching.pow(level as u32) {
+            tree = tree.with_link(
+                first_on_level[level] + index,
+                up_port,
+                first_on_level[level - 1] + index / branching,
+                index % branching,
+            );
+        }
+    }
+    let leaves: Vec<_> = (0..branching.pow(depth))
+        .map(|leaf| {
+            (
+                leaf,
+                first_on_level[bottom] + leaf / branching,
+                leaf % branching,
+            )
+        })
+        .collect();
+    let mut tables = updown_tables(&tree, &leaves);
+
+    let mut switches: Vec<_> = (0..num_switches)
+        .map(|switch| make_switch(tables.remove(&switch).unwrap()))
+        .collect();
+    let tracker = Arc::new(NetworkTracker::default());
+    let (leaf_latency, leaf_depth) = cfg.level(bottom);
+    let endpoints = leaves
+        .iter()
+        .map(|(_, switch, port)| {
+            let (port, endpoint) = node_port(ctx, *port, leaf_latency, leaf_depth, &tracker);
+            switches[*switch].add_port(port);
+            endpoint
+        })
+        .collect();
+    for link in tree.links() {
+        // The parent's level, which the link hangs below.
+        let level = first_on_level.partition_point(|first| *first <= link.parent) - 1;
+        let (latency, channel_depth) = cfg.level(level);
+        for (from, from_port, to, to_port) in [
+            (link.child, link.up_port, link.parent, link.down_port),
+            (link.parent, link.down_port, link.child, link.up_port),
+        ] {
+            let (sending, receiving) =
+                switch_ports(ctx, from_port, to_port, latency, channel_depth, &tracker);
+            switches[from].add_port(sending);
+            switches[to].add_port(receiving);
+        }
+    }
+    switches
+        .into_iter()
+        .for_each(|switch| ctx.add_child(switch));
+    endpoints
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use dam::{simulation::ProgramBuilder, utility_contexts::*};
+    use fxhash::FxHashSet;
+
+    use crate::switches::{
+        routing::{HoppedPacket, SimplePacket},
+        SimpleSwitch,
+    };
+
+    use super::{build_tree_with, TreeConfig};
+
+    const BRANCHING: usize = 2;
+    const DEPTH: u32 = 3;
+    const LEAVES: usize = 8;
+
+    type TestPacket = HoppedPacket<SimplePacket<usize, u32>>;
+
+    /// Counts the switches a packet has been through.
+    fn count_hop(packet: &mut TestPacket, _targets: &FxHashSet<usize>) {
+        packet.hops += 1;
+    }
+
+    #[test]
+    fn binary_tree_test() {
+        // Slower links nearer the root.
+        let cfg = TreeConfig {
+            link_latencies: vec![3, 2, 1],
+            ..Default::default()
+        };
+        let router_latency = cfg.router_latency;
+        let mut ctx = ProgramBuilder::default();
+        let leaves = build_tree_with(&mut ctx, BRANCHING, DEPTH, cfg, |table| {
+            SimpleSwitch::new(table, router_latency).with_on_forward(count_hop)
+        });
+        assert_eq!(leaves.len(), LEAVES);
+
+        let arrivals = Arc::new(Mutex::new(vec![]));
+        for (source, (inject, eject)) in leaves.into_iter().enumerate() {
+            let packets: Vec<_> = (1..LEAVES)
+                .map(|offset| HoppedPacket {
+                    packet: SimplePacket {
+                        location: (source + offset) % LEAVES,
+                        payload: source as u32,
+                    },
+                    hops: 0,
+                })
+                .collect();
+            ctx.add_child(GeneratorContext::new(move || packets.into_iter(), inject));
+
+            let mut sink = FunctionContext::new();
+            eject.attach_receiver(&sink);
+            let log = arrivals.clone();
+            sink.set_run(move |time| {
+                while let Ok(element) = eject.dequeue(time) {
+                    log.lock().unwrap().push((source, element.data));
+                }
+            });
+            ctx.add_child(sink);
+        }
+        ctx.initialize(Default::default())
+            .unwrap()
+            .run(Default::default());
+
+        let arrivals = arrivals.lock().unwrap();
+        assert_eq!(arrivals.len(), LEAVES * (LEAVES - 1));
+        for (leaf, packet) in arrivals.iter() {
+            let source = packet.packet.payload as usize;
+            assert_eq!(packet.packet.location, *leaf);
+            // How many levels up the closest switch above both leaves is, counting their shared parent as the first.
+            let up = (1..=DEPTH)
+                .find(|up| source / BRANCHING.pow(*up) == leaf / BRANCHING.pow(*up))
+                .unwrap();
+            // Up to that switch and back down, so siblings only go through their parent, and leaves in different halves
+            // of the tree go through the root.
+            assert_eq!(packet.hops as u32, 2 * up - 1);
+        }
+    }
+}